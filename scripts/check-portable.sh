@@ -0,0 +1,12 @@
+#!/bin/sh
+# Checks that the portable part of the library (the protocol and networking
+# layers, see the module list in src/lib.rs) still builds on a unix platform
+# other than Linux, where the client, server, driver and hwdb modules are
+# compiled out. Nothing has to be installed for the target besides its
+# standard library, `cargo check` doesn't link.
+set -eu
+
+target="${1:-x86_64-apple-darwin}"
+
+rustup target add "$target"
+cargo check -p usbip-rs --lib --target "$target"
@@ -1,33 +1,72 @@
 use std::{
+    fmt,
     io::{self, ErrorKind},
+    net::TcpStream,
     os::fd::AsRawFd,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    UsbDeviceInfo, UsbDeviceInfoValidationError,
+    UsbDeviceInfo, UsbDeviceInfoValidationError, UsbSpeed,
+    client::{
+        MAX_PROTOCOL_RETRIES,
+        detach::{self, detach_device, find_attached_port},
+        list::{self, RemoteExportedDevice, list_remote_exported_devices_with_options},
+        read_only::{self, ReadOnlyOptions, make_read_only_with_options},
+    },
     drivers::vhci::{
-        Error as VhciHcdError, VhciHcd,
-        state::{ConnectionRecord, FsStateError, save_connection_record},
+        Error as VhciHcdError, HubSpeed, VhciDeviceStatus, VhciHcd,
+        state::{ConnectionRecord, FsStateError, is_valid_alias, save_connection_record},
     },
-    net::UsbIpSocket,
+    net::{ConnectOptions, NetworkError, UsbIpSocket},
     proto::{
         ImportReply, ImportRequest, OperationError, OperationKind, SYSFS_BUS_ID_SIZE,
         char_buf::CharBuf,
     },
+    url::{UrlParseError, parse_usbip_url},
 };
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Network connection failed ({0})")]
-    NetworkIo(io::Error),
+    NetworkIo(NetworkError),
 
     #[error("Provided bus ID is too long (max size is {SYSFS_BUS_ID_SIZE} bytes)")]
     BusIdTooLong,
     #[error("Bus ID returned by the server did not match the one that was sent")]
     BusIdMismatch,
 
+    #[error("Failed to list the devices exported by the server ({0})")]
+    Listing(list::Error),
+    #[error("No exported device with device number {0} found on the server")]
+    DeviceNumberNotFound(DeviceNumber),
+
+    #[error("Attaching the device did not finish before the deadline")]
+    Timeout,
     #[error("Maximum number of attempts exceeded while waiting for a free port")]
     MaxAttemptsExceeded,
+    #[error("The device was attached to port {0}, which is out of the range of port numbers")]
+    PortOutOfRange(u32),
+    #[error(
+        "Device attached to port {port} did not come up as expected (expected speed {expected:?}, {})",
+        format_actual_speed(actual)
+    )]
+    AttachVerificationFailed {
+        port: u32,
+        expected: UsbSpeed,
+        actual: Option<UsbSpeed>,
+    },
+
+    #[error("Device `{0}` is already exported to another client")]
+    DeviceBusy(String),
+    #[error(
+        "Device `{0}` is in an error state on the server (try unplugging and replugging it on the host)"
+    )]
+    DeviceInErrorState(String),
+    #[error("Device `{0}` does not exist on the server or is not exported (see `usbip list -r`)")]
+    NoSuchDeviceOnServer(String),
 
     #[error("Failed to parse PDU: {0}")]
     Protocol(#[from] UsbDeviceInfoValidationError),
@@ -36,50 +75,718 @@ pub enum Error {
     #[error(transparent)]
     VhciHcdDriver(#[from] VhciHcdError),
 
-    #[error(transparent)]
-    FsState(#[from] FsStateError),
+    #[error("Failed to make the device read-only, so it was detached again ({0})")]
+    ReadOnly(read_only::Error),
+
+    #[error("Invalid alias `{0}` (must not be empty or contain whitespace)")]
+    InvalidAlias(String),
+    #[error("The alias `{0}` is already used by the device attached to port {1}")]
+    AliasInUse(String, u16),
+    #[error("An alias can only be given when the connection is recorded")]
+    AliasNotRecorded,
+    #[error("Failed to check whether the alias is already in use ({0})")]
+    CheckingAlias(detach::Error),
+    #[error(
+        "The device was attached to port {port}, but its connection record could not be saved, so it was detached again ({error})"
+    )]
+    RecordNotSaved { port: u32, error: FsStateError },
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::NetworkIo(e.into())
+    }
+}
+
+fn format_actual_speed(actual: &Option<UsbSpeed>) -> String {
+    match actual {
+        Some(speed) => format!("got {speed:?}"),
+        None => "but the port never became used".into(),
+    }
+}
+
+/// Options for [`attach_device_with_options`]
+#[derive(Debug, Clone)]
+pub struct AttachOptions {
+    /// Options of the connection which is handed off to `vhci_hcd`
+    pub connect: ConnectOptions,
+    /// After attaching, wait for the port to show up as used and check that
+    /// the kernel enumerated the device at the requested speed. This costs an
+    /// extra round-trip through sysfs and udev, so it is off by default.
+    pub verify: bool,
+    /// After attaching, block writes to the device by making its disks
+    /// read-only. This is only supported for mass storage devices, any other
+    /// device is detached again (see [`read_only`] for the caveats).
+    pub read_only: bool,
+    /// How long to wait for the disks of the device when
+    /// [`read_only`](AttachOptions::read_only) is set
+    pub read_only_options: ReadOnlyOptions,
+    /// Save a connection record for the port, which is what lets `usbip port`
+    /// show the remote host of the device. Failing to save it does not fail
+    /// the attach (the device is already attached by then), it is only
+    /// logged, unless an [`alias`](AttachOptions::alias) was given (see
+    /// [`Error::RecordNotSaved`]). Can be turned off where the state directory
+    /// isn't writable or the record isn't needed (e.g. in ephemeral
+    /// containers).
+    pub record_state: bool,
+    /// Point in time by which the device must be attached, covering name
+    /// resolution, connecting, the import request, waiting for a free port and
+    /// verifying the device (but not making the device read-only). Attaching
+    /// fails with [`Error::Timeout`] once it has passed, and a device which was
+    /// already attached by then is detached again. Name resolution itself
+    /// can't be interrupted, so the deadline is only checked once it is done.
+    ///
+    /// When attaching by device number, the listing which resolves the number
+    /// is not bounded by the deadline (it uses the short lived connection
+    /// timeouts instead), it is only checked once the listing is done.
+    pub deadline: Option<Instant>,
+    /// Name to save in the connection record of the port, by which the device
+    /// can be detached later (see
+    /// [`detach_device_by_alias`](crate::client::detach::detach_device_by_alias)).
+    /// Must be unique among the attached devices and may not contain
+    /// whitespace. Requires [`AttachOptions::record_state`].
+    pub alias: Option<String>,
+    /// Number of times connecting and importing is started over on a fresh
+    /// connection after the server sent a reply which could not be decoded
+    /// (see [`Error::is_protocol_error`]). Network errors are not retried.
+    /// Only the exchange with the server is retried, nothing that happens
+    /// after the connection was handed off to `vhci_hcd` (like
+    /// [`verify`](AttachOptions::verify)) is, so a device is never attached
+    /// twice. Capped at [`MAX_PROTOCOL_RETRIES`]. Off (0) by default.
+    pub protocol_retries: u32,
+    /// Number of free ports tried before failing with
+    /// [`Error::MaxAttemptsExceeded`], in case another process attaches a
+    /// device to the free port first. 8 by default.
+    pub port_attempts: u32,
+    /// Number of times the port is checked while waiting for the device to
+    /// come up when [`verify`](AttachOptions::verify) is set. 10 by default.
+    pub verify_attempts: u32,
+    /// How long to wait between those checks. 100ms by default.
+    pub verify_retry_delay: Duration,
+}
+
+impl Default for AttachOptions {
+    fn default() -> Self {
+        Self {
+            connect: ConnectOptions::default(),
+            verify: false,
+            read_only: false,
+            read_only_options: ReadOnlyOptions::default(),
+            record_state: true,
+            deadline: None,
+            alias: None,
+            protocol_retries: 0,
+            port_attempts: 8,
+            verify_attempts: 10,
+            verify_retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl Error {
+    /// Returns whether the error was caused by a reply which could not be
+    /// decoded, which may succeed when the operation is tried again
+    pub fn is_protocol_error(&self) -> bool {
+        match self {
+            Self::Protocol(_) | Self::BusIdMismatch => true,
+            Self::Operation(e) => e.is_decode_error(),
+            Self::Listing(e) => e.is_protocol_error(),
+            _ => false,
+        }
+    }
 }
 
 pub fn attach_device(host: &str, bus_id: &str) -> Result<u32, Error> {
-    let mut socket = UsbIpSocket::connect_host_and_port(host, UsbIpSocket::DEFAULT_PORT)
-        .map_err(Error::NetworkIo)?;
+    attach_device_with_options(
+        host,
+        UsbIpSocket::DEFAULT_PORT,
+        bus_id,
+        &AttachOptions::default(),
+    )
+}
+
+/// Same as [`attach_device`], but fails with [`Error::Timeout`] if the device
+/// is not attached by the given deadline (see [`AttachOptions::deadline`])
+pub fn attach_device_with_deadline(
+    host: &str,
+    port: u16,
+    bus_id: &str,
+    deadline: Instant,
+) -> Result<u32, Error> {
+    attach_device_with_options(
+        host,
+        port,
+        bus_id,
+        &AttachOptions {
+            deadline: Some(deadline),
+            ..Default::default()
+        },
+    )
+}
+
+/// Same as [`attach_device`], but allows specifying the server's TCP port and
+/// configuring the attachment (e.g. keepalive parameters of the connection)
+pub fn attach_device_with_options(
+    host: &str,
+    port: u16,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<u32, Error> {
+    if let Some(alias) = &options.alias {
+        check_alias(alias, options.record_state)?;
+    }
+
+    let max_attempts = options.protocol_retries.min(MAX_PROTOCOL_RETRIES);
+    let mut attempt = 0;
+
+    // only the exchange with the server is retried, once the connection has
+    // been handed off to vhci_hcd the device may already be attached
+    let (mut socket, remote_device) = loop {
+        match connect_and_request_import(host, port, bus_id, options) {
+            Err(e) if e.is_protocol_error() && attempt < max_attempts => {
+                attempt += 1;
+                tracing::warn!(
+                    "importing device `{bus_id}` from {host}:{port} failed ({e}), retrying ({attempt}/{max_attempts})"
+                );
+            }
+            result => break result?,
+        }
+    };
+
+    let rh_port = timed(tracing::debug_span!("import_device"), || {
+        import_device(&mut socket, &remote_device, options)
+    })?;
+
+    tracing::info!("device imported with port: {rh_port}");
+
+    record_connection(rh_port, host, port, bus_id, options, save_connection_record)?;
+
+    if options.read_only {
+        apply_read_only(rh_port, &options.read_only_options)?;
+    }
+
+    Ok(rh_port)
+}
+
+/// Saves the connection record of the port if
+/// [`record_state`](AttachOptions::record_state) is set, using `save` to write
+/// the record
+fn record_connection(
+    rh_port: u32,
+    host: &str,
+    port: u16,
+    bus_id: &str,
+    options: &AttachOptions,
+    save: impl FnOnce(u32, ConnectionRecord) -> Result<(), FsStateError>,
+) -> Result<(), Error> {
+    if !options.record_state {
+        return Ok(());
+    }
+
+    let record = ConnectionRecord {
+        host: host.into(),
+        port,
+        bus_id: bus_id.into(),
+        alias: options.alias.clone(),
+    };
+
+    match save(rh_port, record) {
+        Ok(()) => tracing::debug!("connection recorded"),
+        // without the record the alias is lost, so the device could only be
+        // found by its port
+        Err(error) if options.alias.is_some() => {
+            tracing::warn!("detaching port {rh_port} since its alias could not be recorded");
+            detach_after_failure(rh_port);
+
+            return Err(Error::RecordNotSaved {
+                port: rh_port,
+                error,
+            });
+        }
+        Err(e) => tracing::warn!(
+            "device attached to port {rh_port}, but its connection could not be recorded: {e}"
+        ),
+    }
+
+    Ok(())
+}
+
+/// Connects to the server and requests the import of the device over a fresh
+/// connection, returning the connection to hand off to `vhci_hcd`
+fn connect_and_request_import(
+    host: &str,
+    port: u16,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<(UsbIpSocket, UsbDeviceInfo), Error> {
+    let deadline = options.deadline;
+
+    let mut socket = timed(
+        tracing::debug_span!("connect", host, port),
+        || match deadline {
+            Some(deadline) => connect_before(host, port, &options.connect, deadline),
+            None => UsbIpSocket::connect_host_and_port_with_options(host, port, &options.connect)
+                .map_err(Error::from),
+        },
+    )?;
+
+    let remote_device = timed(tracing::debug_span!("request_import", bus_id), || {
+        request_import(&mut socket, bus_id, deadline)
+    })
+    .map_err(|e| deadline_error(e, deadline))?;
+
+    Ok((socket, remote_device))
+}
+
+/// Makes sure the alias can be recorded and is not used by another attached
+/// device
+fn check_alias(alias: &str, record_state: bool) -> Result<(), Error> {
+    if !record_state {
+        return Err(Error::AliasNotRecorded);
+    }
+
+    if !is_valid_alias(alias) {
+        return Err(Error::InvalidAlias(alias.into()));
+    }
+
+    match find_attached_port(|record| record.alias.as_deref() == Some(alias)) {
+        Ok(Some(port)) => Err(Error::AliasInUse(alias.into(), port)),
+        Ok(None) => Ok(()),
+        Err(e) => Err(Error::CheckingAlias(e)),
+    }
+}
+
+/// Same as [`attach_device_with_options`], but imports the device over a
+/// stream which was already connected by the caller (e.g. through a tunnel)
+/// instead of connecting to the server itself. The connect options are applied
+/// to the adopted stream.
+///
+/// The actual server is not known, so the connection record names the peer of
+/// the stream instead (e.g. the local end of the tunnel).
+pub fn attach_device_on_stream(
+    stream: TcpStream,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<u32, Error> {
+    if let Some(alias) = &options.alias {
+        check_alias(alias, options.record_state)?;
+    }
 
-    let rh_port = query_and_import(&mut socket, bus_id)?;
+    let peer = stream.peer_addr()?;
+    let mut socket = UsbIpSocket::from_std(stream, &options.connect)?;
+
+    let deadline = options.deadline;
+
+    let rh_port = timed(tracing::debug_span!("query_and_import", bus_id), || {
+        query_and_import(&mut socket, bus_id, options)
+    })
+    .map_err(|e| deadline_error(e, deadline))?;
 
     tracing::info!("device imported with port: {rh_port}");
 
-    save_connection_record(
+    record_connection(
         rh_port,
-        ConnectionRecord {
+        &peer.ip().to_string(),
+        peer.port(),
+        bus_id,
+        options,
+        save_connection_record,
+    )?;
+
+    if options.read_only {
+        apply_read_only(rh_port, &options.read_only_options)?;
+    }
+
+    Ok(rh_port)
+}
+
+/// Time left until the deadline (`None` if there is none), failing once it
+/// has passed
+fn remaining_time(deadline: Option<Instant>) -> Result<Option<Duration>, Error> {
+    let Some(deadline) = deadline else {
+        return Ok(None);
+    };
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+
+    if remaining.is_zero() {
+        return Err(Error::Timeout);
+    }
+
+    Ok(Some(remaining))
+}
+
+/// Reports network operations which timed out because the socket timeouts were
+/// capped by the deadline as [`Error::Timeout`]
+fn deadline_error(error: Error, deadline: Option<Instant>) -> Error {
+    match error {
+        Error::NetworkIo(NetworkError::Timeout(_))
+            if deadline.is_some_and(|d| Instant::now() >= d) =>
+        {
+            Error::Timeout
+        }
+        e => e,
+    }
+}
+
+/// Connects to the server, trying each of its addresses in turn, without
+/// waiting beyond the deadline
+fn connect_before(
+    host: &str,
+    port: u16,
+    options: &ConnectOptions,
+    deadline: Instant,
+) -> Result<UsbIpSocket, Error> {
+    let addrs = UsbIpSocket::resolve_host_and_port(host, port)?;
+
+    let mut last_error = None;
+
+    for addr in addrs {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Err(Error::Timeout);
+        }
+
+        let options = ConnectOptions {
+            connect_timeout: options.connect_timeout.min(remaining),
+            ..options.clone()
+        };
+
+        match UsbIpSocket::connect_with_options(addr, &options) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                tracing::debug!("failed to connect to {addr}: {e}");
+                last_error = Some(deadline_error(e.into(), Some(deadline)));
+            }
+        }
+    }
+
+    Err(last_error.expect("resolve_host_and_port never returns an empty list"))
+}
+
+/// Makes the device on the port read-only, detaching it if that fails so that
+/// a writable device is never left behind
+fn apply_read_only(rh_port: u32, read_only_options: &ReadOnlyOptions) -> Result<(), Error> {
+    let error = match make_read_only_with_options(rh_port, read_only_options) {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+
+    tracing::warn!("detaching port {rh_port} since it could not be made read-only");
+    detach_after_failure(rh_port);
+
+    Err(Error::ReadOnly(error))
+}
+
+/// Detaches a port whose device was attached, but which failed a later step of
+/// the attach, so that it isn't left behind attached while the error claims
+/// otherwise. A failure to detach is only logged, since the original error is
+/// the more useful one to report.
+fn detach_after_failure(rh_port: u32) {
+    if let Err(e) = detach_device(rh_port as _, false) {
+        tracing::error!("failed to detach port {rh_port}: {e}");
+    }
+}
+
+/// Identifies a device by the bus and device numbers the remote host assigned
+/// to it (`BUS_NUM/DEV_NUM`, as shown by `lsusb`). Device numbers are
+/// reassigned whenever a device is replugged, so they are only stable as long
+/// as the device stays connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceNumber {
+    pub bus_num: u32,
+    pub dev_num: u32,
+}
+
+impl fmt::Display for DeviceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.bus_num, self.dev_num)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Expected a device number in the form `BUS_NUM/DEV_NUM` (e.g. `3/5`)")]
+pub struct DeviceNumberParseError;
+
+impl FromStr for DeviceNumber {
+    type Err = DeviceNumberParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bus_num, dev_num) = s.split_once('/').ok_or(DeviceNumberParseError)?;
+
+        Ok(Self {
+            bus_num: bus_num.trim().parse().map_err(|_| DeviceNumberParseError)?,
+            dev_num: dev_num.trim().parse().map_err(|_| DeviceNumberParseError)?,
+        })
+    }
+}
+
+/// Finds the device with the given device number in a list of devices exported
+/// by a server. If several devices match (which a well behaved server never
+/// reports), a warning is logged and the first one is returned.
+pub fn find_device_by_number(
+    devices: &[RemoteExportedDevice],
+    number: DeviceNumber,
+) -> Option<&RemoteExportedDevice> {
+    let mut matches = devices.iter().filter(|d| {
+        d.remote_device_info.bus_num == number.bus_num
+            && d.remote_device_info.dev_num == number.dev_num
+    });
+
+    let first = matches.next()?;
+
+    let others: Vec<_> = matches
+        .map(|d| d.remote_device_info.bus_id.as_str())
+        .collect();
+
+    if !others.is_empty() {
+        tracing::warn!(
+            "device number {number} is ambiguous (also matches {others:?}), using `{}`",
+            first.remote_device_info.bus_id
+        );
+    }
+
+    Some(first)
+}
+
+/// Attaches the device with the given device number, by listing the devices
+/// exported by the server and attaching the matching one by its bus ID.
+///
+/// Since device numbers change when a device is replugged, the number may
+/// refer to a different device than expected if the server's devices changed
+/// after the number was looked up.
+///
+/// The listing is not bounded by [`AttachOptions::deadline`], which is only
+/// checked once the number was resolved.
+pub fn attach_device_by_number_with_options(
+    host: &str,
+    port: u16,
+    number: DeviceNumber,
+    options: &AttachOptions,
+) -> Result<u32, Error> {
+    let devices = list_remote_exported_devices_with_options(host, port, &Default::default())
+        .map_err(Error::Listing)?;
+
+    let bus_id = find_device_by_number(&devices, number)
+        .ok_or(Error::DeviceNumberNotFound(number))?
+        .remote_device_info
+        .bus_id
+        .to_string();
+
+    tracing::debug!("device number {number} resolved to bus ID `{bus_id}`");
+
+    remaining_time(options.deadline)?;
+
+    attach_device_with_options(host, port, &bus_id, options)
+}
+
+/// Attaches a device returned by [`list::list_remote_exported_devices`] from the
+/// same server it was listed on
+pub fn attach_exported(device: &RemoteExportedDevice) -> Result<u32, Error> {
+    attach_exported_with_options(device, &AttachOptions::default())
+}
+
+/// Same as [`attach_exported`], but allows configuring the attachment
+pub fn attach_exported_with_options(
+    device: &RemoteExportedDevice,
+    options: &AttachOptions,
+) -> Result<u32, Error> {
+    attach_device_with_options(
+        &device.host,
+        device.port,
+        &device.remote_device_info.bus_id,
+        options,
+    )
+}
+
+/// A single device to attach with [`attach_batch`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct AttachSpec {
+    pub host: String,
+    pub port: u16,
+    pub bus_id: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AttachSpecParseError {
+    #[error("Invalid URL on line {line}: {error}")]
+    Url { line: usize, error: UrlParseError },
+    #[error("Expected `HOST BUS_ID` or a `usbip://` URL on line {0}")]
+    Malformed(usize),
+}
+
+/// Parses a list of devices to attach. Each line is either a `usbip://` URL or
+/// a `HOST BUS_ID` pair (using the default port). Blank lines and lines
+/// starting with `#` are ignored.
+pub fn parse_attach_specs(text: &str) -> Result<Vec<AttachSpec>, AttachSpecParseError> {
+    let mut specs = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with("usbip://") {
+            let url = parse_usbip_url(line)
+                .map_err(|error| AttachSpecParseError::Url { line: i + 1, error })?;
+
+            specs.push(AttachSpec {
+                host: url.host,
+                port: url.port,
+                bus_id: url.bus_id,
+            });
+
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+
+        let (Some(host), Some(bus_id), None) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(AttachSpecParseError::Malformed(i + 1));
+        };
+
+        specs.push(AttachSpec {
             host: host.into(),
             port: UsbIpSocket::DEFAULT_PORT,
             bus_id: bus_id.into(),
-        },
-    )?;
+        });
+    }
 
-    tracing::debug!("connection recorded");
+    Ok(specs)
+}
 
-    Ok(rh_port)
+/// Attaches each of the given devices in order. A failure to attach one device
+/// does not prevent the remaining ones from being attached, so the result of
+/// each attachment is returned alongside its spec.
+pub fn attach_batch(specs: &[AttachSpec]) -> Vec<(AttachSpec, Result<u32, Error>)> {
+    attach_batch_with_options(specs, &AttachOptions::default())
 }
 
-fn query_and_import(socket: &mut UsbIpSocket, bus_id: &str) -> Result<u32, Error> {
-    let op_kind = OperationKind::Import;
+/// Same as [`attach_batch`], but uses the given options for every attachment
+pub fn attach_batch_with_options(
+    specs: &[AttachSpec],
+    options: &AttachOptions,
+) -> Vec<(AttachSpec, Result<u32, Error>)> {
+    attach_batch_with_progress(specs, options, |_| {})
+}
 
-    socket
-        .send_request_header(op_kind)
-        .map_err(Error::NetworkIo)?;
-    socket
-        .send_encoded(ImportRequest {
-            bus_id: CharBuf::new(bus_id).ok_or(Error::BusIdTooLong)?,
+/// Progress of [`attach_batch_with_progress`], reported before and after each
+/// device is attached. `index` is the position of the device in the batch.
+#[derive(Debug)]
+pub enum BatchProgress<'a> {
+    Starting {
+        index: usize,
+        total: usize,
+        spec: &'a AttachSpec,
+    },
+    Finished {
+        index: usize,
+        total: usize,
+        spec: &'a AttachSpec,
+        result: &'a Result<u32, Error>,
+    },
+}
+
+/// Same as [`attach_batch_with_options`], but reports the progress of the
+/// batch to the callback while the devices are being attached (e.g. to show
+/// which device is currently being attached in a long batch)
+pub fn attach_batch_with_progress(
+    specs: &[AttachSpec],
+    options: &AttachOptions,
+    mut progress: impl FnMut(BatchProgress<'_>),
+) -> Vec<(AttachSpec, Result<u32, Error>)> {
+    let total = specs.len();
+
+    specs
+        .iter()
+        .enumerate()
+        .map(|(index, spec)| {
+            progress(BatchProgress::Starting { index, total, spec });
+
+            let result = attach_device_with_options(&spec.host, spec.port, &spec.bus_id, options);
+
+            if let Err(e) = &result {
+                tracing::warn!(
+                    "failed to attach `{}` from {}:{}: {e}",
+                    spec.bus_id,
+                    spec.host,
+                    spec.port
+                );
+            }
+
+            progress(BatchProgress::Finished {
+                index,
+                total,
+                spec,
+                result: &result,
+            });
+
+            (spec.clone(), result)
         })
-        .map_err(Error::NetworkIo)?;
+        .collect()
+}
+
+/// Performs the import on a socket which was already connected by the caller
+/// and attaches the device to a free `vhci_hcd` port, returning the port along
+/// with the socket.
+///
+/// Once attached, `vhci_hcd` holds its own reference to the underlying
+/// connection, so closing the returned socket does not detach the device; it
+/// is only handed back so callers can decide when their fd gets closed. The
+/// connection belongs to the kernel from this point on and must not be read
+/// from or written to, or the URB stream will be corrupted. To tear down the
+/// connection, detach the port instead.
+///
+/// Unlike [`attach_device`], no connection record is saved, so the port will
+/// show up without remote host information unless the caller saves one with
+/// [`save_connection_record`].
+pub fn import_on_socket(
+    mut socket: UsbIpSocket,
+    bus_id: &str,
+) -> Result<(u16, UsbIpSocket), Error> {
+    let rh_port = query_and_import(&mut socket, bus_id, &AttachOptions::default())?;
+    let rh_port = u16::try_from(rh_port).map_err(|_| Error::PortOutOfRange(rh_port))?;
+
+    Ok((rh_port, socket))
+}
+
+fn query_and_import(
+    socket: &mut UsbIpSocket,
+    bus_id: &str,
+    options: &AttachOptions,
+) -> Result<u32, Error> {
+    let remote_device = request_import(socket, bus_id, options.deadline)?;
+
+    timed(tracing::debug_span!("import_device"), || {
+        import_device(socket, &remote_device, options)
+    })
+}
+
+/// Sends the import request and receives the reply, returning the device
+/// which the server is ready to export over the connection
+fn request_import(
+    socket: &mut UsbIpSocket,
+    bus_id: &str,
+    deadline: Option<Instant>,
+) -> Result<UsbDeviceInfo, Error> {
+    let op_kind = OperationKind::Import;
+
+    if let Some(remaining) = remaining_time(deadline)? {
+        socket.set_read_timeout(Some(remaining))?;
+    }
+
+    socket.send_request_header(op_kind)?;
+    socket.send_encoded(ImportRequest {
+        bus_id: CharBuf::new(bus_id).ok_or(Error::BusIdTooLong)?,
+    })?;
 
     socket
-        .recv_reply_header(op_kind)
-        .map_err(Error::NetworkIo)??;
-    let reply = socket
-        .recv_encoded::<ImportReply>()
-        .map_err(Error::NetworkIo)?;
+        .recv_reply_header(op_kind)?
+        .map_err(|e| import_error(e, bus_id))?;
+    let reply = socket.recv_encoded::<ImportReply>()?;
 
     if reply
         .usb_device
@@ -92,18 +799,119 @@ fn query_and_import(socket: &mut UsbIpSocket, bus_id: &str) -> Result<u32, Error
 
     tracing::debug!(?reply);
 
-    import_device(socket, &reply.usb_device.try_into()?)
+    let remote_device = reply.usb_device.try_into()?;
+
+    socket.clear_timeouts_for_handoff()?;
+
+    Ok(remote_device)
+}
+
+/// Maps the import specific statuses returned by the server to their own
+/// errors, so each can come with advice on what to do about it
+fn import_error(error: OperationError, bus_id: &str) -> Error {
+    match error {
+        OperationError::DeviceBusy => Error::DeviceBusy(bus_id.into()),
+        OperationError::DeviceError => Error::DeviceInErrorState(bus_id.into()),
+        OperationError::NoSuchDevice => Error::NoSuchDeviceOnServer(bus_id.into()),
+        e => Error::Operation(e),
+    }
+}
+
+/// Returns the warning to log when the device runs below its rated speed on
+/// the root hub of the port it was attached to
+fn degraded_speed_warning(rh_port: u32, hub_speed: HubSpeed, speed: UsbSpeed) -> Option<String> {
+    hub_speed.degrades(speed).then(|| {
+        format!(
+            "device is rated for {speed:?} speed, but the root hub of port {rh_port} only supports {:?} speed, so it will operate below its rated speed",
+            hub_speed.max_speed()
+        )
+    })
+}
+
+/// Waits for the kernel to finish enumerating the device on the given port and
+/// checks that it came up at the expected speed
+fn verify_attached_device(
+    vhci_hcd: &mut VhciHcd,
+    rh_port: u32,
+    expected: UsbSpeed,
+    options: &AttachOptions,
+) -> Result<(), Error> {
+    for _ in 0..options.verify_attempts {
+        remaining_time(options.deadline)?;
+
+        // the local usb device may not have been registered with udev yet, in
+        // which case querying it fails and we just try again later
+        match vhci_hcd.refresh_port(rh_port as u16) {
+            Ok(()) => {}
+            Err(e @ VhciHcdError::QueryingLocalUsbDevice(_)) => {
+                tracing::debug!("imported device not visible yet ({e})");
+                thread::sleep(options.verify_retry_delay);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let device = &vhci_hcd.cached_imported_devices()[rh_port as usize];
+
+        match device.status() {
+            VhciDeviceStatus::Used => {
+                let actual = device
+                    .connected_device()
+                    .expect("used ports always have a connected device")
+                    .device
+                    .speed;
+
+                if actual != expected {
+                    return Err(Error::AttachVerificationFailed {
+                        port: rh_port,
+                        expected,
+                        actual: Some(actual),
+                    });
+                }
+
+                tracing::debug!("verified device on port {rh_port} at speed {actual:?}");
+
+                return Ok(());
+            }
+            // the kernel is still assigning an address to the device
+            VhciDeviceStatus::NotAssigned => thread::sleep(options.verify_retry_delay),
+            VhciDeviceStatus::NotConnected | VhciDeviceStatus::Error => break,
+        }
+    }
+
+    Err(Error::AttachVerificationFailed {
+        port: rh_port,
+        expected,
+        actual: None,
+    })
+}
+
+/// Runs `f` inside of `span`, logging how long it took once it returns so that
+/// `--debug` output shows where the time of an attach is spent
+fn timed<T>(span: tracing::Span, f: impl FnOnce() -> T) -> T {
+    let _span = span.entered();
+    let start = Instant::now();
+
+    let result = f();
+
+    tracing::debug!(elapsed = ?start.elapsed(), "finished");
+
+    result
 }
 
-fn import_device(socket: &mut UsbIpSocket, remote_device: &UsbDeviceInfo) -> Result<u32, Error> {
+fn import_device(
+    socket: &mut UsbIpSocket,
+    remote_device: &UsbDeviceInfo,
+    options: &AttachOptions,
+) -> Result<u32, Error> {
     let mut vhci_hcd = VhciHcd::open()?;
 
     tracing::debug!(?vhci_hcd);
     tracing::debug!(?remote_device);
 
-    const MAX_ATTEMPTS: u32 = 8;
+    for _ in 0..options.port_attempts {
+        remaining_time(options.deadline)?;
 
-    for _ in 0..MAX_ATTEMPTS {
         let rh_port = vhci_hcd.get_free_port(remote_device.speed)?;
 
         tracing::debug!("attempting to use free port: {rh_port}");
@@ -113,11 +921,29 @@ fn import_device(socket: &mut UsbIpSocket, remote_device: &UsbDeviceInfo) -> Res
             socket.as_raw_fd(),
             remote_device.bus_num,
             remote_device.dev_num,
-            remote_device.speed as _,
+            remote_device.speed,
         ) {
             Ok(_) => {
                 tracing::debug!("successfully attached device to port: {rh_port}");
 
+                let hub_speed = vhci_hcd.cached_imported_devices()[rh_port as usize].hub_speed;
+
+                if let Some(warning) =
+                    degraded_speed_warning(rh_port, hub_speed, remote_device.speed)
+                {
+                    tracing::warn!("{warning}");
+                }
+
+                if options.verify
+                    && let Err(e) =
+                        verify_attached_device(&mut vhci_hcd, rh_port, remote_device.speed, options)
+                {
+                    tracing::warn!("detaching port {rh_port} since the device failed verification");
+                    detach_after_failure(rh_port);
+
+                    return Err(e);
+                }
+
                 return Ok(rh_port);
             }
             Err(VhciHcdError::SysfsIo(e)) if e.kind() == ErrorKind::ResourceBusy => {
@@ -136,3 +962,556 @@ fn import_device(socket: &mut UsbIpSocket, remote_device: &UsbDeviceInfo) -> Res
 
     Err(Error::MaxAttemptsExceeded)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::TcpListener,
+        sync::{
+            Arc,
+            atomic::{AtomicU32, Ordering},
+        },
+    };
+
+    use super::*;
+    use crate::{proto::OperationStatus, util::capture_logs};
+
+    #[test]
+    fn deadline_bounds_a_server_which_never_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // accepts the connection, but never replies to the import request
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = io::copy(&mut stream, &mut io::sink());
+        });
+
+        let start = Instant::now();
+        let result = attach_device_with_deadline(
+            "127.0.0.1",
+            port,
+            "1-1",
+            start + Duration::from_millis(200),
+        );
+
+        assert!(matches!(result, Err(Error::Timeout)), "{result:?}");
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        server.join().unwrap();
+    }
+
+    /// Serves a single import request, replying with the given status and
+    /// (if it is ok) a device with the given bus ID. Returns the connection
+    /// of the client.
+    fn serve_import(
+        status: OperationStatus,
+        bus_id: &'static str,
+    ) -> (UsbIpSocket, thread::JoinHandle<()>) {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = UsbIpSocket::connect(listener.local_addr().unwrap()).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+
+            socket.recv_request_header().unwrap().unwrap();
+            socket.recv_encoded::<ImportRequest>().unwrap();
+
+            socket
+                .send_response_header(OperationKind::Import, status)
+                .unwrap();
+
+            if status == OperationStatus::Ok {
+                let device = UsbDeviceInfo {
+                    sys_path: format!("/sys/devices/platform/dummy_hcd.0/usb1/{bus_id}"),
+                    bus_id: bus_id.into(),
+                    bus_num: 1,
+                    dev_num: 2,
+                    speed: UsbSpeed::High,
+                    id_vendor: 0x1d6b,
+                    id_product: 0x0104,
+                    bcd_device: 0x0100,
+                    b_device_class: 0,
+                    b_device_sub_class: 0,
+                    b_device_protocol: 0,
+                    b_configuration_value: Some(1),
+                    b_num_configurations: 1,
+                    b_num_interfaces: 1,
+                };
+
+                socket
+                    .send_encoded(ImportReply {
+                        usb_device: (&device).into(),
+                    })
+                    .unwrap();
+            }
+        });
+
+        (client, server)
+    }
+
+    #[test]
+    fn import_on_socket_reports_refused_imports() {
+        let (socket, server) = serve_import(OperationStatus::NoSuchDevice, "1-1");
+
+        let error = import_on_socket(socket, "1-1").err().unwrap();
+
+        assert!(
+            matches!(&error, Error::NoSuchDeviceOnServer(bus_id) if bus_id == "1-1"),
+            "{error:?}"
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn import_on_socket_reports_busy_devices() {
+        let (socket, server) = serve_import(OperationStatus::DeviceBusy, "1-1");
+
+        let error = import_on_socket(socket, "1-1").err().unwrap();
+
+        assert!(
+            matches!(&error, Error::DeviceBusy(bus_id) if bus_id == "1-1"),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            "Device `1-1` is already exported to another client"
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn import_on_socket_reports_devices_in_an_error_state() {
+        let (socket, server) = serve_import(OperationStatus::DeviceError, "1-1");
+
+        let error = import_on_socket(socket, "1-1").err().unwrap();
+
+        assert!(
+            matches!(&error, Error::DeviceInErrorState(bus_id) if bus_id == "1-1"),
+            "{error:?}"
+        );
+        assert!(
+            error.to_string().contains("unplugging and replugging"),
+            "{error}"
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn attaches_exported_devices_from_the_server_they_were_listed_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // refuses the import, since there is nothing to attach to in tests
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = UsbIpSocket::from_std(stream, &Default::default()).unwrap();
+
+            socket.recv_request_header().unwrap().unwrap();
+            let request = socket.recv_encoded::<ImportRequest>().unwrap();
+
+            socket
+                .send_response_header(OperationKind::Import, OperationStatus::NoSuchDevice)
+                .unwrap();
+
+            request
+                .bus_id
+                .as_c_str()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned()
+        });
+
+        let device = RemoteExportedDevice {
+            host: "127.0.0.1".into(),
+            port,
+            url: format!("usbip://127.0.0.1:{port}/1-1.3"),
+            remote_device_info: UsbDeviceInfo {
+                bus_id: "1-1.3".into(),
+                ..Default::default()
+            },
+            vendor: None,
+            product: None,
+            class: None,
+            sub_class: None,
+            protocol: None,
+            interfaces: Vec::new(),
+        };
+
+        let result = attach_exported(&device);
+
+        assert!(
+            matches!(&result, Err(Error::NoSuchDeviceOnServer(bus_id)) if bus_id == "1-1.3"),
+            "{result:?}"
+        );
+        assert_eq!(server.join().unwrap(), "1-1.3");
+    }
+
+    #[test]
+    fn reports_progress_once_per_batch_item() {
+        // nothing listens on the port, so each attach fails right away
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let specs: Vec<_> = ["1-1", "1-2", "1-3"]
+            .into_iter()
+            .map(|bus_id| AttachSpec {
+                host: "127.0.0.1".into(),
+                port,
+                bus_id: bus_id.into(),
+            })
+            .collect();
+
+        let mut events = Vec::new();
+        let results = attach_batch_with_progress(&specs, &AttachOptions::default(), |p| {
+            events.push(match p {
+                BatchProgress::Starting { index, total, spec } => {
+                    ("starting", index, total, spec.bus_id.clone(), None)
+                }
+                BatchProgress::Finished {
+                    index,
+                    total,
+                    spec,
+                    result,
+                } => (
+                    "finished",
+                    index,
+                    total,
+                    spec.bus_id.clone(),
+                    Some(result.is_ok()),
+                ),
+            })
+        });
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            events,
+            [
+                ("starting", 0, 3, "1-1".into(), None),
+                ("finished", 0, 3, "1-1".into(), Some(false)),
+                ("starting", 1, 3, "1-2".into(), None),
+                ("finished", 1, 3, "1-2".into(), Some(false)),
+                ("starting", 2, 3, "1-3".into(), None),
+                ("finished", 2, 3, "1-3".into(), Some(false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn import_on_socket_rejects_replies_for_other_devices() {
+        let (socket, server) = serve_import(OperationStatus::Ok, "2-1");
+
+        let error = import_on_socket(socket, "1-1").err().unwrap();
+
+        assert!(matches!(error, Error::BusIdMismatch), "{error:?}");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn logs_the_time_spent_in_each_stage() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // accepts the connection, but never replies to the import request
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = io::copy(&mut stream, &mut io::sink());
+        });
+
+        let logs = capture_logs(|| {
+            let result = attach_device_with_deadline(
+                "127.0.0.1",
+                port,
+                "1-1",
+                Instant::now() + Duration::from_millis(100),
+            );
+
+            assert!(result.is_err());
+        });
+
+        server.join().unwrap();
+
+        let finished = |span: &str| {
+            logs.lines()
+                .any(|l| l.contains(span) && l.contains("finished") && l.contains("elapsed="))
+        };
+
+        assert!(finished("connect{"), "{logs}");
+        assert!(finished("request_import{"), "{logs}");
+    }
+
+    #[test]
+    fn protocol_retries_stop_at_the_first_deliberate_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicU32::new(0));
+
+        // answers the first two imports with a reply for the wrong operation
+        // (which can't be decoded) and the third one with a busy status
+        let server = thread::spawn({
+            let connections = connections.clone();
+
+            move || {
+                for i in 0..3 {
+                    let (stream, _) = listener.accept().unwrap();
+                    connections.fetch_add(1, Ordering::SeqCst);
+
+                    let mut socket = UsbIpSocket::from_std(stream, &Default::default()).unwrap();
+                    socket.recv_request_header().unwrap().unwrap();
+                    socket.recv_encoded::<ImportRequest>().unwrap();
+
+                    let (kind, status) = match i {
+                        0 | 1 => (OperationKind::ListDevices, OperationStatus::Ok),
+                        _ => (OperationKind::Import, OperationStatus::DeviceBusy),
+                    };
+                    socket.send_response_header(kind, status).unwrap();
+                }
+            }
+        });
+
+        let result = attach_device_with_options(
+            "127.0.0.1",
+            port,
+            "1-1",
+            &AttachOptions {
+                protocol_retries: 5,
+                record_state: false,
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(result, Err(Error::DeviceBusy(_))), "{result:?}");
+
+        server.join().unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn warns_when_the_hub_degrades_the_device() {
+        let warning = degraded_speed_warning(9, HubSpeed::Super, UsbSpeed::SuperPlus).unwrap();
+
+        assert!(warning.contains("rated for SuperPlus speed"), "{warning}");
+        assert!(
+            warning.contains("port 9 only supports Super speed"),
+            "{warning}"
+        );
+    }
+
+    #[test]
+    fn does_not_warn_when_the_device_runs_at_its_rated_speed() {
+        assert_eq!(
+            degraded_speed_warning(9, HubSpeed::Super, UsbSpeed::Super),
+            None
+        );
+        assert_eq!(
+            degraded_speed_warning(1, HubSpeed::High, UsbSpeed::High),
+            None
+        );
+        assert_eq!(
+            degraded_speed_warning(1, HubSpeed::High, UsbSpeed::Full),
+            None
+        );
+    }
+
+    fn spec(host: &str, port: u16, bus_id: &str) -> AttachSpec {
+        AttachSpec {
+            host: host.into(),
+            port,
+            bus_id: bus_id.into(),
+        }
+    }
+
+    #[test]
+    fn parses_urls_and_host_bus_id_pairs() {
+        let specs = parse_attach_specs(
+            "# lab devices\n\
+             usbip://192.168.1.20:4000/1-1.2\n\
+             \n\
+             server.local   2-3\n\
+             \t::1 1-1\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            specs,
+            [
+                spec("192.168.1.20", 4000, "1-1.2"),
+                spec("server.local", UsbIpSocket::DEFAULT_PORT, "2-3"),
+                spec("::1", UsbIpSocket::DEFAULT_PORT, "1-1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_an_empty_list() {
+        assert!(parse_attach_specs("").unwrap().is_empty());
+        assert!(parse_attach_specs("# nothing yet\n\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_the_line_of_malformed_specs() {
+        let malformed = ["server", "server 1-1 extra"];
+
+        for line in malformed {
+            let text = format!("server 1-1\n{line}\n");
+
+            assert!(
+                matches!(
+                    parse_attach_specs(&text),
+                    Err(AttachSpecParseError::Malformed(2))
+                ),
+                "{line:?}"
+            );
+        }
+
+        assert!(matches!(
+            parse_attach_specs("\n\nusbip://server:port/1-1"),
+            Err(AttachSpecParseError::Url { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn parses_device_numbers() {
+        let number = DeviceNumber {
+            bus_num: 3,
+            dev_num: 5,
+        };
+
+        assert_eq!("3/5".parse::<DeviceNumber>().unwrap(), number);
+        assert_eq!(" 3 / 5 ".parse::<DeviceNumber>().unwrap(), number);
+        assert_eq!(number.to_string(), "3/5");
+    }
+
+    #[test]
+    fn rejects_malformed_device_numbers() {
+        for text in ["", "3", "3/", "/5", "3-5", "3/5/1", "a/5", "-1/5"] {
+            assert!(text.parse::<DeviceNumber>().is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn finds_devices_by_number() {
+        let exported = |bus_id: &str, bus_num, dev_num| RemoteExportedDevice {
+            host: "server".into(),
+            port: UsbIpSocket::DEFAULT_PORT,
+            url: String::new(),
+            remote_device_info: UsbDeviceInfo {
+                bus_id: bus_id.into(),
+                bus_num,
+                dev_num,
+                ..Default::default()
+            },
+            vendor: None,
+            product: None,
+            class: None,
+            sub_class: None,
+            protocol: None,
+            interfaces: Vec::new(),
+        };
+        let devices = [exported("1-1", 1, 2), exported("3-1", 3, 5)];
+
+        let found = find_device_by_number(
+            &devices,
+            DeviceNumber {
+                bus_num: 3,
+                dev_num: 5,
+            },
+        );
+        assert_eq!(found.unwrap().remote_device_info.bus_id, "3-1");
+
+        let missing = find_device_by_number(
+            &devices,
+            DeviceNumber {
+                bus_num: 2,
+                dev_num: 5,
+            },
+        );
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn streams_check_the_alias_before_importing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        for (alias, record_state) in [("camera", false), ("front camera", true)] {
+            let stream = TcpStream::connect(addr).unwrap();
+            let (mut server, _) = listener.accept().unwrap();
+
+            let result = attach_device_on_stream(
+                stream,
+                "1-1",
+                &AttachOptions {
+                    alias: Some(alias.into()),
+                    record_state,
+                    ..Default::default()
+                },
+            );
+
+            match record_state {
+                false => assert!(matches!(result, Err(Error::AliasNotRecorded)), "{result:?}"),
+                true => assert!(matches!(result, Err(Error::InvalidAlias(_))), "{result:?}"),
+            }
+
+            // nothing was sent to the server
+            server.set_nonblocking(true).unwrap();
+            let mut buf = [0; 1];
+            assert!(matches!(io::Read::read(&mut server, &mut buf), Ok(0)));
+        }
+    }
+
+    /// Fails like saving into a state dir owned by root does for other users
+    fn save_in_unwritable_state_dir(
+        _rh_port: u32,
+        _record: ConnectionRecord,
+    ) -> Result<(), FsStateError> {
+        Err(FsStateError::StateDirPermissions {
+            owner_uid: 0,
+            euid: 1000,
+        })
+    }
+
+    #[test]
+    fn failing_to_record_the_connection_does_not_fail_the_attach() {
+        let logs = capture_logs(|| {
+            let result = record_connection(
+                3,
+                "10.0.0.1",
+                3240,
+                "1-1",
+                &AttachOptions::default(),
+                save_in_unwritable_state_dir,
+            );
+
+            assert!(result.is_ok(), "{result:?}");
+        });
+
+        assert!(
+            logs.contains("device attached to port 3, but its connection could not be recorded"),
+            "{logs}"
+        );
+    }
+
+    #[test]
+    fn does_not_record_the_connection_when_turned_off() {
+        let options = AttachOptions {
+            record_state: false,
+            ..Default::default()
+        };
+
+        let result = record_connection(3, "10.0.0.1", 3240, "1-1", &options, |_, _| {
+            panic!("connection recorded")
+        });
+
+        assert!(result.is_ok(), "{result:?}");
+    }
+}
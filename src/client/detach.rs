@@ -1,6 +1,11 @@
-use crate::drivers::vhci::{
-    Error as VhciHcdError, VhciDeviceStatus, VhciHcd,
-    state::{FsStateError, delete_connection_record},
+use std::ops::Range;
+
+use crate::{
+    drivers::vhci::{
+        Error as VhciHcdError, VhciDevice, VhciDeviceStatus, VhciHcd,
+        state::{ConnectionRecord, FsStateError, delete_connection_record, read_all_records},
+    },
+    url::{UrlParseError, UsbipUrl, parse_usbip_url},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -9,9 +14,18 @@ pub enum Error {
     VhciHcd(#[from] VhciHcdError),
     #[error("Port number is greater than the max port number advertised by vhci_hcd")]
     InvalidPortNumber,
+    #[error("There is no controller {index} (vhci_hcd has {count} controller(s))")]
+    InvalidControllerIndex { index: u16, count: u16 },
 
     #[error(transparent)]
     FsState(FsStateError),
+
+    #[error("Invalid device URL ({0})")]
+    InvalidUrl(#[from] UrlParseError),
+    #[error("No local port is attached to `{0}` (see `usbip port`)")]
+    NoPortForUrl(String),
+    #[error("No local port is attached with the alias `{0}` (see `usbip port`)")]
+    NoPortForAlias(String),
 }
 
 pub fn detach_device(port: u16, remove_state_dir: bool) -> Result<(), Error> {
@@ -36,3 +50,237 @@ pub fn detach_device(port: u16, remove_state_dir: bool) -> Result<(), Error> {
 
     Ok(())
 }
+
+/// Detaches the device which was attached from the given `usbip://` URL,
+/// looking up its local port in the connection records. Returns the port the
+/// device was detached from.
+///
+/// Only devices attached with a connection record can be found, and the host
+/// has to be given the same way it was when attaching (e.g. a hostname does not
+/// match a record of its IP address).
+pub fn detach_device_by_url(url: &str, remove_state_dir: bool) -> Result<u16, Error> {
+    let parsed = parse_usbip_url(url)?;
+
+    let port = find_attached_port(|record| record_matches_url(record, &parsed))?
+        .ok_or_else(|| Error::NoPortForUrl(url.into()))?;
+
+    tracing::debug!("`{url}` is attached to port {port}");
+
+    detach_device(port, remove_state_dir)?;
+
+    Ok(port)
+}
+
+/// Detaches the device which was attached with the given alias (see
+/// [`AttachOptions::alias`](crate::client::attach::AttachOptions::alias)).
+/// Returns the port the device was detached from.
+pub fn detach_device_by_alias(alias: &str, remove_state_dir: bool) -> Result<u16, Error> {
+    let port = find_attached_port(|record| record.alias.as_deref() == Some(alias))?
+        .ok_or_else(|| Error::NoPortForAlias(alias.into()))?;
+
+    tracing::debug!("alias `{alias}` is attached to port {port}");
+
+    detach_device(port, remove_state_dir)?;
+
+    Ok(port)
+}
+
+/// Detaches every device attached to a port of the given `vhci_hcd`
+/// controller, leaving the devices on other controllers attached. Returns the
+/// ports which were detached.
+///
+/// Stops at the first port which fails to detach, the ports before it stay
+/// detached.
+pub fn detach_controller(controller_index: u16, remove_state_dir: bool) -> Result<Vec<u16>, Error> {
+    let vhci_hcd = VhciHcd::open()?;
+
+    let ports =
+        vhci_hcd
+            .controller_ports(controller_index)
+            .ok_or(Error::InvalidControllerIndex {
+                index: controller_index,
+                count: vhci_hcd.controller_count(),
+            })?;
+
+    let used_ports = used_ports_in(vhci_hcd.cached_imported_devices(), ports);
+
+    tracing::debug!("used ports of controller {controller_index}: {used_ports:?}");
+
+    for &port in &used_ports {
+        detach_device(port, remove_state_dir)?;
+    }
+
+    Ok(used_ports)
+}
+
+/// The ports in the given range which have a device attached
+fn used_ports_in(devices: &[VhciDevice], ports: Range<u16>) -> Vec<u16> {
+    devices
+        .iter()
+        .filter(|d| ports.contains(&d.port) && d.status() != VhciDeviceStatus::NotConnected)
+        .map(|d| d.port)
+        .collect()
+}
+
+/// Finds the local port whose connection record matches and which is still in
+/// use (records of ports which were detached by other means are left behind).
+/// If the `vhci_hcd` driver isn't loaded, nothing can be in use.
+pub(crate) fn find_attached_port(
+    matches: impl Fn(&ConnectionRecord) -> bool,
+) -> Result<Option<u16>, Error> {
+    let records = read_all_records().map_err(Error::FsState)?;
+
+    let vhci_hcd = match VhciHcd::open() {
+        Ok(vhci_hcd) => vhci_hcd,
+        Err(VhciHcdError::VhciDeviceNotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(find_port_in_use(
+        records,
+        vhci_hcd.cached_imported_devices(),
+        matches,
+    ))
+}
+
+/// Finds the port of the first matching record which is still in use by one of
+/// the given devices
+fn find_port_in_use(
+    records: Vec<(u16, ConnectionRecord)>,
+    devices: &[VhciDevice],
+    matches: impl Fn(&ConnectionRecord) -> bool,
+) -> Option<u16> {
+    records
+        .into_iter()
+        .filter(|(_, record)| matches(record))
+        .map(|(port, _)| port)
+        .find(|&port| {
+            devices
+                .iter()
+                .any(|d| d.port == port && d.status() != VhciDeviceStatus::NotConnected)
+        })
+}
+
+fn record_matches_url(record: &ConnectionRecord, url: &UsbipUrl) -> bool {
+    record.host.eq_ignore_ascii_case(&url.host)
+        && record.port == url.port
+        && record.bus_id == url.bus_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        drivers::vhci::{
+            HubSpeed, VhciDeviceState, VhciImportedDevice,
+            state::{read_all_records_in, save_connection_record_in},
+        },
+        util::TempDir,
+    };
+
+    fn record(bus_id: &str, alias: Option<&str>) -> ConnectionRecord {
+        ConnectionRecord {
+            host: "192.168.1.20".into(),
+            port: 3240,
+            bus_id: bus_id.into(),
+            alias: alias.map(Into::into),
+        }
+    }
+
+    fn device(port: u16, state: VhciDeviceState) -> VhciDevice {
+        VhciDevice {
+            hub_speed: HubSpeed::High,
+            port,
+            state,
+        }
+    }
+
+    fn used() -> VhciDeviceState {
+        VhciDeviceState::Used(VhciImportedDevice {
+            remote_device_id: 0x0001_0002,
+            socket_fd: 3,
+            kernel_speed: 3,
+            device: Default::default(),
+        })
+    }
+
+    /// Records saved in a temporary state dir, read back like the CLI does
+    fn saved_records(records: Vec<(u32, ConnectionRecord)>) -> Vec<(u16, ConnectionRecord)> {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+
+        for (port, record) in records {
+            save_connection_record_in(&state_path, port, record).unwrap();
+        }
+
+        read_all_records_in(&state_path).unwrap()
+    }
+
+    fn by_alias(alias: &str) -> impl Fn(&ConnectionRecord) -> bool {
+        move |record| record.alias.as_deref() == Some(alias)
+    }
+
+    #[test]
+    fn only_detaches_the_ports_of_the_given_controller() {
+        // two controllers with four ports each, both with devices attached
+        let devices: Vec<_> = (0..8)
+            .map(|port| match port {
+                1 | 2 | 5 | 7 => device(port, used()),
+                _ => device(port, VhciDeviceState::NotConnected),
+            })
+            .collect();
+
+        assert_eq!(used_ports_in(&devices, 4..8), [5, 7]);
+        assert_eq!(used_ports_in(&devices, 0..4), [1, 2]);
+    }
+
+    #[test]
+    fn resolves_aliases_to_their_port() {
+        let records = saved_records(vec![
+            (0, record("1-1", Some("camera"))),
+            (1, record("1-2", Some("keyboard"))),
+            (2, record("1-3", None)),
+        ]);
+        let devices = [device(0, used()), device(1, used()), device(2, used())];
+
+        assert_eq!(
+            find_port_in_use(records, &devices, by_alias("keyboard")),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn skips_records_of_detached_ports() {
+        // port 0 was detached without deleting its record, then the alias was
+        // reused for an attach to port 4
+        let records = saved_records(vec![
+            (0, record("1-1", Some("camera"))),
+            (4, record("1-1", Some("camera"))),
+        ]);
+        let devices = [device(0, VhciDeviceState::NotConnected), device(4, used())];
+
+        assert_eq!(
+            find_port_in_use(records, &devices, by_alias("camera")),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn resolves_unknown_aliases_to_nothing() {
+        let records = saved_records(vec![(0, record("1-1", Some("camera")))]);
+        let devices = [device(0, used())];
+
+        assert_eq!(
+            find_port_in_use(records, &devices, by_alias("printer")),
+            None
+        );
+    }
+
+    #[test]
+    fn matches_records_by_url() {
+        let url = parse_usbip_url("usbip://192.168.1.20:3240/1-2").unwrap();
+
+        assert!(record_matches_url(&record("1-2", None), &url));
+        assert!(!record_matches_url(&record("1-1", None), &url));
+    }
+}
@@ -0,0 +1,269 @@
+use std::io;
+
+use crate::{
+    client::list::{
+        Error as ListError, ListOptions, RemoteExportedDevice,
+        list_remote_exported_devices_with_options, recv_exported_device,
+    },
+    hwdb::{IdSourceError, NameResolver},
+    net::{ConnectOptions, NetworkError, UsbIpSocket},
+    proto::{
+        DeviceInfoRequest, OperationError, OperationKind, SYSFS_BUS_ID_SIZE, char_buf::CharBuf,
+    },
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Network connection failed ({0})")]
+    NetworkIo(NetworkError),
+
+    #[error("Provided bus ID is too long (max size is {SYSFS_BUS_ID_SIZE} bytes)")]
+    BusIdTooLong,
+    #[error("Bus ID returned by the server did not match the one that was sent")]
+    BusIdMismatch,
+    #[error("Device with bus ID `{0}` is not exported by the server")]
+    NoSuchDevice(String),
+
+    #[error("usbip network operation failed ({0})")]
+    Operation(#[from] OperationError),
+    #[error(transparent)]
+    List(#[from] ListError),
+
+    #[error("Failed to set up USB name lookup ({0})")]
+    NameLookup(#[from] IdSourceError),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::NetworkIo(e.into())
+    }
+}
+
+/// Queries a single exported device from a remote host. This uses the
+/// DeviceInfo operation when the server supports it, and otherwise falls back
+/// to requesting the full device list and filtering it.
+pub fn query_exported_device(
+    host: &str,
+    port: u16,
+    bus_id: &str,
+) -> Result<RemoteExportedDevice, Error> {
+    match query_device_info(host, port, bus_id) {
+        Ok(device) => return Ok(device),
+        Err(Error::Operation(OperationError::NoSuchDevice)) => {
+            return Err(Error::NoSuchDevice(bus_id.into()));
+        }
+        Err(e) if is_unsupported(&e) => {
+            tracing::debug!("server does not support DeviceInfo ({e}), falling back to list");
+        }
+        Err(e) => return Err(e),
+    }
+
+    list_remote_exported_devices_with_options(host, port, &ListOptions::default())?
+        .into_iter()
+        .find(|d| d.remote_device_info.bus_id == bus_id)
+        .ok_or_else(|| Error::NoSuchDevice(bus_id.into()))
+}
+
+/// Whether the error indicates that the server does not implement DeviceInfo
+/// (as opposed to the request itself failing)
+fn is_unsupported(error: &Error) -> bool {
+    match error {
+        Error::Operation(
+            OperationError::RequestFailed
+            | OperationError::InvalidData
+            | OperationError::UnknownOperation(_),
+        ) => true,
+        // servers which don't know the operation close the connection
+        Error::NetworkIo(NetworkError::ConnectionReset(_)) => true,
+        _ => false,
+    }
+}
+
+fn query_device_info(host: &str, port: u16, bus_id: &str) -> Result<RemoteExportedDevice, Error> {
+    let names = NameResolver::new(None)?;
+    let mut socket = UsbIpSocket::connect_host_and_port_with_options(
+        host,
+        port,
+        &ConnectOptions::short_lived(),
+    )?;
+
+    let op_kind = OperationKind::DeviceInfo;
+
+    socket.send_request_header(op_kind)?;
+    socket.send_encoded(DeviceInfoRequest {
+        bus_id: CharBuf::new(bus_id).ok_or(Error::BusIdTooLong)?,
+    })?;
+
+    socket.recv_reply_header(op_kind)??;
+
+    let device = recv_exported_device(&mut socket, &names, host, port, 0)?;
+
+    if device.remote_device_info.bus_id != bus_id {
+        return Err(Error::BusIdMismatch);
+    }
+
+    Ok(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::{
+        UsbDeviceInfo, UsbSpeed,
+        proto::{
+            DeviceInfoReply, ListDevicesReply, OperationStatus, RawUsbDeviceInfo, UsbInterfaceInfo,
+        },
+    };
+
+    fn device(bus_id: &str) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            sys_path: format!("/sys/devices/platform/dummy_hcd.0/usb1/{bus_id}"),
+            bus_id: bus_id.into(),
+            bus_num: 1,
+            dev_num: 2,
+            speed: UsbSpeed::High,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            b_configuration_value: Some(1),
+            b_num_configurations: 1,
+            b_num_interfaces: 1,
+            ..Default::default()
+        }
+    }
+
+    type Handler = Box<dyn FnOnce(&mut UsbIpSocket, OperationKind) + Send>;
+
+    /// Accepts one connection per handler and passes it the socket along with
+    /// the operation which was requested on it
+    fn serve(handlers: Vec<Handler>) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            for handler in handlers {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = UsbIpSocket::from_std(stream, &Default::default()).unwrap();
+
+                let kind = socket.recv_request_header().unwrap().unwrap();
+                handler(&mut socket, kind);
+            }
+        });
+
+        (port, server)
+    }
+
+    fn send_device(socket: &mut UsbIpSocket, device: &UsbDeviceInfo) {
+        socket.send_encoded(RawUsbDeviceInfo::from(device)).unwrap();
+        socket
+            .send_encoded(UsbInterfaceInfo::new(0x08, 0x06, 0x50))
+            .unwrap();
+    }
+
+    /// Answers a DeviceInfo request with the device of the requested bus ID
+    fn device_info() -> Handler {
+        Box::new(|socket, kind| {
+            assert_eq!(kind, OperationKind::DeviceInfo);
+
+            let request = socket.recv_encoded::<DeviceInfoRequest>().unwrap();
+            let bus_id = request.bus_id.as_c_str().unwrap().to_str().unwrap();
+
+            socket
+                .send_response_header(OperationKind::DeviceInfo, OperationStatus::Ok)
+                .unwrap();
+            socket
+                .send_encoded(DeviceInfoReply {
+                    usb_device: RawUsbDeviceInfo::from(&device(bus_id)),
+                })
+                .unwrap();
+            socket
+                .send_encoded(UsbInterfaceInfo::new(0x08, 0x06, 0x50))
+                .unwrap();
+        })
+    }
+
+    /// Closes the connection without replying, like servers which don't know
+    /// the DeviceInfo operation
+    fn unsupported() -> Handler {
+        Box::new(|_, kind| assert_eq!(kind, OperationKind::DeviceInfo))
+    }
+
+    /// Answers a ListDevices request with the devices of the given bus IDs
+    fn list(bus_ids: &'static [&'static str]) -> Handler {
+        Box::new(move |socket, kind| {
+            assert_eq!(kind, OperationKind::ListDevices);
+
+            socket
+                .send_response_header(OperationKind::ListDevices, OperationStatus::Ok)
+                .unwrap();
+            socket
+                .send_encoded(ListDevicesReply {
+                    num_devices: bus_ids.len() as _,
+                })
+                .unwrap();
+
+            for bus_id in bus_ids {
+                send_device(socket, &device(bus_id));
+            }
+        })
+    }
+
+    #[test]
+    fn queries_the_device_with_device_info() {
+        let (port, server) = serve(vec![device_info()]);
+
+        let device = query_exported_device("127.0.0.1", port, "1-2").unwrap();
+
+        assert_eq!(device.remote_device_info.bus_id, "1-2");
+        assert_eq!(device.interfaces.len(), 1);
+        assert_eq!(device.url, format!("usbip://127.0.0.1:{port}/1-2"));
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_listing_devices() {
+        let (port, server) = serve(vec![unsupported(), list(&["1-1", "1-2", "2-1"])]);
+
+        let device = query_exported_device("127.0.0.1", port, "1-2").unwrap();
+
+        assert_eq!(device.remote_device_info.bus_id, "1-2");
+        assert_eq!(device.interfaces.len(), 1);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reports_devices_which_are_not_exported() {
+        let (port, server) = serve(vec![Box::new(|socket, kind| {
+            assert_eq!(kind, OperationKind::DeviceInfo);
+
+            socket.recv_encoded::<DeviceInfoRequest>().unwrap();
+            socket
+                .send_response_header(OperationKind::DeviceInfo, OperationStatus::NoSuchDevice)
+                .unwrap();
+        })]);
+
+        let result = query_exported_device("127.0.0.1", port, "1-2");
+        assert!(
+            matches!(&result, Err(Error::NoSuchDevice(bus_id)) if bus_id == "1-2"),
+            "{result:?}"
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn reports_devices_missing_from_the_fallback_list() {
+        let (port, server) = serve(vec![unsupported(), list(&["1-1"])]);
+
+        let result = query_exported_device("127.0.0.1", port, "1-2");
+        assert!(
+            matches!(&result, Err(Error::NoSuchDevice(bus_id)) if bus_id == "1-2"),
+            "{result:?}"
+        );
+
+        server.join().unwrap();
+    }
+}
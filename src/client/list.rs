@@ -1,16 +1,19 @@
-use std::{io, str::Utf8Error};
+use std::{cell::Cell, io, net::SocketAddr, str::Utf8Error};
 
+pub use crate::DeviceInterface;
 use crate::{
-    UsbDeviceInfo, UsbDeviceInfoValidationError,
-    hwdb::{get_class_display_strings, get_device_display_strings},
-    net::UsbIpSocket,
+    BusIdKey, UsbDeviceInfo, UsbDeviceInfoValidationError,
+    client::MAX_PROTOCOL_RETRIES,
+    hwdb::{IdSource, IdSourceError, NameResolver},
+    net::{ConnectOptions, NetworkError, UsbIpSocket},
     proto::{ListDevicesReply, OperationError, OperationKind, RawUsbDeviceInfo, UsbInterfaceInfo},
+    url::format_usbip_url,
 };
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Network connection failed ({0})")]
-    NetworkIo(io::Error),
+    NetworkIo(NetworkError),
 
     #[error("usbip network operation failed ({0})")]
     Operation(#[from] OperationError),
@@ -19,9 +22,41 @@ pub enum Error {
     ProtocolUsbDevice(#[from] UsbDeviceInfoValidationError),
     #[error("Failed to decode PDU strings as UTF-8")]
     Utf8(#[from] Utf8Error),
+    #[error("Server reported {count} exported devices, which exceeds the maximum of {max}")]
+    TooManyDevices { count: u32, max: u32 },
+    #[error(
+        "Connection closed after receiving {got} of {expected} interfaces of device #{device_index} in reply"
+    )]
+    TruncatedInterfaceList {
+        device_index: u32,
+        expected: u8,
+        got: u8,
+    },
+    #[error(
+        "Device #{device_index} in reply is malformed (the server probably sent a different number of interfaces for the previous device than it announced)"
+    )]
+    MalformedDeviceRecord { device_index: u32 },
 
-    #[error("Failed to initialize udev hwdb")]
-    UdevHwdb(io::Error),
+    #[error("Failed to set up USB name lookup ({0})")]
+    NameLookup(#[from] IdSourceError),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::NetworkIo(e.into())
+    }
+}
+
+impl Error {
+    /// Returns whether the error was caused by a reply which could not be
+    /// decoded, which may succeed when the operation is tried again
+    pub fn is_protocol_error(&self) -> bool {
+        match self {
+            Self::Operation(e) => e.is_decode_error(),
+            Self::ProtocolUsbDevice(_) | Self::Utf8(_) | Self::MalformedDeviceRecord { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -43,112 +78,673 @@ pub struct RemoteExportedDevice {
     pub interfaces: Vec<DeviceInterface>,
 }
 
-#[derive(Debug, serde::Serialize)]
-pub struct DeviceInterface {
-    pub b_interface_class: u8,
-    pub b_interface_sub_class: u8,
-    pub b_interface_protocol: u8,
+impl RemoteExportedDevice {
+    /// Key which orders devices by server and then by bus ID in natural order
+    /// (see [`compare_bus_ids`](crate::compare_bus_ids))
+    pub fn sort_key(&self) -> (&str, u16, BusIdKey<'_>) {
+        (
+            &self.host,
+            self.port,
+            BusIdKey(&self.remote_device_info.bus_id),
+        )
+    }
+}
 
-    pub class: Option<String>,
-    pub sub_class: Option<String>,
-    pub protocol: Option<String>,
+/// Options for [`list_remote_exported_devices_with_options`]
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+    /// Upper bound on the number of devices accepted from the server. Replies
+    /// announcing more devices than this are rejected, so a misbehaving server
+    /// can't make us wait on billions of reads.
+    pub max_devices: u32,
+    /// Number of times the whole list is retried on the next address the host
+    /// resolves to after a network error (e.g. a server behind round-robin DNS
+    /// resetting the connection). Each operation uses a fresh connection, so
+    /// retrying from scratch is always safe. Off (0) by default.
+    pub address_retries: u32,
+    /// Number of times the whole list is retried on a fresh connection after
+    /// the server sent a reply which could not be decoded (see
+    /// [`Error::is_protocol_error`]), e.g. when a flaky middlebox corrupts the
+    /// stream. These are counted separately from
+    /// [`address_retries`](ListOptions::address_retries) and capped at
+    /// [`MAX_PROTOCOL_RETRIES`]. Off (0) by default.
+    pub protocol_retries: u32,
+    /// Where the names of the devices are looked up, instead of the process
+    /// wide source set with [`set_id_source`](crate::hwdb::set_id_source)
+    pub id_source: Option<IdSource>,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            max_devices: 1024,
+            address_retries: 0,
+            protocol_retries: 0,
+            id_source: None,
+        }
+    }
 }
 
 /// Connects to a remote host to request a list of all of its exported devices
 /// (those currently bound to the usbip-host driver)
 pub fn list_remote_exported_devices(host: &str) -> Result<Vec<RemoteExportedDevice>, Error> {
-    #[cfg(feature = "runtime-hwdb")]
-    let hwdb = udev::Hwdb::new().map_err(Error::UdevHwdb)?; // TODO: fallback to baked hwdb?
-    let mut socket = UsbIpSocket::connect_host_and_port(host, UsbIpSocket::DEFAULT_PORT)
-        .map_err(Error::NetworkIo)?;
+    list_remote_exported_devices_with_options(
+        host,
+        UsbIpSocket::DEFAULT_PORT,
+        &ListOptions::default(),
+    )
+}
+
+/// Same as [`list_remote_exported_devices`], but allows specifying the
+/// server's TCP port and limits on the reply
+pub fn list_remote_exported_devices_with_options(
+    host: &str,
+    port: u16,
+    options: &ListOptions,
+) -> Result<Vec<RemoteExportedDevice>, Error> {
+    let names = NameResolver::new(options.id_source.as_ref())?;
+
+    with_retries(
+        host,
+        port,
+        options,
+        |socket| {
+            let mut devices = Vec::new();
+
+            list_on_socket(socket, &names, host, port, options, &mut |device| {
+                devices.push(device)
+            })?;
+
+            Ok(devices)
+        },
+        || true,
+    )
+}
+
+/// Same as [`list_remote_exported_devices_with_options`], but passes each
+/// device to the callback as soon as it has been received instead of
+/// collecting them, so they can be shown while a large reply is still coming
+/// in. Returns the number of devices received.
+///
+/// Devices which were already passed to the callback can't be taken back, so
+/// [`ListOptions::address_retries`] and [`ListOptions::protocol_retries`] only
+/// apply until the first device has been received.
+pub fn list_remote_exported_devices_streaming(
+    host: &str,
+    port: u16,
+    options: &ListOptions,
+    mut on_device: impl FnMut(RemoteExportedDevice),
+) -> Result<u32, Error> {
+    let names = NameResolver::new(options.id_source.as_ref())?;
+
+    let received = Cell::new(0);
+
+    with_retries(
+        host,
+        port,
+        options,
+        |socket| {
+            list_on_socket(socket, &names, host, port, options, &mut |device| {
+                received.set(received.get() + 1);
+                on_device(device)
+            })
+        },
+        || received.get() == 0,
+    )?;
+
+    Ok(received.get())
+}
+
+/// Runs the listing, starting over on a fresh connection after protocol
+/// errors as configured by [`ListOptions::protocol_retries`] (as long as
+/// `may_retry` allows it)
+fn with_retries<T>(
+    host: &str,
+    port: u16,
+    options: &ListOptions,
+    mut list: impl FnMut(&mut UsbIpSocket) -> Result<T, Error>,
+    may_retry: impl Fn() -> bool,
+) -> Result<T, Error> {
+    let max_attempts = options.protocol_retries.min(MAX_PROTOCOL_RETRIES);
+    let mut attempt = 0;
+
+    loop {
+        match with_address_retries(host, port, options, &mut list, &may_retry) {
+            Err(e) if e.is_protocol_error() && attempt < max_attempts && may_retry() => {
+                attempt += 1;
+                tracing::warn!(
+                    "listing devices from {host}:{port} failed ({e}), retrying ({attempt}/{max_attempts})"
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Runs the listing on a fresh connection, retrying on the next address the
+/// host resolves to after network errors as configured by
+/// [`ListOptions::address_retries`] (as long as `may_retry` allows it)
+fn with_address_retries<T>(
+    host: &str,
+    port: u16,
+    options: &ListOptions,
+    mut list: impl FnMut(&mut UsbIpSocket) -> Result<T, Error>,
+    may_retry: impl Fn() -> bool,
+) -> Result<T, Error> {
+    if options.address_retries == 0 {
+        let mut socket = UsbIpSocket::connect_host_and_port_with_options(
+            host,
+            port,
+            &ConnectOptions::short_lived(),
+        )?;
+
+        return list(&mut socket);
+    }
 
+    let addrs = UsbIpSocket::resolve_host_and_port(host, port)?;
+
+    list_on_addresses(&addrs, options, list, may_retry)
+}
+
+fn list_on_addresses<T>(
+    addrs: &[SocketAddr],
+    options: &ListOptions,
+    mut list: impl FnMut(&mut UsbIpSocket) -> Result<T, Error>,
+    may_retry: impl Fn() -> bool,
+) -> Result<T, Error> {
+    let mut attempt = 0;
+
+    loop {
+        let addr = addrs[attempt as usize % addrs.len()];
+
+        let result = UsbIpSocket::connect_with_options(addr, &ConnectOptions::short_lived())
+            .map_err(Error::from)
+            .and_then(|mut socket| list(&mut socket));
+
+        match result {
+            // the connection dying while the interfaces are being received
+            // shows up as a truncated list
+            Err(e @ (Error::NetworkIo(_) | Error::TruncatedInterfaceList { .. }))
+                if attempt < options.address_retries && may_retry() =>
+            {
+                tracing::warn!("listing devices from {addr} failed ({e}), retrying");
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn list_on_socket(
+    socket: &mut UsbIpSocket,
+    names: &NameResolver,
+    host: &str,
+    port: u16,
+    options: &ListOptions,
+    on_device: &mut dyn FnMut(RemoteExportedDevice),
+) -> Result<(), Error> {
     let op_kind = OperationKind::ListDevices;
 
-    socket
-        .send_request_header(op_kind)
-        .map_err(Error::NetworkIo)?;
-    socket
-        .recv_reply_header(op_kind)
-        .map_err(Error::NetworkIo)??;
+    socket.send_request_header(op_kind)?;
+    socket.recv_reply_header(op_kind)??;
 
-    let reply = socket
-        .recv_encoded::<ListDevicesReply>()
-        .map_err(Error::NetworkIo)?;
+    let reply = socket.recv_encoded::<ListDevicesReply>()?;
 
     tracing::debug!("expecting {} devices", reply.num_devices);
 
-    let mut results = Vec::new();
+    if reply.num_devices > options.max_devices {
+        return Err(Error::TooManyDevices {
+            count: reply.num_devices,
+            max: options.max_devices,
+        });
+    }
 
     if reply.num_devices == 0 {
         tracing::info!("no exported devices found");
-        return Ok(results);
+        return Ok(());
     }
 
-    for _ in 0..reply.num_devices {
-        let remote_device: UsbDeviceInfo = socket
-            .recv_encoded::<RawUsbDeviceInfo>()
-            .map_err(Error::NetworkIo)?
-            .try_into()?;
+    for i in 0..reply.num_devices {
+        on_device(recv_exported_device(socket, names, host, port, i)?);
+    }
+
+    Ok(())
+}
+
+/// Whether the record plausibly describes an actual device. The stream has no
+/// framing, so if a server sends more or fewer interfaces than it announced
+/// for a device, the next device is decoded starting at the wrong offset. The
+/// shifted record then starts with interface bytes (control characters) in
+/// its sysfs path or has an empty bus ID, which is caught here instead of
+/// listing garbage.
+fn looks_like_device_record(info: &UsbDeviceInfo) -> bool {
+    !info.bus_id.is_empty() && !info.sys_path.chars().any(char::is_control)
+}
+
+/// Receives a single device (and its interfaces) as encoded in the ListDevices
+/// and DeviceInfo replies. `device_index` is the position of the device within
+/// the reply and is only used for error reporting.
+pub(crate) fn recv_exported_device(
+    socket: &mut UsbIpSocket,
+    names: &NameResolver,
+    host: &str,
+    port: u16,
+    device_index: u32,
+) -> Result<RemoteExportedDevice, Error> {
+    let remote_device: UsbDeviceInfo = socket.recv_encoded::<RawUsbDeviceInfo>()?.try_into()?;
+
+    if !looks_like_device_record(&remote_device) {
+        return Err(Error::MalformedDeviceRecord { device_index });
+    }
+
+    let (vendor, product) =
+        names.device_display_strings(remote_device.id_vendor, remote_device.id_product);
+
+    let num_interfaces = remote_device.b_num_interfaces;
+
+    let mut raw_interfaces = Vec::with_capacity(num_interfaces as _);
+
+    for i in 0..num_interfaces {
+        let iface = socket.recv_encoded::<UsbInterfaceInfo>().map_err(|e| {
+            // the server promised more interfaces than it actually sent
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                Error::TruncatedInterfaceList {
+                    device_index,
+                    expected: num_interfaces,
+                    got: i,
+                }
+            } else {
+                e.into()
+            }
+        })?;
+
+        raw_interfaces.push(iface);
+    }
+
+    // the device class may be defined by its interfaces, so it can only be
+    // resolved once they have all been received
+    let (class, sub_class, protocol) = names.device_class_display_strings(
+        remote_device.b_device_class,
+        remote_device.b_device_sub_class,
+        remote_device.b_device_protocol,
+        &raw_interfaces,
+    );
+
+    // the server sends the interfaces in order of their interface number
+    let interfaces = raw_interfaces
+        .iter()
+        .zip(0..)
+        .map(|(iface, i)| DeviceInterface::new(names, remote_device.interface_bus_id(i), iface))
+        .collect();
+
+    Ok(RemoteExportedDevice {
+        host: host.to_string(),
+        port,
+        url: format_usbip_url(host, port, &remote_device.bus_id),
+        remote_device_info: remote_device,
+        vendor,
+        product,
+        class,
+        sub_class,
+        protocol,
+        interfaces,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, thread};
+
+    use compact_str::ToCompactString;
+
+    use super::*;
+    use crate::{UsbSpeed, proto::OperationStatus};
+
+    fn device(bus_id: &str) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            sys_path: format!("/sys/devices/platform/dummy_hcd.0/usb1/{bus_id}"),
+            bus_id: bus_id.to_compact_string(),
+            bus_num: 1,
+            dev_num: 2,
+            speed: UsbSpeed::High,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            b_device_class: 0,
+            b_device_sub_class: 0,
+            b_device_protocol: 0,
+            b_configuration_value: Some(1),
+            b_num_configurations: 1,
+            b_num_interfaces: 1,
+        }
+    }
 
-        let (vendor, product) = get_device_display_strings(
-            #[cfg(feature = "runtime-hwdb")]
-            &hwdb,
-            remote_device.id_vendor,
-            remote_device.id_product,
+    /// Serves a single list request, leaving everything after the reply
+    /// header to `reply`
+    fn serve_reply(
+        reply: impl FnOnce(&mut UsbIpSocket) + Send + 'static,
+    ) -> (u16, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = UsbIpSocket::from_std(stream, &Default::default()).unwrap();
+
+            socket.recv_request_header().unwrap().unwrap();
+
+            socket
+                .send_response_header(OperationKind::ListDevices, OperationStatus::Ok)
+                .unwrap();
+
+            reply(&mut socket);
+        });
+
+        (port, server)
+    }
+
+    /// Serves a single list request with the given devices, each with one
+    /// interface
+    fn serve_list(devices: Vec<UsbDeviceInfo>) -> (u16, thread::JoinHandle<()>) {
+        serve_reply(move |socket| {
+            socket
+                .send_encoded(ListDevicesReply {
+                    num_devices: devices.len() as _,
+                })
+                .unwrap();
+
+            for device in &devices {
+                socket.send_encoded(RawUsbDeviceInfo::from(device)).unwrap();
+                socket
+                    .send_encoded(UsbInterfaceInfo::new(0x08, 0x06, 0x50))
+                    .unwrap();
+            }
+        })
+    }
+
+    fn options() -> ListOptions {
+        ListOptions {
+            id_source: Some(IdSource::None),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn retries_the_list_on_the_next_address_after_a_reset() {
+        let flaky = TcpListener::bind("127.0.0.1:0").unwrap();
+        let flaky_addr = flaky.local_addr().unwrap();
+
+        let flaky_server = thread::spawn(move || {
+            let (mut stream, _) = flaky.accept().unwrap();
+
+            // closing with unread data makes the kernel reset the connection
+            stream.read_exact(&mut [0; 4]).unwrap();
+        });
+
+        let (port, server) = serve_list(vec![device("1-1")]);
+        let addrs = [flaky_addr, SocketAddr::from(([127, 0, 0, 1], port))];
+
+        let options = ListOptions {
+            address_retries: 1,
+            ..options()
+        };
+        let names = NameResolver::new(options.id_source.as_ref()).unwrap();
+
+        let devices = list_on_addresses(
+            &addrs,
+            &options,
+            |socket| {
+                let mut devices = Vec::new();
+                list_on_socket(socket, &names, "127.0.0.1", port, &options, &mut |device| {
+                    devices.push(device)
+                })?;
+                Ok(devices)
+            },
+            || true,
+        )
+        .unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].remote_device_info.bus_id, "1-1");
+
+        flaky_server.join().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streaming_calls_back_once_per_device_in_order() {
+        let (port, server) = serve_list(vec![device("1-2"), device("1-1"), device("1-3")]);
+
+        let mut seen = Vec::new();
+        let count =
+            list_remote_exported_devices_streaming("127.0.0.1", port, &options(), |device| {
+                assert_eq!(device.interfaces.len(), 1);
+                seen.push(device.remote_device_info.bus_id.to_string());
+            })
+            .unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(seen, ["1-2", "1-1", "1-3"]);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streaming_an_empty_list_never_calls_back() {
+        let (port, server) = serve_list(Vec::new());
+
+        let count =
+            list_remote_exported_devices_streaming("127.0.0.1", port, &options(), |device| {
+                panic!("unexpected device {device:?}")
+            })
+            .unwrap();
+
+        assert_eq!(count, 0);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn names_the_interfaces_of_a_multi_interface_device() {
+        let (port, server) = serve_reply(|socket| {
+            let device = UsbDeviceInfo {
+                b_configuration_value: Some(2),
+                b_num_interfaces: 3,
+                ..device("1-1.4")
+            };
+
+            socket
+                .send_encoded(ListDevicesReply { num_devices: 1 })
+                .unwrap();
+            socket
+                .send_encoded(RawUsbDeviceInfo::from(&device))
+                .unwrap();
+
+            for _ in 0..3 {
+                socket
+                    .send_encoded(UsbInterfaceInfo::new(0x03, 0x01, 0x01))
+                    .unwrap();
+            }
+        });
+
+        let devices =
+            list_remote_exported_devices_with_options("127.0.0.1", port, &options()).unwrap();
+
+        let bus_ids: Vec<_> = devices[0]
+            .interfaces
+            .iter()
+            .map(|iface| iface.bus_id.as_str())
+            .collect();
+        assert_eq!(bus_ids, ["1-1.4:2.0", "1-1.4:2.1", "1-1.4:2.2"]);
+
+        server.join().unwrap();
+    }
+
+    /// Serves a zero-interface device followed by another device, sending
+    /// `extra_interfaces` interfaces for the first one anyway
+    fn serve_zero_interface_device(extra_interfaces: u8) -> (u16, thread::JoinHandle<()>) {
+        serve_reply(move |socket| {
+            let empty = UsbDeviceInfo {
+                b_configuration_value: None,
+                b_num_interfaces: 0,
+                ..device("1-1")
+            };
+
+            socket
+                .send_encoded(ListDevicesReply { num_devices: 2 })
+                .unwrap();
+            socket.send_encoded(RawUsbDeviceInfo::from(&empty)).unwrap();
+
+            for _ in 0..extra_interfaces {
+                socket
+                    .send_encoded(UsbInterfaceInfo::new(0x08, 0x06, 0x50))
+                    .unwrap();
+            }
+
+            socket
+                .send_encoded(RawUsbDeviceInfo::from(&device("1-2")))
+                .unwrap();
+            socket
+                .send_encoded(UsbInterfaceInfo::new(0x08, 0x06, 0x50))
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn lists_a_zero_interface_device_followed_by_another_device() {
+        let (port, server) = serve_zero_interface_device(0);
+
+        let devices =
+            list_remote_exported_devices_with_options("127.0.0.1", port, &options()).unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].remote_device_info.bus_id, "1-1");
+        assert!(devices[0].interfaces.is_empty());
+        assert_eq!(devices[1].remote_device_info.bus_id, "1-2");
+        assert_eq!(devices[1].interfaces.len(), 1);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_interfaces_sent_for_a_zero_interface_device() {
+        let (port, server) = serve_zero_interface_device(1);
+
+        let result = list_remote_exported_devices_with_options("127.0.0.1", port, &options());
+
+        assert!(
+            matches!(
+                result,
+                Err(Error::MalformedDeviceRecord { device_index: 1 })
+            ),
+            "{result:?}"
         );
 
-        let (class, sub_class, protocol) = get_class_display_strings(
-            #[cfg(feature = "runtime-hwdb")]
-            &hwdb,
-            remote_device.b_device_class,
-            remote_device.b_device_sub_class,
-            remote_device.b_device_protocol,
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_an_enormous_device_count() {
+        // announces more devices than could ever be received, without sending
+        // any of them
+        let (port, server) = serve_reply(|socket| {
+            socket
+                .send_encoded(ListDevicesReply {
+                    num_devices: u32::MAX,
+                })
+                .unwrap();
+        });
+
+        let result = list_remote_exported_devices_with_options("127.0.0.1", port, &options());
+
+        assert!(
+            matches!(
+                result,
+                Err(Error::TooManyDevices {
+                    count: u32::MAX,
+                    max: 1024
+                })
+            ),
+            "{result:?}"
         );
 
-        let num_interfaces = remote_device.b_num_interfaces;
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn fails_when_the_connection_closes_mid_interfaces() {
+        // announces three interfaces, but closes the connection after the
+        // first one
+        let (port, server) = serve_reply(|socket| {
+            let device = UsbDeviceInfo {
+                b_num_interfaces: 3,
+                ..device("1-1")
+            };
+
+            socket
+                .send_encoded(ListDevicesReply { num_devices: 1 })
+                .unwrap();
+            socket
+                .send_encoded(RawUsbDeviceInfo::from(&device))
+                .unwrap();
+            socket
+                .send_encoded(UsbInterfaceInfo::new(0x08, 0x06, 0x50))
+                .unwrap();
+        });
+
+        let result = list_remote_exported_devices_with_options("127.0.0.1", port, &options());
 
-        let mut exported = RemoteExportedDevice {
-            host: host.to_string(),
-            port: UsbIpSocket::DEFAULT_PORT, // TODO: update when we add dynamic port support
-            url: format!(
-                "usbip://{host}:{}/{}",
-                UsbIpSocket::DEFAULT_PORT,
-                remote_device.bus_id
+        assert!(
+            matches!(
+                result,
+                Err(Error::TruncatedInterfaceList {
+                    device_index: 0,
+                    expected: 3,
+                    got: 1
+                })
             ),
-            remote_device_info: remote_device,
-            vendor,
-            product,
-            class,
-            sub_class,
-            protocol,
-            interfaces: Vec::with_capacity(num_interfaces as _),
+            "{result:?}"
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn sorts_devices_by_server_and_natural_bus_id() {
+        let exported = |host: &str, bus_id: &str| RemoteExportedDevice {
+            host: host.into(),
+            port: UsbIpSocket::DEFAULT_PORT,
+            url: format_usbip_url(host, UsbIpSocket::DEFAULT_PORT, bus_id),
+            remote_device_info: device(bus_id),
+            vendor: None,
+            product: None,
+            class: None,
+            sub_class: None,
+            protocol: None,
+            interfaces: Vec::new(),
         };
 
-        for _ in 0..num_interfaces {
-            let iface = socket
-                .recv_encoded::<UsbInterfaceInfo>()
-                .map_err(Error::NetworkIo)?;
-
-            let (class, sub_class, protocol) = get_class_display_strings(
-                #[cfg(feature = "runtime-hwdb")]
-                &hwdb,
-                iface.b_interface_class,
-                iface.b_interface_sub_class,
-                iface.b_interface_protocol,
-            );
-
-            exported.interfaces.push(DeviceInterface {
-                b_interface_class: iface.b_interface_class,
-                b_interface_sub_class: iface.b_interface_sub_class,
-                b_interface_protocol: iface.b_interface_protocol,
-                class,
-                sub_class,
-                protocol,
-            });
-        }
+        let mut devices = [
+            exported("b.local", "1-1"),
+            exported("a.local", "1-10"),
+            exported("a.local", "2-1"),
+            exported("a.local", "1-2"),
+        ];
+        devices.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
 
-        results.push(exported);
-    }
+        let order: Vec<_> = devices
+            .iter()
+            .map(|d| (d.host.as_str(), d.remote_device_info.bus_id.as_str()))
+            .collect();
 
-    Ok(results)
+        assert_eq!(
+            order,
+            [
+                ("a.local", "1-2"),
+                ("a.local", "1-10"),
+                ("a.local", "2-1"),
+                ("b.local", "1-1"),
+            ]
+        );
+    }
 }
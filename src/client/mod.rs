@@ -3,5 +3,14 @@
 
 pub mod attach;
 pub mod detach;
+pub mod device_info;
 pub mod list;
+pub mod ping;
 pub mod port;
+pub mod read_only;
+pub mod watchdog;
+
+/// Upper bound on the number of times an operation is retried after the server
+/// sent a reply which could not be decoded (see e.g.
+/// [`ListOptions::protocol_retries`](list::ListOptions::protocol_retries))
+pub const MAX_PROTOCOL_RETRIES: u32 = 5;
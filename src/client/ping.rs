@@ -0,0 +1,193 @@
+//! Probes which operations a remote server implements. Servers other than the
+//! Linux usbipd (and older versions of it) only implement a subset of the
+//! protocol, so this can be used to pick the cheapest supported request.
+
+use std::io::{self, ErrorKind};
+
+use endian_codec::{DecodeBE, PackedSize};
+use serde::Serialize;
+
+use crate::{
+    net::{ConnectOptions, NetworkError, UsbIpSocket},
+    proto::{
+        DeviceInfoRequest, Direction, ExportRequest, OperationHeader, OperationKind,
+        RawUsbDeviceInfo, char_buf::CharBuf,
+    },
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Network connection failed ({0})")]
+    NetworkIo(NetworkError),
+    #[error("Server did not reply to a ListDevices request, it is probably not a usbip server")]
+    NotUsbip,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::NetworkIo(e.into())
+    }
+}
+
+/// The operations supported by a server, as determined by
+/// [`server_capabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    /// Protocol version the server replied with (not necessarily the one we
+    /// speak)
+    pub version: u16,
+    /// Whether single devices can be queried with DeviceInfo instead of
+    /// listing all of them
+    pub supports_devinfo: bool,
+    /// Whether the server accepts devices pushed to it with Export
+    pub supports_export: bool,
+}
+
+/// Determines which operations the server supports. ListDevices is part of
+/// every usbip implementation, so it is used to check that the server speaks
+/// usbip at all and to learn its protocol version. The optional operations
+/// are then probed with requests which are guaranteed to fail (an empty bus
+/// ID), so they have no side effects on the server. Each probe uses its own
+/// connection, since servers close the connection after one request.
+pub fn server_capabilities(host: &str, port: u16) -> Result<Capabilities, Error> {
+    let version = probe_operation(host, port, OperationKind::ListDevices, |_| Ok(()))?
+        .ok_or(Error::NotUsbip)?;
+
+    let supports_devinfo = probe_operation(host, port, OperationKind::DeviceInfo, |socket| {
+        socket.send_encoded(DeviceInfoRequest {
+            bus_id: CharBuf::new_truncated(""),
+        })
+    })?
+    .is_some();
+
+    let supports_export = probe_operation(host, port, OperationKind::Export, |socket| {
+        // an all-zero device has an empty bus ID, which no server can import
+        let usb_device = RawUsbDeviceInfo::decode_from_be_bytes(&[0; RawUsbDeviceInfo::PACKED_LEN]);
+
+        socket.send_encoded(ExportRequest { usb_device })
+    })?
+    .is_some();
+
+    tracing::debug!(version, supports_devinfo, supports_export);
+
+    Ok(Capabilities {
+        version,
+        supports_devinfo,
+        supports_export,
+    })
+}
+
+/// Sends a request for the operation on a new connection and returns the
+/// version of the reply header if the server replied to that operation (with
+/// any status). Servers which don't implement an operation either close the
+/// connection or reply with a different operation code.
+fn probe_operation(
+    host: &str,
+    port: u16,
+    kind: OperationKind,
+    send_body: impl FnOnce(&mut UsbIpSocket) -> io::Result<()>,
+) -> Result<Option<u16>, Error> {
+    let mut socket = UsbIpSocket::connect_host_and_port_with_options(
+        host,
+        port,
+        &ConnectOptions::short_lived(),
+    )?;
+
+    let header = socket
+        .send_request_header(kind)
+        .and_then(|_| send_body(&mut socket))
+        .and_then(|_| socket.recv_encoded::<OperationHeader>());
+
+    let header = match header {
+        Ok(header) => header,
+        Err(e) if is_closed(&e) => {
+            tracing::debug!("server closed the connection when probing {kind:?} ({e})");
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let supported = Direction::from_code(header.code) == Direction::Reply
+        && OperationKind::from_code(header.code) == kind;
+
+    tracing::debug!(?header, supported, "probed {kind:?}");
+
+    Ok(supported.then_some(header.version))
+}
+
+fn is_closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::proto::{ListDevicesReply, OperationStatus, USBIP_VERSION};
+
+    /// Serves the given number of connections, replying only to ListDevices
+    /// requests (with an empty list) and closing the connection on any other
+    /// request
+    fn serve_list_only(connections: usize) -> (u16, thread::JoinHandle<()>) {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            for _ in 0..connections {
+                let (mut socket, _) = listener.accept().unwrap();
+
+                if let Ok(Ok(OperationKind::ListDevices)) = socket.recv_request_header() {
+                    socket
+                        .send_response_header(OperationKind::ListDevices, OperationStatus::Ok)
+                        .unwrap();
+                    socket
+                        .send_encoded(ListDevicesReply { num_devices: 0 })
+                        .unwrap();
+                }
+            }
+        });
+
+        (port, server)
+    }
+
+    #[test]
+    fn detects_a_server_which_only_lists_devices() {
+        let (port, server) = serve_list_only(3);
+
+        let capabilities = server_capabilities("127.0.0.1", port).unwrap();
+
+        assert_eq!(
+            capabilities,
+            Capabilities {
+                version: USBIP_VERSION,
+                supports_devinfo: false,
+                supports_export: false,
+            }
+        );
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn rejects_servers_which_do_not_list_devices() {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // closes the connection without replying
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let _ = socket.recv_request_header();
+        });
+
+        assert!(matches!(
+            server_capabilities("127.0.0.1", port),
+            Err(Error::NotUsbip)
+        ));
+
+        server.join().unwrap();
+    }
+}
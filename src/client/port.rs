@@ -1,12 +1,21 @@
-use std::io::{self};
+use std::{
+    collections::HashMap,
+    io::{self},
+    path::PathBuf,
+};
 
 use crate::{
     UsbDeviceInfo,
-    drivers::vhci::{
-        Error as VhciHcdError, HubSpeed, VhciDeviceStatus, VhciHcd,
-        state::{ConnectionRecord, read_connection_record},
+    drivers::{
+        DeviceLookupError, open_usb_device,
+        vhci::{
+            Error as VhciHcdError, HubSpeed, VhciDevice, VhciDeviceStatus, VhciHcd,
+            state::{ConnectionRecord, FsStateError, read_all_records, read_connection_record},
+        },
     },
-    hwdb::get_device_display_strings,
+    hwdb::{IdSourceError, NameResolver},
+    url::format_usbip_url,
+    util::{find_dev_nodes, read_display_attribute},
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -14,14 +23,20 @@ pub enum Error {
     #[error(transparent)]
     VhciHcdDriver(#[from] VhciHcdError),
 
-    #[error(
-        "An I/O error occurred while querying string descriptors from imported USB device with bus ID `{1}` ({0})"
-    )]
-    QueryingLocalUsbDevice(io::Error, String),
+    #[error("Failed to create udev context ({0})")]
+    CreatingUdevContext(io::Error),
+    #[error("Failed to open imported USB device ({0})")]
+    QueryingLocalUsbDevice(DeviceLookupError),
     #[error(
         "Failed to get value for udev attribute `{attribute}` from USB device with bus ID `{bus_id}`"
     )]
     MissingUdevAttribute { bus_id: String, attribute: String },
+    #[error("Failed to enumerate device nodes of imported USB device with bus ID `{1}` ({0})")]
+    EnumeratingDevNodes(io::Error, String),
+    #[error("Failed to read the connection records ({0})")]
+    ReadingRecords(FsStateError),
+    #[error("Failed to set up USB name lookup ({0})")]
+    NameLookup(#[from] IdSourceError),
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -35,6 +50,8 @@ pub struct ImportedDevice {
     pub remote_bus_id: Option<String>,
 
     pub url: Option<String>,
+    /// Name given to the device when attaching it
+    pub alias: Option<String>,
 
     pub remote_bus_num: u16,
     pub remote_dev_num: u16,
@@ -46,27 +63,166 @@ pub struct ImportedDevice {
     pub product_string: String,
 
     pub local_device_info: UsbDeviceInfo,
+    /// The `/dev` nodes of the local device and its descendants, starting
+    /// with the USB device itself (`/dev/bus/usb/BBB/DDD`)
+    pub dev_nodes: Vec<PathBuf>,
+}
+
+impl ImportedDevice {
+    /// Key which orders devices by their port, the order in which `vhci_hcd`
+    /// lists them
+    pub fn sort_key(&self) -> u16 {
+        self.port
+    }
+}
+
+/// A borrowed view of an imported device which only contains the information
+/// cached by [`VhciHcd`]. Unlike [`ImportedDevice`], creating one does not
+/// clone any data or perform additional lookups (connection records, string
+/// descriptors, hwdb), which makes it suitable for frequent polling.
+#[derive(Debug, Clone, Copy)]
+pub struct ImportedDeviceRef<'a> {
+    pub port: u16,
+    pub hub_speed: HubSpeed,
+    pub status: VhciDeviceStatus,
+
+    pub remote_bus_num: u16,
+    pub remote_dev_num: u16,
+
+    pub local_device_info: &'a UsbDeviceInfo,
+}
+
+/// Iterates over the devices currently imported on the given `vhci_hcd`, as of
+/// its last refresh
+pub fn imported_devices_ref(vhci_hcd: &VhciHcd) -> impl Iterator<Item = ImportedDeviceRef<'_>> {
+    connected_devices_ref(vhci_hcd.cached_imported_devices())
+}
+
+fn connected_devices_ref(devices: &[VhciDevice]) -> impl Iterator<Item = ImportedDeviceRef<'_>> {
+    devices.iter().filter_map(|imported_dev| {
+        let local_dev = imported_dev.connected_device()?;
+
+        Some(ImportedDeviceRef {
+            port: imported_dev.port,
+            hub_speed: imported_dev.hub_speed,
+            status: imported_dev.status(),
+            remote_bus_num: imported_dev.remote_bus_num(),
+            remote_dev_num: imported_dev.remote_dev_num(),
+            local_device_info: &local_dev.device,
+        })
+    })
+}
+
+/// A used port and the remote device it was attached from, as returned by
+/// [`list_remote_endpoints`]
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PortEndpoint {
+    pub port: u16,
+    pub status: VhciDeviceStatus,
+    /// The connection record of the port, `None` if there is none (e.g. the
+    /// device was attached without recording it)
+    pub remote_host: Option<String>,
+    pub remote_port: Option<u16>,
+    pub remote_bus_id: Option<String>,
+    pub alias: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Lists the used ports along with the remote endpoints they are connected to.
+/// Unlike [`list_imported_devices`], this only combines the `vhci_hcd` status
+/// with the connection records and doesn't query the local devices any
+/// further, which makes it cheap enough for monitoring.
+pub fn list_remote_endpoints() -> Result<Vec<PortEndpoint>, Error> {
+    let records = read_all_records().map_err(Error::ReadingRecords)?;
+    let vhci_hcd = VhciHcd::open()?;
+
+    Ok(pair_with_records(imported_devices_ref(&vhci_hcd), records))
+}
+
+/// Pairs each device with the connection record of its port (if any)
+fn pair_with_records<'a>(
+    devices: impl Iterator<Item = ImportedDeviceRef<'a>>,
+    mut records: Vec<(u16, ConnectionRecord)>,
+) -> Vec<PortEndpoint> {
+    devices
+        .map(|device| {
+            let record = records
+                .iter()
+                .position(|(port, _)| *port == device.port)
+                .map(|i| records.swap_remove(i).1);
+
+            let (url, remote_host, remote_port, remote_bus_id, alias) = match record {
+                Some(ConnectionRecord {
+                    host,
+                    port,
+                    bus_id,
+                    alias,
+                }) => (
+                    Some(format_usbip_url(&host, port, &bus_id)),
+                    Some(host),
+                    Some(port),
+                    Some(bus_id),
+                    alias,
+                ),
+                None => Default::default(),
+            };
+
+            PortEndpoint {
+                port: device.port,
+                status: device.status,
+                remote_host,
+                remote_port,
+                remote_bus_id,
+                alias,
+                url,
+            }
+        })
+        .collect()
+}
+
+/// Key of the group of [`group_by_host`] which holds the devices whose remote
+/// host is unknown (e.g. because they were attached without a connection
+/// record). Host names are never empty, so it can't clash with a real host.
+pub const UNKNOWN_HOST: &str = "";
+
+/// Groups imported devices by their remote host, keeping the order of the
+/// devices within each group. Devices without a known remote host are grouped
+/// under [`UNKNOWN_HOST`].
+pub fn group_by_host(devices: Vec<ImportedDevice>) -> HashMap<String, Vec<ImportedDevice>> {
+    let mut groups: HashMap<String, Vec<ImportedDevice>> = HashMap::new();
+
+    for device in devices {
+        let host = device.remote_host.as_deref().unwrap_or(UNKNOWN_HOST);
+
+        groups.entry(host.into()).or_default().push(device);
+    }
+
+    groups
 }
 
 pub fn list_imported_devices() -> Result<Vec<ImportedDevice>, Error> {
-    #[cfg(feature = "runtime-hwdb")]
-    let hwdb = udev::Hwdb::new()?;
+    let names = NameResolver::new(None)?;
     let vhci_hdc = VhciHcd::open()?;
+    let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
 
     let mut res = Vec::new();
 
-    for imported_dev in vhci_hdc.cached_imported_devices() {
-        let Some(local_dev) = imported_dev.connected_device() else {
-            continue;
-        };
+    for imported_dev in imported_devices_ref(&vhci_hdc) {
+        let local_dev = imported_dev.local_device_info;
 
-        let (url, remote_host, remote_port, remote_bus_id) =
+        let (url, remote_host, remote_port, remote_bus_id, alias) =
             match read_connection_record(imported_dev.port) {
-                Ok(ConnectionRecord { host, port, bus_id }) => (
-                    Some(format!("usbip://{host}:{port}/{bus_id}")),
+                Ok(ConnectionRecord {
+                    host,
+                    port,
+                    bus_id,
+                    alias,
+                }) => (
+                    Some(format_usbip_url(&host, port, &bus_id)),
                     Some(host),
                     Some(port),
                     Some(bus_id),
+                    alias,
                 ),
                 Err(e) => {
                     tracing::error!("failed to read state for port {}: {e}", imported_dev.port);
@@ -74,56 +230,211 @@ pub fn list_imported_devices() -> Result<Vec<ImportedDevice>, Error> {
                 }
             };
 
-        let (manufacturer_string, product_string) =
-            query_device_string_descriptors(&local_dev.device.bus_id)?;
-        let (vendor, product) = get_device_display_strings(
-            #[cfg(feature = "runtime-hwdb")]
-            &hwdb,
-            local_dev.device.id_vendor,
-            local_dev.device.id_product,
-        );
+        let dev =
+            open_usb_device(&context, &local_dev.bus_id).map_err(Error::QueryingLocalUsbDevice)?;
+
+        let (manufacturer_string, product_string) = query_device_string_descriptors(&dev)?;
+        let dev_nodes = find_dev_nodes(&dev)
+            .map_err(|e| Error::EnumeratingDevNodes(e, local_dev.bus_id.to_string()))?;
+        let (vendor, product) =
+            names.device_display_strings(local_dev.id_vendor, local_dev.id_product);
 
         res.push(ImportedDevice {
             port: imported_dev.port,
             hub_speed: imported_dev.hub_speed,
-            status: imported_dev.status(),
+            status: imported_dev.status,
             remote_host,
             remote_port,
             remote_bus_id,
             url,
-            remote_bus_num: imported_dev.remote_bus_num(),
-            remote_dev_num: imported_dev.remote_dev_num(),
+            alias,
+            remote_bus_num: imported_dev.remote_bus_num,
+            remote_dev_num: imported_dev.remote_dev_num,
             vendor,
             product,
             manufacturer_string,
             product_string,
-            local_device_info: local_dev.device.clone(),
+            local_device_info: local_dev.clone(),
+            dev_nodes,
         });
     }
 
     Ok(res)
 }
 
-fn query_device_string_descriptors(local_bus_id: &str) -> Result<(String, String), Error> {
-    let dev = udev::Device::from_subsystem_sysname("usb".into(), local_bus_id.into())
-        .map_err(|e| Error::QueryingLocalUsbDevice(e, local_bus_id.into()))?;
-
-    let manufacturer = dev
-        .attribute_value("manufacturer")
-        .ok_or_else(|| Error::MissingUdevAttribute {
-            bus_id: local_bus_id.into(),
-            attribute: "manufacturer".into(),
-        })?
-        .to_string_lossy()
-        .to_string();
-    let product = dev
-        .attribute_value("product")
-        .ok_or_else(|| Error::MissingUdevAttribute {
-            bus_id: local_bus_id.into(),
-            attribute: "product".into(),
-        })?
-        .to_string_lossy()
-        .to_string();
-
-    Ok((manufacturer, product))
+fn query_device_string_descriptors(dev: &udev::Device) -> Result<(String, String), Error> {
+    let local_bus_id = dev.sysname().to_string_lossy().to_string();
+
+    let read = |attribute: &str| {
+        read_display_attribute(dev, attribute).ok_or_else(|| Error::MissingUdevAttribute {
+            bus_id: local_bus_id.clone(),
+            attribute: attribute.into(),
+        })
+    };
+
+    Ok((read("manufacturer")?, read("product")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(port: u16, remote_host: Option<&str>) -> ImportedDevice {
+        ImportedDevice {
+            port,
+            hub_speed: HubSpeed::High,
+            status: VhciDeviceStatus::Used,
+            remote_host: remote_host.map(Into::into),
+            remote_port: None,
+            remote_bus_id: None,
+            url: None,
+            alias: None,
+            remote_bus_num: 0,
+            remote_dev_num: 0,
+            vendor: None,
+            product: None,
+            manufacturer_string: String::new(),
+            product_string: String::new(),
+            local_device_info: UsbDeviceInfo::default(),
+            dev_nodes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn pairs_the_used_ports_with_their_records() {
+        use crate::{
+            drivers::vhci::{
+                VhciDeviceState, VhciImportedDevice,
+                state::{read_all_records_in, save_connection_record_in},
+            },
+            util::TempDir,
+        };
+
+        let imported = VhciDeviceState::Used(VhciImportedDevice {
+            remote_device_id: (2 << 16) | 5,
+            socket_fd: 3,
+            kernel_speed: 3,
+            device: UsbDeviceInfo::default(),
+        });
+        let devices = [
+            VhciDevice {
+                hub_speed: HubSpeed::High,
+                port: 0,
+                state: VhciDeviceState::NotConnected,
+            },
+            VhciDevice {
+                hub_speed: HubSpeed::High,
+                port: 1,
+                state: imported.clone(),
+            },
+            VhciDevice {
+                hub_speed: HubSpeed::Super,
+                port: 8,
+                state: imported,
+            },
+        ];
+
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+        let record = |host: &str, bus_id: &str, alias: Option<&str>| ConnectionRecord {
+            host: host.into(),
+            port: 3240,
+            bus_id: bus_id.into(),
+            alias: alias.map(Into::into),
+        };
+        save_connection_record_in(&state_path, 1, record("10.0.0.1", "1-1", Some("camera")))
+            .unwrap();
+        // left behind by a port which is no longer used
+        save_connection_record_in(&state_path, 0, record("10.0.0.2", "1-2", None)).unwrap();
+
+        let records = read_all_records_in(&state_path).unwrap();
+        let endpoints = pair_with_records(connected_devices_ref(&devices), records);
+
+        assert_eq!(endpoints.len(), 2);
+
+        assert_eq!(endpoints[0].port, 1);
+        assert_eq!(endpoints[0].status, VhciDeviceStatus::Used);
+        assert_eq!(endpoints[0].remote_host.as_deref(), Some("10.0.0.1"));
+        assert_eq!(endpoints[0].remote_port, Some(3240));
+        assert_eq!(endpoints[0].remote_bus_id.as_deref(), Some("1-1"));
+        assert_eq!(endpoints[0].alias.as_deref(), Some("camera"));
+        assert_eq!(
+            endpoints[0].url.as_deref(),
+            Some("usbip://10.0.0.1:3240/1-1")
+        );
+
+        // attached without recording it
+        assert_eq!(endpoints[1].port, 8);
+        assert_eq!(endpoints[1].remote_host, None);
+        assert_eq!(endpoints[1].url, None);
+    }
+
+    #[test]
+    fn borrows_the_connected_devices() {
+        use crate::drivers::vhci::{VhciDeviceState, VhciImportedDevice};
+
+        let imported = |bus_id: &str| VhciImportedDevice {
+            remote_device_id: (2 << 16) | 5,
+            socket_fd: 3,
+            kernel_speed: 3,
+            device: UsbDeviceInfo {
+                bus_id: bus_id.into(),
+                ..Default::default()
+            },
+        };
+        let vhci_device = |port, state| VhciDevice {
+            hub_speed: HubSpeed::High,
+            port,
+            state,
+        };
+
+        let devices = [
+            vhci_device(0, VhciDeviceState::NotConnected),
+            vhci_device(1, VhciDeviceState::Used(imported("3-2"))),
+            vhci_device(2, VhciDeviceState::NotAssigned),
+            vhci_device(3, VhciDeviceState::Error(imported("3-4"))),
+        ];
+
+        let refs: Vec<_> = connected_devices_ref(&devices).collect();
+
+        assert_eq!(refs.len(), 2);
+
+        for (r, device) in refs.iter().zip([&devices[1], &devices[3]]) {
+            assert_eq!(r.port, device.port);
+            assert_eq!(r.hub_speed, device.hub_speed);
+            assert_eq!(r.status, device.status());
+            assert_eq!((r.remote_bus_num, r.remote_dev_num), (2, 5));
+            assert!(std::ptr::eq(
+                r.local_device_info,
+                &device.connected_device().unwrap().device
+            ));
+        }
+
+        assert_eq!(refs[0].status, VhciDeviceStatus::Used);
+        assert_eq!(refs[1].status, VhciDeviceStatus::Error);
+    }
+
+    fn ports(devices: &[ImportedDevice]) -> Vec<u16> {
+        devices.iter().map(|d| d.port).collect()
+    }
+
+    #[test]
+    fn groups_devices_by_host_in_order() {
+        let groups = group_by_host(vec![
+            device(3, Some("alpha")),
+            device(0, Some("beta")),
+            device(1, Some("alpha")),
+            device(8, None),
+        ]);
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(ports(&groups["alpha"]), [3, 1]);
+        assert_eq!(ports(&groups["beta"]), [0]);
+        assert_eq!(ports(&groups[UNKNOWN_HOST]), [8]);
+    }
+
+    #[test]
+    fn groups_nothing_without_devices() {
+        assert!(group_by_host(Vec::new()).is_empty());
+    }
 }
@@ -0,0 +1,257 @@
+//! Makes imported mass storage devices read-only. usbip itself has no notion of
+//! read-only devices, so this is applied locally after the import by setting
+//! the kernel's read-only flag on the device's disks (the same as `blockdev
+//! --setro`), which partitions inherit. Only mass storage devices are backed by
+//! block devices, so this is not supported for any other device class.
+//!
+//! NOTE: the flag can only be set once the kernel has created the disks, so
+//! anything which opens them as soon as they appear (e.g. an automounter) may
+//! still get a writable handle.
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    drivers::vhci::{Error as VhciHcdError, VhciDeviceStatus, VhciHcd},
+    proto::class::{USB_CLASS_MASS_STORAGE, sysfs_class_value},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    VhciHcd(#[from] VhciHcdError),
+
+    #[error("Device on port {0} was not enumerated in time")]
+    DeviceNotEnumerated(u32),
+    #[error("Device on port {0} is not a mass storage device, so it can't be made read-only")]
+    NotMassStorage(u32),
+    #[error("No disks of the device on port {0} appeared in time")]
+    NoDisks(u32),
+
+    #[error("Failed to create udev context ({0})")]
+    CreatingUdevContext(io::Error),
+    #[error("Failed to open USB device `{path}` ({error})")]
+    OpeningUsbDevice { path: String, error: io::Error },
+    #[error("Failed to enumerate devices with udev ({0})")]
+    EnumeratingUdevDevices(io::Error),
+
+    #[error("Failed to run `blockdev` ({0})")]
+    RunningBlockdev(io::Error),
+    #[error("Failed to make `{}` read-only (`blockdev` exited with {status})", device.display())]
+    SettingReadOnly { device: PathBuf, status: ExitStatus },
+}
+
+/// Options for [`make_read_only_with_options`]
+#[derive(Debug, Clone)]
+pub struct ReadOnlyOptions {
+    /// How long to wait for the device and its disks to show up. Disks only
+    /// appear once the SCSI layer has probed the device, which can take a few
+    /// seconds. 10 seconds by default.
+    pub timeout: Duration,
+    /// How long to wait between checks for the device and its disks. 100ms by
+    /// default.
+    pub retry_delay: Duration,
+}
+
+impl Default for ReadOnlyOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            retry_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Sets the read-only flag on all disks of the device attached to the given
+/// `vhci_hcd` port, waiting for them to appear first. Returns the device nodes
+/// of the disks.
+pub fn make_read_only(rh_port: u32) -> Result<Vec<PathBuf>, Error> {
+    make_read_only_with_options(rh_port, &ReadOnlyOptions::default())
+}
+
+/// Same as [`make_read_only`], but allows configuring how long to wait for the
+/// disks
+pub fn make_read_only_with_options(
+    rh_port: u32,
+    options: &ReadOnlyOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    let deadline = Instant::now() + options.timeout;
+
+    let sys_path = wait_for_local_device(rh_port, deadline, options.retry_delay)?;
+
+    let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
+
+    let usb_device = udev::Device::from_syspath_with_context(context.clone(), Path::new(&sys_path))
+        .map_err(|error| Error::OpeningUsbDevice {
+            path: sys_path.clone(),
+            error,
+        })?;
+
+    // the interfaces are registered together with the device, so unlike the
+    // disks they don't have to be waited for
+    let is_mass_storage = children(&context, &usb_device, "usb")?.iter().any(|i| {
+        i.attribute_value("bInterfaceClass")
+            == Some(OsStr::new(&sysfs_class_value(USB_CLASS_MASS_STORAGE)))
+    });
+
+    if !is_mass_storage {
+        return Err(Error::NotMassStorage(rh_port));
+    }
+
+    let disks = loop {
+        let disks: Vec<_> = children(&context, &usb_device, "block")?
+            .iter()
+            .filter(|d| d.devtype() == Some(OsStr::new("disk")))
+            .filter_map(|d| d.devnode().map(Path::to_path_buf))
+            .collect();
+
+        if !disks.is_empty() {
+            break disks;
+        }
+
+        if Instant::now() >= deadline {
+            return Err(Error::NoDisks(rh_port));
+        }
+
+        thread::sleep(options.retry_delay);
+    };
+
+    for disk in &disks {
+        let status = Command::new("blockdev")
+            .arg("--setro")
+            .arg(disk)
+            .status()
+            .map_err(Error::RunningBlockdev)?;
+
+        if !status.success() {
+            return Err(Error::SettingReadOnly {
+                device: disk.clone(),
+                status,
+            });
+        }
+
+        tracing::info!("`{}` set to read-only", disk.display());
+    }
+
+    Ok(disks)
+}
+
+/// Waits until the port is in use and returns the sysfs path of the local USB
+/// device created for it
+fn wait_for_local_device(
+    rh_port: u32,
+    deadline: Instant,
+    retry_delay: Duration,
+) -> Result<String, Error> {
+    let mut vhci_hcd = VhciHcd::open()?;
+
+    loop {
+        match vhci_hcd.refresh_port(rh_port as u16) {
+            Ok(()) => {
+                let device = &vhci_hcd.cached_imported_devices()[rh_port as usize];
+
+                match (device.status(), device.connected_device()) {
+                    (VhciDeviceStatus::Used, Some(connected)) => {
+                        return Ok(connected.device.sys_path.clone());
+                    }
+                    (VhciDeviceStatus::NotConnected | VhciDeviceStatus::Error, _) => {
+                        return Err(Error::DeviceNotEnumerated(rh_port));
+                    }
+                    _ => {}
+                }
+            }
+            // the local usb device may not have been registered with udev yet
+            Err(e @ VhciHcdError::QueryingLocalUsbDevice(_)) => {
+                tracing::debug!("imported device not visible yet ({e})");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if Instant::now() >= deadline {
+            return Err(Error::DeviceNotEnumerated(rh_port));
+        }
+
+        thread::sleep(retry_delay);
+    }
+}
+
+fn children(
+    context: &udev::Udev,
+    parent: &udev::Device,
+    subsystem: &str,
+) -> Result<Vec<udev::Device>, Error> {
+    let mut enumerator =
+        udev::Enumerator::with_udev(context.clone()).map_err(Error::EnumeratingUdevDevices)?;
+
+    enumerator
+        .match_parent(parent)
+        .map_err(Error::EnumeratingUdevDevices)?;
+    enumerator
+        .match_subsystem(subsystem)
+        .map_err(Error::EnumeratingUdevDevices)?;
+
+    Ok(enumerator
+        .scan_devices()
+        .map_err(Error::EnumeratingUdevDevices)?
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    fn short_timeout() -> ReadOnlyOptions {
+        ReadOnlyOptions {
+            timeout: Duration::from_millis(100),
+            retry_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn fails_without_vhci_hcd() {
+        // only meaningful where the module is not loaded
+        if Path::new("/sys/bus/platform/devices/vhci_hcd.0").exists() {
+            return;
+        }
+
+        let result = make_read_only_with_options(0, &short_timeout());
+
+        assert!(
+            matches!(
+                result,
+                Err(Error::VhciHcd(VhciHcdError::VhciDeviceNotFound { .. }))
+            ),
+            "{result:?}"
+        );
+    }
+
+    /// Needs root and a mass storage device attached to the port given in
+    /// `USBIP_READ_ONLY_TEST_PORT`, e.g. `USBIP_READ_ONLY_TEST_PORT=0 cargo
+    /// test -- --ignored makes_the_disks_read_only`
+    #[test]
+    #[ignore = "needs a mass storage device attached to vhci_hcd"]
+    fn makes_the_disks_read_only() {
+        let port = env::var("USBIP_READ_ONLY_TEST_PORT")
+            .expect("USBIP_READ_ONLY_TEST_PORT is not set")
+            .parse()
+            .unwrap();
+
+        let disks = make_read_only(port).unwrap();
+        assert!(!disks.is_empty());
+
+        for disk in disks {
+            let name = disk.file_name().unwrap();
+            let ro = fs::read_to_string(Path::new("/sys/class/block").join(name).join("ro"));
+
+            assert_eq!(ro.unwrap().trim(), "1", "{}", disk.display());
+        }
+    }
+}
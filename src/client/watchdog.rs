@@ -0,0 +1,438 @@
+//! Implements a supervisor which keeps a remote device imported for as long as
+//! it is running. This is meant for long-lived imports (e.g. appliances which
+//! import a device at boot) that need to survive server restarts and network
+//! outages.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    client::{
+        attach::{AttachOptions, Error as AttachError, attach_device_with_options},
+        detach::detach_device,
+    },
+    drivers::vhci::{VhciDeviceStatus, VhciHcd, state::read_connection_record},
+};
+
+/// The connection parameters and timings used by an [`ImportWatchdog`]
+#[derive(Debug, Clone)]
+pub struct WatchdogParams {
+    pub host: String,
+    pub port: u16,
+    pub bus_id: String,
+    pub attach_options: AttachOptions,
+
+    /// How often the state of the imported port is checked
+    pub poll_interval: Duration,
+    /// Delay before the first re-attach attempt after the device was lost
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between re-attach attempts
+    pub max_backoff: Duration,
+}
+
+impl WatchdogParams {
+    pub fn new(host: &str, port: u16, bus_id: &str) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            bus_id: bus_id.into(),
+            attach_options: AttachOptions::default(),
+            poll_interval: Duration::from_secs(2),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Attaches a remote device and re-attaches it from a background thread
+/// whenever its port goes into the error state or is disconnected
+pub struct ImportWatchdog {
+    port: Arc<AtomicU32>,
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Number of polls in a row in which the state of the port could not be
+/// queried before the device is considered lost. A single failed query (e.g.
+/// while the `vhci_hcd` module is being reloaded) doesn't mean the device is
+/// gone, but if the module was unloaded for good, its ports are.
+const MAX_UNKNOWN_POLLS: u32 = 3;
+
+impl ImportWatchdog {
+    /// Performs the initial attach (returning any error it produces) and then
+    /// starts supervising the imported port
+    pub fn start(params: WatchdogParams) -> Result<Self, AttachError> {
+        Self::start_with(params, SystemBackend)
+    }
+
+    fn start_with(
+        params: WatchdogParams,
+        mut backend: impl Backend + Send + 'static,
+    ) -> Result<Self, AttachError> {
+        let rh_port = backend.attach(&params)?;
+
+        let port = Arc::new(AtomicU32::new(rh_port));
+        let (stop, stop_rx) = mpsc::channel::<()>();
+
+        let handle = {
+            let port = port.clone();
+
+            thread::spawn(move || {
+                let mut backoff = None;
+                let mut unknown_polls = 0;
+
+                loop {
+                    let timeout = backoff.unwrap_or(params.poll_interval);
+
+                    match stop_rx.recv_timeout(timeout) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => break,
+                    }
+
+                    let current = port.load(Ordering::SeqCst);
+
+                    let health = match backend.port_health(&params, current) {
+                        PortHealth::Unknown if unknown_polls + 1 < MAX_UNKNOWN_POLLS => {
+                            unknown_polls += 1;
+                            continue;
+                        }
+                        health => health,
+                    };
+                    unknown_polls = 0;
+
+                    if health == PortHealth::Alive {
+                        backoff = None;
+                        continue;
+                    }
+
+                    if health == PortHealth::Errored {
+                        backend.release(current);
+                    }
+
+                    match backend.attach(&params) {
+                        Ok(new_port) => {
+                            tracing::info!("device re-attached to port {new_port}");
+
+                            port.store(new_port, Ordering::SeqCst);
+                            backoff = None;
+                        }
+                        Err(e) => {
+                            let next = backoff
+                                .map_or(params.initial_backoff, |b: Duration| b * 2)
+                                .min(params.max_backoff);
+
+                            tracing::warn!("failed to re-attach device, retrying in {next:?}: {e}");
+
+                            backoff = Some(next);
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            port,
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+
+    /// The local vhci_hcd port the device is currently attached to
+    pub fn port(&self) -> u32 {
+        self.port.load(Ordering::SeqCst)
+    }
+
+    /// Stops supervising the device. The device is left attached.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    /// Blocks the current thread until the watchdog is stopped (which only
+    /// happens if the supervising thread panics)
+    pub fn wait(mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for ImportWatchdog {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PortHealth {
+    /// The supervised device is still attached and in use
+    Alive,
+    /// The port is in the error state and has to be released before reuse
+    Errored,
+    /// The port no longer holds the supervised device
+    Gone,
+    /// The state of the port could not be queried
+    Unknown,
+}
+
+/// What the watchdog does to the system, so that the supervision itself can be
+/// tested without a `vhci_hcd` device
+trait Backend {
+    fn attach(&mut self, params: &WatchdogParams) -> Result<u32, AttachError>;
+    /// Checks whether the supervised device is still in use on the given port
+    fn port_health(&mut self, params: &WatchdogParams, rh_port: u32) -> PortHealth;
+    /// Detaches a port in the error state so it can be reused
+    fn release(&mut self, rh_port: u32);
+}
+
+struct SystemBackend;
+
+impl Backend for SystemBackend {
+    fn attach(&mut self, params: &WatchdogParams) -> Result<u32, AttachError> {
+        attach_device_with_options(
+            &params.host,
+            params.port,
+            &params.bus_id,
+            &params.attach_options,
+        )
+    }
+
+    fn port_health(&mut self, params: &WatchdogParams, rh_port: u32) -> PortHealth {
+        port_health(params, rh_port)
+    }
+
+    fn release(&mut self, rh_port: u32) {
+        if let Err(e) = detach_device(rh_port as u16, false) {
+            tracing::debug!("failed to detach port {rh_port} before re-attaching: {e}");
+        }
+    }
+}
+
+fn port_health(params: &WatchdogParams, rh_port: u32) -> PortHealth {
+    let vhci_hcd = match VhciHcd::open() {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::warn!("failed to query vhci_hcd state: {e}");
+            return PortHealth::Unknown;
+        }
+    };
+
+    let Some(device) = vhci_hcd
+        .cached_imported_devices()
+        .iter()
+        .find(|d| d.port as u32 == rh_port)
+    else {
+        return PortHealth::Gone;
+    };
+
+    match device.status() {
+        VhciDeviceStatus::Used | VhciDeviceStatus::NotAssigned => {
+            // make sure the port wasn't re-used by another import after we
+            // lost our device
+            let ours = read_connection_record(device.port)
+                .map(|r| r.host == params.host && r.bus_id == params.bus_id)
+                .unwrap_or(true);
+
+            if ours {
+                PortHealth::Alive
+            } else {
+                tracing::warn!("port {rh_port} has been re-used by another device");
+                PortHealth::Gone
+            }
+        }
+        VhciDeviceStatus::Error => {
+            tracing::warn!("port {rh_port} is in the error state");
+            PortHealth::Errored
+        }
+        VhciDeviceStatus::NotConnected => {
+            tracing::warn!("port {rh_port} is no longer connected");
+            PortHealth::Gone
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{Mutex, MutexGuard},
+        time::Instant,
+    };
+
+    use super::*;
+
+    /// What the mock backend was asked to do, along with the scripted health
+    /// of the port
+    #[derive(Debug, Default)]
+    struct Calls {
+        health: VecDeque<PortHealth>,
+        next_ports: VecDeque<u32>,
+        attaches: u32,
+        released: Vec<u32>,
+    }
+
+    #[derive(Clone, Default)]
+    struct MockBackend(Arc<Mutex<Calls>>);
+
+    impl MockBackend {
+        fn new(health: &[PortHealth], ports: &[u32]) -> Self {
+            Self(Arc::new(Mutex::new(Calls {
+                health: health.iter().copied().collect(),
+                next_ports: ports.iter().copied().collect(),
+                ..Default::default()
+            })))
+        }
+
+        fn calls(&self) -> MutexGuard<'_, Calls> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    impl Backend for MockBackend {
+        fn attach(&mut self, _: &WatchdogParams) -> Result<u32, AttachError> {
+            let mut calls = self.calls();
+            calls.attaches += 1;
+
+            calls.next_ports.pop_front().ok_or(AttachError::Timeout)
+        }
+
+        fn port_health(&mut self, _: &WatchdogParams, _: u32) -> PortHealth {
+            // once the script is done, the device stays attached
+            self.calls().health.pop_front().unwrap_or(PortHealth::Alive)
+        }
+
+        fn release(&mut self, rh_port: u32) {
+            self.calls().released.push(rh_port);
+        }
+    }
+
+    fn params() -> WatchdogParams {
+        WatchdogParams {
+            poll_interval: Duration::from_millis(1),
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            ..WatchdogParams::new("server", 3240, "1-1")
+        }
+    }
+
+    /// Waits until the polls scripted for the backend have all happened
+    fn wait_for_script(backend: &MockBackend) {
+        let start = Instant::now();
+
+        while !backend.calls().health.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "watchdog stalled");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn reattaches_a_port_in_the_error_state() {
+        use PortHealth::*;
+
+        let backend = MockBackend::new(&[Alive, Errored, Alive], &[2, 5]);
+        let watchdog = ImportWatchdog::start_with(params(), backend.clone()).unwrap();
+        assert_eq!(watchdog.port(), 2);
+
+        wait_for_script(&backend);
+        watchdog.stop();
+
+        assert_eq!(backend.calls().attaches, 2);
+        assert_eq!(backend.calls().released, [2]);
+    }
+
+    #[test]
+    fn reattaches_a_lost_port_without_releasing_it() {
+        use PortHealth::*;
+
+        let backend = MockBackend::new(&[Gone], &[0, 1]);
+        let watchdog = ImportWatchdog::start_with(params(), backend.clone()).unwrap();
+
+        wait_for_script(&backend);
+        let start = Instant::now();
+        while watchdog.port() != 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "never re-attached"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        watchdog.stop();
+
+        assert!(backend.calls().released.is_empty());
+    }
+
+    #[test]
+    fn keeps_retrying_failed_reattaches() {
+        use PortHealth::*;
+
+        // the first re-attach fails since no port is left to hand out
+        let backend = MockBackend::new(&[Gone, Gone], &[0]);
+        let watchdog = ImportWatchdog::start_with(params(), backend.clone()).unwrap();
+
+        wait_for_script(&backend);
+        {
+            let mut calls = backend.calls();
+            calls.next_ports.push_back(7);
+            calls.health.push_back(Gone);
+        }
+
+        let start = Instant::now();
+        while watchdog.port() != 7 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "never re-attached"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        watchdog.stop();
+    }
+
+    #[test]
+    fn tolerates_a_few_failed_state_queries() {
+        use PortHealth::*;
+
+        let backend = MockBackend::new(&[Unknown, Unknown, Alive, Unknown, Alive], &[0]);
+        let watchdog = ImportWatchdog::start_with(params(), backend.clone()).unwrap();
+
+        wait_for_script(&backend);
+        watchdog.stop();
+
+        assert_eq!(backend.calls().attaches, 1);
+    }
+
+    #[test]
+    fn reattaches_once_the_state_stays_unknown() {
+        use PortHealth::*;
+
+        // vhci_hcd was unloaded, so the device is gone along with it
+        let backend = MockBackend::new(&[Unknown; MAX_UNKNOWN_POLLS as usize], &[0, 0]);
+        let watchdog = ImportWatchdog::start_with(params(), backend.clone()).unwrap();
+
+        wait_for_script(&backend);
+        let start = Instant::now();
+        while backend.calls().attaches < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "never re-attached"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        watchdog.stop();
+
+        assert!(backend.calls().released.is_empty());
+    }
+}
@@ -1,9 +1,39 @@
 //! Driver for the Linux kernel usbip-host module
 //! (/drivers/usb/usbip/stub_main.c)
 
-use std::path::Path;
+use std::{
+    io,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
 
-use crate::drivers::{SysfsIoError, write_sysfs_attribute};
+use crate::drivers::{
+    SysfsIoError, USB_DEVICES_PATH, USB_DRIVERS_PATH, read_sysfs_attribute, write_sysfs_attribute,
+};
+
+pub mod state;
+
+/// Name of the usbip-host driver as registered with the USB core
+pub const USBIP_HOST_DRIVER_NAME: &str = "usbip-host";
+
+fn match_list_path() -> PathBuf {
+    Path::new(USB_DRIVERS_PATH)
+        .join(USBIP_HOST_DRIVER_NAME)
+        .join("match_busid")
+}
+
+fn read_match_list_in(path: &Path) -> Result<Vec<String>, SysfsIoError> {
+    // the kernel separates the entries with spaces and ends the list with a
+    // newline
+    Ok(read_sysfs_attribute(path)?
+        .split_whitespace()
+        .map(Into::into)
+        .collect())
+}
+
+fn is_in_match_list_in(path: &Path, bus_id: &str) -> Result<bool, SysfsIoError> {
+    Ok(read_match_list_in(path)?.iter().any(|b| b == bus_id))
+}
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
@@ -11,6 +41,20 @@ pub struct UsbipHost {
     // TODO
 }
 
+/// Status of a device bound to usbip-host as reported by its `usbip_status`
+/// attribute (`SDEV_ST_*` in usbip_common.h)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u32)]
+pub enum UsbipHostDeviceStatus {
+    /// SDEV_ST_AVAILABLE
+    Available = 1,
+    /// SDEV_ST_USED
+    Used,
+    /// SDEV_ST_ERROR
+    Error,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MatchListOperation {
     Add,
@@ -30,14 +74,27 @@ impl UsbipHost {
         bus_id: &str,
         operation: MatchListOperation,
     ) -> Result<(), SysfsIoError> {
-        let path = Path::new("/sys/bus/usb/drivers/usbip-host/match_busid");
+        let path = match_list_path();
 
         let buf = match operation {
             MatchListOperation::Add => format!("add {bus_id}"),
             MatchListOperation::Remove => format!("del {bus_id}"),
         };
 
-        write_sysfs_attribute(path, buf)
+        write_sysfs_attribute(&path, buf)
+    }
+
+    /// Reads the bus IDs in usbip-host's match list. Bus IDs stay in the list
+    /// if binding failed after they were added, in which case usbip-host
+    /// considers the device its own even though it isn't bound to it.
+    pub fn current_match_list() -> Result<Vec<String>, SysfsIoError> {
+        read_match_list_in(&match_list_path())
+    }
+
+    /// Checks whether the bus ID is in usbip-host's match list (see
+    /// [`UsbipHost::current_match_list`])
+    pub fn is_in_match_list(bus_id: &str) -> Result<bool, SysfsIoError> {
+        is_in_match_list_in(&match_list_path(), bus_id)
     }
 
     /// Asks the usbip-host driver to make a call into usbcore to try and
@@ -45,13 +102,88 @@ impl UsbipHost {
     /// driver. Fails if the device could not be bound back to its original
     /// driver.
     pub fn trigger_device_rebind(bus_id: &str) -> Result<(), SysfsIoError> {
-        let path = Path::new("/sys/bus/usb/drivers/usbip-host/rebind");
+        let path = Path::new(USB_DRIVERS_PATH)
+            .join(USBIP_HOST_DRIVER_NAME)
+            .join("rebind");
 
         // TODO: should do the same type of error matching that we do in
         // bind_usb_driver to provide better error messages? rebind_store in
         // stub_main.c returns whatever error was returned by device_attach so
         // the codes are the same as bind_store in the driver core.
 
-        write_sysfs_attribute(path, bus_id)
+        write_sysfs_attribute(&path, bus_id)
+    }
+
+    /// Reads the `usbip_status` attribute of a device bound to usbip-host
+    pub fn device_status(bus_id: &str) -> Result<UsbipHostDeviceStatus, SysfsIoError> {
+        let path = Path::new(USB_DEVICES_PATH)
+            .join(bus_id)
+            .join("usbip_status");
+
+        let value = read_sysfs_attribute(&path)?;
+
+        value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .and_then(|v| UsbipHostDeviceStatus::try_from(v).ok())
+            .ok_or_else(|| SysfsIoError::Other {
+                error: io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid usbip_status value {value:?}"),
+                ),
+                path,
+            })
+    }
+
+    /// Hands the connected socket off to usbip-host by writing it to the
+    /// device's `usbip_sockfd` attribute. After this, the kernel owns the
+    /// connection and will start servicing URBs for the device over it.
+    pub fn export_device(bus_id: &str, socket_fd: RawFd) -> Result<(), SysfsIoError> {
+        let path = Path::new(USB_DEVICES_PATH)
+            .join(bus_id)
+            .join("usbip_sockfd");
+
+        write_sysfs_attribute(&path, format!("{socket_fd}\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn reads_the_match_list() {
+        let dir = TempDir::new();
+        let path = dir.path().join("match_busid");
+        fs::write(&path, "1-1 1-1.2 3-4\n").unwrap();
+
+        assert_eq!(read_match_list_in(&path).unwrap(), ["1-1", "1-1.2", "3-4"]);
+    }
+
+    #[test]
+    fn checks_whether_bus_ids_are_in_the_match_list() {
+        let dir = TempDir::new();
+        let path = dir.path().join("match_busid");
+        fs::write(&path, "1-1 1-1.2 3-4\n").unwrap();
+
+        assert!(is_in_match_list_in(&path, "1-1.2").unwrap());
+        assert!(is_in_match_list_in(&path, "3-4").unwrap());
+        // only whole entries match
+        assert!(!is_in_match_list_in(&path, "1-1.").unwrap());
+        assert!(!is_in_match_list_in(&path, "2-1").unwrap());
+    }
+
+    #[test]
+    fn reads_an_empty_match_list() {
+        let dir = TempDir::new();
+        let path = dir.path().join("match_busid");
+        fs::write(&path, "\n").unwrap();
+
+        assert!(read_match_list_in(&path).unwrap().is_empty());
+        assert!(!is_in_match_list_in(&path, "1-1").unwrap());
     }
 }
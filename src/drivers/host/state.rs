@@ -0,0 +1,150 @@
+//! Keeps track of the driver each device was bound to before it was bound to
+//! usbip-host. Once a device is unbound from its original driver, the kernel
+//! no longer knows which driver that was, so without this state unbinding can
+//! only ask the kernel to probe the device again and hope that it picks the
+//! same driver.
+
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+const USBIP_HOST_STATE_PATH: &str = "/var/run/usbip_host";
+
+#[derive(Debug, thiserror::Error)]
+pub enum HostStateError {
+    #[error("Failed to save userspace `usbip-host` state to the file-system ({0})")]
+    IoWrite(io::Error),
+    #[error(
+        "Failed to read userspace `usbip-host` state from the file-system for device with bus ID `{1}` ({0})"
+    )]
+    IoRead(io::Error, String),
+    #[error("Failed to delete userspace `usbip-host` state from the file-system ({0})")]
+    IoRemove(io::Error),
+}
+
+fn driver_record_path(state_path: &Path, bus_id: &str) -> PathBuf {
+    state_path.join(format!("{bus_id}.driver"))
+}
+
+/// Records the driver the device was bound to before being bound to
+/// usbip-host, so it can be restored by [`read_original_driver`] later
+pub fn save_original_driver(bus_id: &str, driver: &str) -> Result<(), HostStateError> {
+    save_original_driver_in(Path::new(USBIP_HOST_STATE_PATH), bus_id, driver)
+}
+
+fn save_original_driver_in(
+    state_path: &Path,
+    bus_id: &str,
+    driver: &str,
+) -> Result<(), HostStateError> {
+    match fs::DirBuilder::new().mode(0o700).create(state_path) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(HostStateError::IoWrite(e)),
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(driver_record_path(state_path, bus_id))
+        .map_err(HostStateError::IoWrite)?;
+
+    writeln!(file, "{driver}").map_err(HostStateError::IoWrite)
+}
+
+/// Reads the original driver of the device saved by [`save_original_driver`].
+/// Returns `None` if nothing was recorded for the device.
+pub fn read_original_driver(bus_id: &str) -> Result<Option<String>, HostStateError> {
+    read_original_driver_in(Path::new(USBIP_HOST_STATE_PATH), bus_id)
+}
+
+fn read_original_driver_in(
+    state_path: &Path,
+    bus_id: &str,
+) -> Result<Option<String>, HostStateError> {
+    match fs::read_to_string(driver_record_path(state_path, bus_id)) {
+        Ok(driver) if driver.trim().is_empty() => Ok(None),
+        Ok(driver) => Ok(Some(driver.trim().into())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(HostStateError::IoRead(e, bus_id.into())),
+    }
+}
+
+/// Deletes the original driver record of the device (if any)
+pub fn delete_original_driver(bus_id: &str) -> Result<(), HostStateError> {
+    delete_original_driver_in(Path::new(USBIP_HOST_STATE_PATH), bus_id)
+}
+
+fn delete_original_driver_in(state_path: &Path, bus_id: &str) -> Result<(), HostStateError> {
+    match fs::remove_file(driver_record_path(state_path, bus_id)) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(HostStateError::IoRemove(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn saves_restores_and_deletes_the_original_driver() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("usbip_host");
+
+        save_original_driver_in(&state_path, "1-1.2", "usbhid").unwrap();
+        save_original_driver_in(&state_path, "1-3", "usb-storage").unwrap();
+
+        assert_eq!(
+            read_original_driver_in(&state_path, "1-1.2").unwrap(),
+            Some("usbhid".into())
+        );
+        assert_eq!(
+            read_original_driver_in(&state_path, "1-3").unwrap(),
+            Some("usb-storage".into())
+        );
+
+        delete_original_driver_in(&state_path, "1-1.2").unwrap();
+
+        assert_eq!(read_original_driver_in(&state_path, "1-1.2").unwrap(), None);
+        assert_eq!(
+            read_original_driver_in(&state_path, "1-3").unwrap(),
+            Some("usb-storage".into())
+        );
+    }
+
+    #[test]
+    fn overwrites_the_previous_driver() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("usbip_host");
+
+        save_original_driver_in(&state_path, "1-1", "usbhid").unwrap();
+        save_original_driver_in(&state_path, "1-1", "usbfs").unwrap();
+
+        assert_eq!(
+            read_original_driver_in(&state_path, "1-1").unwrap(),
+            Some("usbfs".into())
+        );
+    }
+
+    #[test]
+    fn missing_state_means_no_original_driver() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("usbip_host");
+
+        // neither the state dir nor the record exist
+        assert_eq!(read_original_driver_in(&state_path, "1-1").unwrap(), None);
+        delete_original_driver_in(&state_path, "1-1").unwrap();
+
+        // an empty record is treated like a missing one
+        fs::create_dir(&state_path).unwrap();
+        fs::write(state_path.join("1-1.driver"), "\n").unwrap();
+        assert_eq!(read_original_driver_in(&state_path, "1-1").unwrap(), None);
+    }
+}
@@ -1,14 +1,25 @@
 use std::{
     ffi::OsStr,
     fs,
-    io::{self, ErrorKind, Write},
-    path::Path,
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
 };
 
 use nix::errno::Errno;
 
+use crate::{
+    proto::UsbInterfaceInfo,
+    util::{UsbInfoExtractError, extract_usb_interface_info, retry_interrupted},
+};
+
 pub mod host;
 pub mod vhci;
+pub mod vudc;
+
+/// Directory containing the USB drivers registered with the kernel
+pub(crate) const USB_DRIVERS_PATH: &str = "/sys/bus/usb/drivers";
+/// Directory containing all USB devices (and interfaces) known to the kernel
+pub(crate) const USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
 
 #[derive(Debug, thiserror::Error)]
 pub enum DriverBindingError {
@@ -29,11 +40,11 @@ pub enum DriverBindingError {
 /// driver does not exist, access to sysfs is denied, another driver is already
 /// bound to the device, or if the device does not exist.
 pub(crate) fn bind_usb_driver(driver: &OsStr, bus_id: &str) -> Result<(), DriverBindingError> {
-    let path = Path::new("/sys/bus/usb/drivers/").join(driver).join("bind");
+    let path = Path::new(USB_DRIVERS_PATH).join(driver).join("bind");
 
     let result = write_sysfs_attribute(&path, bus_id);
 
-    if let Err(SysfsIoError::Other(e)) = &result
+    if let Err(SysfsIoError::Other { error: e, .. }) = &result
         && let Some(errno) = e.raw_os_error().map(Errno::from_raw)
     {
         match errno {
@@ -63,13 +74,11 @@ pub enum DriverUnbindingError {
 /// driver does not exist, acccess to sysfs is denied, the given device is
 /// not bound to this driver, or if the device does not exist.
 pub(crate) fn unbind_usb_driver(driver: &OsStr, bus_id: &str) -> Result<(), DriverUnbindingError> {
-    let path = Path::new("/sys/bus/usb/drivers/")
-        .join(driver)
-        .join("unbind");
+    let path = Path::new(USB_DRIVERS_PATH).join(driver).join("unbind");
 
     let result = write_sysfs_attribute(&path, bus_id);
 
-    if let Err(SysfsIoError::Other(e)) = &result
+    if let Err(SysfsIoError::Other { error: e, .. }) = &result
         && let Some(errno) = e.raw_os_error().map(Errno::from_raw)
     {
         match errno {
@@ -82,17 +91,191 @@ pub(crate) fn unbind_usb_driver(driver: &OsStr, bus_id: &str) -> Result<(), Driv
     result.map_err(DriverUnbindingError::Sysfs)
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceLookupError {
+    #[error("Failed to create udev context ({0})")]
+    CreatingUdevContext(io::Error),
+    #[error("No USB device with bus ID `{0}` exists")]
+    NotFound(String),
+    #[error("Failed to open USB device with bus ID `{bus_id}` with udev ({error})")]
+    Udev { bus_id: String, error: io::Error },
+}
+
+/// Opens the USB device (or interface) with the given bus ID through udev
+pub(crate) fn open_usb_device(
+    context: &udev::Udev,
+    bus_id: &str,
+) -> Result<udev::Device, DeviceLookupError> {
+    udev::Device::from_subsystem_sysname_with_context(context.clone(), "usb".into(), bus_id.into())
+        .map_err(|error| {
+            // udev returns ENODEV if the sysfs device was not there
+            if error.raw_os_error().map(Errno::from_raw) == Some(Errno::ENODEV) {
+                DeviceLookupError::NotFound(bus_id.into())
+            } else {
+                DeviceLookupError::Udev {
+                    bus_id: bus_id.into(),
+                    error,
+                }
+            }
+        })
+}
+
+/// Resolves the sysfs path of the USB device (or interface) with the given bus
+/// ID, e.g. `/sys/devices/pci0000:00/0000:00:14.0/usb1/1-1` for `1-1`
+pub fn resolve_sys_path(bus_id: &str) -> Result<PathBuf, DeviceLookupError> {
+    let context = udev::Udev::new().map_err(DeviceLookupError::CreatingUdevContext)?;
+
+    Ok(open_usb_device(&context, bus_id)?.syspath().to_path_buf())
+}
+
+/// Returns the name of the driver bound to the USB device (or interface) with
+/// the given bus ID, or `None` if no driver is bound to it
+pub fn current_driver(bus_id: &str) -> Result<Option<String>, DeviceLookupError> {
+    let context = udev::Udev::new().map_err(DeviceLookupError::CreatingUdevContext)?;
+
+    Ok(driver_name(&open_usb_device(&context, bus_id)?))
+}
+
+/// The name of the driver bound to the device, if any
+pub(crate) fn driver_name(device: &udev::Device) -> Option<String> {
+    device.driver().map(|d| d.to_string_lossy().into_owned())
+}
+
+/// An interface of the active configuration of a USB device, as returned by
+/// [`enumerate_interfaces`]
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    /// Bus ID of the interface (e.g. `1-2:1.0`)
+    pub bus_id: String,
+    pub b_interface_number: u8,
+    pub info: UsbInterfaceInfo,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InterfaceEnumerationError {
+    #[error("Failed to enumerate USB interfaces with udev ({0})")]
+    Udev(io::Error),
+    #[error("Failed to query USB interface with bus ID `{bus_id}` ({error})")]
+    UsbInfoExtraction {
+        bus_id: String,
+        error: UsbInfoExtractError,
+    },
+}
+
+/// Enumerates the interfaces of the device's active configuration (the child
+/// devices named like `x-y:c.i`), sorted by interface number. Unconfigured
+/// devices have no interfaces.
+pub fn enumerate_interfaces(
+    dev: &udev::Device,
+) -> Result<Vec<InterfaceInfo>, InterfaceEnumerationError> {
+    let mut enumerator = udev::Enumerator::new().map_err(InterfaceEnumerationError::Udev)?;
+
+    enumerator
+        .match_parent(dev)
+        .map_err(InterfaceEnumerationError::Udev)?;
+    enumerator
+        .match_subsystem("usb")
+        .map_err(InterfaceEnumerationError::Udev)?;
+    enumerator
+        .match_property("DEVTYPE", "usb_interface")
+        .map_err(InterfaceEnumerationError::Udev)?;
+
+    let interfaces: Vec<_> = enumerator
+        .scan_devices()
+        .map_err(InterfaceEnumerationError::Udev)?
+        // the parent match also covers the interfaces of devices further down
+        // the tree (if the device is a hub)
+        .filter(|iface| iface.parent().is_some_and(|p| p.syspath() == dev.syspath()))
+        .collect();
+
+    read_interfaces(interfaces.iter().map(|iface| {
+        (
+            iface.sysname().to_string_lossy().into_owned(),
+            |name: &str| iface.attribute_value(name),
+        )
+    }))
+}
+
+/// Reads the interfaces from their bus IDs and the attributes returned by
+/// their `attribute` function, sorted by interface number
+fn read_interfaces<'a, F>(
+    interfaces: impl IntoIterator<Item = (String, F)>,
+) -> Result<Vec<InterfaceInfo>, InterfaceEnumerationError>
+where
+    F: Fn(&str) -> Option<&'a OsStr>,
+{
+    let mut results = Vec::new();
+
+    for (bus_id, attribute) in interfaces {
+        let extraction_error = |error| InterfaceEnumerationError::UsbInfoExtraction {
+            bus_id: bus_id.clone(),
+            error,
+        };
+
+        let b_interface_number = attribute("bInterfaceNumber")
+            .ok_or_else(|| {
+                extraction_error(UsbInfoExtractError::AttributeMissing(
+                    "bInterfaceNumber".into(),
+                ))
+            })?
+            .to_str()
+            .and_then(|v| u8::from_str_radix(v.trim(), 16).ok())
+            .ok_or_else(|| {
+                extraction_error(UsbInfoExtractError::AttributeParsingFailed(
+                    "bInterfaceNumber".into(),
+                ))
+            })?;
+
+        let info = extract_usb_interface_info(&attribute).map_err(extraction_error)?;
+
+        results.push(InterfaceInfo {
+            bus_id,
+            b_interface_number,
+            info,
+        });
+    }
+
+    results.sort_by_key(|i| i.b_interface_number);
+
+    Ok(results)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SysfsIoError {
     #[error(
-        "missing permissions to access sysfs attribute{}",
+        "missing permissions to access sysfs attribute `{}`{}",
+        path.display(),
         format_permissions_help()
     )]
-    PermissionDenied,
-    #[error("sysfs attribute does not exist")]
-    DoesNotExist,
-    #[error(transparent)]
-    Other(io::Error),
+    PermissionDenied { path: PathBuf },
+    #[error("sysfs attribute `{}` does not exist", path.display())]
+    DoesNotExist { path: PathBuf },
+    #[error("failed to access sysfs attribute `{}` ({error})", path.display())]
+    Other { path: PathBuf, error: io::Error },
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Root privileges are required to {operation}. Try executing again with sudo.")]
+pub struct RootRequiredError {
+    pub operation: String,
+}
+
+/// Checks that we are running as root before attempting an operation which
+/// writes to sysfs (e.g. `"bind devices"`), so that non-root users get a clear
+/// error before any partial changes are made. Permission errors of the sysfs
+/// accesses themselves are still reported if they fail regardless.
+pub fn require_root_for(operation: &str) -> Result<(), RootRequiredError> {
+    require_root_as(nix::unistd::geteuid(), operation)
+}
+
+fn require_root_as(euid: nix::unistd::Uid, operation: &str) -> Result<(), RootRequiredError> {
+    if euid.is_root() {
+        return Ok(());
+    }
+
+    Err(RootRequiredError {
+        operation: operation.into(),
+    })
 }
 
 fn format_permissions_help() -> String {
@@ -113,15 +296,212 @@ pub(crate) fn write_sysfs_attribute(
         String::from_utf8_lossy(value.as_ref())
     );
 
-    let mut file = fs::OpenOptions::new().write(true).open(path).map_err(|e| {
-        if e.kind() == ErrorKind::PermissionDenied {
-            SysfsIoError::PermissionDenied
-        } else {
-            SysfsIoError::Other(e)
-        }
-    })?;
+    let mut file =
+        retry_interrupted(|| fs::OpenOptions::new().write(true).open(path)).map_err(|e| {
+            if e.kind() == ErrorKind::PermissionDenied {
+                SysfsIoError::PermissionDenied { path: path.into() }
+            } else {
+                SysfsIoError::Other {
+                    path: path.into(),
+                    error: e,
+                }
+            }
+        })?;
     file.write_all(value.as_ref())
-        .map_err(SysfsIoError::Other)?;
+        .map_err(|e| SysfsIoError::Other {
+            path: path.into(),
+            error: e,
+        })?;
 
     Ok(())
 }
+
+pub(crate) fn read_sysfs_attribute(path: &Path) -> Result<String, SysfsIoError> {
+    tracing::debug!("reading from sysfs (path = \"{}\")", path.display());
+
+    let mut file =
+        retry_interrupted(|| fs::OpenOptions::new().read(true).open(path)).map_err(|e| match e
+            .kind()
+        {
+            ErrorKind::PermissionDenied => SysfsIoError::PermissionDenied { path: path.into() },
+            ErrorKind::NotFound => SysfsIoError::DoesNotExist { path: path.into() },
+            _ => SysfsIoError::Other {
+                path: path.into(),
+                error: e,
+            },
+        })?;
+
+    let mut buf = String::new();
+    file.read_to_string(&mut buf)
+        .map_err(|e| SysfsIoError::Other {
+            path: path.into(),
+            error: e,
+        })?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn resolves_the_sys_path_of_a_local_device() {
+        // any device will do, but there may be none (e.g. in a container)
+        let Some(entry) = fs::read_dir("/sys/bus/usb/devices")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .next()
+        else {
+            return;
+        };
+
+        let bus_id = entry.file_name();
+        let bus_id = bus_id.to_str().unwrap();
+
+        assert_eq!(
+            resolve_sys_path(bus_id).unwrap(),
+            fs::canonicalize(entry.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn fails_to_resolve_unknown_bus_ids() {
+        let result = resolve_sys_path("999-999");
+
+        assert!(
+            matches!(&result, Err(DeviceLookupError::NotFound(bus_id)) if bus_id == "999-999"),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn reads_the_driver_of_a_local_device() {
+        // any device will do, but there may be none (e.g. in a container)
+        let Some(entry) = fs::read_dir("/sys/bus/usb/devices")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .next()
+        else {
+            return;
+        };
+
+        let bus_id = entry.file_name();
+        let bus_id = bus_id.to_str().unwrap();
+
+        let expected = fs::read_link(entry.path().join("driver"))
+            .ok()
+            .map(|driver| driver.file_name().unwrap().to_string_lossy().into_owned());
+
+        assert_eq!(current_driver(bus_id).unwrap(), expected);
+    }
+
+    #[test]
+    fn fails_to_read_the_driver_of_unknown_bus_ids() {
+        let result = current_driver("999-999");
+
+        assert!(
+            matches!(&result, Err(DeviceLookupError::NotFound(bus_id)) if bus_id == "999-999"),
+            "{result:?}"
+        );
+    }
+
+    /// The attributes of an interface of a mock mass storage device
+    fn interface(
+        bus_id: &str,
+        number: &'static str,
+    ) -> (String, impl Fn(&str) -> Option<&'static OsStr>) {
+        let attribute = move |name: &str| {
+            let value = match name {
+                "bInterfaceNumber" => number,
+                "bInterfaceClass" => "08",
+                "bInterfaceSubClass" => "06",
+                "bInterfaceProtocol" => "50",
+                _ => return None,
+            };
+
+            Some(OsStr::new(value))
+        };
+
+        (bus_id.into(), attribute)
+    }
+
+    #[test]
+    fn reads_interfaces_sorted_by_interface_number() {
+        let interfaces = read_interfaces([
+            interface("1-1:1.10", "0a\n"),
+            interface("1-1:1.0", "00\n"),
+            interface("1-1:1.2", "02\n"),
+        ])
+        .unwrap();
+
+        let bus_ids: Vec<_> = interfaces.iter().map(|i| i.bus_id.as_str()).collect();
+        assert_eq!(bus_ids, ["1-1:1.0", "1-1:1.2", "1-1:1.10"]);
+
+        let numbers: Vec<_> = interfaces.iter().map(|i| i.b_interface_number).collect();
+        assert_eq!(numbers, [0, 2, 10]);
+
+        assert_eq!(interfaces[0].info.b_interface_class, 0x08);
+        assert_eq!(interfaces[0].info.b_interface_sub_class, 0x06);
+        assert_eq!(interfaces[0].info.b_interface_protocol, 0x50);
+    }
+
+    #[test]
+    fn fails_on_interfaces_with_an_invalid_number() {
+        let result = read_interfaces([interface("1-1:1.0", "00"), interface("1-1:1.1", "x")]);
+
+        assert!(
+            matches!(
+                &result,
+                Err(InterfaceEnumerationError::UsbInfoExtraction {
+                    bus_id,
+                    error: UsbInfoExtractError::AttributeParsingFailed(name),
+                }) if bus_id == "1-1:1.1" && name == "bInterfaceNumber"
+            ),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn sysfs_errors_name_the_attribute() {
+        let root = TempDir::new();
+        let missing = root.path().join("usbip_status");
+
+        let error = read_sysfs_attribute(&missing).unwrap_err();
+        assert!(matches!(error, SysfsIoError::DoesNotExist { .. }));
+        assert_eq!(
+            error.to_string(),
+            format!("sysfs attribute `{}` does not exist", missing.display())
+        );
+
+        // reading a directory fails after it was opened
+        let error = read_sysfs_attribute(root.path()).unwrap_err();
+        assert!(matches!(error, SysfsIoError::Other { .. }));
+        assert!(
+            error.to_string().starts_with(&format!(
+                "failed to access sysfs attribute `{}` (",
+                root.path().display()
+            )),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn allows_root() {
+        assert!(require_root_as(nix::unistd::Uid::from_raw(0), "bind devices").is_ok());
+    }
+
+    #[test]
+    fn rejects_other_users() {
+        let error = require_root_as(nix::unistd::Uid::from_raw(1000), "bind devices").unwrap_err();
+
+        assert_eq!(error.operation, "bind devices");
+        assert_eq!(
+            error.to_string(),
+            "Root privileges are required to bind devices. Try executing again with sudo."
+        );
+    }
+}
@@ -1,36 +1,108 @@
 use std::{
-    fs,
+    env, fs,
     io::{self, ErrorKind},
+    ops::Range,
     os::fd::RawFd,
-    str::FromStr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
 use compact_str::CompactString;
 
 use crate::{
     UsbDeviceInfo, UsbSpeed,
-    util::{UsbInfoExtractError, extract_usb_info_from_udev_device},
+    drivers::{DeviceLookupError, driver_name, open_usb_device},
+    util::{UsbInfoExtractError, extract_usb_info_from_udev_device, retry_interrupted},
 };
 
 pub mod state;
 
+/// Name of the vhci_hcd platform driver
+pub const VHCI_DRIVER_NAME: &str = "vhci_hcd";
+/// Name of the first vhci_hcd platform device, which exposes the attributes of
+/// all controllers
+pub const VHCI_DEVICE_NAME: &str = "vhci_hcd.0";
+/// Subsystem of the vhci_hcd devices
+pub const VHCI_DEVICE_SUBSYSTEM: &str = "platform";
+
+/// Environment variable which overrides [`VHCI_DRIVER_NAME`]
+pub const VHCI_DRIVER_NAME_ENV: &str = "USBIP_VHCI_DRIVER_NAME";
+/// Environment variable which overrides [`VHCI_DEVICE_NAME`]
+pub const VHCI_DEVICE_NAME_ENV: &str = "USBIP_VHCI_DEVICE_NAME";
+
+/// Where the `vhci_hcd` device is looked up (see
+/// [`VhciHcd::open_with_options`]), for custom kernels or out-of-tree modules
+/// which use different names
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhciOptions {
+    /// Name of the platform driver, which also prefixes the names of the
+    /// controllers (`vhci_hcd.N`). [`VHCI_DRIVER_NAME`] by default.
+    pub driver_name: String,
+    /// Name of the first controller, which exposes the attributes of all
+    /// controllers. [`VHCI_DEVICE_NAME`] by default.
+    pub device_name: String,
+    /// Subsystem the controllers are registered with.
+    /// [`VHCI_DEVICE_SUBSYSTEM`] by default.
+    pub subsystem: String,
+    /// Where sysfs is mounted. `/sys` by default.
+    pub sysfs_root: PathBuf,
+}
+
+impl Default for VhciOptions {
+    /// The default names, unless they are overridden by the
+    /// [`VHCI_DRIVER_NAME_ENV`] and [`VHCI_DEVICE_NAME_ENV`] environment
+    /// variables (which are only read once per process)
+    fn default() -> Self {
+        static FROM_ENV: OnceLock<(Option<String>, Option<String>)> = OnceLock::new();
+
+        let (driver_name, device_name) = FROM_ENV.get_or_init(|| {
+            (
+                env::var(VHCI_DRIVER_NAME_ENV).ok(),
+                env::var(VHCI_DEVICE_NAME_ENV).ok(),
+            )
+        });
+
+        Self {
+            driver_name: driver_name.as_deref().unwrap_or(VHCI_DRIVER_NAME).into(),
+            device_name: device_name.as_deref().unwrap_or(VHCI_DEVICE_NAME).into(),
+            subsystem: VHCI_DEVICE_SUBSYSTEM.into(),
+            sysfs_root: "/sys".into(),
+        }
+    }
+}
+
+impl VhciOptions {
+    /// Whether the given sysfs name is one of the `vhci_hcd.N` controllers
+    fn is_controller_name(&self, name: &str) -> bool {
+        name.strip_prefix(self.driver_name.as_str())
+            .is_some_and(|rest| rest.starts_with('.'))
+    }
+
+    fn device_not_found(&self) -> Error {
+        Error::VhciDeviceNotFound {
+            device: self.device_name.clone(),
+            driver: self.driver_name.clone(),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to create udev context ({0})")]
     CreatingUdevContext(io::Error),
-    #[error("Device `vhci_hcd.0` not found. Is is the kernel module `vhci_hcd` loaded?")]
-    VhciDeviceNotFound,
-    #[error("Failed to open device `vhci_hcd.0` with udev ({0})")]
-    VhciDeviceUdev(io::Error),
-    #[error("Could not access parent device `platform` of `vhci_hcd.0`")]
-    VhciDeviceParentNotFound,
-
-    #[error("Failed to get value for udev attribute `{0}` from `vhci_hcd` device")]
-    VhciDeviceMissingUdevAttribute(String),
-    #[error("Failed to decode value of udev attribute `{0}` of `vhci_hcd` device as UTF-8")]
-    VhciDeviceUtf8UdevAttribute(String),
-    #[error("Failed to parse value of udev attribute `{0}` of `vhci_hcd` device")]
-    VhciDeviceParsingUdevAttribute(String),
+    #[error("Device `{device}` not found. Is the kernel module `{driver}` loaded?")]
+    VhciDeviceNotFound { device: String, driver: String },
+    #[error("Failed to open device `{0}` ({1})")]
+    OpeningVhciDevice(String, io::Error),
+
+    #[error("The `vhci_hcd` device has no sysfs attribute `{0}`")]
+    VhciDeviceMissingSysfsAttribute(String),
+    #[error("Failed to decode value of sysfs attribute `{0}` of `vhci_hcd` device as UTF-8")]
+    VhciDeviceUtf8SysfsAttribute(String),
+    #[error("Failed to parse value of sysfs attribute `{0}` of `vhci_hcd` device")]
+    VhciDeviceParsingSysfsAttribute(String),
+    #[error("Failed to parse line {line} of sysfs attribute `{attribute}` of `vhci_hcd` device")]
+    VhciDeviceParsingStatusLine { attribute: String, line: usize },
 
     #[error(
         "An I/O error occurred while communicating with the `vhci_hcd` device through sysfs ({0})"
@@ -42,7 +114,7 @@ pub enum Error {
     )]
     SysfsPermissionDenied,
     #[error(
-        "No ports available on `vhci_hcd` root hub(s). How the hell did you even manage to screw this up?"
+        "The `vhci_hcd` device reports 0 ports (`nports`). Check the `CONFIG_USBIP_VHCI_HC_PORTS` option the module was built with."
     )]
     VhciNoAvailablePorts,
     #[error(
@@ -50,16 +122,36 @@ pub enum Error {
     )]
     EnumeratingControllers(io::Error),
     #[error(
-        "Data parsed from `vhci_hcd` device status attributes did not match up with previously acquired device information"
+        "No `vhci_hcd` controllers were found next to `{device}`. Was the `{driver}` module unloaded while running?"
     )]
-    ConflictingStatusData,
-    #[error("No free ports available matching requried speed (all in use)")]
-    NoFreePorts,
-
+    NoControllers { device: String, driver: String },
     #[error(
-        "An I/O error occurred while querying imported USB device with bus ID `{bus_id}` ({error})"
+        "Data parsed from `vhci_hcd` device status attributes did not match up with previously acquired device information ({0})"
     )]
-    QueryingLocalUsbDevice { bus_id: String, error: io::Error },
+    ConflictingStatusData(String),
+    #[error(
+        "The status attributes of {num_controllers} `vhci_hcd` controller(s) cover {covered} ports, but `nports` reports {num_ports}. Was the `vhci_hcd` module reloaded while running?"
+    )]
+    StatusPortCountMismatch {
+        covered: u32,
+        num_ports: u32,
+        num_controllers: u32,
+    },
+    #[error(
+        "The `vhci_hcd` module was reloaded while running, and its status still does not match after reopening it ({0})"
+    )]
+    ReloadedWhileRunning(Box<Error>),
+    #[error("Port {port} does not exist (the `vhci_hcd` device has {num_ports} ports)")]
+    PortOutOfRange { port: u32, num_ports: u32 },
+    #[error("`{0}` is not an open file descriptor")]
+    InvalidSocketFd(RawFd),
+    #[error("File descriptor `{0}` does not refer to a socket")]
+    NotASocket(RawFd),
+    #[error("No free ports available on the {0:?} speed root hub(s) (all in use)")]
+    NoFreePorts(HubSpeed),
+
+    #[error("Failed to query imported USB device ({0})")]
+    QueryingLocalUsbDevice(DeviceLookupError),
     #[error("Failed to query USB device with bus ID `{bus_id}` ({error})")]
     UsbInfoExtraction {
         bus_id: String,
@@ -76,13 +168,347 @@ fn format_permissions_help() -> String {
     }
 }
 
+/// Maps an I/O error from accessing the vhci_hcd sysfs attributes, singling out
+/// permission errors (on either the open or the write) so the user gets a hint
+/// to run as root
+fn map_sysfs_error(e: io::Error) -> Error {
+    if e.kind() == ErrorKind::PermissionDenied {
+        Error::SysfsPermissionDenied
+    } else {
+        Error::SysfsIo(e)
+    }
+}
+
+/// Checks that the file descriptor handed to `vhci_hcd` is an open socket by
+/// looking at its `/proc/self/fd` link (which reads `socket:[inode]` for
+/// sockets). Skipped if procfs is not mounted.
+fn validate_socket_fd(fd: RawFd) -> Result<(), Error> {
+    if fd < 0 {
+        return Err(Error::InvalidSocketFd(fd));
+    }
+
+    match fs::read_link(format!("/proc/self/fd/{fd}")) {
+        Ok(target) if target.to_string_lossy().starts_with("socket:") => Ok(()),
+        Ok(_) => Err(Error::NotASocket(fd)),
+        Err(e)
+            if e.kind() == ErrorKind::NotFound && fs::exists("/proc/self/fd").unwrap_or(false) =>
+        {
+            Err(Error::InvalidSocketFd(fd))
+        }
+        Err(e) => {
+            tracing::debug!("could not check socket file descriptor `{fd}` ({e})");
+            Ok(())
+        }
+    }
+}
+
+/// Reads the total number of root hub ports (across all controllers) of the
+/// `vhci_hcd` device.
+///
+/// Unlike [`VhciHcd::open`], this does not enumerate controllers or query the
+/// imported devices, so it is cheap enough to call when all you want to know is
+/// whether `vhci_hcd` is available and how many ports it has.
+pub fn read_total_ports() -> Result<u32, Error> {
+    read_total_ports_with_options(&VhciOptions::default())
+}
+
+/// Same as [`read_total_ports`], but allows changing where the device is
+/// looked up
+pub fn read_total_ports_with_options(options: &VhciOptions) -> Result<u32, Error> {
+    let sys_path = open_device(options)?;
+
+    read_num_ports(&sys_path)
+}
+
+/// Version of the running kernel as reported by `uname -r`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl KernelVersion {
+    /// Parses the leading `major.minor[.patch]` of a kernel release string
+    /// like `6.8.0-45-generic`. Anything after the version is ignored.
+    pub fn parse(release: &str) -> Option<Self> {
+        let version = release
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()?;
+
+        let mut parts = version.split('.');
+
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let patch = parts.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+
+        Some(Self {
+            major,
+            minor,
+            patch,
+        })
+    }
+
+    const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl std::fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Whether the running kernel's `vhci_hcd` uses the sysfs interface this crate
+/// expects
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "compat", rename_all = "snake_case")]
+pub enum KernelCompat {
+    Supported {
+        version: KernelVersion,
+    },
+    Unsupported {
+        version: KernelVersion,
+        reason: &'static str,
+    },
+    /// The kernel version could not be determined
+    Unknown {
+        release: String,
+    },
+}
+
+impl KernelCompat {
+    pub fn is_supported(&self) -> bool {
+        !matches!(self, KernelCompat::Unsupported { .. })
+    }
+}
+
+/// A change to the format of the `vhci_hcd` status attributes
+struct FormatChange {
+    /// The release which introduced the change
+    changed_in: KernelVersion,
+    /// The first releases of older stable series the change was backported to
+    backported_to: &'static [KernelVersion],
+    /// What kernels without the change are missing
+    missing: &'static str,
+}
+
+impl FormatChange {
+    fn is_in(&self, version: KernelVersion) -> bool {
+        version >= self.changed_in
+            || self.backported_to.iter().any(|backport| {
+                (backport.major, backport.minor) == (version.major, version.minor)
+                    && version.patch >= backport.patch
+            })
+    }
+}
+
+/// Changes to the format of the `vhci_hcd` status attributes. Kernels which
+/// have all of them are supported.
+const VHCI_FORMAT_CHANGES: &[FormatChange] = &[
+    FormatChange {
+        changed_in: KernelVersion::new(4, 13, 0),
+        backported_to: &[],
+        missing: "the status attributes have no `hub` column (USB 3 support was added to vhci_hcd in 4.13)",
+    },
+    FormatChange {
+        changed_in: KernelVersion::new(4, 15, 0),
+        backported_to: &[KernelVersion::new(4, 14, 8)],
+        missing: "the status attributes report socket addresses instead of socket fds (changed in 4.15 and 4.14.8)",
+    },
+];
+
+/// Checks the version of the running kernel (from `uname`) against the
+/// versions whose `vhci_hcd` status format is known to be supported, logging
+/// a warning for unsupported kernels. A version which can't be determined is
+/// assumed to be supported.
+///
+/// The kernel can't change while running, so it is only checked (and warned
+/// about) once per process.
+pub fn kernel_compat() -> KernelCompat {
+    static COMPAT: OnceLock<KernelCompat> = OnceLock::new();
+
+    COMPAT
+        .get_or_init(|| {
+            let release = nix::sys::utsname::uname()
+                .map(|u| u.release().to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            let compat = kernel_compat_for_release(&release);
+
+            if let KernelCompat::Unsupported { version, reason } = &compat {
+                tracing::warn!(
+                    "kernel {version} is not supported by this version of usbip: {reason}"
+                );
+            }
+
+            compat
+        })
+        .clone()
+}
+
+/// Same as [`kernel_compat`], but for the given kernel release string
+pub fn kernel_compat_for_release(release: &str) -> KernelCompat {
+    let Some(version) = KernelVersion::parse(release) else {
+        return KernelCompat::Unknown {
+            release: release.into(),
+        };
+    };
+
+    match VHCI_FORMAT_CHANGES
+        .iter()
+        .find(|change| !change.is_in(version))
+    {
+        Some(change) => KernelCompat::Unsupported {
+            version,
+            reason: change.missing,
+        },
+        None => KernelCompat::Supported { version },
+    }
+}
+
+/// Checks whether a USB device was created by `vhci_hcd` (i.e. it was imported
+/// from a remote host) by walking up its parent hierarchy looking for the
+/// `vhci_hcd` platform device. Such devices cannot be re-exported since
+/// usbip-host refuses to bind to them.
+pub fn is_vhci_attached(device: &udev::Device) -> bool {
+    let ancestors = std::iter::successors(device.parent(), |d| d.parent())
+        .map(|d| (driver_name(&d), d.sysname().to_string_lossy().into_owned()));
+
+    has_vhci_ancestor(&VhciOptions::default(), ancestors)
+}
+
+/// Whether any of the given `(driver, sysname)` pairs of a device's ancestors
+/// is a `vhci_hcd` controller
+fn has_vhci_ancestor(
+    options: &VhciOptions,
+    mut ancestors: impl Iterator<Item = (Option<String>, String)>,
+) -> bool {
+    ancestors.any(|(driver, sysname)| {
+        driver.as_deref() == Some(options.driver_name.as_str())
+            || options.is_controller_name(&sysname)
+    })
+}
+
+/// Looks up the sysfs directory of the first controller through the devices
+/// of its subsystem (`/sys/bus/platform/devices/vhci_hcd.0`)
+fn open_device(options: &VhciOptions) -> Result<PathBuf, Error> {
+    let link = options
+        .sysfs_root
+        .join("bus")
+        .join(&options.subsystem)
+        .join("devices")
+        .join(&options.device_name);
+
+    fs::canonicalize(&link).map_err(|e| {
+        if e.kind() == ErrorKind::NotFound {
+            options.device_not_found()
+        } else {
+            Error::OpeningVhciDevice(link.display().to_string(), e)
+        }
+    })
+}
+
+/// Reads one of the sysfs attributes of the vhci_hcd device
+fn read_attribute(sys_path: &Path, name: &str) -> Result<String, Error> {
+    let value = retry_interrupted(|| fs::read(sys_path.join(name))).map_err(|e| {
+        if e.kind() == ErrorKind::NotFound {
+            Error::VhciDeviceMissingSysfsAttribute(name.into())
+        } else {
+            map_sysfs_error(e)
+        }
+    })?;
+
+    String::from_utf8(value).map_err(|_| Error::VhciDeviceUtf8SysfsAttribute(name.into()))
+}
+
+fn read_num_ports(sys_path: &Path) -> Result<u32, Error> {
+    const NUM_PORTS_ATTR: &str = "nports";
+
+    let num_ports = read_attribute(sys_path, NUM_PORTS_ATTR)?
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| Error::VhciDeviceParsingSysfsAttribute(NUM_PORTS_ATTR.into()))?;
+
+    if num_ports == 0 {
+        return Err(Error::VhciNoAvailablePorts);
+    }
+
+    Ok(num_ports)
+}
+
+/// Reads the total number of ports and the number of controllers they are
+/// spread across
+fn read_port_layout(options: &VhciOptions, sys_path: &Path) -> Result<(u32, u32), Error> {
+    let num_ports = read_num_ports(sys_path)?;
+
+    tracing::debug!("available ports = {num_ports}");
+
+    // the device path is canonical, so it always has a parent
+    let platform = sys_path.parent().unwrap_or(sys_path);
+    let num_controllers = count_controllers(options, platform)?;
+
+    tracing::debug!("available controllers = {num_controllers}");
+
+    // the device itself is one of the controllers, so none being left means
+    // the module was unloaded after the device was opened
+    if num_controllers == 0 {
+        return Err(Error::NoControllers {
+            device: options.device_name.clone(),
+            driver: options.driver_name.clone(),
+        });
+    }
+
+    Ok((num_ports, num_controllers))
+}
+
+/// Counts the controllers below the platform device. The platform device may
+/// not be visible in containers which only expose part of the sysfs hierarchy,
+/// in which case the first controller is enough to get by.
+fn count_controllers(options: &VhciOptions, platform: &Path) -> Result<u32, Error> {
+    let entries = match fs::read_dir(platform) {
+        Ok(entries) => entries,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied) => {
+            tracing::warn!(
+                "cannot list the controllers next to `{}` in `{}` ({e}), assuming a single controller",
+                options.device_name,
+                platform.display()
+            );
+
+            return Ok(1);
+        }
+        Err(e) => return Err(Error::EnumeratingControllers(e)),
+    };
+
+    let mut num_controllers = 0;
+    for entry in entries {
+        if entry
+            .map_err(Error::EnumeratingControllers)?
+            .file_name()
+            .to_str()
+            .is_some_and(|name| options.is_controller_name(name))
+        {
+            num_controllers += 1;
+        }
+    }
+
+    Ok(num_controllers)
+}
+
 /// USB/IP 'Virtual' Host Controller (VHCI) Driver
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct VhciHcd {
     #[derivative(Debug = "ignore")]
     context: udev::Udev,
-    device: udev::Device,
+    options: VhciOptions,
+    /// The sysfs directory of the first controller
+    sys_path: PathBuf,
 
     num_ports: u32,
     num_controllers: u32,
@@ -164,11 +590,29 @@ pub struct VhciImportedDevice {
     pub remote_device_id: u32,
     /// The socket fd passed to vhci_hcd during device attachment
     pub socket_fd: u32,
+    /// The raw `enum usb_device_speed` value reported by `vhci_hcd` in its
+    /// status attribute. Unlike `device.speed` (which is parsed from the sysfs
+    /// `speed` string), this is the numeric code the kernel was given when the
+    /// device was attached. Use [`UsbSpeed::from_kernel_code`] to interpret it.
+    pub kernel_speed: u8,
     /// The info gathered from udev about the locally mounted device (created by
     /// vhci_hcd)
     pub device: UsbDeviceInfo,
 }
 
+impl VhciImportedDevice {
+    /// Combines the status line of a port with the local device created for
+    /// it
+    fn new(status_line: &StatusLine, device: UsbDeviceInfo) -> Self {
+        Self {
+            remote_device_id: status_line.device_id,
+            socket_fd: status_line.socket_fd,
+            kernel_speed: status_line.speed,
+            device,
+        }
+    }
+}
+
 #[derive(
     Debug,
     Clone,
@@ -189,69 +633,52 @@ pub enum HubSpeed {
     Super,
 }
 
-impl VhciHcd {
-    pub fn open() -> Result<Self, Error> {
-        let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
+impl HubSpeed {
+    /// Whether a device of the given speed can be attached to a port of a hub
+    /// with this speed (see [`UsbSpeed::required_hub_speed`])
+    pub fn accepts(self, speed: UsbSpeed) -> bool {
+        speed.required_hub_speed() == self
+    }
 
-        let device = udev::Device::from_subsystem_sysname_with_context(
-            context.clone(),
-            "platform".into(),
-            "vhci_hcd.0".into(),
-        )
-        .map_err(|e| {
-            // udev returns ENODEV if the sysfs device was not there
-            if e.raw_os_error()
-                .is_some_and(|c| nix::errno::Errno::from_raw(c) == nix::errno::Errno::ENODEV)
-            {
-                Error::VhciDeviceNotFound
-            } else {
-                Error::VhciDeviceUdev(e.into())
-            }
-        })?;
+    /// The fastest speed a device attached to a port of a hub with this speed
+    /// runs at. The SuperSpeed hub of `vhci_hcd` is a USB 3.0 hub, so
+    /// SuperSpeedPlus devices only run at 5000 Mbps.
+    pub fn max_speed(self) -> UsbSpeed {
+        match self {
+            Self::High => UsbSpeed::High,
+            Self::Super => UsbSpeed::Super,
+        }
+    }
 
-        const NUM_PORTS_ATTR: &str = "nports";
+    /// Whether a device of the given speed runs below its rated speed when
+    /// attached to a hub with this speed. This only happens to SuperSpeedPlus
+    /// devices, which the SuperSpeed hub runs at SuperSpeed (see
+    /// [`HubSpeed::max_speed`]).
+    pub fn degrades(self, speed: UsbSpeed) -> bool {
+        self == HubSpeed::Super && speed == UsbSpeed::SuperPlus
+    }
+}
 
-        let num_ports = device
-            .attribute_value(NUM_PORTS_ATTR)
-            .ok_or_else(|| Error::VhciDeviceMissingUdevAttribute(NUM_PORTS_ATTR.into()))?
-            .to_str()
-            .ok_or_else(|| Error::VhciDeviceUtf8UdevAttribute(NUM_PORTS_ATTR.into()))?
-            .parse::<u32>()
-            .map_err(|_| Error::VhciDeviceParsingUdevAttribute(NUM_PORTS_ATTR.into()))?;
-
-        if num_ports == 0 {
-            return Err(Error::VhciNoAvailablePorts);
-        }
-
-        tracing::debug!("available ports = {num_ports}");
-
-        let platform = device
-            .parent()
-            .ok_or_else(|| Error::VhciDeviceParentNotFound)?;
-        let sys_path = platform.syspath();
-
-        let mut num_controllers = 0;
-        for entry in fs::read_dir(sys_path).map_err(Error::EnumeratingControllers)? {
-            if entry
-                .map_err(Error::EnumeratingControllers)?
-                .file_name()
-                .to_string_lossy()
-                .starts_with("vhci_hcd.")
-            {
-                num_controllers += 1;
-            }
-        }
+impl VhciHcd {
+    pub fn open() -> Result<Self, Error> {
+        Self::open_with_options(VhciOptions::default())
+    }
 
-        tracing::debug!("available controllers = {num_controllers}");
+    /// Same as [`open`](Self::open), but allows changing where the device is
+    /// looked up
+    pub fn open_with_options(options: VhciOptions) -> Result<Self, Error> {
+        let sys_path = open_device(&options)?;
+        let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
 
-        assert_ne!(
-            num_controllers, 0,
-            "should always have more than one controller if we opened the device initially"
-        );
+        // only warns, in case the distribution backported the newer format
+        kernel_compat();
+
+        let (num_ports, num_controllers) = read_port_layout(&options, &sys_path)?;
 
         let mut this = Self {
             context,
-            device,
+            options,
+            sys_path,
             num_ports,
             num_controllers,
             virtual_devices: vec![Default::default(); num_ports as usize],
@@ -266,107 +693,208 @@ impl VhciHcd {
     /// device to get a list of imported devices from each controller. After
     /// collecting the results, uses udev to query more information from the USB
     /// devices to update the internal cache.
+    ///
+    /// If the status doesn't match the number of ports read when the device
+    /// was opened, the module was most likely reloaded in the meantime. In that
+    /// case the device is reopened and the status read once more.
     pub fn refresh_imported_device_list(&mut self) -> Result<(), Error> {
+        match self.read_imported_devices() {
+            Err(e @ (Error::ConflictingStatusData(_) | Error::StatusPortCountMismatch { .. })) => {
+                tracing::warn!("`vhci_hcd` status is inconsistent, reopening the device ({e})");
+            }
+            result => return result,
+        }
+
+        self.reopen()?;
+
+        self.read_imported_devices().map_err(|e| match e {
+            e @ (Error::ConflictingStatusData(_) | Error::StatusPortCountMismatch { .. }) => {
+                Error::ReloadedWhileRunning(Box::new(e))
+            }
+            e => e,
+        })
+    }
+
+    /// Opens the device again and updates the port layout, which may have
+    /// changed if the module was reloaded with different parameters
+    fn reopen(&mut self) -> Result<(), Error> {
+        let sys_path = open_device(&self.options)?;
+        let (num_ports, num_controllers) = read_port_layout(&self.options, &sys_path)?;
+
+        if (num_ports, num_controllers) != (self.num_ports, self.num_controllers) {
+            tracing::info!(
+                "`vhci_hcd` port layout changed from {} ports on {} controller(s) to {num_ports} ports on {num_controllers} controller(s)",
+                self.num_ports,
+                self.num_controllers
+            );
+        }
+
+        self.sys_path = sys_path;
+        self.num_ports = num_ports;
+        self.num_controllers = num_controllers;
+        self.virtual_devices = vec![Default::default(); num_ports as usize];
+
+        Ok(())
+    }
+
+    fn read_imported_devices(&mut self) -> Result<(), Error> {
         // we expect the total number of lines returned to match the `nports`
         // value we read during initialization. since the total number of
         // controllers and ports is baked into the kernel module at compile
         // time, this constraint should never be violated unless the module was
         // reloaded in between our driver's initialization and the calling of
-        // this function. if that is the case, we have bigger problems anyway so
-        // we report a conflict.
+        // this function. if that is the case, we report the mismatch so the
+        // device can be reopened.
 
         let mut total_devices = 0;
 
         for i in 0..self.num_controllers {
-            let attr_name = if i == 0 {
-                "status"
-            } else {
-                &format!("status.{i}")
-            };
+            let attr_name = &status_attr_name(i);
 
             tracing::debug!("controller {i}");
 
-            let status_attr = self
-                .device
-                .attribute_value(attr_name)
-                .ok_or_else(|| Error::VhciDeviceMissingUdevAttribute(attr_name.into()))?
-                .to_str()
-                .ok_or_else(|| Error::VhciDeviceUtf8UdevAttribute(attr_name.into()))?
-                .to_owned();
+            let status_attr = read_attribute(&self.sys_path, attr_name)?;
 
             for (j, r) in parse_vhci_hcd_status_attr(&status_attr).enumerate() {
-                if total_devices >= self.num_ports {
-                    return Err(Error::ConflictingStatusData);
-                }
-
                 total_devices += 1;
 
-                let status_line =
-                    r.map_err(|_| Error::VhciDeviceParsingUdevAttribute(attr_name.into()))?;
-
-                let speed = match status_line.hub.as_str() {
-                    "hs" => HubSpeed::High,
-                    "ss" => HubSpeed::Super,
-                    _ => return Err(Error::VhciDeviceParsingUdevAttribute(attr_name.into())),
-                };
+                // keep counting the remaining lines so the error below can
+                // report how many ports were actually covered
+                if total_devices > self.num_ports {
+                    continue;
+                }
 
-                if status_line.port >= self.num_ports as _ {
-                    return Err(Error::ConflictingStatusData);
+                let status_line = r.map_err(|e| Error::VhciDeviceParsingStatusLine {
+                    attribute: attr_name.into(),
+                    line: e.line,
+                })?;
+
+                let expected_port = i as usize * self.ports_per_controller() as usize + j;
+                if status_line.port as usize != expected_port {
+                    return Err(Error::ConflictingStatusData(format!(
+                        "`{attr_name}` lists port {} where port {expected_port} was expected",
+                        status_line.port
+                    )));
                 }
 
-                let status = VhciDeviceStatus::try_from(status_line.status)
-                    .map_err(|_| Error::VhciDeviceParsingUdevAttribute(attr_name.into()))?;
-
-                let state = match status {
-                    VhciDeviceStatus::NotConnected => VhciDeviceState::NotConnected,
-                    VhciDeviceStatus::NotAssigned => VhciDeviceState::NotAssigned,
-                    s @ (VhciDeviceStatus::Used | VhciDeviceStatus::Error) => {
-                        let device = self.query_imported_device(&status_line.local_bus_id)?;
-
-                        let connected_device = VhciImportedDevice {
-                            remote_device_id: status_line.device_id,
-                            socket_fd: status_line.socket_fd,
-                            device,
-                        };
-
-                        if s == VhciDeviceStatus::Used {
-                            VhciDeviceState::Used(connected_device)
-                        } else {
-                            VhciDeviceState::Error(connected_device)
-                        }
-                    }
-                };
-
-                assert_eq!(
-                    status_line.port as usize,
-                    i as usize * self.ports_per_controller() as usize + j as usize,
-                );
+                // the header is the first line of the attribute
+                let device = self.device_from_status_line(attr_name, j + 2, &status_line)?;
 
-                self.virtual_devices[status_line.port as usize] = VhciDevice {
-                    hub_speed: speed,
-                    port: status_line.port,
-                    state,
-                };
+                self.virtual_devices[status_line.port as usize] = device;
             }
         }
 
-        if total_devices < self.num_ports {
-            return Err(Error::ConflictingStatusData);
+        if total_devices != self.num_ports {
+            return Err(Error::StatusPortCountMismatch {
+                covered: total_devices,
+                num_ports: self.num_ports,
+                num_controllers: self.num_controllers,
+            });
         }
 
         Ok(())
     }
 
+    /// Updates the state of a single port from the status attribute of its
+    /// controller, which is much cheaper than
+    /// [`refresh_imported_device_list`](Self::refresh_imported_device_list)
+    /// when only one port is of interest (like right after attaching a device).
+    ///
+    /// Falls back to refreshing all ports if the port can't be found where it
+    /// is expected.
+    pub fn refresh_port(&mut self, port: u16) -> Result<(), Error> {
+        if port as u32 >= self.num_ports {
+            tracing::debug!("port {port} is out of range, refreshing all ports");
+            return self.refresh_imported_device_list();
+        }
+
+        let attr_name = &status_attr_name(port as u32 / self.ports_per_controller() as u32);
+        let status_attr = read_attribute(&self.sys_path, attr_name)?;
+
+        for (j, r) in parse_vhci_hcd_status_attr(&status_attr).enumerate() {
+            // other ports don't matter here, but a line which can't be parsed
+            // means the kernel changed the format (see `kernel_compat`)
+            let status_line = match r {
+                Ok(status_line) => status_line,
+                Err(e) => {
+                    tracing::warn!(
+                        "skipping line {} of `{attr_name}` which could not be parsed: {:?}",
+                        e.line,
+                        status_attr.lines().nth(e.line - 1).unwrap_or_default()
+                    );
+                    continue;
+                }
+            };
+
+            if status_line.port != port {
+                continue;
+            }
+
+            let device = self.device_from_status_line(attr_name, j + 2, &status_line)?;
+            self.virtual_devices[port as usize] = device;
+
+            return Ok(());
+        }
+
+        tracing::debug!("port {port} not found in `{attr_name}`, refreshing all ports");
+
+        self.refresh_imported_device_list()
+    }
+
+    /// Interprets a single line of a status attribute, querying the local USB
+    /// device created for the port (if any)
+    fn device_from_status_line(
+        &mut self,
+        attr_name: &str,
+        line: usize,
+        status_line: &StatusLine,
+    ) -> Result<VhciDevice, Error> {
+        let invalid_line = || Error::VhciDeviceParsingStatusLine {
+            attribute: attr_name.into(),
+            line,
+        };
+
+        let speed = match status_line.hub.as_str() {
+            "hs" => HubSpeed::High,
+            "ss" => HubSpeed::Super,
+            _ => return Err(invalid_line()),
+        };
+
+        if status_line.port >= self.num_ports as _ {
+            return Err(Error::ConflictingStatusData(format!(
+                "port {} is out of range for the {} ports reported by `nports`",
+                status_line.port, self.num_ports
+            )));
+        }
+
+        let status = VhciDeviceStatus::try_from(status_line.status).map_err(|_| invalid_line())?;
+
+        let state = match status {
+            VhciDeviceStatus::NotConnected => VhciDeviceState::NotConnected,
+            VhciDeviceStatus::NotAssigned => VhciDeviceState::NotAssigned,
+            s @ (VhciDeviceStatus::Used | VhciDeviceStatus::Error) => {
+                let device = self.query_imported_device(&status_line.bus_id)?;
+
+                let connected_device = VhciImportedDevice::new(status_line, device);
+
+                if s == VhciDeviceStatus::Used {
+                    VhciDeviceState::Used(connected_device)
+                } else {
+                    VhciDeviceState::Error(connected_device)
+                }
+            }
+        };
+
+        Ok(VhciDevice {
+            hub_speed: speed,
+            port: status_line.port,
+            state,
+        })
+    }
+
     fn query_imported_device(&mut self, local_bus_id: &str) -> Result<UsbDeviceInfo, Error> {
-        let udev = udev::Device::from_subsystem_sysname_with_context(
-            self.context.clone(),
-            "usb".into(),
-            local_bus_id.into(),
-        )
-        .map_err(|error| Error::QueryingLocalUsbDevice {
-            bus_id: local_bus_id.into(),
-            error,
-        })?;
+        let udev =
+            open_usb_device(&self.context, local_bus_id).map_err(Error::QueryingLocalUsbDevice)?;
 
         extract_usb_info_from_udev_device(&udev).map_err(|e| Error::UsbInfoExtraction {
             bus_id: local_bus_id.into(),
@@ -378,17 +906,8 @@ impl VhciHcd {
         for i in 0..self.num_ports {
             let device = &self.virtual_devices[i as usize];
 
-            match speed {
-                UsbSpeed::Super => {
-                    if device.hub_speed != HubSpeed::Super {
-                        continue;
-                    }
-                }
-                _ => {
-                    if device.hub_speed != HubSpeed::High {
-                        continue;
-                    }
-                }
+            if !device.hub_speed.accepts(speed) {
+                continue;
             }
 
             if device.status() == VhciDeviceStatus::NotConnected {
@@ -396,7 +915,7 @@ impl VhciHcd {
             }
         }
 
-        Err(Error::NoFreePorts)
+        Err(Error::NoFreePorts(speed.required_hub_speed()))
     }
 
     pub fn attach_device(
@@ -405,50 +924,52 @@ impl VhciHcd {
         socket_fd: RawFd,
         bus_num: u32,
         dev_num: u32,
-        speed: u32,
+        speed: UsbSpeed,
     ) -> Result<(), Error> {
-        use std::{fs, io::Write};
+        // the kernel rejects all of these with a plain EINVAL, so check them
+        // here where we can say what is actually wrong
+        if rh_port >= self.num_ports {
+            return Err(Error::PortOutOfRange {
+                port: rh_port,
+                num_ports: self.num_ports,
+            });
+        }
+        validate_socket_fd(socket_fd)?;
 
         let device_id = (bus_num << 16) | dev_num;
-        let buf = format!("{rh_port} {socket_fd} {device_id} {speed}");
-        let attach_path = self.device.syspath().join("attach");
-
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .open(attach_path)
-            .map_err(|e| {
-                if e.kind() == ErrorKind::PermissionDenied {
-                    Error::SysfsPermissionDenied
-                } else {
-                    Error::SysfsIo(e.into())
-                }
-            })?;
-        file.write_all(buf.as_bytes()).map_err(Error::SysfsIo)?;
+        let buf = format!("{rh_port} {socket_fd} {device_id} {}", speed.kernel_code());
 
-        Ok(())
+        self.write_attribute("attach", &buf)
     }
 
     pub fn detach_device(&mut self, port: u16) -> Result<(), Error> {
+        self.write_attribute("detach", &format!("{port}"))
+    }
+
+    /// Writes to one of the vhci_hcd device's sysfs attributes
+    fn write_attribute(&self, name: &str, value: &str) -> Result<(), Error> {
         use std::{fs, io::Write};
 
-        let buf = format!("{port}");
-        let detach_path = self.device.syspath().join("detach");
+        let path = self.sys_path.join(name);
 
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .open(detach_path)
-            .map_err(|e| {
-                if e.kind() == ErrorKind::PermissionDenied {
-                    Error::SysfsPermissionDenied
-                } else {
-                    Error::SysfsIo(e.into())
-                }
-            })?;
-        file.write_all(buf.as_bytes()).map_err(Error::SysfsIo)?;
+        tracing::debug!(
+            "writing to sysfs (path = \"{}\", value = {value:?})",
+            path.display()
+        );
+
+        let mut file = retry_interrupted(|| fs::OpenOptions::new().write(true).open(&path))
+            .map_err(map_sysfs_error)?;
+        file.write_all(value.as_bytes()).map_err(map_sysfs_error)?;
 
         Ok(())
     }
 
+    /// The sysfs directory of the first controller, which holds the
+    /// attributes of all controllers
+    pub fn sys_path(&self) -> &Path {
+        &self.sys_path
+    }
+
     pub fn controller_count(&self) -> u16 {
         self.num_controllers as _
     }
@@ -463,50 +984,985 @@ impl VhciHcd {
         self.total_port_count() / self.controller_count()
     }
 
+    /// Returns the root hub ports which belong to the given controller, or
+    /// `None` if there is no controller with that index. Each controller has
+    /// the same number of ports, which are numbered consecutively across the
+    /// controllers.
+    pub fn controller_ports(&self, controller: u16) -> Option<Range<u16>> {
+        if controller >= self.controller_count() {
+            return None;
+        }
+
+        let per_controller = self.ports_per_controller();
+        let start = controller * per_controller;
+
+        Some(start..start + per_controller)
+    }
+
+    /// Returns the number of root hub ports of the given speed
+    pub fn port_count(&self, hub_speed: HubSpeed) -> u16 {
+        self.virtual_devices
+            .iter()
+            .filter(|d| d.hub_speed == hub_speed)
+            .count() as _
+    }
+
+    /// Returns the number of root hub ports of the given speed which do not
+    /// have a device connected (based on the cached state)
+    pub fn free_port_count(&self, hub_speed: HubSpeed) -> u16 {
+        self.virtual_devices
+            .iter()
+            .filter(|d| d.hub_speed == hub_speed && d.status() == VhciDeviceStatus::NotConnected)
+            .count() as _
+    }
+
     pub fn cached_imported_devices(&self) -> &[VhciDevice] {
         &self.virtual_devices
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
-struct VhciHcdStatusLine {
-    hub: CompactString,
-    port: u16,
-    status: u32,
-    speed: u8,
-    device_id: u32,
-    socket_fd: u32,
-    local_bus_id: CompactString,
+/// The unparsed `status` (and `status.N`) attributes of every controller (see
+/// [`read_raw_status`])
+#[derive(Debug, Clone)]
+pub struct RawStatus {
+    pub num_ports: u32,
+    pub num_controllers: u32,
+    /// The attribute contents exactly as reported by the kernel, in controller
+    /// order
+    pub status: Vec<String>,
+}
+
+/// Reads the status attributes of every controller without parsing them.
+/// Meant for troubleshooting, so unlike [`VhciHcd::open`] this still works if
+/// the kernel reports a status which can't be parsed.
+pub fn read_raw_status() -> Result<RawStatus, Error> {
+    read_raw_status_with_options(&VhciOptions::default())
+}
+
+/// Same as [`read_raw_status`], but allows changing where the device is looked
+/// up
+pub fn read_raw_status_with_options(options: &VhciOptions) -> Result<RawStatus, Error> {
+    let sys_path = open_device(options)?;
+    let (num_ports, num_controllers) = read_port_layout(options, &sys_path)?;
+
+    let status = (0..num_controllers)
+        .map(|i| read_attribute(&sys_path, &status_attr_name(i)))
+        .collect::<Result<_, _>>()?;
+
+    Ok(RawStatus {
+        num_ports,
+        num_controllers,
+        status,
+    })
+}
+
+/// Name of the status attribute of the given controller. The first controller
+/// uses `status` while the others are suffixed with their index.
+pub fn status_attr_name(controller: u32) -> String {
+    if controller == 0 {
+        "status".into()
+    } else {
+        format!("status.{controller}")
+    }
+}
+
+/// A single port entry of the `status` (or `status.N`) attribute of the
+/// vhci_hcd device
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct StatusLine {
+    /// Hub speed of the port (`hs` or `ss`)
+    pub hub: CompactString,
+    /// Root hub port number
+    pub port: u16,
+    /// Raw `VDEV_ST_*` status of the port (see [`VhciDeviceStatus`])
+    pub status: u32,
+    /// Raw `enum usb_device_speed` value of the attached device
+    pub speed: u8,
+    /// Encodes the bus_num and dev_num of the device on the remote machine
+    pub device_id: u32,
+    /// The socket fd passed to vhci_hcd during device attachment
+    pub socket_fd: u32,
+    /// Bus ID of the locally created USB device (`0-0` when unused)
+    pub bus_id: CompactString,
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("Failed to parse vhci_hcd controller status")]
-struct VhciHcdStatusParseError;
+#[error("Failed to parse line {line} of vhci_hcd controller status")]
+pub struct StatusParseError {
+    /// The (1-based) line number within the attribute, including the header
+    pub line: usize,
+}
+
+impl StatusLine {
+    /// Parses a single line of the status attribute. The columns are split on
+    /// any whitespace since the alignment of the columns has changed between
+    /// kernel versions.
+    fn parse(s: &str) -> Option<Self> {
+        let mut fields = s.split_whitespace();
+        let mut next = || fields.next();
+
+        let line = Self {
+            hub: next()?.into(),
+            port: next()?.parse().ok()?,
+            status: next()?.parse().ok()?,
+            speed: next()?.parse().ok()?,
+            device_id: u32::from_str_radix(next()?, 16).ok()?,
+            socket_fd: next()?.parse().ok()?,
+            bus_id: next()?.into(),
+        };
+
+        // anything after the bus ID means we don't understand this format
+        if next().is_some() {
+            return None;
+        }
+
+        Some(line)
+    }
+}
+
+/// Parses a captured `status` attribute of a vhci_hcd controller (e.g. from
+/// `/sys/devices/platform/vhci_hcd.0/status`). The header line is skipped.
+pub fn parse_status(text: &str) -> Result<Vec<StatusLine>, StatusParseError> {
+    parse_vhci_hcd_status_attr(text).collect()
+}
+
+/// Parses the output of /sys/devices/platform/vhci_hcd.0/status line by line
+fn parse_vhci_hcd_status_attr(
+    text: &str,
+) -> impl Iterator<Item = Result<StatusLine, StatusParseError>> {
+    text.lines()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| StatusLine::parse(l).ok_or(StatusParseError { line: i + 1 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+    use crate::util::TempDir;
+
+    const SPEEDS: [UsbSpeed; 7] = [
+        UsbSpeed::Unknown,
+        UsbSpeed::Low,
+        UsbSpeed::Full,
+        UsbSpeed::High,
+        UsbSpeed::Wireless,
+        UsbSpeed::Super,
+        UsbSpeed::SuperPlus,
+    ];
+
+    #[test]
+    fn only_usb_3_devices_need_the_super_speed_hub() {
+        for speed in SPEEDS {
+            let expected = match speed {
+                UsbSpeed::Super | UsbSpeed::SuperPlus => HubSpeed::Super,
+                _ => HubSpeed::High,
+            };
+
+            assert_eq!(speed.required_hub_speed(), expected, "{speed:?}");
+            assert!(expected.accepts(speed), "{speed:?}");
+        }
+    }
+
+    #[test]
+    fn hubs_reject_devices_of_the_other_kind() {
+        assert!(!HubSpeed::High.accepts(UsbSpeed::Super));
+        assert!(!HubSpeed::High.accepts(UsbSpeed::SuperPlus));
+        assert!(!HubSpeed::Super.accepts(UsbSpeed::High));
+        assert!(!HubSpeed::Super.accepts(UsbSpeed::Low));
+    }
+
+    #[test]
+    fn super_plus_devices_degrade_on_the_super_speed_hub() {
+        assert!(HubSpeed::Super.degrades(UsbSpeed::SuperPlus));
+    }
 
-impl FromStr for VhciHcdStatusLine {
-    type Err = VhciHcdStatusParseError;
+    #[test]
+    fn no_other_device_degrades() {
+        for speed in SPEEDS {
+            if speed != UsbSpeed::SuperPlus {
+                assert!(!HubSpeed::Super.degrades(speed), "{speed:?}");
+            }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (hub, port, status, speed, device_id, socket_fd, local_bus_id) =
-            sscanf::sscanf!(s, "{str}  {u16} {u32} {u8} {u32:x} {u32} {str}",)
-                .map_err(|_| VhciHcdStatusParseError)?;
+            assert!(!HubSpeed::High.degrades(speed), "{speed:?}");
+        }
+    }
 
-        Ok(Self {
+    const STATUS: &str = "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 004 000 00000000 000000 0-0
+hs  0001 006 003 00010002 000003 3-1
+ss  0008 004 000 00000000 000000 0-0
+ss  0009 006 005 0002000a 000004 4-1
+";
+
+    fn line(
+        hub: &str,
+        port: u16,
+        status: u32,
+        speed: u8,
+        device_id: u32,
+        socket_fd: u32,
+        bus_id: &str,
+    ) -> StatusLine {
+        StatusLine {
             hub: hub.into(),
             port,
             status,
             speed,
             device_id,
             socket_fd,
-            local_bus_id: local_bus_id.into(),
-        })
+            bus_id: bus_id.into(),
+        }
     }
-}
 
-/// Parses the output of /sys/devices/platform/vhci_hcd.0/status line by line
-fn parse_vhci_hcd_status_attr(
-    text: &str,
-) -> impl Iterator<Item = Result<VhciHcdStatusLine, VhciHcdStatusParseError>> {
-    text.lines().skip(1).map(|l| l.parse())
+    #[test]
+    fn parses_empty_and_used_ports() {
+        assert_eq!(
+            parse_status(STATUS).unwrap(),
+            [
+                line("hs", 0, 4, 0, 0, 0, "0-0"),
+                line("hs", 1, 6, 3, 0x0001_0002, 3, "3-1"),
+                line("ss", 8, 4, 0, 0, 0, "0-0"),
+                line("ss", 9, 6, 5, 0x0002_000a, 4, "4-1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_a_status_without_ports() {
+        assert!(
+            parse_status("hub port sta spd dev      sockfd local_busid\n")
+                .unwrap()
+                .is_empty()
+        );
+        assert!(parse_status("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn skips_blank_lines() {
+        let text = STATUS.replace("\nss  0008", "\n\nss  0008");
+
+        assert_eq!(parse_status(&text).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn reports_the_line_which_failed_to_parse() {
+        let text = STATUS.replace("hs  0001 006 003", "hs  0001 006 fast");
+
+        assert_eq!(parse_status(&text).unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn parses_lines_with_any_whitespace_between_columns() {
+        let expected = line("hs", 1, 6, 3, 0x0001_0002, 3, "3-1");
+
+        for text in [
+            "hs 0001 006 003 00010002 000003 3-1",
+            "hs  0001 006 003 00010002 000003 3-1",
+            "hs\t0001\t006\t003\t00010002\t000003\t3-1",
+            "  hs  0001  006 003\t00010002 000003   3-1  ",
+        ] {
+            assert_eq!(StatusLine::parse(text), Some(expected.clone()), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_lines_with_missing_or_extra_columns() {
+        assert_eq!(StatusLine::parse("hs 0001 006 003 00010002 000003"), None);
+        assert_eq!(
+            StatusLine::parse("hs 0001 006 003 00010002 000003 3-1 extra"),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_the_device_id_as_hex() {
+        let parsed = StatusLine::parse("ss 0009 006 005 000a00ff 000004 4-1").unwrap();
+
+        assert_eq!(parsed.device_id, 0x000a_00ff);
+        assert_eq!(
+            StatusLine::parse("ss 0009 006 005 0000000g 000004 4-1"),
+            None
+        );
+    }
+
+    #[test]
+    fn keeps_the_kernel_speed_apart_from_the_sysfs_speed() {
+        // a SuperSpeedPlus device attached to the SuperSpeed hub, which sysfs
+        // reports at the speed it actually runs at
+        let status = parse_status(
+            "hub port sta spd dev      sockfd local_busid\n\
+             ss  0009 006 006 0002000a 000004 4-1\n",
+        )
+        .unwrap();
+        let device = UsbDeviceInfo {
+            bus_id: "4-1".into(),
+            speed: "5000".parse().unwrap(),
+            ..Default::default()
+        };
+
+        let imported = VhciImportedDevice::new(&status[0], device);
+
+        assert_eq!(imported.kernel_speed, 6);
+        assert_eq!(imported.device.speed, UsbSpeed::Super);
+        assert_eq!(
+            UsbSpeed::from_kernel_code(imported.kernel_speed as u32),
+            Some(UsbSpeed::SuperPlus)
+        );
+        assert_ne!(
+            imported.device.speed.kernel_code(),
+            imported.kernel_speed as u32
+        );
+    }
+
+    fn version(major: u32, minor: u32, patch: u32) -> KernelVersion {
+        KernelVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    #[test]
+    fn parses_kernel_releases() {
+        assert_eq!(
+            KernelVersion::parse("6.8.0-45-generic"),
+            Some(version(6, 8, 0))
+        );
+        assert_eq!(
+            KernelVersion::parse("5.15.153.1-microsoft-standard-WSL2"),
+            Some(version(5, 15, 153))
+        );
+        assert_eq!(KernelVersion::parse("6.10"), Some(version(6, 10, 0)));
+        assert_eq!(KernelVersion::parse("6.11-rc3"), Some(version(6, 11, 0)));
+        assert_eq!(KernelVersion::parse("4.19.0+"), Some(version(4, 19, 0)));
+    }
+
+    #[test]
+    fn rejects_releases_without_a_version() {
+        for release in ["", "6", "linux-6.8", "6.x", ".8.0"] {
+            assert_eq!(KernelVersion::parse(release), None, "{release:?}");
+        }
+    }
+
+    #[test]
+    fn orders_versions_numerically() {
+        assert!(version(4, 9, 0) < version(4, 13, 0));
+        assert!(version(4, 15, 0) < version(4, 15, 1));
+        assert!(version(5, 0, 0) > version(4, 20, 0));
+    }
+
+    #[test]
+    fn checks_releases_against_the_format_changes() {
+        assert!(matches!(
+            kernel_compat_for_release("4.9.0"),
+            KernelCompat::Unsupported { reason, .. } if reason.contains("hub")
+        ));
+        assert!(matches!(
+            kernel_compat_for_release("4.13.16"),
+            KernelCompat::Unsupported { reason, .. } if reason.contains("socket")
+        ));
+        assert!(matches!(
+            kernel_compat_for_release("4.14.7"),
+            KernelCompat::Unsupported { reason, .. } if reason.contains("socket")
+        ));
+        assert_eq!(
+            kernel_compat_for_release("4.14.8"),
+            KernelCompat::Supported {
+                version: version(4, 14, 8)
+            }
+        );
+        assert_eq!(
+            kernel_compat_for_release("4.14.300"),
+            KernelCompat::Supported {
+                version: version(4, 14, 300)
+            }
+        );
+        assert_eq!(
+            kernel_compat_for_release("4.15.0"),
+            KernelCompat::Supported {
+                version: version(4, 15, 0)
+            }
+        );
+        assert_eq!(
+            kernel_compat_for_release("6.8.0-45-generic"),
+            KernelCompat::Supported {
+                version: version(6, 8, 0)
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_releases_are_assumed_supported() {
+        let compat = kernel_compat_for_release("unknown");
+
+        assert_eq!(
+            compat,
+            KernelCompat::Unknown {
+                release: "unknown".into()
+            }
+        );
+        assert!(compat.is_supported());
+    }
+
+    /// Options which look up the device with the given names in a mock sysfs
+    /// tree
+    fn mock_options(root: &Path, driver_name: &str, device_name: &str) -> VhciOptions {
+        VhciOptions {
+            driver_name: driver_name.into(),
+            device_name: device_name.into(),
+            sysfs_root: root.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a controller in a mock sysfs tree, linked from the devices of
+    /// the platform subsystem like the kernel does. Returns its directory.
+    fn mock_controller(root: &Path, name: &str) -> PathBuf {
+        let device = root.join("devices/platform").join(name);
+        let devices = root.join("bus/platform/devices");
+
+        fs::create_dir_all(&device).unwrap();
+        fs::create_dir_all(&devices).unwrap();
+        std::os::unix::fs::symlink(&device, devices.join(name)).unwrap();
+
+        device
+    }
+
+    /// Writes `nports` and a `status` in which every port is free. The first
+    /// half of the ports belongs to the high speed hub.
+    fn write_free_ports(device: &Path, num_ports: u16) {
+        let mut status = String::from("hub port sta spd dev      sockfd local_busid\n");
+
+        for port in 0..num_ports {
+            let hub = if port < num_ports / 2 { "hs" } else { "ss" };
+            status += &format!("{hub}  {port:04} 004 000 00000000 000000 0-0\n");
+        }
+
+        fs::write(device.join("nports"), format!("{num_ports}\n")).unwrap();
+        fs::write(device.join("status"), status).unwrap();
+    }
+
+    #[test]
+    fn opens_the_device_with_the_given_names() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_test.0");
+        write_free_ports(&device, 4);
+
+        let vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_test", "vhci_test.0"))
+                .unwrap();
+
+        assert_eq!(vhci_hcd.sys_path(), fs::canonicalize(&device).unwrap());
+        assert_eq!(vhci_hcd.total_port_count(), 4);
+        assert_eq!(vhci_hcd.controller_count(), 1);
+        assert_eq!(vhci_hcd.free_port_count(HubSpeed::High), 2);
+        assert_eq!(vhci_hcd.free_port_count(HubSpeed::Super), 2);
+    }
+
+    #[test]
+    fn does_not_open_devices_with_other_names() {
+        let root = TempDir::new();
+        write_free_ports(&mock_controller(root.path(), "vhci_test.0"), 4);
+
+        let result = VhciHcd::open_with_options(VhciOptions {
+            sysfs_root: root.path().into(),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            result,
+            Err(Error::VhciDeviceNotFound { device, driver })
+                if device == VHCI_DEVICE_NAME && driver == VHCI_DRIVER_NAME
+        ));
+    }
+
+    #[test]
+    fn counts_the_controllers_next_to_the_device() {
+        let root = TempDir::new();
+        let options = mock_options(root.path(), "vhci_hcd", "vhci_hcd.0");
+        write_free_ports(&mock_controller(root.path(), "vhci_hcd.0"), 8);
+        mock_controller(root.path(), "vhci_hcd.1");
+        mock_controller(root.path(), "dummy_hcd.0");
+
+        let sys_path = open_device(&options).unwrap();
+
+        assert_eq!(read_port_layout(&options, &sys_path).unwrap(), (8, 2));
+    }
+
+    #[test]
+    fn assumes_a_single_controller_without_the_platform_device() {
+        let root = TempDir::new();
+        let options = mock_options(root.path(), "vhci_hcd", "vhci_hcd.0");
+
+        // only the device itself is exposed, without any siblings
+        let device = root.path().join("bus/platform/devices/vhci_hcd.0");
+        fs::create_dir_all(&device).unwrap();
+        write_free_ports(&device, 4);
+
+        let sys_path = open_device(&options).unwrap();
+
+        assert_eq!(read_port_layout(&options, &sys_path).unwrap(), (4, 1));
+        assert_eq!(
+            count_controllers(&options, &root.path().join("devices/platform")).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn counts_the_ports_of_each_hub_across_controllers() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        mock_controller(root.path(), "vhci_hcd.1");
+
+        // two controllers with two ports per hub, one of which is taken on
+        // the high speed hub of the first controller and the super speed hub
+        // of the second one
+        let status = |ports: [(&str, u16, u32); 4]| {
+            let mut status = String::from("hub port sta spd dev      sockfd local_busid\n");
+
+            for (hub, port, sta) in ports {
+                status += &format!("{hub}  {port:04} {sta:03} 000 00000000 000000 0-0\n");
+            }
+
+            status
+        };
+
+        fs::write(device.join("nports"), "8\n").unwrap();
+        fs::write(
+            device.join("status"),
+            status([("hs", 0, 4), ("hs", 1, 5), ("ss", 2, 4), ("ss", 3, 4)]),
+        )
+        .unwrap();
+        fs::write(
+            device.join("status.1"),
+            status([("hs", 4, 4), ("hs", 5, 4), ("ss", 6, 5), ("ss", 7, 4)]),
+        )
+        .unwrap();
+
+        let vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        assert_eq!(vhci_hcd.controller_count(), 2);
+        assert_eq!(vhci_hcd.total_port_count(), 8);
+        assert_eq!(vhci_hcd.port_count(HubSpeed::High), 4);
+        assert_eq!(vhci_hcd.port_count(HubSpeed::Super), 4);
+        assert_eq!(vhci_hcd.free_port_count(HubSpeed::High), 3);
+        assert_eq!(vhci_hcd.free_port_count(HubSpeed::Super), 3);
+
+        assert_eq!(vhci_hcd.controller_ports(0), Some(0..4));
+        assert_eq!(vhci_hcd.controller_ports(1), Some(4..8));
+        assert_eq!(vhci_hcd.controller_ports(2), None);
+    }
+
+    #[test]
+    fn maps_permission_errors_to_the_root_hint() {
+        assert!(matches!(
+            map_sysfs_error(ErrorKind::PermissionDenied.into()),
+            Error::SysfsPermissionDenied
+        ));
+        assert!(matches!(
+            map_sysfs_error(ErrorKind::ResourceBusy.into()),
+            Error::SysfsIo(e) if e.kind() == ErrorKind::ResourceBusy
+        ));
+    }
+
+    #[test]
+    fn writes_the_port_to_detach() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        // the kernel always provides the attribute, so a missing one is not
+        // mistaken for a permission error
+        assert!(matches!(
+            vhci_hcd.detach_device(3),
+            Err(Error::SysfsIo(e)) if e.kind() == ErrorKind::NotFound
+        ));
+
+        fs::write(device.join("detach"), "").unwrap();
+        vhci_hcd.detach_device(3).unwrap();
+
+        assert_eq!(fs::read_to_string(device.join("detach")).unwrap(), "3");
+    }
+
+    #[test]
+    fn reads_the_total_ports_without_the_status() {
+        let root = TempDir::new();
+        let options = mock_options(root.path(), "vhci_hcd", "vhci_hcd.0");
+
+        assert!(matches!(
+            read_total_ports_with_options(&options),
+            Err(Error::VhciDeviceNotFound { .. })
+        ));
+
+        // no status attributes, which would fail a full open
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        fs::write(device.join("nports"), "16\n").unwrap();
+
+        assert_eq!(read_total_ports_with_options(&options).unwrap(), 16);
+
+        fs::write(device.join("nports"), "0\n").unwrap();
+
+        assert!(matches!(
+            read_total_ports_with_options(&options),
+            Err(Error::VhciNoAvailablePorts)
+        ));
+    }
+
+    /// The `(driver, sysname)` pairs of a synthetic device tree, starting at
+    /// the parent of the device
+    fn ancestors<'a>(
+        tree: &'a [(Option<&str>, &str)],
+    ) -> impl Iterator<Item = (Option<String>, String)> + 'a {
+        tree.iter()
+            .map(|(driver, sysname)| (driver.map(Into::into), sysname.to_string()))
+    }
+
+    #[test]
+    fn detects_devices_below_a_vhci_controller() {
+        let options = VhciOptions::default();
+
+        // 3-1 imported on the second controller
+        assert!(has_vhci_ancestor(
+            &options,
+            ancestors(&[
+                (Some("usb"), "usb5"),
+                (Some("vhci_hcd"), "vhci_hcd.1"),
+                (None, "platform"),
+            ])
+        ));
+
+        // the controller is recognized by its name even without a driver
+        assert!(has_vhci_ancestor(
+            &options,
+            ancestors(&[(Some("usb"), "usb3"), (None, "vhci_hcd.0")])
+        ));
+    }
+
+    #[test]
+    fn does_not_detect_devices_on_other_controllers() {
+        let options = VhciOptions::default();
+
+        assert!(!has_vhci_ancestor(
+            &options,
+            ancestors(&[
+                (Some("usb"), "1-1"),
+                (Some("usb"), "usb1"),
+                (Some("xhci_hcd"), "0000:00:14.0"),
+                (None, "pci0000:00"),
+            ])
+        ));
+        assert!(!has_vhci_ancestor(
+            &options,
+            ancestors(&[(Some("usb"), "usb2"), (Some("dummy_hcd"), "dummy_hcd.0")])
+        ));
+        assert!(!has_vhci_ancestor(&options, ancestors(&[])));
+    }
+
+    #[test]
+    fn errors_include_the_line_and_hub_speed() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let status = fs::read_to_string(device.join("status"))
+            .unwrap()
+            .replace("hs  0001", "xs  0001");
+        fs::write(device.join("status"), status).unwrap();
+
+        let error = VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+            .unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .contains("line 3 of sysfs attribute `status`"),
+            "{error}"
+        );
+
+        assert_eq!(
+            Error::NoFreePorts(HubSpeed::Super).to_string(),
+            "No free ports available on the Super speed root hub(s) (all in use)"
+        );
+    }
+
+    #[test]
+    fn reads_the_raw_status_verbatim() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        mock_controller(root.path(), "vhci_hcd.1");
+        write_free_ports(&device, 4);
+
+        // the status of the second controller can't be parsed
+        let second = "hub port sta spd dev      sockfd local_busid\nss  0003 fast\n\n";
+        fs::write(device.join("status.1"), second).unwrap();
+
+        let raw =
+            read_raw_status_with_options(&mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        assert_eq!(raw.num_ports, 4);
+        assert_eq!(raw.num_controllers, 2);
+        assert_eq!(
+            raw.status,
+            [
+                fs::read_to_string(device.join("status")).unwrap(),
+                second.to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn rereads_the_port_count_when_it_changes_between_reads() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+        assert_eq!(vhci_hcd.total_port_count(), 4);
+
+        // the module was reloaded with more ports
+        write_free_ports(&device, 8);
+        vhci_hcd.refresh_imported_device_list().unwrap();
+
+        assert_eq!(vhci_hcd.total_port_count(), 8);
+        assert_eq!(vhci_hcd.cached_imported_devices().len(), 8);
+        assert_eq!(vhci_hcd.free_port_count(HubSpeed::Super), 4);
+    }
+
+    #[test]
+    fn fails_if_the_status_covers_fewer_ports_than_nports() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        // one port short of `nports`
+        let status = fs::read_to_string(device.join("status")).unwrap();
+        let status = status.lines().take(4).collect::<Vec<_>>().join("\n");
+        fs::write(device.join("status"), status).unwrap();
+
+        let result =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"));
+
+        assert!(matches!(
+            result,
+            Err(Error::ReloadedWhileRunning(e)) if matches!(
+                *e,
+                Error::StatusPortCountMismatch {
+                    covered: 3,
+                    num_ports: 4,
+                    num_controllers: 1
+                }
+            )
+        ));
+    }
+
+    #[test]
+    fn reports_how_many_ports_the_status_covers() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+
+        // the status lists two ports more than `nports`
+        write_free_ports(&device, 6);
+        fs::write(device.join("nports"), "4\n").unwrap();
+
+        let error = VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+            .unwrap_err();
+
+        let Error::ReloadedWhileRunning(e) = &error else {
+            panic!("unexpected error {error:?}");
+        };
+        assert!(matches!(
+            **e,
+            Error::StatusPortCountMismatch {
+                covered: 6,
+                num_ports: 4,
+                num_controllers: 1
+            }
+        ));
+        assert!(
+            error
+                .to_string()
+                .contains("1 `vhci_hcd` controller(s) cover 6 ports, but `nports` reports 4"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn rejects_attaching_to_ports_out_of_range() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let result = vhci_hcd.attach_device(4, socket.as_raw_fd(), 1, 2, UsbSpeed::High);
+
+        assert!(
+            matches!(
+                result,
+                Err(Error::PortOutOfRange {
+                    port: 4,
+                    num_ports: 4
+                })
+            ),
+            "{result:?}"
+        );
+        assert!(!device.join("attach").exists());
+    }
+
+    #[test]
+    fn rejects_attaching_negative_socket_fds() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        let result = vhci_hcd.attach_device(0, -1, 1, 2, UsbSpeed::High);
+
+        assert!(
+            matches!(result, Err(Error::InvalidSocketFd(-1))),
+            "{result:?}"
+        );
+        assert!(!device.join("attach").exists());
+    }
+
+    #[test]
+    fn rejects_attaching_fds_which_are_not_sockets() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+        let file = fs::File::open(device.join("nports")).unwrap();
+
+        let result = vhci_hcd.attach_device(0, file.as_raw_fd(), 1, 2, UsbSpeed::High);
+
+        assert!(
+            matches!(result, Err(Error::NotASocket(fd)) if fd == file.as_raw_fd()),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn writes_valid_attach_requests() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+        fs::write(device.join("attach"), "").unwrap();
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = socket.as_raw_fd();
+
+        vhci_hcd.attach_device(3, fd, 1, 2, UsbSpeed::High).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(device.join("attach")).unwrap(),
+            format!("3 {fd} {} 3", (1 << 16) | 2)
+        );
+    }
+
+    #[test]
+    fn refreshes_only_the_given_port() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        // both ports get used, but only one of them is refreshed
+        let status = fs::read_to_string(device.join("status"))
+            .unwrap()
+            .replace("hs  0001 004", "hs  0001 005")
+            .replace("ss  0002 004", "ss  0002 005");
+        fs::write(device.join("status"), status).unwrap();
+
+        vhci_hcd.refresh_port(2).unwrap();
+
+        let statuses: Vec<_> = vhci_hcd
+            .cached_imported_devices()
+            .iter()
+            .map(|device| device.status())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                VhciDeviceStatus::NotConnected,
+                VhciDeviceStatus::NotConnected,
+                VhciDeviceStatus::NotAssigned,
+                VhciDeviceStatus::NotConnected,
+            ]
+        );
+    }
+
+    #[test]
+    fn refreshes_a_port_next_to_lines_which_fail_to_parse() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        let status = fs::read_to_string(device.join("status"))
+            .unwrap()
+            .replace("ss  0003 004", "ss  0003 fast");
+        fs::write(device.join("status"), status).unwrap();
+
+        vhci_hcd.refresh_port(2).unwrap();
+
+        assert_eq!(
+            vhci_hcd.cached_imported_devices()[2].status(),
+            VhciDeviceStatus::NotConnected
+        );
+    }
+
+    #[test]
+    fn fails_if_the_status_stays_inconsistent() {
+        let root = TempDir::new();
+        let device = mock_controller(root.path(), "vhci_hcd.0");
+        write_free_ports(&device, 4);
+
+        let mut vhci_hcd =
+            VhciHcd::open_with_options(mock_options(root.path(), "vhci_hcd", "vhci_hcd.0"))
+                .unwrap();
+
+        // ports listed out of order, which no reload explains
+        let status = fs::read_to_string(device.join("status"))
+            .unwrap()
+            .replace("hs  0001", "hs  0003");
+        fs::write(device.join("status"), status).unwrap();
+
+        assert!(matches!(
+            vhci_hcd.refresh_imported_device_list(),
+            Err(Error::ReloadedWhileRunning(e))
+                if matches!(*e, Error::ConflictingStatusData(_))
+        ));
+    }
 }
@@ -9,7 +9,7 @@
 use std::{
     fs,
     io::{self, ErrorKind, Read, Write},
-    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
     path::Path,
 };
 
@@ -21,6 +21,10 @@ pub enum FsStateError {
     IoWrite(io::Error),
     #[error("File-system `vhci_hcd` state path already exists, but is not a directory")]
     NotADirectory,
+    #[error(
+        "Insufficient permissions for the `vhci_hcd` state directory `{VHCI_STATE_PATH}` (owned by uid {owner_uid}, running as uid {euid}). Try executing again with sudo, or remove the directory so it can be recreated."
+    )]
+    StateDirPermissions { owner_uid: u32, euid: u32 },
 
     #[error(
         "Failed to read userspace `vhci_hcd` state from the file-system for device on port {1} ({0})"
@@ -28,6 +32,8 @@ pub enum FsStateError {
     IoRead(io::Error, u16),
     #[error("Failed to parse file-system `vhci_hcd` state file for device on port {0}")]
     Parsing(u16),
+    #[error("Failed to list the `vhci_hcd` state directory `{VHCI_STATE_PATH}` ({0})")]
+    IoReadDir(io::Error),
 
     #[error("Failed to delete userspace `vhci_hcd` state from the file-system ({0})")]
     IoRemove(io::Error),
@@ -43,35 +49,53 @@ pub struct ConnectionRecord {
     pub port: u16,
     /// Remote USB bus ID that this vhci_hcd port is connected to
     pub bus_id: String,
+    /// Name given to the attachment by the user (see [`is_valid_alias`])
+    pub alias: Option<String>,
+}
+
+/// Checks whether the name may be used as the alias of a connection record.
+/// Aliases are stored as an extra column after the fields of the original
+/// format (which the original CLI ignores), so they may not contain
+/// whitespace.
+pub fn is_valid_alias(alias: &str) -> bool {
+    !alias.is_empty() && !alias.contains(char::is_whitespace)
 }
 
 /// Records the remote connection in a file like `/var/run/vhci_hcd/portX` to be
 /// referenced by other processes. This is done in the same way as the original
 /// implementation to keep backwards compatability.
 pub fn save_connection_record(rh_port: u32, record: ConnectionRecord) -> Result<(), FsStateError> {
-    /* ==== mkdir with permissions ==== */
+    save_connection_record_in(Path::new(VHCI_STATE_PATH), rh_port, record)
+}
 
-    let state_path = Path::new(VHCI_STATE_PATH);
+pub(crate) fn save_connection_record_in(
+    state_path: &Path,
+    rh_port: u32,
+    record: ConnectionRecord,
+) -> Result<(), FsStateError> {
+    /* ==== mkdir with permissions ==== */
 
     match fs::create_dir(state_path) {
         Ok(_) => {}
-        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
-            if !state_path
-                .metadata()
-                .map_err(FsStateError::IoWrite)?
-                .is_dir()
-            {
-                return Err(FsStateError::NotADirectory);
-            }
-        }
-        Err(e) => return Err(FsStateError::IoWrite(e)),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(map_write_error(state_path, e)),
+    }
+
+    let metadata = state_path.metadata().map_err(FsStateError::IoWrite)?;
+
+    if !metadata.is_dir() {
+        return Err(FsStateError::NotADirectory);
     }
 
-    let mut perms = fs::metadata(state_path)
-        .map_err(FsStateError::IoWrite)?
-        .permissions();
-    perms.set_mode(0o700);
-    fs::set_permissions(state_path, perms).map_err(FsStateError::IoWrite)?;
+    // only the owner (or root) may change the mode, so skip it if there is
+    // nothing to change. this way a directory which was already set up
+    // correctly by someone else doesn't cause a confusing failure here.
+    if metadata.permissions().mode() & 0o777 != 0o700 {
+        let mut perms = metadata.permissions();
+        perms.set_mode(0o700);
+
+        fs::set_permissions(state_path, perms).map_err(|e| map_write_error(state_path, e))?;
+    }
 
     /* ==== create the port file ==== */
 
@@ -81,20 +105,52 @@ pub fn save_connection_record(rh_port: u32, record: ConnectionRecord) -> Result<
         .write(true)
         .create(true)
         .truncate(true)
-        .mode(0o700)
+        .mode(0o600)
         .open(port_path)
-        .map_err(FsStateError::IoWrite)?;
+        .map_err(|e| map_write_error(state_path, e))?;
 
-    file.write_all(format!("{} {} {}\n", record.host, record.port, record.bus_id).as_bytes())
+    let line = match &record.alias {
+        Some(alias) => format!(
+            "{} {} {} {alias}\n",
+            record.host, record.port, record.bus_id
+        ),
+        None => format!("{} {} {}\n", record.host, record.port, record.bus_id),
+    };
+
+    file.write_all(line.as_bytes())
         .map_err(FsStateError::IoWrite)?;
 
     Ok(())
 }
 
+/// Reports a lack of permissions to set up the state dir as such, since it
+/// needs different advice than other I/O errors
+fn map_write_error(state_path: &Path, e: io::Error) -> FsStateError {
+    if e.kind() == ErrorKind::PermissionDenied {
+        state_dir_permissions_error(state_path)
+    } else {
+        FsStateError::IoWrite(e)
+    }
+}
+
+fn state_dir_permissions_error(state_path: &Path) -> FsStateError {
+    FsStateError::StateDirPermissions {
+        // if the directory itself is inaccessible, report it as owned by root
+        // since that is what it almost always is in practice
+        owner_uid: state_path.metadata().map(|m| m.uid()).unwrap_or(0),
+        euid: nix::unistd::geteuid().as_raw(),
+    }
+}
+
 pub fn read_connection_record(rh_port: u16) -> Result<ConnectionRecord, FsStateError> {
-    use std::fs;
+    read_connection_record_in(Path::new(VHCI_STATE_PATH), rh_port)
+}
 
-    let port_path = Path::new(VHCI_STATE_PATH).join(format!("port{rh_port}"));
+fn read_connection_record_in(
+    state_path: &Path,
+    rh_port: u16,
+) -> Result<ConnectionRecord, FsStateError> {
+    let port_path = state_path.join(format!("port{rh_port}"));
 
     let mut file = fs::OpenOptions::new()
         .read(true)
@@ -105,40 +161,261 @@ pub fn read_connection_record(rh_port: u16) -> Result<ConnectionRecord, FsStateE
     file.read_to_string(&mut buf)
         .map_err(|e| FsStateError::IoRead(e, rh_port))?;
 
-    let (remote_host, port, remote_bus_id) = sscanf::sscanf!(buf.trim(), "{str} {u16} {str}")
-        .map_err(|_| FsStateError::Parsing(rh_port))?;
+    let buf = buf.trim();
+
+    // records without an alias are in the format of the original CLI
+    let (remote_host, port, remote_bus_id, alias) =
+        match sscanf::sscanf!(buf, "{str} {u16} {str} {str}") {
+            Ok((host, port, bus_id, alias)) => (host, port, bus_id, Some(alias)),
+            Err(_) => {
+                let (host, port, bus_id) = sscanf::sscanf!(buf, "{str} {u16} {str}")
+                    .map_err(|_| FsStateError::Parsing(rh_port))?;
+
+                (host, port, bus_id, None)
+            }
+        };
 
     Ok(ConnectionRecord {
         host: remote_host.into(),
         port,
         bus_id: remote_bus_id.into(),
+        alias: alias.map(Into::into),
     })
 }
 
+/// Reads all the connection records in the state directory, ordered by port.
+/// Files which are not named like a port record or fail to be read or parsed
+/// are skipped with a warning. If the directory doesn't exist (nothing was
+/// ever recorded), no records are returned.
+pub fn read_all_records() -> Result<Vec<(u16, ConnectionRecord)>, FsStateError> {
+    read_all_records_in(Path::new(VHCI_STATE_PATH))
+}
+
+pub(crate) fn read_all_records_in(
+    state_path: &Path,
+) -> Result<Vec<(u16, ConnectionRecord)>, FsStateError> {
+    let entries = match fs::read_dir(state_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(FsStateError::IoReadDir(e)),
+    };
+
+    let mut records = Vec::new();
+
+    for entry in entries {
+        let entry = entry.map_err(FsStateError::IoReadDir)?;
+        let name = entry.file_name();
+
+        let Some(rh_port) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("port"))
+            .and_then(|n| n.parse::<u16>().ok())
+        else {
+            tracing::warn!(
+                "ignoring unexpected file {name:?} in `{}`",
+                state_path.display()
+            );
+            continue;
+        };
+
+        match read_connection_record_in(state_path, rh_port) {
+            Ok(record) => records.push((rh_port, record)),
+            Err(e) => tracing::warn!("skipping connection record of port {rh_port}: {e}"),
+        }
+    }
+
+    records.sort_by_key(|(rh_port, _)| *rh_port);
+
+    Ok(records)
+}
+
 /// Deletes a previously saved connection record from the file system state
 /// directory. If no other entries exist in the `/var/run/vhci_hcd` directory,
 /// it is also removed.
 pub fn delete_connection_record(port: u16, remove_state_dir: bool) -> Result<(), FsStateError> {
-    let state_path = Path::new(VHCI_STATE_PATH);
+    delete_connection_record_in(Path::new(VHCI_STATE_PATH), port, remove_state_dir)
+}
+
+fn delete_connection_record_in(
+    state_path: &Path,
+    port: u16,
+    remove_state_dir: bool,
+) -> Result<(), FsStateError> {
     let port_path = state_path.join(format!("port{port}"));
 
-    if let Err(e) = fs::remove_file(port_path) {
-        if e.kind() != io::ErrorKind::NotFound {
+    if let Err(e) = fs::remove_file(port_path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        return Err(FsStateError::IoRemove(e));
+    }
+
+    if remove_state_dir && let Err(e) = fs::remove_dir(state_path) {
+        if e.kind() != io::ErrorKind::DirectoryNotEmpty && e.kind() != io::ErrorKind::NotFound {
             return Err(FsStateError::IoRemove(e));
         }
+
+        if e.kind() == io::ErrorKind::NotFound {
+            tracing::warn!("vhci_hcd state directory not found")
+        }
     }
 
-    if remove_state_dir {
-        if let Err(e) = fs::remove_dir(state_path) {
-            if e.kind() != io::ErrorKind::DirectoryNotEmpty && e.kind() != io::ErrorKind::NotFound {
-                return Err(FsStateError::IoRemove(e));
-            }
+    Ok(())
+}
 
-            if e.kind() == io::ErrorKind::NotFound {
-                tracing::warn!("vhci_hcd state directory not found")
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    fn record(alias: Option<&str>) -> ConnectionRecord {
+        ConnectionRecord {
+            host: "192.168.1.20".into(),
+            port: 3240,
+            bus_id: "1-1".into(),
+            alias: alias.map(Into::into),
         }
     }
 
-    Ok(())
+    #[test]
+    fn saves_and_reads_records() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+
+        save_connection_record_in(&state_path, 3, record(None)).unwrap();
+        save_connection_record_in(&state_path, 4, record(Some("camera"))).unwrap();
+
+        let mode = state_path.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        let first = read_connection_record_in(&state_path, 3).unwrap();
+        assert_eq!(
+            (first.host.as_str(), first.port, first.bus_id.as_str()),
+            ("192.168.1.20", 3240, "1-1")
+        );
+        assert_eq!(first.alias, None);
+
+        let second = read_connection_record_in(&state_path, 4).unwrap();
+        assert_eq!(second.alias.as_deref(), Some("camera"));
+    }
+
+    #[test]
+    fn maps_permission_errors_to_the_state_dir_hint() {
+        let dir = TempDir::new();
+
+        let error = map_write_error(dir.path(), ErrorKind::PermissionDenied.into());
+
+        assert!(
+            matches!(
+                error,
+                FsStateError::StateDirPermissions { owner_uid, euid }
+                    if owner_uid == nix::unistd::geteuid().as_raw()
+                        && euid == nix::unistd::geteuid().as_raw()
+            ),
+            "{error:?}"
+        );
+        assert!(matches!(
+            map_write_error(dir.path(), ErrorKind::StorageFull.into()),
+            FsStateError::IoWrite(_)
+        ));
+    }
+
+    #[test]
+    fn reports_a_state_dir_which_is_not_writable() {
+        // root can write to the directory regardless of its mode
+        if nix::unistd::geteuid().is_root() {
+            return;
+        }
+
+        let dir = TempDir::new();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o500)).unwrap();
+        let state_path = dir.path().join("vhci_hcd");
+
+        let result = save_connection_record_in(&state_path, 3, record(None));
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+
+        assert!(
+            matches!(result, Err(FsStateError::StateDirPermissions { .. })),
+            "{result:?}"
+        );
+        assert!(!state_path.exists());
+    }
+
+    #[test]
+    fn reports_a_state_path_which_is_not_a_directory() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+        fs::write(&state_path, "").unwrap();
+
+        let result = save_connection_record_in(&state_path, 3, record(None));
+
+        assert!(
+            matches!(result, Err(FsStateError::NotADirectory)),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn reads_all_records_ordered_by_port() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+
+        save_connection_record_in(&state_path, 12, record(Some("camera"))).unwrap();
+        save_connection_record_in(&state_path, 0, record(None)).unwrap();
+        save_connection_record_in(&state_path, 3, record(None)).unwrap();
+
+        let records = read_all_records_in(&state_path).unwrap();
+
+        assert_eq!(
+            records.iter().map(|(port, _)| *port).collect::<Vec<_>>(),
+            [0, 3, 12]
+        );
+        assert_eq!(records[2].1.alias.as_deref(), Some("camera"));
+    }
+
+    #[test]
+    fn skips_files_which_are_not_records() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+
+        save_connection_record_in(&state_path, 1, record(None)).unwrap();
+        fs::write(state_path.join("port2"), "not a record\n").unwrap();
+        fs::write(state_path.join("portX"), "").unwrap();
+        fs::write(state_path.join("lock"), "").unwrap();
+
+        let records = read_all_records_in(&state_path).unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, 1);
+    }
+
+    #[test]
+    fn reads_no_records_without_a_state_dir() {
+        let dir = TempDir::new();
+
+        assert!(
+            read_all_records_in(&dir.path().join("vhci_hcd"))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn deletes_records_and_the_empty_state_dir() {
+        let dir = TempDir::new();
+        let state_path = dir.path().join("vhci_hcd");
+
+        save_connection_record_in(&state_path, 1, record(None)).unwrap();
+        save_connection_record_in(&state_path, 2, record(None)).unwrap();
+
+        delete_connection_record_in(&state_path, 1, true).unwrap();
+        assert!(!state_path.join("port1").exists());
+        assert!(state_path.exists());
+
+        delete_connection_record_in(&state_path, 2, true).unwrap();
+        assert!(!state_path.exists());
+
+        // deleting a record which is already gone is not an error
+        delete_connection_record_in(&state_path, 2, true).unwrap();
+    }
 }
@@ -0,0 +1,433 @@
+//! Driver for the Linux kernel usbip-vudc module
+//! (/drivers/usb/usbip/vudc_main.c)
+//!
+//! usbip-vudc provides virtual USB device controllers (UDCs) which USB gadgets
+//! can be bound to (e.g. through configfs). A gadget bound to one of them can
+//! then be exported to a remote host, which is what `usbipd --device` does.
+
+use std::{
+    fs, io,
+    os::fd::RawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    UsbDeviceInfo, UsbSpeed,
+    drivers::{SysfsIoError, read_sysfs_attribute, write_sysfs_attribute},
+};
+
+/// Name of the usbip-vudc platform driver (and prefix of its devices)
+pub const VUDC_DRIVER_NAME: &str = "usbip-vudc";
+/// Subsystem of the UDCs registered by usbip-vudc
+pub const UDC_SUBSYSTEM: &str = "udc";
+
+const PLATFORM_DEVICES_PATH: &str = "/sys/bus/platform/devices";
+/// Directory of the gadgets created through configfs
+const GADGET_CONFIGFS_PATH: &str = "/sys/kernel/config/usb_gadget";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to create udev context ({0})")]
+    CreatingUdevContext(io::Error),
+    #[error("Failed to create udev enumerator ({0})")]
+    CreatingUdevEnumerator(io::Error),
+    #[error("Failed to enumerate UDCs with udev ({0})")]
+    EnumeratingUdevDevices(io::Error),
+
+    #[error(
+        "No `{VUDC_DRIVER_NAME}` UDCs found. Is the kernel module `{VUDC_DRIVER_NAME}` loaded?"
+    )]
+    NoVirtualUdcs,
+    #[error("Could not access the platform device of UDC `{0}`")]
+    PlatformDeviceNotFound(String),
+    #[error("Invalid `usbip_status` value {value:?} of UDC `{udc}`")]
+    InvalidStatus { udc: String, value: String },
+    #[error("Invalid device descriptor of the gadget bound to UDC `{0}`")]
+    InvalidDeviceDescriptor(String),
+
+    #[error(transparent)]
+    Sysfs(#[from] SysfsIoError),
+}
+
+/// Status of a virtual UDC as reported by its `usbip_status` attribute (the
+/// same `SDEV_ST_*` values as usbip-host devices)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u32)]
+pub enum VudcStatus {
+    /// SDEV_ST_AVAILABLE
+    Available = 1,
+    /// SDEV_ST_USED
+    Used,
+    /// SDEV_ST_ERROR
+    Error,
+}
+
+/// The standard USB device descriptor of a gadget, as exposed by the `dev_desc`
+/// attribute of the UDC it is bound to
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct DeviceDescriptor {
+    pub bcd_usb: u16,
+    pub b_device_class: u8,
+    pub b_device_sub_class: u8,
+    pub b_device_protocol: u8,
+    pub b_max_packet_size0: u8,
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub b_num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    const LEN: usize = 18;
+    const TYPE: u8 = 0x01;
+
+    /// Parses the raw descriptor (multi-byte fields are little endian)
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; Self::LEN] = bytes.try_into().ok()?;
+
+        if bytes[0] as usize != Self::LEN || bytes[1] != Self::TYPE {
+            return None;
+        }
+
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+
+        Some(Self {
+            bcd_usb: u16_at(2),
+            b_device_class: bytes[4],
+            b_device_sub_class: bytes[5],
+            b_device_protocol: bytes[6],
+            b_max_packet_size0: bytes[7],
+            id_vendor: u16_at(8),
+            id_product: u16_at(10),
+            bcd_device: u16_at(12),
+            b_num_configurations: bytes[17],
+        })
+    }
+}
+
+/// A single UDC provided by usbip-vudc
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct VirtualUdc {
+    /// Name of the UDC and its platform device (e.g. `usbip-vudc.0`)
+    pub name: String,
+    /// sysfs path of the platform device
+    pub sys_path: PathBuf,
+    pub status: VudcStatus,
+    /// Speed the bound gadget is running at (unknown if no gadget is bound)
+    pub current_speed: UsbSpeed,
+    /// Descriptor of the gadget bound to the UDC, if any
+    pub device_descriptor: Option<DeviceDescriptor>,
+}
+
+impl VirtualUdc {
+    pub fn has_gadget(&self) -> bool {
+        self.device_descriptor.is_some()
+    }
+
+    /// The device information sent to clients when the bound gadget is
+    /// exported. Gadgets are not enumerated locally, so there is no bus or
+    /// device number, configuration or interfaces (the same is reported by
+    /// the original usbipd in device mode).
+    pub fn usb_device_info(&self) -> Option<UsbDeviceInfo> {
+        let desc = self.device_descriptor.as_ref()?;
+
+        Some(UsbDeviceInfo {
+            sys_path: self.sys_path.to_string_lossy().into(),
+            bus_id: self.name.as_str().into(),
+            bus_num: 0,
+            dev_num: 0,
+            speed: self.current_speed,
+            id_vendor: desc.id_vendor,
+            id_product: desc.id_product,
+            bcd_device: desc.bcd_device,
+            b_device_class: desc.b_device_class,
+            b_device_sub_class: desc.b_device_sub_class,
+            b_device_protocol: desc.b_device_protocol,
+            b_configuration_value: None,
+            b_num_configurations: desc.b_num_configurations,
+            b_num_interfaces: 0,
+        })
+    }
+}
+
+/// USB/IP Virtual USB Device Controller (VUDC) Driver
+#[derive(Debug)]
+pub struct Vudc {
+    udcs: Vec<VirtualUdc>,
+}
+
+impl Vudc {
+    /// Enumerates all UDCs provided by usbip-vudc. Fails if there are none
+    /// (most likely because the module is not loaded).
+    pub fn open() -> Result<Self, Error> {
+        let mut this = Self { udcs: Vec::new() };
+
+        this.refresh()?;
+
+        if this.udcs.is_empty() {
+            return Err(Error::NoVirtualUdcs);
+        }
+
+        Ok(this)
+    }
+
+    /// Reads the state of all UDCs again (e.g. after a gadget was bound)
+    pub fn refresh(&mut self) -> Result<(), Error> {
+        let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
+
+        let mut enumerator =
+            udev::Enumerator::with_udev(context).map_err(Error::CreatingUdevEnumerator)?;
+
+        enumerator
+            .match_subsystem(UDC_SUBSYSTEM)
+            .map_err(Error::CreatingUdevEnumerator)?;
+
+        let mut udcs = Vec::new();
+
+        for dev in enumerator
+            .scan_devices()
+            .map_err(Error::EnumeratingUdevDevices)?
+        {
+            let name = dev.sysname().to_string_lossy();
+
+            if !is_vudc_device_name(&name) {
+                continue;
+            }
+
+            udcs.push(read_virtual_udc(&dev)?);
+        }
+
+        tracing::debug!("found {} virtual UDCs", udcs.len());
+
+        self.udcs = udcs;
+
+        Ok(())
+    }
+
+    pub fn udcs(&self) -> &[VirtualUdc] {
+        &self.udcs
+    }
+
+    pub fn find(&self, name: &str) -> Option<&VirtualUdc> {
+        self.udcs.iter().find(|u| u.name == name)
+    }
+
+    /// Reads the `usbip_status` attribute of the UDC
+    pub fn device_status(name: &str) -> Result<VudcStatus, Error> {
+        read_status(&Path::new(PLATFORM_DEVICES_PATH).join(name), name)
+    }
+
+    /// Hands the connected socket off to usbip-vudc by writing it to the UDC's
+    /// `usbip_sockfd` attribute, after which the kernel services the gadget
+    /// over the connection
+    pub fn export_device(name: &str, socket_fd: RawFd) -> Result<(), SysfsIoError> {
+        let path = Path::new(PLATFORM_DEVICES_PATH)
+            .join(name)
+            .join("usbip_sockfd");
+
+        write_sysfs_attribute(&path, format!("{socket_fd}\n"))
+    }
+
+    /// Binds a gadget created through configfs (the name of its directory in
+    /// `/sys/kernel/config/usb_gadget`) to the UDC
+    pub fn bind_gadget(gadget: &str, udc: &str) -> Result<(), SysfsIoError> {
+        write_sysfs_attribute(&gadget_udc_path(gadget), udc)
+    }
+
+    /// Unbinds a gadget created through configfs from its UDC
+    pub fn unbind_gadget(gadget: &str) -> Result<(), SysfsIoError> {
+        write_sysfs_attribute(&gadget_udc_path(gadget), "\n")
+    }
+}
+
+fn gadget_udc_path(gadget: &str) -> PathBuf {
+    Path::new(GADGET_CONFIGFS_PATH).join(gadget).join("UDC")
+}
+
+/// Whether the given sysfs name is one of the `usbip-vudc.N` devices
+fn is_vudc_device_name(name: &str) -> bool {
+    name.strip_prefix(VUDC_DRIVER_NAME)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+fn read_virtual_udc(udc: &udev::Device) -> Result<VirtualUdc, Error> {
+    let name = udc.sysname().to_string_lossy().into_owned();
+
+    // the usbip attributes live on the platform device the UDC belongs to
+    let platform = udc
+        .parent()
+        .ok_or_else(|| Error::PlatformDeviceNotFound(name.clone()))?;
+    let sys_path = platform.syspath().to_path_buf();
+
+    let status = read_status(&sys_path, &name)?;
+
+    let current_speed = udc
+        .attribute_value("current_speed")
+        .and_then(|s| s.to_str())
+        .and_then(parse_udc_speed)
+        .unwrap_or(UsbSpeed::Unknown);
+
+    let device_descriptor = read_device_descriptor(&sys_path, &name)?;
+
+    Ok(VirtualUdc {
+        name,
+        sys_path,
+        status,
+        current_speed,
+        device_descriptor,
+    })
+}
+
+/// Reads the descriptor of the gadget bound to the UDC, if there is one
+fn read_device_descriptor(
+    platform_path: &Path,
+    name: &str,
+) -> Result<Option<DeviceDescriptor>, Error> {
+    // reading the descriptor fails while no gadget is bound
+    match fs::read(platform_path.join("dev_desc")) {
+        Ok(bytes) => DeviceDescriptor::parse(&bytes)
+            .map(Some)
+            .ok_or_else(|| Error::InvalidDeviceDescriptor(name.into())),
+        Err(e) => {
+            tracing::debug!("no gadget bound to `{name}` ({e})");
+            Ok(None)
+        }
+    }
+}
+
+fn read_status(platform_path: &Path, name: &str) -> Result<VudcStatus, Error> {
+    let value = read_sysfs_attribute(&platform_path.join("usbip_status"))?;
+
+    value
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .and_then(|v| VudcStatus::try_from(v).ok())
+        .ok_or_else(|| Error::InvalidStatus {
+            udc: name.into(),
+            value,
+        })
+}
+
+/// Parses the `current_speed` attribute of a UDC, which uses the kernel's
+/// `usb_speed_string` names instead of the Mbps values of USB devices
+fn parse_udc_speed(s: &str) -> Option<UsbSpeed> {
+    Some(match s.trim() {
+        "UNKNOWN" => UsbSpeed::Unknown,
+        "low-speed" => UsbSpeed::Low,
+        "full-speed" => UsbSpeed::Full,
+        "high-speed" => UsbSpeed::High,
+        "wireless" => UsbSpeed::Wireless,
+        "super-speed" => UsbSpeed::Super,
+        "super-speed-plus" => UsbSpeed::SuperPlus,
+        _ => return None,
+    })
+}
+
+// Enumerating the UDCs goes through udev, which can't be pointed at a mock
+// sysfs tree, so only the attributes of the platform device are covered here
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    /// Descriptor of a gadget (1d6b:0104, USB 2.0, composite)
+    const DEV_DESC: [u8; 18] = [
+        0x12, 0x01, 0x00, 0x02, 0xef, 0x02, 0x01, 0x40, 0x6b, 0x1d, 0x04, 0x01, 0x00, 0x01, 0x01,
+        0x02, 0x03, 0x01,
+    ];
+
+    #[test]
+    fn parses_device_descriptor() {
+        assert_eq!(
+            DeviceDescriptor::parse(&DEV_DESC),
+            Some(DeviceDescriptor {
+                bcd_usb: 0x0200,
+                b_device_class: 0xef,
+                b_device_sub_class: 0x02,
+                b_device_protocol: 0x01,
+                b_max_packet_size0: 0x40,
+                id_vendor: 0x1d6b,
+                id_product: 0x0104,
+                bcd_device: 0x0100,
+                b_num_configurations: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_device_descriptors() {
+        assert_eq!(DeviceDescriptor::parse(&DEV_DESC[..17]), None);
+
+        let mut wrong_length = DEV_DESC;
+        wrong_length[0] = 0x09;
+        assert_eq!(DeviceDescriptor::parse(&wrong_length), None);
+
+        // a configuration descriptor
+        let mut wrong_type = DEV_DESC;
+        wrong_type[1] = 0x02;
+        assert_eq!(DeviceDescriptor::parse(&wrong_type), None);
+    }
+
+    #[test]
+    fn reads_attributes_of_mock_platform_device() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("usbip_status"), "2\n").unwrap();
+        fs::write(dir.path().join("dev_desc"), DEV_DESC).unwrap();
+
+        assert_eq!(
+            read_status(dir.path(), "usbip-vudc.0").unwrap(),
+            VudcStatus::Used
+        );
+        assert_eq!(
+            read_device_descriptor(dir.path(), "usbip-vudc.0")
+                .unwrap()
+                .map(|d| (d.id_vendor, d.id_product)),
+            Some((0x1d6b, 0x0104))
+        );
+    }
+
+    #[test]
+    fn missing_descriptor_means_no_gadget() {
+        let dir = TempDir::new();
+
+        assert_eq!(
+            read_device_descriptor(dir.path(), "usbip-vudc.0").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_attributes() {
+        let dir = TempDir::new();
+        fs::write(dir.path().join("usbip_status"), "7\n").unwrap();
+        fs::write(dir.path().join("dev_desc"), [0; 4]).unwrap();
+
+        assert!(matches!(
+            read_status(dir.path(), "usbip-vudc.0"),
+            Err(Error::InvalidStatus { .. })
+        ));
+        assert!(matches!(
+            read_device_descriptor(dir.path(), "usbip-vudc.0"),
+            Err(Error::InvalidDeviceDescriptor(_))
+        ));
+    }
+
+    #[test]
+    fn parses_udc_speeds() {
+        assert_eq!(parse_udc_speed("high-speed\n"), Some(UsbSpeed::High));
+        assert_eq!(
+            parse_udc_speed("super-speed-plus"),
+            Some(UsbSpeed::SuperPlus)
+        );
+        assert_eq!(parse_udc_speed("480"), None);
+    }
+
+    #[test]
+    fn recognizes_vudc_device_names() {
+        assert!(is_vudc_device_name("usbip-vudc.0"));
+        assert!(!is_vudc_device_name("usbip-vudc"));
+        assert!(!is_vudc_device_name("dummy_udc.0"));
+    }
+}
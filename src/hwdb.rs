@@ -1,109 +1,475 @@
-//! Helper functions for performing udev hwdb queries
+//! Lookup of USB vendor, product and class names. Which database the names
+//! are looked up in can be changed at runtime with [`set_id_source`]; by
+//! default it is the udev hwdb if the `runtime-hwdb` feature is enabled and the
+//! baked database if only `baked-hwdb` is.
 
-pub(crate) fn get_device_display_strings(
-    #[cfg(feature = "runtime-hwdb")] hwdb: &udev::Hwdb,
-    vendor_id: u16,
-    product_id: u16,
-) -> (Option<String>, Option<String>) {
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use crate::proto::UsbInterfaceInfo;
+
+/// Where USB vendor, product and class names are looked up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdSource {
+    /// The udev hwdb of the system (requires the `runtime-hwdb` feature)
+    Runtime,
+    /// The database compiled into the crate (requires the `baked-hwdb`
+    /// feature)
+    Baked,
+    /// A database in the format of `usb.ids` (e.g. `/usr/share/hwdata/usb.ids`)
+    File(PathBuf),
+    /// Don't look up any names
+    None,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdSourceError {
+    #[error("Unknown ID source `{0}` (expected `runtime`, `baked`, `file:PATH` or `none`)")]
+    Unknown(String),
+    #[error(
+        "The `{0}` ID source is not available since the crate was built without the `{0}-hwdb` feature"
+    )]
+    NotCompiledIn(&'static str),
+    #[error("Failed to read USB ID database `{}` ({error})", path.display())]
+    ReadingFile { path: PathBuf, error: io::Error },
+    #[error("Failed to open the udev hwdb ({0})")]
+    OpeningHwdb(io::Error),
+}
+
+impl FromStr for IdSource {
+    type Err = IdSourceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "runtime" => Ok(Self::Runtime),
+            "baked" => Ok(Self::Baked),
+            "none" => Ok(Self::None),
+            s => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(Self::File(path.into())),
+                _ => Err(IdSourceError::Unknown(s.into())),
+            },
+        }
+    }
+}
+
+impl fmt::Display for IdSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Runtime => write!(f, "runtime"),
+            Self::Baked => write!(f, "baked"),
+            Self::File(path) => write!(f, "file:{}", path.display()),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum ActiveSource {
     #[cfg(feature = "runtime-hwdb")]
-    let (vendor, product) = {
-        // TODO: add an option to fall back to baked hwdb?
+    Runtime,
+    #[cfg(feature = "baked-hwdb")]
+    Baked,
+    File(PathBuf, Arc<IdDatabase>),
+    None,
+}
+
+impl ActiveSource {
+    /// Checks that the source is available, reading it if it is a file
+    fn load(source: &IdSource) -> Result<Self, IdSourceError> {
+        Ok(match source {
+            #[cfg(feature = "runtime-hwdb")]
+            IdSource::Runtime => Self::Runtime,
+            #[cfg(not(feature = "runtime-hwdb"))]
+            IdSource::Runtime => return Err(IdSourceError::NotCompiledIn("runtime")),
+            #[cfg(feature = "baked-hwdb")]
+            IdSource::Baked => Self::Baked,
+            #[cfg(not(feature = "baked-hwdb"))]
+            IdSource::Baked => return Err(IdSourceError::NotCompiledIn("baked")),
+            IdSource::File(path) => {
+                let text = fs::read(path).map_err(|error| IdSourceError::ReadingFile {
+                    path: path.clone(),
+                    error,
+                })?;
+
+                Self::File(
+                    path.clone(),
+                    Arc::new(IdDatabase::parse(&String::from_utf8_lossy(&text))),
+                )
+            }
+            IdSource::None => Self::None,
+        })
+    }
+}
+
+#[cfg(feature = "runtime-hwdb")]
+const DEFAULT_SOURCE: ActiveSource = ActiveSource::Runtime;
+#[cfg(all(feature = "baked-hwdb", not(feature = "runtime-hwdb")))]
+const DEFAULT_SOURCE: ActiveSource = ActiveSource::Baked;
+#[cfg(not(any(feature = "runtime-hwdb", feature = "baked-hwdb")))]
+const DEFAULT_SOURCE: ActiveSource = ActiveSource::None;
+
+static ACTIVE_SOURCE: RwLock<ActiveSource> = RwLock::new(DEFAULT_SOURCE);
+
+/// Changes where names are looked up for the rest of the process. Files are
+/// read (and checked) right away. Operations which take an `id_source` option
+/// (like [`ListOptions`](crate::client::list::ListOptions)) can override this
+/// for a single call.
+pub fn set_id_source(source: &IdSource) -> Result<(), IdSourceError> {
+    let active = ActiveSource::load(source)?;
+
+    *ACTIVE_SOURCE
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = active;
+
+    Ok(())
+}
+
+/// Where names are currently looked up (see [`set_id_source`])
+pub fn id_source() -> IdSource {
+    match &*ACTIVE_SOURCE.read().unwrap_or_else(PoisonError::into_inner) {
+        #[cfg(feature = "runtime-hwdb")]
+        ActiveSource::Runtime => IdSource::Runtime,
+        #[cfg(feature = "baked-hwdb")]
+        ActiveSource::Baked => IdSource::Baked,
+        ActiveSource::File(path, _) => IdSource::File(path.clone()),
+        ActiveSource::None => IdSource::None,
+    }
+}
+
+/// Names parsed from a `usb.ids` file
+#[derive(Debug, Default)]
+pub(crate) struct IdDatabase {
+    vendors: HashMap<u16, NamedEntry<u16>>,
+    classes: HashMap<u8, NamedEntry<u8, NamedEntry<u8>>>,
+}
+
+/// A name along with the names of its children (like the products of a
+/// vendor)
+#[derive(Debug)]
+struct NamedEntry<K, C = String> {
+    name: String,
+    children: HashMap<K, C>,
+}
+
+impl<K, C> NamedEntry<K, C> {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl IdDatabase {
+    /// Parses the vendor and class sections of a `usb.ids` file. Entries are
+    /// indented with tabs below their parent, e.g.:
+    ///
+    /// ```text
+    /// 046d  Logitech, Inc.
+    /// \tc52b  Unifying Receiver
+    /// C 03  Human Interface Device
+    /// \t01  Boot Interface Subclass
+    /// \t\t01  Keyboard
+    /// ```
+    ///
+    /// The other sections and any lines which can't be understood are
+    /// skipped.
+    fn parse(text: &str) -> Self {
+        enum Section {
+            Vendor(u16),
+            Class(u8),
+            SubClass(u8, u8),
+            Other,
+        }
+
+        let mut db = Self::default();
+        let mut section = Section::Other;
+
+        for line in text.lines() {
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
 
-        let results: Vec<_> = hwdb
-            .query(format!("usb:v{vendor_id:04X}p{product_id:04X}*"))
-            .collect();
+            let depth = line.bytes().take_while(|&b| b == b'\t').count();
+            let Some((id, name)) = line[depth..].split_once(char::is_whitespace) else {
+                continue;
+            };
+            let name = name.trim().to_owned();
 
-        let mut vendor = results
-            .iter()
-            .find(|e| e.name().to_string_lossy() == "ID_VENDOR_FROM_DATABASE")
-            .map(|e| e.value().to_string_lossy().to_string());
-        let mut product = results
-            .iter()
-            .find(|e| e.name().to_string_lossy() == "ID_MODEL_FROM_DATABASE")
-            .map(|e| e.value().to_string_lossy().to_string());
+            let parse_id = |id: &str, digits| {
+                (id.len() == digits)
+                    .then(|| u16::from_str_radix(id, 16).ok())
+                    .flatten()
+            };
 
-        (vendor, product)
-    };
+            match (depth, &section) {
+                (0, _) if id == "C" => {
+                    section = match name.split_once(char::is_whitespace) {
+                        Some((class, name)) if let Some(class) = parse_id(class, 2) => {
+                            let class = class as u8;
+                            db.classes
+                                .insert(class, NamedEntry::new(name.trim().into()));
 
+                            Section::Class(class)
+                        }
+                        _ => Section::Other,
+                    };
+                }
+                (0, _) => {
+                    section = match parse_id(id, 4) {
+                        Some(vendor) => {
+                            db.vendors.insert(vendor, NamedEntry::new(name));
+
+                            Section::Vendor(vendor)
+                        }
+                        None => Section::Other,
+                    };
+                }
+                (1, &Section::Vendor(vendor)) => {
+                    if let Some(product) = parse_id(id, 4)
+                        && let Some(entry) = db.vendors.get_mut(&vendor)
+                    {
+                        entry.children.insert(product, name);
+                    }
+                }
+                (1, &(Section::Class(class) | Section::SubClass(class, _))) => {
+                    if let Some(sub_class) = parse_id(id, 2)
+                        && let Some(entry) = db.classes.get_mut(&class)
+                    {
+                        entry
+                            .children
+                            .insert(sub_class as u8, NamedEntry::new(name));
+                        section = Section::SubClass(class, sub_class as u8);
+                    }
+                }
+                (2, &Section::SubClass(class, sub_class)) => {
+                    if let Some(protocol) = parse_id(id, 2)
+                        && let Some(entry) = db.classes.get_mut(&class)
+                        && let Some(entry) = entry.children.get_mut(&sub_class)
+                    {
+                        entry.children.insert(protocol as u8, name);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        db
+    }
+
+    fn device_names(&self, vendor_id: u16, product_id: u16) -> (Option<String>, Option<String>) {
+        let Some(vendor) = self.vendors.get(&vendor_id) else {
+            return (None, None);
+        };
+
+        (
+            Some(vendor.name.clone()),
+            vendor.children.get(&product_id).cloned(),
+        )
+    }
+
+    fn class_names(
+        &self,
+        class: u8,
+        sub_class: u8,
+        protocol: u8,
+    ) -> (Option<String>, Option<String>, Option<String>) {
+        let Some(class) = self.classes.get(&class) else {
+            return (None, None, None);
+        };
+        let Some(sub_class) = class.children.get(&sub_class) else {
+            return (Some(class.name.clone()), None, None);
+        };
+
+        (
+            Some(class.name.clone()),
+            Some(sub_class.name.clone()),
+            sub_class.children.get(&protocol).cloned(),
+        )
+    }
+}
+
+/// Looks up names in a single source for the duration of an operation. The
+/// udev hwdb is only opened when it is the source, so the other sources also
+/// work on hosts without one.
+pub(crate) enum NameResolver {
+    #[cfg(feature = "runtime-hwdb")]
+    Runtime(udev::Hwdb),
     #[cfg(feature = "baked-hwdb")]
-    let (vendor, product) = {
-        let mut vendor = None;
-        let mut product = None;
+    Baked,
+    File(Arc<IdDatabase>),
+    None,
+}
+
+impl NameResolver {
+    /// Resolves names using `source`, or the process wide source (see
+    /// [`set_id_source`]) if it is `None`. A file given as `source` is read
+    /// on every call.
+    pub(crate) fn new(source: Option<&IdSource>) -> Result<Self, IdSourceError> {
+        let active = match source {
+            Some(source) => ActiveSource::load(source)?,
+            None => ACTIVE_SOURCE
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        };
+
+        Ok(match active {
+            #[cfg(feature = "runtime-hwdb")]
+            ActiveSource::Runtime => {
+                Self::Runtime(udev::Hwdb::new().map_err(IdSourceError::OpeningHwdb)?)
+            }
+            #[cfg(feature = "baked-hwdb")]
+            ActiveSource::Baked => Self::Baked,
+            ActiveSource::File(_, db) => Self::File(db),
+            ActiveSource::None => Self::None,
+        })
+    }
+
+    pub(crate) fn device_display_strings(
+        &self,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> (Option<String>, Option<String>) {
+        match self {
+            #[cfg(feature = "runtime-hwdb")]
+            Self::Runtime(hwdb) => {
+                let results: Vec<_> = hwdb
+                    .query(format!("usb:v{vendor_id:04X}p{product_id:04X}*"))
+                    .collect();
 
-        for v in usb_ids::Vendors::iter() {
-            if v.id() == vendor_id {
-                vendor = Some(v.name().to_string());
+                let vendor = results
+                    .iter()
+                    .find(|e| e.name().to_string_lossy() == "ID_VENDOR_FROM_DATABASE")
+                    .map(|e| e.value().to_string_lossy().to_string());
+                let product = results
+                    .iter()
+                    .find(|e| e.name().to_string_lossy() == "ID_MODEL_FROM_DATABASE")
+                    .map(|e| e.value().to_string_lossy().to_string());
+
+                (vendor, product)
+            }
+            #[cfg(feature = "baked-hwdb")]
+            Self::Baked => {
+                let mut vendor = None;
+                let mut product = None;
+
+                for v in usb_ids::Vendors::iter() {
+                    if v.id() == vendor_id {
+                        vendor = Some(v.name().to_string());
+
+                        for d in v.devices() {
+                            if d.id() == product_id {
+                                product = Some(d.name().to_string());
+                                break;
+                            }
+                        }
 
-                for d in v.devices() {
-                    if d.id() == product_id {
-                        product = Some(d.name().to_string());
                         break;
                     }
                 }
 
-                break;
+                (vendor, product)
             }
+            Self::File(db) => db.device_names(vendor_id, product_id),
+            Self::None => (None, None),
         }
+    }
 
-        (vendor, product)
-    };
+    /// Resolves the class display strings of a device. Devices with a class of 0
+    /// define their class at the interface level (which lsusb reports as "Defined
+    /// at Interface level"), so for those the class of the first interface is used
+    /// instead. Falls back to the device level class if no interfaces are known.
+    pub(crate) fn device_class_display_strings(
+        &self,
+        class: u8,
+        sub_class: u8,
+        protocol: u8,
+        interfaces: &[UsbInterfaceInfo],
+    ) -> (Option<String>, Option<String>, Option<String>) {
+        if class == 0
+            && let Some(iface) = interfaces.first()
+        {
+            return self.interface_class_display_strings(
+                iface.b_interface_class,
+                iface.b_interface_sub_class,
+                iface.b_interface_protocol,
+            );
+        }
 
-    (vendor, product)
-}
+        self.class_display_strings(class, sub_class, protocol)
+    }
 
-pub(crate) fn get_class_display_strings(
-    #[cfg(feature = "runtime-hwdb")] hwdb: &udev::Hwdb,
-    class: u8,
-    sub_class: u8,
-    protocol: u8,
-) -> (Option<String>, Option<String>, Option<String>) {
-    #[cfg(feature = "runtime-hwdb")]
-    let (class, sub_class, protocol) = {
-        // TODO: investigate using interface level queries first and then
-        // falling back to device level if none are found. We should check what
-        // lsusb does here.
-
-        // TODO: add an option to fall back to baked hwdb
-
-        let results: Vec<_> = hwdb
-            .query(format!(
-                "usb:v*p*d*dc{class:02X}dsc{sub_class:02X}dp{protocol:02X}*"
-            ))
-            .collect();
-
-        let mut class = results
-            .iter()
-            .find(|e| e.name().to_string_lossy() == "ID_USB_CLASS_FROM_DATABASE")
-            .map(|e| e.value().to_string_lossy().to_string());
-        let mut sub_class = results
-            .iter()
-            .find(|e| e.name().to_string_lossy() == "ID_USB_SUBCLASS_FROM_DATABASE")
-            .map(|e| e.value().to_string_lossy().to_string());
-        let mut protocol = results
-            .iter()
-            .find(|e| e.name().to_string_lossy() == "ID_USB_PROTOCOL_FROM_DATABASE")
-            .map(|e| e.value().to_string_lossy().to_string());
-
-        (class, sub_class, protocol)
-    };
+    /// Resolves the class display strings of an interface. Interface and
+    /// device classes share the same tables, so this is the same lookup as for
+    /// a device class.
+    pub(crate) fn interface_class_display_strings(
+        &self,
+        class: u8,
+        sub_class: u8,
+        protocol: u8,
+    ) -> (Option<String>, Option<String>, Option<String>) {
+        self.class_display_strings(class, sub_class, protocol)
+    }
+
+    fn class_display_strings(
+        &self,
+        class: u8,
+        sub_class: u8,
+        protocol: u8,
+    ) -> (Option<String>, Option<String>, Option<String>) {
+        match self {
+            #[cfg(feature = "runtime-hwdb")]
+            Self::Runtime(hwdb) => {
+                // The class names of the hwdb (`20-usb-classes.hwdb`) are only
+                // keyed by the device class, even for interface classes
+                let results: Vec<_> = hwdb
+                    .query(format!(
+                        "usb:v*p*d*dc{class:02X}dsc{sub_class:02X}dp{protocol:02X}*"
+                    ))
+                    .collect();
+
+                let class = results
+                    .iter()
+                    .find(|e| e.name().to_string_lossy() == "ID_USB_CLASS_FROM_DATABASE")
+                    .map(|e| e.value().to_string_lossy().to_string());
+                let sub_class = results
+                    .iter()
+                    .find(|e| e.name().to_string_lossy() == "ID_USB_SUBCLASS_FROM_DATABASE")
+                    .map(|e| e.value().to_string_lossy().to_string());
+                let protocol = results
+                    .iter()
+                    .find(|e| e.name().to_string_lossy() == "ID_USB_PROTOCOL_FROM_DATABASE")
+                    .map(|e| e.value().to_string_lossy().to_string());
+
+                (class, sub_class, protocol)
+            }
+            #[cfg(feature = "baked-hwdb")]
+            Self::Baked => {
+                let mut class_display = None;
+                let mut sub_class_display = None;
+                let mut protocol_display = None;
+
+                for c in usb_ids::Classes::iter() {
+                    if c.id() == class {
+                        class_display = Some(c.name().to_string());
+
+                        for s in c.sub_classes() {
+                            if s.id() == sub_class {
+                                sub_class_display = Some(s.name().to_string());
+
+                                for p in s.protocols() {
+                                    if p.id() == protocol {
+                                        protocol_display = Some(p.name().to_string());
+                                        break;
+                                    }
+                                }
 
-    #[cfg(feature = "baked-hwdb")]
-    let (class, sub_class, protocol) = {
-        let mut class_display = None;
-        let mut sub_class_display = None;
-        let mut protocol_display = None;
-
-        for c in usb_ids::Classes::iter() {
-            if c.id() == class {
-                class_display = Some(c.name().to_string());
-
-                for s in c.sub_classes() {
-                    if s.id() == sub_class {
-                        sub_class_display = Some(s.name().to_string());
-
-                        for p in s.protocols() {
-                            if p.id() == protocol {
-                                protocol_display = Some(p.name().to_string());
                                 break;
                             }
                         }
@@ -112,12 +478,171 @@ pub(crate) fn get_class_display_strings(
                     }
                 }
 
-                break;
+                (class_display, sub_class_display, protocol_display)
             }
+            Self::File(db) => db.class_names(class, sub_class, protocol),
+            Self::None => (None, None, None),
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USB_IDS: &str = "\
+# comment
+046d  Logitech, Inc.
+\tc52b  Unifying Receiver
+\tc077  M105 Optical Mouse
+1d6b  Linux Foundation
+\t0002  2.0 root hub
+
+C 03  Human Interface Device
+\t01  Boot Interface Subclass
+\t\t01  Keyboard
+\t\t02  Mouse
+C 09  Hub
+\t00  Unused
+AT 0001  Some Audio Terminal
+\tnot a product
+";
+
+    #[test]
+    fn parses_vendors_and_products() {
+        let db = IdDatabase::parse(USB_IDS);
+
+        assert_eq!(
+            db.device_names(0x046d, 0xc52b),
+            (
+                Some("Logitech, Inc.".into()),
+                Some("Unifying Receiver".into())
+            )
+        );
+        assert_eq!(
+            db.device_names(0x1d6b, 0x0003),
+            (Some("Linux Foundation".into()), None)
+        );
+        assert_eq!(db.device_names(0xffff, 0x0001), (None, None));
+    }
+
+    #[test]
+    fn parses_classes_sub_classes_and_protocols() {
+        let db = IdDatabase::parse(USB_IDS);
+
+        assert_eq!(
+            db.class_names(0x03, 0x01, 0x02),
+            (
+                Some("Human Interface Device".into()),
+                Some("Boot Interface Subclass".into()),
+                Some("Mouse".into())
+            )
+        );
+        assert_eq!(
+            db.class_names(0x09, 0x00, 0x01),
+            (Some("Hub".into()), Some("Unused".into()), None)
+        );
+        assert_eq!(
+            db.class_names(0x09, 0x01, 0x00),
+            (Some("Hub".into()), None, None)
+        );
+        assert_eq!(db.class_names(0xfe, 0x00, 0x00), (None, None, None));
+    }
 
-        (class_display, sub_class_display, protocol_display)
-    };
+    #[test]
+    fn skips_other_sections() {
+        let db = IdDatabase::parse(USB_IDS);
+
+        assert_eq!(db.vendors.len(), 2);
+        assert_eq!(db.classes.len(), 2);
+    }
+
+    #[test]
+    fn parses_id_sources() {
+        assert_eq!("runtime".parse::<IdSource>().unwrap(), IdSource::Runtime);
+        assert_eq!("none".parse::<IdSource>().unwrap(), IdSource::None);
+        assert_eq!(
+            "file:/usr/share/hwdata/usb.ids"
+                .parse::<IdSource>()
+                .unwrap(),
+            IdSource::File("/usr/share/hwdata/usb.ids".into())
+        );
+        assert!(matches!(
+            "file:".parse::<IdSource>(),
+            Err(IdSourceError::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn resolver_uses_the_given_source() {
+        let dir = crate::util::TempDir::new();
+        let path = dir.path().join("usb.ids");
+        fs::write(&path, USB_IDS).unwrap();
+
+        let names = NameResolver::new(Some(&IdSource::File(path))).unwrap();
+
+        assert_eq!(
+            names.device_display_strings(0x046d, 0xc077),
+            (
+                Some("Logitech, Inc.".into()),
+                Some("M105 Optical Mouse".into())
+            )
+        );
+
+        let names = NameResolver::new(Some(&IdSource::None)).unwrap();
+        assert_eq!(names.device_display_strings(0x046d, 0xc077), (None, None));
+    }
+
+    #[test]
+    fn class_0_devices_are_named_by_their_first_interface() {
+        let names = NameResolver::File(Arc::new(IdDatabase::parse(USB_IDS)));
+        let interfaces = [
+            UsbInterfaceInfo::new(0x03, 0x01, 0x01),
+            UsbInterfaceInfo::new(0x03, 0x01, 0x02),
+        ];
+
+        assert_eq!(
+            names.device_class_display_strings(0x00, 0x00, 0x00, &interfaces),
+            (
+                Some("Human Interface Device".into()),
+                Some("Boot Interface Subclass".into()),
+                Some("Keyboard".into())
+            )
+        );
+        assert_eq!(
+            names.device_class_display_strings(0x00, 0x00, 0x00, &[]),
+            (None, None, None)
+        );
+        assert_eq!(
+            names.device_class_display_strings(0x09, 0x00, 0x00, &interfaces),
+            (Some("Hub".into()), Some("Unused".into()), None)
+        );
+    }
+
+    #[test]
+    fn interface_classes_use_the_device_class_tables() {
+        let names = NameResolver::File(Arc::new(IdDatabase::parse(USB_IDS)));
+
+        assert_eq!(
+            names.interface_class_display_strings(0x03, 0x01, 0x02),
+            names.class_display_strings(0x03, 0x01, 0x02)
+        );
+        assert_eq!(
+            names.interface_class_display_strings(0x03, 0x01, 0x02).2,
+            Some("Mouse".into())
+        );
+    }
+
+    #[cfg(feature = "baked-hwdb")]
+    #[test]
+    fn baked_names_class_0_devices_by_their_interface() {
+        let (class, _, _) = NameResolver::Baked.device_class_display_strings(
+            0x00,
+            0x00,
+            0x00,
+            &[UsbInterfaceInfo::new(0x08, 0x06, 0x50)],
+        );
 
-    (class, sub_class, protocol)
+        assert_eq!(class.as_deref(), Some("Mass Storage"));
+    }
 }
@@ -1,26 +1,58 @@
-#![forbid(unsafe_code)]
+// the only exception is reading socket options which have no safe wrapper
+#![deny(unsafe_code)]
 #![allow(incomplete_features)]
 #![feature(generic_const_exprs)]
 
+use core::cmp::Ordering;
+
 use compact_str::{CompactString, ToCompactString};
 
-use crate::proto::RawUsbDeviceInfo;
+use crate::proto::{
+    RawUsbDeviceInfo,
+    char_buf::CharBuf,
+    class::{USB_CLASS_HUB, USB_CLASS_PER_INTERFACE},
+};
+#[cfg(target_os = "linux")]
+use crate::{drivers::vhci::HubSpeed, hwdb::NameResolver, proto::UsbInterfaceInfo};
 
+// The client, server and driver modules are built on top of udev, sysfs and
+// the usbip kernel modules, so they are only available on Linux. The protocol
+// and networking layers only need a unix platform, which allows them to be used
+// to talk to usbip servers from other platforms as well. Run
+// `scripts/check-portable.sh` to make sure they still build without the
+// Linux-only modules.
+#[cfg(target_os = "linux")]
 pub mod client;
+#[cfg(target_os = "linux")]
 pub mod drivers;
-mod hwdb;
+#[cfg(target_os = "linux")]
+pub mod hwdb;
 pub mod net;
 pub mod proto;
+#[cfg(target_os = "linux")]
 pub mod server;
+pub mod url;
 mod util;
 
-#[cfg(not(target_os = "linux"))]
-compile_error!("the usbip-rs crate only supports linux for the time being");
+#[cfg(not(unix))]
+compile_error!(
+    "the usbip-rs crate requires a unix platform (and Linux for anything but the protocol layer)"
+);
 
+/// The speed a USB device is operating at.
+///
+/// The kernel exposes this in two different encodings which should not be
+/// confused with each other. The sysfs `speed` attribute of a USB device is a
+/// human readable string in Mbps (e.g. `"480"`), which is what the
+/// [`FromStr`](core::str::FromStr) impl parses. The `speed` column of the
+/// `vhci_hcd` status attribute, the `speed` field of the usbip PDUs and the
+/// `attach` attribute of `vhci_hcd` all use the numeric value of the kernel's
+/// `enum usb_device_speed`, which is what [`UsbSpeed::kernel_code`] returns.
 #[derive(
     Debug,
     Clone,
     Copy,
+    Default,
     PartialEq,
     Eq,
     PartialOrd,
@@ -33,6 +65,7 @@ compile_error!("the usbip-rs crate only supports linux for the time being");
 #[repr(u32)]
 pub enum UsbSpeed {
     /// Enumerating
+    #[default]
     #[strum(serialize = "unknown")]
     Unknown,
     /// USB 1.1
@@ -58,7 +91,36 @@ pub enum UsbSpeed {
     SuperPlus,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+impl UsbSpeed {
+    /// Returns the numeric value of the kernel's `enum usb_device_speed` for
+    /// this speed (as used on the wire and by `vhci_hcd`)
+    pub fn kernel_code(self) -> u32 {
+        self as u32
+    }
+
+    /// Converts a numeric `enum usb_device_speed` value reported by the kernel
+    /// or a remote server into a [`UsbSpeed`]
+    pub fn from_kernel_code(code: u32) -> Option<Self> {
+        Self::try_from(code).ok()
+    }
+
+    /// Returns the kind of `vhci_hcd` root hub a device of this speed has to be
+    /// attached to. USB 3.x devices need a SuperSpeed hub, everything else is
+    /// attached to a USB 2.0 hub.
+    #[cfg(target_os = "linux")]
+    pub fn required_hub_speed(self) -> HubSpeed {
+        match self {
+            Self::Super | Self::SuperPlus => HubSpeed::Super,
+            Self::Unknown | Self::Low | Self::Full | Self::High | Self::Wireless => HubSpeed::High,
+        }
+    }
+}
+
+/// Information about a USB device, as read from sysfs or received from a
+/// server. Devices can also be constructed by hand (e.g. by tools which display
+/// devices without a live one) from the [`Default`] value, which is an
+/// unconfigured device of unknown speed with every other field zeroed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
 pub struct UsbDeviceInfo {
     pub sys_path: String,
     pub bus_id: CompactString,
@@ -74,11 +136,149 @@ pub struct UsbDeviceInfo {
     pub b_device_class: u8,
     pub b_device_sub_class: u8,
     pub b_device_protocol: u8,
-    pub b_configuration_value: u8,
+    /// The active configuration of the device, or `None` if the device is not
+    /// configured (encoded as 0 in the PDUs, which is not a valid
+    /// configuration value)
+    pub b_configuration_value: Option<u8>,
     pub b_num_configurations: u8,
     pub b_num_interfaces: u8,
 }
 
+impl UsbDeviceInfo {
+    /// The `(bDeviceClass, bDeviceSubClass, bDeviceProtocol)` of the device
+    pub fn class_triple(&self) -> (u8, u8, u8) {
+        (
+            self.b_device_class,
+            self.b_device_sub_class,
+            self.b_device_protocol,
+        )
+    }
+
+    /// Whether the device leaves its class to be defined by each of its
+    /// interfaces (which lsusb shows as "Defined at Interface level")
+    pub fn class_defined_at_interface_level(&self) -> bool {
+        self.class_triple() == (USB_CLASS_PER_INTERFACE, 0, 0)
+    }
+
+    /// Whether the device is a hub, which can't be exported
+    pub fn is_hub(&self) -> bool {
+        self.b_device_class == USB_CLASS_HUB
+    }
+
+    /// The bus ID of one of the interfaces of the device's active
+    /// configuration (`<bus id>:<configuration>.<interface>`, the name of the
+    /// interface in sysfs)
+    pub fn interface_bus_id(&self, interface_number: u8) -> String {
+        format!(
+            "{}:{}.{}",
+            self.bus_id,
+            self.b_configuration_value.unwrap_or_default(),
+            interface_number
+        )
+    }
+}
+
+/// An interface of a USB device in a listing, local or remote, with the names
+/// of its class triple if they are known
+#[derive(Debug, serde::Serialize)]
+pub struct DeviceInterface {
+    /// Bus ID of the interface (e.g. `1-2:1.0`)
+    pub bus_id: String,
+
+    pub b_interface_class: u8,
+    pub b_interface_sub_class: u8,
+    pub b_interface_protocol: u8,
+
+    pub class: Option<String>,
+    pub sub_class: Option<String>,
+    pub protocol: Option<String>,
+}
+
+impl DeviceInterface {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new(names: &NameResolver, bus_id: String, iface: &UsbInterfaceInfo) -> Self {
+        let (class, sub_class, protocol) = names.interface_class_display_strings(
+            iface.b_interface_class,
+            iface.b_interface_sub_class,
+            iface.b_interface_protocol,
+        );
+
+        Self {
+            bus_id,
+            b_interface_class: iface.b_interface_class,
+            b_interface_sub_class: iface.b_interface_sub_class,
+            b_interface_protocol: iface.b_interface_protocol,
+            class,
+            sub_class,
+            protocol,
+        }
+    }
+}
+
+/// Compares bus IDs (or any other sysfs names) in natural order, so numbers
+/// are compared by their value instead of character by character (`1-2` comes
+/// before `1-10`)
+pub fn compare_bus_ids(a: &str, b: &str) -> Ordering {
+    let mut a = a.as_bytes();
+    let mut b = b.as_bytes();
+
+    // splits off the leading run of digits or non-digits
+    fn next_run<'a>(s: &mut &'a [u8]) -> Option<&'a [u8]> {
+        let first = s.first()?;
+        let len = s
+            .iter()
+            .position(|c| c.is_ascii_digit() != first.is_ascii_digit())
+            .unwrap_or(s.len());
+
+        let (run, rest) = s.split_at(len);
+        *s = rest;
+
+        Some(run)
+    }
+
+    loop {
+        let (run_a, run_b) = match (next_run(&mut a), next_run(&mut b)) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => (a, b),
+        };
+
+        let ordering = if run_a[0].is_ascii_digit() && run_b[0].is_ascii_digit() {
+            // compare numbers without parsing them so they can't overflow
+            let trim = |r: &[u8]| -> usize { r.iter().take_while(|c| **c == b'0').count() };
+            let (num_a, num_b) = (&run_a[trim(run_a)..], &run_b[trim(run_b)..]);
+
+            num_a.len().cmp(&num_b.len()).then_with(|| num_a.cmp(num_b))
+        } else {
+            run_a.cmp(run_b)
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// A bus ID which is ordered with [`compare_bus_ids`], for use in the sort
+/// keys of devices. Bus IDs which only differ in leading zeros (`1-01` and
+/// `1-1`) are ordered by their text, so the ordering stays consistent with
+/// equality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BusIdKey<'a>(pub &'a str);
+
+impl Ord for BusIdKey<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_bus_ids(self.0, other.0).then_with(|| self.0.cmp(other.0))
+    }
+}
+
+impl PartialOrd for BusIdKey<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("Failed to validate raw USB device info object")]
 pub struct UsbDeviceInfoValidationError;
@@ -102,7 +302,7 @@ impl TryFrom<RawUsbDeviceInfo> for UsbDeviceInfo {
             .map_err(|_| UsbDeviceInfoValidationError)?
             .to_compact_string();
 
-        let speed = UsbSpeed::try_from(value.speed).map_err(|_| UsbDeviceInfoValidationError)?;
+        let speed = UsbSpeed::from_kernel_code(value.speed).ok_or(UsbDeviceInfoValidationError)?;
 
         Ok(Self {
             sys_path,
@@ -116,9 +316,163 @@ impl TryFrom<RawUsbDeviceInfo> for UsbDeviceInfo {
             b_device_class: value.b_device_class,
             b_device_sub_class: value.b_device_sub_class,
             b_device_protocol: value.b_device_protocol,
-            b_configuration_value: value.b_configuration_value,
+            b_configuration_value: match value.b_configuration_value {
+                0 => None,
+                v => Some(v),
+            },
             b_num_configurations: value.b_num_configurations,
             b_num_interfaces: value.b_num_interfaces,
         })
     }
 }
+
+impl From<&UsbDeviceInfo> for RawUsbDeviceInfo {
+    fn from(value: &UsbDeviceInfo) -> Self {
+        Self {
+            path: CharBuf::new_truncated(&value.sys_path),
+            bus_id: CharBuf::new_truncated(&value.bus_id),
+            bus_num: value.bus_num,
+            dev_num: value.dev_num,
+            speed: value.speed.kernel_code(),
+            id_vendor: value.id_vendor,
+            id_product: value.id_product,
+            bcd_device: value.bcd_device,
+            b_device_class: value.b_device_class,
+            b_device_sub_class: value.b_device_sub_class,
+            b_device_protocol: value.b_device_protocol,
+            b_configuration_value: value.b_configuration_value.unwrap_or(0),
+            b_num_configurations: value.b_num_configurations,
+            b_num_interfaces: value.b_num_interfaces,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_with_class(b_device_class: u8) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            sys_path: "/sys/devices/platform/dummy_hcd.0/usb1/1-1".into(),
+            bus_id: "1-1".into(),
+            bus_num: 1,
+            dev_num: 2,
+            speed: UsbSpeed::High,
+            id_vendor: 0x1d6b,
+            id_product: 0x0104,
+            bcd_device: 0x0100,
+            b_device_class,
+            b_device_sub_class: 0,
+            b_device_protocol: 0,
+            b_configuration_value: Some(1),
+            b_num_configurations: 1,
+            b_num_interfaces: 1,
+        }
+    }
+
+    #[test]
+    fn hub_check_matches_class_9() {
+        assert_eq!(proto::class::USB_CLASS_HUB, 9);
+        assert!(device_with_class(proto::class::USB_CLASS_HUB).is_hub());
+        assert!(device_with_class(0x09).is_hub());
+
+        assert!(!device_with_class(proto::class::USB_CLASS_PER_INTERFACE).is_hub());
+        assert!(!device_with_class(proto::class::USB_CLASS_MASS_STORAGE).is_hub());
+        assert!(!device_with_class(0x90).is_hub());
+    }
+
+    #[test]
+    fn constructs_and_serializes_a_device_by_hand() {
+        let device = UsbDeviceInfo {
+            bus_id: "1-1".into(),
+            speed: UsbSpeed::High,
+            id_vendor: 0x0781,
+            id_product: 0x5567,
+            ..Default::default()
+        };
+
+        assert_eq!(device.b_configuration_value, None);
+        assert_eq!(UsbDeviceInfo::default().speed, UsbSpeed::Unknown);
+
+        let json = serde_json::to_value(&device).unwrap();
+
+        assert_eq!(json["bus_id"], "1-1");
+        assert_eq!(json["speed"], "high");
+        assert_eq!(json["id_vendor"], 0x0781);
+        assert_eq!(json["id_product"], 0x5567);
+        assert!(json["b_configuration_value"].is_null());
+    }
+
+    #[test]
+    fn class_is_defined_at_interface_level_only_for_class_0() {
+        let per_interface = device_with_class(USB_CLASS_PER_INTERFACE);
+        assert_eq!(per_interface.class_triple(), (0, 0, 0));
+        assert!(per_interface.class_defined_at_interface_level());
+
+        let hub = UsbDeviceInfo {
+            b_device_protocol: 1,
+            ..device_with_class(USB_CLASS_HUB)
+        };
+        assert_eq!(hub.class_triple(), (9, 0, 1));
+        assert!(!hub.class_defined_at_interface_level());
+
+        // class 0 with a subclass or protocol is not a plain per-interface
+        // device
+        let odd = UsbDeviceInfo {
+            b_device_sub_class: 2,
+            ..device_with_class(USB_CLASS_PER_INTERFACE)
+        };
+        assert!(!odd.class_defined_at_interface_level());
+    }
+
+    #[test]
+    fn encodes_unconfigured_devices_as_configuration_0() {
+        let unconfigured = UsbDeviceInfo {
+            b_configuration_value: None,
+            ..device_with_class(0)
+        };
+
+        let raw = RawUsbDeviceInfo::from(&unconfigured);
+        assert_eq!(raw.b_configuration_value, 0);
+
+        let decoded = UsbDeviceInfo::try_from(raw).unwrap();
+        assert_eq!(decoded.b_configuration_value, None);
+    }
+
+    #[test]
+    fn keeps_the_active_configuration_in_the_pdu() {
+        let raw = RawUsbDeviceInfo::from(&device_with_class(0));
+        assert_eq!(raw.b_configuration_value, 1);
+
+        let decoded = UsbDeviceInfo::try_from(raw).unwrap();
+        assert_eq!(decoded.b_configuration_value, Some(1));
+    }
+
+    #[test]
+    fn compares_bus_ids_in_natural_order() {
+        let mut bus_ids = ["2-1", "1-10", "1-2", "1-1", "1-1.10", "1-1.2"];
+        bus_ids.sort_by(|a, b| compare_bus_ids(a, b));
+
+        assert_eq!(bus_ids, ["1-1", "1-1.2", "1-1.10", "1-2", "1-10", "2-1"]);
+    }
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert_eq!(compare_bus_ids("1-01", "1-1"), Ordering::Equal);
+        assert_eq!(compare_bus_ids("1-9", "1-010"), Ordering::Less);
+        // longer than any integer type
+        assert_eq!(
+            compare_bus_ids("1-99999999999999999999999", "1-100000000000000000000000"),
+            Ordering::Less
+        );
+        assert_eq!(compare_bus_ids("usb2", "usb10"), Ordering::Less);
+    }
+
+    #[test]
+    fn bus_id_keys_stay_consistent_with_equality() {
+        assert_eq!(BusIdKey("1-2").cmp(&BusIdKey("1-10")), Ordering::Less);
+        // equal in natural order, but different bus IDs
+        assert_ne!(BusIdKey("1-01").cmp(&BusIdKey("1-1")), Ordering::Equal);
+        assert_eq!(BusIdKey("1-1").cmp(&BusIdKey("1-1")), Ordering::Equal);
+    }
+}
@@ -1,55 +1,440 @@
+//! Networking primitives for the usbip protocol.
+//!
+//! NOTE: usbip traffic is not encrypted. The protocol reserves an operation for
+//! negotiating an encryption key ([`OperationKind::EncryptionKey`]), but
+//! neither the original implementation nor the kernel modules (which take over
+//! the connection after an import) support it. If the connection has to cross
+//! an untrusted network, tunnel it through SSH, WireGuard or similar instead.
+
 use std::{
-    io::{self, Read, Write},
-    net::{IpAddr, SocketAddr, ToSocketAddrs},
-    os::fd::{AsRawFd, RawFd},
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    os::fd::{AsRawFd, OwnedFd, RawFd},
     time::Duration,
 };
 
 use endian_codec::{DecodeBE, EncodeBE};
-use socket2::{Domain, Socket, Type};
+use socket2::{Domain, Socket, TcpKeepalive, Type};
 
-use crate::proto::{
-    Direction, OperationError, OperationHeader, OperationKind, OperationStatus, USBIP_VERSION,
+use crate::{
+    proto::{
+        Direction, OperationError, OperationHeader, OperationKind, OperationStatus, USBIP_VERSION,
+    },
+    util::retry_interrupted,
 };
 
+/// An I/O error on a usbip connection, classified by what went wrong so that
+/// callers can react to (or retry) specific failures without inspecting the
+/// underlying [`io::Error`]
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    #[error(transparent)]
+    ConnectionRefused(io::Error),
+    /// The host (or its network) could not be reached
+    #[error(transparent)]
+    HostUnreachable(io::Error),
+    /// The connection was closed or reset by the remote host while it was in
+    /// use
+    #[error(transparent)]
+    ConnectionReset(io::Error),
+    /// Connecting or waiting for data timed out
+    #[error(transparent)]
+    Timeout(io::Error),
+    #[error(transparent)]
+    OtherIo(io::Error),
+}
+
+impl NetworkError {
+    /// The underlying I/O error
+    pub fn io_error(&self) -> &io::Error {
+        match self {
+            Self::ConnectionRefused(e)
+            | Self::HostUnreachable(e)
+            | Self::ConnectionReset(e)
+            | Self::Timeout(e)
+            | Self::OtherIo(e) => e,
+        }
+    }
+}
+
+impl From<io::Error> for NetworkError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::ConnectionRefused => Self::ConnectionRefused(e),
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => Self::HostUnreachable(e),
+            // reading a reply which the server stopped sending halfway through
+            // fails with `UnexpectedEof`
+            ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof => Self::ConnectionReset(e),
+            // read timeouts on sockets are reported as `WouldBlock`
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Self::Timeout(e),
+            _ => Self::OtherIo(e),
+        }
+    }
+}
+
+/// Parameters used to configure TCP keepalive on a [`UsbIpSocket`]. The OS
+/// defaults on Linux wait for hours before probing an idle connection, which is
+/// far too long to notice that a server holding an imported device has died.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveParams {
+    /// How long the connection must be idle before probes are sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Number of unanswered probes before the connection is considered dead
+    pub count: u32,
+}
+
+impl Default for KeepaliveParams {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(10),
+            count: 3,
+        }
+    }
+}
+
+/// Options used when establishing a connection to a usbip server
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// TCP keepalive parameters, or `None` to disable keepalive. Only worth it
+    /// for long-lived connections (like the ones handed off to `vhci_hcd` when
+    /// attaching).
+    pub keepalive: Option<KeepaliveParams>,
+    /// How long to wait for the TCP connection to be established
+    pub connect_timeout: Duration,
+    /// Size (in bytes) of the socket's send and receive buffers, `None` keeps
+    /// the OS defaults. Larger buffers help the throughput of bulk transfers
+    /// (e.g. mass storage) over links with a high latency. They are set
+    /// before connecting, since the TCP window scale is negotiated then.
+    pub buffer_size: Option<usize>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            keepalive: Some(KeepaliveParams::default()),
+            connect_timeout: Duration::from_secs(4),
+            buffer_size: None,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Options for connections which only exchange a single request and reply
+    /// (like listing devices), which don't need keepalive
+    pub fn short_lived() -> Self {
+        Self {
+            keepalive: None,
+            ..Default::default()
+        }
+    }
+}
+
+/// A snapshot of some of the TCP parameters of a connection, as reported by
+/// [`UsbIpSocket::tcp_info`]
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpInfo {
+    /// Maximum segment size of the connection
+    pub mss: u32,
+    /// Whether Nagle's algorithm is disabled
+    pub nodelay: bool,
+    /// The keepalive parameters in effect, or `None` if keepalive is disabled
+    pub keepalive: Option<KeepaliveParams>,
+    /// How long transmitted data may remain unacknowledged before the
+    /// connection is dropped (`None` uses the kernel's retransmission limits)
+    pub user_timeout: Option<Duration>,
+    /// Smoothed round trip time estimated by the kernel
+    pub rtt: Duration,
+    /// Variation of the round trip time
+    pub rttvar: Duration,
+}
+
 /// A TCP socket wrapper which is shared by the server and the client and
 /// provides helper methods for common USB IP network operations
 pub struct UsbIpSocket {
     inner: Socket,
 }
 
+fn check_raw_payload_len(len: usize) -> io::Result<()> {
+    if len > UsbIpSocket::MAX_RAW_PAYLOAD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "payload of {len} bytes exceeds the maximum of {} bytes",
+                UsbIpSocket::MAX_RAW_PAYLOAD_LEN
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
 impl UsbIpSocket {
     pub const DEFAULT_PORT: u16 = 3240;
 
+    /// Upper bound on the length of the payloads sent and received with
+    /// [`send_bytes`](Self::send_bytes) and
+    /// [`recv_exact_bytes`](Self::recv_exact_bytes), so a length field read
+    /// from a misbehaving peer can't make us allocate arbitrary amounts of
+    /// memory
+    pub const MAX_RAW_PAYLOAD_LEN: usize = 1 << 20;
+
     pub fn connect_host_and_port(host: &str, port: u16) -> io::Result<Self> {
-        let addr = if let Ok(ip) = host.parse::<IpAddr>() {
-            SocketAddr::new(ip, port)
-        } else {
-            // TODO: try all addresses (original impl does this)
+        Self::connect_host_and_port_with_options(host, port, &ConnectOptions::default())
+    }
 
-            (host, port).to_socket_addrs()?.next().ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::Other, "No addresses found")
-            })?
-        };
+    pub fn connect_host_and_port_with_options(
+        host: &str,
+        port: u16,
+        options: &ConnectOptions,
+    ) -> io::Result<Self> {
+        // TODO: try all addresses (original impl does this)
+        let addr = Self::resolve_host_and_port(host, port)?[0];
 
-        Self::connect(addr)
+        Self::connect_with_options(addr, options)
+    }
+
+    /// Resolves the host to all of its addresses in the order returned by the
+    /// resolver. Never returns an empty list.
+    pub fn resolve_host_and_port(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let addrs: Vec<_> = (host, port).to_socket_addrs()?.collect();
+
+        if addrs.is_empty() {
+            return Err(std::io::Error::other("No addresses found"));
+        }
+
+        Ok(addrs)
     }
 
     pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Self::connect_with_options(addr, &ConnectOptions::default())
+    }
+
+    pub fn connect_with_options(addr: SocketAddr, options: &ConnectOptions) -> io::Result<Self> {
         let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
 
-        socket.set_tcp_nodelay(true)?;
-        socket.set_keepalive(true)?;
+        let this = Self { inner: socket };
+
+        this.configure(options)?;
+
+        this.inner
+            .connect_timeout(&addr.into(), options.connect_timeout)?;
+
+        Ok(this)
+    }
+
+    /// Adopts an already connected TCP stream, e.g. the local end of an
+    /// in-process SSH port forward. The same socket options as for
+    /// [`UsbIpSocket::connect_with_options`] are applied to it.
+    pub fn from_std(stream: TcpStream, options: &ConnectOptions) -> io::Result<Self> {
+        Self::from_fd(stream.into(), options)
+    }
+
+    /// Same as [`UsbIpSocket::from_std`], but adopts a raw file descriptor.
+    /// The fd must refer to a connected TCP socket.
+    pub fn from_fd(fd: OwnedFd, options: &ConnectOptions) -> io::Result<Self> {
+        let this = Self {
+            inner: Socket::from(fd),
+        };
 
-        socket.connect_timeout(&addr.into(), Duration::from_secs(4))?;
+        this.configure(options)?;
+
+        Ok(this)
+    }
+
+    fn configure(&self, options: &ConnectOptions) -> io::Result<()> {
+        self.inner.set_tcp_nodelay(true)?;
+
+        if let Some(size) = options.buffer_size {
+            self.set_send_buffer_size(size)?;
+            self.set_recv_buffer_size(size)?;
+        }
+
+        // adopted sockets may already have keepalive enabled
+        let Some(KeepaliveParams {
+            idle,
+            interval,
+            count,
+        }) = options.keepalive
+        else {
+            return self.inner.set_keepalive(false);
+        };
+
+        self.set_keepalive_params(idle, interval, count)
+    }
+
+    /// Enables TCP keepalive on the socket using the given parameters. Once the
+    /// socket is handed off to `vhci_hcd`, these control how quickly the kernel
+    /// notices a dead remote and moves the port into the error state.
+    pub fn set_keepalive_params(
+        &self,
+        idle: Duration,
+        interval: Duration,
+        count: u32,
+    ) -> io::Result<()> {
+        self.inner.set_keepalive(true)?;
+        self.inner.set_tcp_keepalive(
+            &TcpKeepalive::new()
+                .with_time(idle)
+                .with_interval(interval)
+                .with_retries(count),
+        )
+    }
+
+    /// Sets the size of the socket's send buffer (`SO_SNDBUF`). The kernel
+    /// doubles the value to leave room for bookkeeping and caps it at
+    /// `net.core.wmem_max`.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.inner.set_send_buffer_size(size)
+    }
+
+    /// Sets the size of the socket's receive buffer (`SO_RCVBUF`). The kernel
+    /// doubles the value to leave room for bookkeeping and caps it at
+    /// `net.core.rmem_max`.
+    pub fn set_recv_buffer_size(&self, size: usize) -> io::Result<()> {
+        self.inner.set_recv_buffer_size(size)
+    }
+
+    /// Sets the timeout of blocking reads on the socket (`None` blocks
+    /// forever). Reads which time out fail with [`io::ErrorKind::WouldBlock`].
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    /// Clears the read and write timeouts before the socket is handed off to
+    /// the kernel. The kernel honors the socket's receive timeout as well,
+    /// which would tear down the connection whenever the device is idle.
+    pub fn clear_timeouts_for_handoff(&self) -> io::Result<()> {
+        self.inner.set_read_timeout(None)?;
+        self.inner.set_write_timeout(None)
+    }
+
+    /// Shuts down one or both halves of the connection, so the peer sees a
+    /// clean EOF instead of waiting for more data until the socket is dropped
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Queries the TCP parameters of the connection, which is mostly useful
+    /// for diagnosing a connection that stalls or drops
+    #[cfg(target_os = "linux")]
+    pub fn tcp_info(&self) -> io::Result<TcpInfo> {
+        let keepalive = if self.inner.keepalive()? {
+            Some(KeepaliveParams {
+                idle: self.inner.tcp_keepalive_time()?,
+                interval: self.inner.tcp_keepalive_interval()?,
+                count: self.inner.tcp_keepalive_retries()?,
+            })
+        } else {
+            None
+        };
+
+        let info = self.raw_tcp_info()?;
+
+        Ok(TcpInfo {
+            mss: self.inner.tcp_mss()?,
+            nodelay: self.inner.tcp_nodelay()?,
+            keepalive,
+            user_timeout: self.inner.tcp_user_timeout()?,
+            rtt: Duration::from_micros(info.tcpi_rtt.into()),
+            rttvar: Duration::from_micros(info.tcpi_rttvar.into()),
+        })
+    }
+
+    /// Reads the `TCP_INFO` socket option, which neither socket2 nor nix
+    /// provide a wrapper for
+    #[cfg(target_os = "linux")]
+    #[allow(unsafe_code)]
+    fn raw_tcp_info(&self) -> io::Result<libc::tcp_info> {
+        // SAFETY: `tcp_info` is plain old data, so all zeroes is a valid value
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+        // SAFETY: the kernel writes at most `len` bytes to `info` and stores the
+        // number of bytes written in `len`
+        let result = unsafe {
+            libc::getsockopt(
+                self.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                (&raw mut info).cast(),
+                &mut len,
+            )
+        };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // older kernels fill in fewer fields, but all of them have the RTT
+        let required = std::mem::offset_of!(libc::tcp_info, tcpi_rttvar)
+            + std::mem::size_of_val(&info.tcpi_rttvar);
+
+        if (len as usize) < required {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("`TCP_INFO` is too short ({len} bytes)"),
+            ));
+        }
+
+        Ok(info)
+    }
+
+    /// Creates a listening socket bound to the given address (used by the
+    /// server to accept client connections)
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+
+        socket.set_reuse_address(true)?;
+        // without this, a socket bound to `::` also accepts IPv4 connections on
+        // most systems, which makes binding `0.0.0.0` on the same port fail
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
 
         Ok(Self { inner: socket })
     }
 
-    pub fn bind(_addr: SocketAddr) -> io::Result<Self> {
-        todo!()
+    /// The address the socket is bound to (e.g. to find the port picked by the
+    /// kernel after binding port 0)
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()?.as_socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "local address is not an IP address",
+            )
+        })
     }
 
+    /// Accepts a new connection on a socket created with [`UsbIpSocket::bind`]
+    pub fn accept(&self) -> io::Result<(Self, SocketAddr)> {
+        let (socket, addr) = retry_interrupted(|| self.inner.accept())?;
+
+        socket.set_tcp_nodelay(true)?;
+
+        let addr = addr.as_socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "peer address is not an IP address",
+            )
+        })?;
+
+        Ok((Self { inner: socket }, addr))
+    }
+
+    // `write_all` and `read_exact` retry interrupted system calls themselves
     #[inline]
     pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
         self.inner.write_all(data)
@@ -60,6 +445,32 @@ impl UsbIpSocket {
         self.inner.read_exact(data)
     }
 
+    /// Sends a dynamically sized payload, for data which can't be described by
+    /// an [`EncodeBE`] type. Framing the payload (e.g. sending its length
+    /// first) is up to the caller. Fails with [`io::ErrorKind::InvalidInput`]
+    /// if the payload is longer than [`Self::MAX_RAW_PAYLOAD_LEN`].
+    pub fn send_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        check_raw_payload_len(data.len())?;
+
+        self.send(data)
+    }
+
+    /// Receives exactly `len` bytes, the counterpart of
+    /// [`send_bytes`](Self::send_bytes). Fails with
+    /// [`io::ErrorKind::InvalidInput`] if `len` is larger than
+    /// [`Self::MAX_RAW_PAYLOAD_LEN`] and with
+    /// [`io::ErrorKind::UnexpectedEof`] if the connection is closed before
+    /// all bytes were received.
+    pub fn recv_exact_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        check_raw_payload_len(len)?;
+
+        let mut buffer = vec![0; len];
+
+        self.recv(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
     pub fn send_encoded<T: EncodeBE>(&mut self, data: T) -> io::Result<()>
     where
         [u8; T::PACKED_LEN]:,
@@ -85,7 +496,7 @@ impl UsbIpSocket {
     pub fn send_request_header(&mut self, kind: OperationKind) -> io::Result<()> {
         self.send_encoded(OperationHeader {
             version: USBIP_VERSION,
-            code: Direction::Request as u16 | kind as u16,
+            code: Direction::Request as u16 | kind.code(),
             status: OperationStatus::Ok as _,
         })
     }
@@ -97,13 +508,59 @@ impl UsbIpSocket {
     ) -> io::Result<()> {
         self.send_encoded(OperationHeader {
             version: USBIP_VERSION,
-            code: Direction::Reply as u16 | kind as u16,
+            code: Direction::Reply as u16 | kind.code(),
             status: status as _,
         })
     }
 
-    pub fn recv_request_header(&mut self) -> io::Result<OperationHeader> {
-        todo!()
+    /// Peeks at the start of the incoming data to check whether the peer is
+    /// speaking usbip, without consuming anything (the header can still be read
+    /// normally afterwards). Returns the protocol version from the header if it
+    /// looks like usbip, or `None` if it doesn't. Fails with
+    /// [`io::ErrorKind::UnexpectedEof`] if the peer closed the connection
+    /// without sending anything.
+    ///
+    /// The returned version is not necessarily [`USBIP_VERSION`], so that
+    /// callers can reject incompatible clients gracefully.
+    pub fn probe_protocol(&mut self) -> io::Result<Option<u16>> {
+        // socket2 only peeks into uninitialized buffers, which can't be read
+        // without unsafe code, so peek through a std handle to the same socket
+        let stream = TcpStream::from(self.inner.try_clone()?);
+
+        let mut buffer = [0; 2];
+
+        // this only fails to see the whole version field if the peer splits
+        // its very first segment, which no usbip implementation does
+        match retry_interrupted(|| stream.peek(&mut buffer))? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n if n < buffer.len() => return Ok(None),
+            _ => {}
+        }
+
+        let version = u16::from_be_bytes(buffer);
+
+        // all usbip versions so far (0x0100 - 0x0111) share the major version
+        Ok((version >> 8 == USBIP_VERSION >> 8).then_some(version))
+    }
+
+    pub fn recv_request_header(&mut self) -> io::Result<Result<OperationKind, OperationError>> {
+        let header = self.recv_encoded::<OperationHeader>()?;
+
+        if header.version != USBIP_VERSION {
+            return Ok(Err(OperationError::VersionMismatch));
+        }
+
+        if Direction::from_code(header.code) != Direction::Request {
+            return Ok(Err(OperationError::DirectionMismatch));
+        }
+
+        Ok(Ok(OperationKind::from_code(header.code)))
+    }
+
+    /// Checks whether the header belongs to an attempt at negotiating an
+    /// encryption key, which is not supported (see the module docs)
+    pub fn requests_encryption(header: &OperationHeader) -> bool {
+        OperationKind::from_code(header.code) == OperationKind::EncryptionKey
     }
 
     // TODO: this interface is weird. lets use a global error type instead.
@@ -121,10 +578,23 @@ impl UsbIpSocket {
             return Ok(Err(OperationError::DirectionMismatch));
         }
 
+        if kind != OperationKind::EncryptionKey && Self::requests_encryption(&header) {
+            tracing::warn!("server attempted to negotiate encryption (expected {kind:?})");
+
+            return Ok(Err(OperationError::EncryptionUnsupported));
+        }
+
         match OperationKind::from_code(header.code) {
-            Some(OperationKind::Unspecified) => {}
+            OperationKind::Unspecified => {}
+            OperationKind::Unknown(code) => {
+                tracing::warn!("received unknown operation 0x{code:02x} (expected {kind:?})");
+
+                return Ok(Err(OperationError::UnknownOperation(code)));
+            }
             k => {
-                if k != Some(kind) {
+                if k != kind {
+                    tracing::warn!("received reply for operation {k:?} (expected {kind:?})");
+
                     return Ok(Err(OperationError::InvalidData));
                 }
             }
@@ -148,3 +618,305 @@ impl AsRawFd for UsbIpSocket {
         self.inner.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(kind: ErrorKind) -> NetworkError {
+        io::Error::from(kind).into()
+    }
+
+    #[test]
+    fn classifies_io_errors_by_kind() {
+        assert!(matches!(
+            classify(ErrorKind::ConnectionRefused),
+            NetworkError::ConnectionRefused(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::NetworkUnreachable),
+            NetworkError::HostUnreachable(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::UnexpectedEof),
+            NetworkError::ConnectionReset(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::BrokenPipe),
+            NetworkError::ConnectionReset(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::WouldBlock),
+            NetworkError::Timeout(_)
+        ));
+        assert!(matches!(
+            classify(ErrorKind::PermissionDenied),
+            NetworkError::OtherIo(_)
+        ));
+    }
+
+    #[test]
+    fn keeps_the_underlying_error() {
+        let error = classify(ErrorKind::TimedOut);
+
+        assert_eq!(error.io_error().kind(), ErrorKind::TimedOut);
+    }
+
+    /// Connects to a listener on the loopback interface, returning the client
+    /// and server end of the connection
+    fn connected_pair(options: &ConnectOptions) -> (UsbIpSocket, UsbIpSocket) {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let client =
+            UsbIpSocket::connect_with_options(listener.local_addr().unwrap(), options).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        (client, server)
+    }
+
+    #[test]
+    fn applies_the_keepalive_params() {
+        let (client, _server) = connected_pair(&ConnectOptions {
+            keepalive: Some(KeepaliveParams {
+                idle: Duration::from_secs(17),
+                interval: Duration::from_secs(7),
+                count: 4,
+            }),
+            ..Default::default()
+        });
+
+        assert!(client.inner.keepalive().unwrap());
+        assert_eq!(
+            client.inner.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(17)
+        );
+        assert_eq!(
+            client.inner.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(7)
+        );
+        assert_eq!(client.inner.tcp_keepalive_retries().unwrap(), 4);
+    }
+
+    #[test]
+    fn enables_keepalive_by_default() {
+        let (client, _server) = connected_pair(&ConnectOptions::default());
+
+        assert!(client.inner.keepalive().unwrap());
+        assert_eq!(
+            client.inner.tcp_keepalive_time().unwrap(),
+            KeepaliveParams::default().idle
+        );
+    }
+
+    #[test]
+    fn short_lived_connections_leave_keepalive_off() {
+        let (client, _server) = connected_pair(&ConnectOptions::short_lived());
+
+        assert!(!client.inner.keepalive().unwrap());
+    }
+
+    #[test]
+    fn turns_keepalive_off_on_adopted_sockets() {
+        let (client, _server) = connected_pair(&ConnectOptions::default());
+        assert!(client.inner.keepalive().unwrap());
+
+        let adopted =
+            UsbIpSocket::from_std(client.inner.into(), &ConnectOptions::short_lived()).unwrap();
+
+        assert!(!adopted.inner.keepalive().unwrap());
+    }
+
+    #[test]
+    fn probing_leaves_the_header_to_be_read() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::short_lived());
+
+        client
+            .send_request_header(OperationKind::ListDevices)
+            .unwrap();
+
+        assert_eq!(server.probe_protocol().unwrap(), Some(USBIP_VERSION));
+        assert_eq!(
+            server.recv_request_header().unwrap().unwrap(),
+            OperationKind::ListDevices
+        );
+    }
+
+    #[test]
+    fn probing_rejects_other_protocols() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::short_lived());
+
+        client.send(b"GET / HTTP/1.1\r\n").unwrap();
+
+        assert_eq!(server.probe_protocol().unwrap(), None);
+    }
+
+    #[test]
+    fn peer_sees_eof_after_shutdown() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::short_lived());
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        server.send(&[1, 2]).unwrap();
+        server.shutdown(Shutdown::Write).unwrap();
+
+        let mut data = [0; 2];
+        client.recv(&mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+
+        // the server socket is still open, so this would time out without the
+        // shutdown
+        assert_eq!(
+            client.recv(&mut [0]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn applies_the_buffer_size_before_connecting() {
+        // small enough to stay below the default `net.core.[rw]mem_max`
+        const SIZE: usize = 64 * 1024;
+
+        let (client, _server) = connected_pair(&ConnectOptions {
+            buffer_size: Some(SIZE),
+            ..ConnectOptions::short_lived()
+        });
+
+        assert!(client.inner.send_buffer_size().unwrap() >= SIZE);
+        assert!(client.inner.recv_buffer_size().unwrap() >= SIZE);
+    }
+
+    #[test]
+    fn sets_the_buffer_sizes() {
+        const SIZE: usize = 48 * 1024;
+
+        let (_client, server) = connected_pair(&ConnectOptions::short_lived());
+
+        server.set_send_buffer_size(SIZE).unwrap();
+        server.set_recv_buffer_size(SIZE).unwrap();
+
+        assert!(server.inner.send_buffer_size().unwrap() >= SIZE);
+        assert!(server.inner.recv_buffer_size().unwrap() >= SIZE);
+    }
+
+    #[test]
+    fn clears_the_timeouts_before_the_handoff() {
+        let (_client, server) = connected_pair(&ConnectOptions::short_lived());
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        server
+            .inner
+            .set_write_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        server.clear_timeouts_for_handoff().unwrap();
+
+        assert_eq!(server.inner.read_timeout().unwrap(), None);
+        assert_eq!(server.inner.write_timeout().unwrap(), None);
+    }
+
+    #[test]
+    fn sends_and_receives_raw_payloads() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::short_lived());
+        let payload: Vec<u8> = (0..=255).cycle().take(3000).collect();
+
+        client.send_bytes(&payload).unwrap();
+
+        assert_eq!(server.recv_exact_bytes(payload.len()).unwrap(), payload);
+    }
+
+    #[test]
+    fn rejects_raw_payloads_over_the_limit() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::short_lived());
+
+        let too_long = vec![0; UsbIpSocket::MAX_RAW_PAYLOAD_LEN + 1];
+
+        assert_eq!(
+            client.send_bytes(&too_long).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            server
+                .recv_exact_bytes(UsbIpSocket::MAX_RAW_PAYLOAD_LEN + 1)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        // nothing was sent or received
+        client.send_bytes(&[7]).unwrap();
+        assert_eq!(server.recv_exact_bytes(1).unwrap(), [7]);
+    }
+
+    #[test]
+    fn rejects_replies_negotiating_encryption() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::short_lived());
+
+        client
+            .send_request_header(OperationKind::ListDevices)
+            .unwrap();
+        server.recv_request_header().unwrap().unwrap();
+        server
+            .send_response_header(OperationKind::EncryptionKey, OperationStatus::Ok)
+            .unwrap();
+
+        assert_eq!(
+            client
+                .recv_reply_header(OperationKind::ListDevices)
+                .unwrap(),
+            Err(OperationError::EncryptionUnsupported)
+        );
+    }
+
+    #[test]
+    fn detects_encryption_in_reply_headers() {
+        let header = |kind: OperationKind| OperationHeader {
+            version: USBIP_VERSION,
+            code: Direction::Reply as u16 | kind.code(),
+            status: 0,
+        };
+
+        assert!(UsbIpSocket::requests_encryption(&header(
+            OperationKind::EncryptionKey
+        )));
+        assert!(!UsbIpSocket::requests_encryption(&header(
+            OperationKind::Import
+        )));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reads_the_tcp_info_of_a_connection() {
+        let (mut client, mut server) = connected_pair(&ConnectOptions::default());
+
+        // gives the kernel a round trip to measure
+        client.send(&[1]).unwrap();
+        server.recv(&mut [0]).unwrap();
+        server.send(&[2]).unwrap();
+        client.recv(&mut [0]).unwrap();
+
+        let info = client.tcp_info().unwrap();
+
+        assert!(info.mss > 0);
+        assert!(info.nodelay);
+        assert_eq!(info.keepalive, Some(KeepaliveParams::default()));
+        assert!(info.rtt > Duration::ZERO);
+        // loopback round trips don't take anywhere near this long
+        assert!(info.rtt < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn ipv6_listener_leaves_the_ipv4_port_free() {
+        // skip on hosts without IPv6
+        let Ok(v6) = UsbIpSocket::bind("[::]:0".parse().unwrap()) else {
+            return;
+        };
+        let port = v6.local_addr().unwrap().port();
+
+        UsbIpSocket::bind(SocketAddr::new(
+            std::net::Ipv4Addr::UNSPECIFIED.into(),
+            port,
+        ))
+        .unwrap();
+    }
+}
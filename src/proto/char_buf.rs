@@ -4,6 +4,10 @@ use std::{
 };
 
 use endian_codec::{DecodeBE, EncodeBE, PackedSize};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, SeqAccess, Visitor},
+};
 
 /// Represents a potentially null terminated char buffer
 #[derive(Clone)]
@@ -24,6 +28,18 @@ impl<const N: usize> CharBuf<N> {
     pub fn as_c_str(&self) -> Option<&CStr> {
         CStr::from_bytes_until_nul(&self.buffer).ok()
     }
+
+    /// The buffer as a string, if it holds valid UTF-8 followed only by NUL
+    /// bytes (so the string is all there is to the buffer)
+    fn as_padded_str(&self) -> Option<&str> {
+        let c_str = self.as_c_str()?;
+
+        self.buffer[c_str.count_bytes()..]
+            .iter()
+            .all(|&b| b == 0)
+            .then(|| c_str.to_str().ok())
+            .flatten()
+    }
 }
 
 impl<const N: usize> TryFrom<&str> for CharBuf<N> {
@@ -35,7 +51,7 @@ impl<const N: usize> TryFrom<&str> for CharBuf<N> {
         }
 
         let mut buffer = [0; _];
-        buffer[..value.len()].copy_from_slice(&value.as_bytes());
+        buffer[..value.len()].copy_from_slice(value.as_bytes());
 
         Ok(Self { buffer })
     }
@@ -50,7 +66,7 @@ impl<const N: usize> TryFrom<&OsStr> for CharBuf<N> {
         }
 
         let mut buffer = [0; _];
-        buffer[..value.len()].copy_from_slice(&value.as_bytes());
+        buffer[..value.len()].copy_from_slice(value.as_bytes());
 
         Ok(Self { buffer })
     }
@@ -89,3 +105,121 @@ impl<const N: usize> core::fmt::Debug for CharBuf<N> {
         .finish()
     }
 }
+
+/// Serializes as a string when the buffer holds a valid UTF-8 C string padded
+/// with NUL bytes, and falls back to the raw bytes otherwise (including when
+/// there are leftover bytes after the terminator) so that no information is
+/// lost
+impl<const N: usize> Serialize for CharBuf<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.as_padded_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => serializer.serialize_bytes(&self.buffer),
+        }
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for CharBuf<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CharBufVisitor<const N: usize>;
+
+        impl<'de, const N: usize> Visitor<'de> for CharBufVisitor<N> {
+            type Value = CharBuf<N>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(
+                    f,
+                    "a string shorter than {N} bytes or a byte array of length {N}"
+                )
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                CharBuf::new(v).ok_or_else(|| E::invalid_length(v.len(), &self))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                let buffer = v
+                    .try_into()
+                    .map_err(|_| E::invalid_length(v.len(), &self))?;
+
+                Ok(CharBuf { buffer })
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut buffer = [0; N];
+
+                for (i, b) in buffer.iter_mut().enumerate() {
+                    *b = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+                }
+
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(de::Error::invalid_length(N + 1, &self));
+                }
+
+                Ok(CharBuf { buffer })
+            }
+        }
+
+        deserializer.deserialize_any(CharBufVisitor::<N>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(buf: &CharBuf<8>) -> CharBuf<8> {
+        let json = serde_json::to_string(buf).unwrap();
+
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn serializes_padded_strings_as_strings() {
+        let buf = CharBuf::<8>::new("1-1").unwrap();
+
+        assert_eq!(serde_json::to_value(&buf).unwrap(), "1-1");
+        assert_eq!(round_trip(&buf).buffer, buf.buffer);
+    }
+
+    #[test]
+    fn keeps_bytes_after_the_terminator() {
+        let buf = CharBuf::<8> {
+            buffer: *b"1-1\0junk",
+        };
+
+        assert!(serde_json::to_value(&buf).unwrap().is_array());
+        assert_eq!(round_trip(&buf).buffer, buf.buffer);
+    }
+
+    #[test]
+    fn keeps_unterminated_and_non_utf8_buffers() {
+        for buffer in [*b"12345678", *b"\xff\xfe\0\0\0\0\0\0"] {
+            let buf = CharBuf::<8> { buffer };
+
+            assert_eq!(round_trip(&buf).buffer, buffer);
+        }
+    }
+
+    #[test]
+    fn serde_form_round_trips_through_the_codec() {
+        let buf = CharBuf::<8> {
+            buffer: *b"1-1\0junk",
+        };
+
+        let mut bytes = [0; CharBuf::<8>::PACKED_LEN];
+        round_trip(&buf).encode_as_be_bytes(&mut bytes);
+
+        assert_eq!(
+            CharBuf::<8>::decode_from_be_bytes(&bytes).buffer,
+            buf.buffer
+        );
+    }
+
+    #[test]
+    fn rejects_strings_which_do_not_fit() {
+        assert!(serde_json::from_str::<CharBuf<4>>("\"1-1.2\"").is_err());
+    }
+}
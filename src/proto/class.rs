@@ -0,0 +1,47 @@
+//! USB class codes, as used in `bDeviceClass` and `bInterfaceClass` (named
+//! like the constants of the Linux kernel's `ch9.h`)
+
+/// The device declares a class on each of its interfaces instead (only valid as
+/// a device class)
+pub const USB_CLASS_PER_INTERFACE: u8 = 0x00;
+pub const USB_CLASS_AUDIO: u8 = 0x01;
+pub const USB_CLASS_COMM: u8 = 0x02;
+pub const USB_CLASS_HID: u8 = 0x03;
+pub const USB_CLASS_PHYSICAL: u8 = 0x05;
+pub const USB_CLASS_STILL_IMAGE: u8 = 0x06;
+pub const USB_CLASS_PRINTER: u8 = 0x07;
+pub const USB_CLASS_MASS_STORAGE: u8 = 0x08;
+/// Hubs can't be exported, since `usbip-host` does not support them
+pub const USB_CLASS_HUB: u8 = 0x09;
+pub const USB_CLASS_CDC_DATA: u8 = 0x0a;
+pub const USB_CLASS_CSCID: u8 = 0x0b;
+pub const USB_CLASS_CONTENT_SEC: u8 = 0x0d;
+pub const USB_CLASS_VIDEO: u8 = 0x0e;
+pub const USB_CLASS_PERSONAL_HEALTHCARE: u8 = 0x0f;
+pub const USB_CLASS_AUDIO_VIDEO: u8 = 0x10;
+pub const USB_CLASS_BILLBOARD: u8 = 0x11;
+pub const USB_CLASS_USB_TYPE_C_BRIDGE: u8 = 0x12;
+pub const USB_CLASS_DIAGNOSTIC: u8 = 0xdc;
+pub const USB_CLASS_WIRELESS_CONTROLLER: u8 = 0xe0;
+pub const USB_CLASS_MISC: u8 = 0xef;
+pub const USB_CLASS_APP_SPEC: u8 = 0xfe;
+pub const USB_CLASS_VENDOR_SPEC: u8 = 0xff;
+
+/// Formats a class code the way sysfs shows it in the `bDeviceClass` and
+/// `bInterfaceClass` attributes (two lowercase hex digits), for comparing
+/// against udev attributes
+pub fn sysfs_class_value(class: u8) -> String {
+    format!("{class:02x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sysfs_class_values() {
+        assert_eq!(sysfs_class_value(USB_CLASS_HUB), "09");
+        assert_eq!(sysfs_class_value(USB_CLASS_PER_INTERFACE), "00");
+        assert_eq!(sysfs_class_value(USB_CLASS_VENDOR_SPEC), "ff");
+    }
+}
@@ -3,11 +3,23 @@ use endian_codec::{DecodeBE, EncodeBE, PackedSize};
 use crate::proto::char_buf::CharBuf;
 
 pub mod char_buf;
+pub mod class;
 
 pub const USBIP_VERSION: u16 = 0x0111;
 
+/// Formats a BCD protocol version the way it is usually written (`0x0111` is
+/// `1.1.1`)
+pub fn format_version(version: u16) -> String {
+    format!(
+        "{}.{}.{}",
+        version >> 8,
+        (version >> 4) & 0xf,
+        version & 0xf
+    )
+}
+
 // implicitly packed due to layout, so we can avoid using `#[repr(packed)]`
-#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE)]
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
 #[repr(C)]
 pub struct OperationHeader {
     pub version: u16,
@@ -34,43 +46,49 @@ impl Direction {
 /// Core operations provided by the user-space server before the socket switched
 /// into kernel space
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-#[repr(u16)]
 pub enum OperationKind {
     /// Dummy Code
-    Unspecified = 0x00,
-    /// Retrieve USB device information. (still not used)
-    ///
-    /// NOT IMPLEMENTED IN ORIGINAL
+    Unspecified,
+    /// Retrieve USB device information.
     ///
-    /// TODO: implement this :)
-    DeviceInfo = 0x02,
+    /// NOT IMPLEMENTED IN ORIGINAL (servers which don't support it will
+    /// usually close the connection without replying)
+    DeviceInfo,
     /// Import a remote USB device.
-    Import = 0x03,
-    /// Export a USB device to a remote host.
+    Import,
+    /// Export a USB device to a remote host, the reverse of [`Import`]
+    /// (`usbip connect` in the original). The local device is bound to
+    /// usbip-host and its info sent in an [`ExportRequest`], after which the
+    /// remote host attaches it to its own `vhci_hcd`.
     ///
-    /// NOT IMPLEMENTED IN ORIGINAL
+    /// Only the sending side is implemented (`export_device` in the server
+    /// module, which is only available on Linux), the daemon refuses export
+    /// requests from clients.
     ///
-    /// TODO: implement this :)
-    Export = 0x06,
+    /// [`Import`]: OperationKind::Import
+    Export,
     /// un-Export a USB device from a remote host.
     ///
     /// NOT IMPLEMENTED IN ORIGINAL
     ///
     /// TODO: implement this :)
-    UnExport = 0x07,
+    UnExport,
     /// Negotiate IPSec encryption key. (still not used)
     ///
     /// NOT IMPLEMENTED IN ORIGINAL
     ///
     /// TODO: can this be implemented without modifying the kernel modules?
-    EncryptionKey = 0x04,
+    EncryptionKey,
     /// Retrieve the list of exported USB devices.
-    ListDevices = 0x05,
+    ListDevices,
+    /// An operation not known to this implementation (holds the raw code with
+    /// the direction bit masked off)
+    Unknown(u16),
 }
 
 impl OperationKind {
-    pub fn from_code(code: u16) -> Option<Self> {
-        Some(match code & 0x7FFF {
+    pub fn from_code(code: u16) -> Self {
+        match code & 0x7FFF {
             0x00 => Self::Unspecified,
             0x02 => Self::DeviceInfo,
             0x03 => Self::Import,
@@ -78,8 +96,22 @@ impl OperationKind {
             0x07 => Self::UnExport,
             0x04 => Self::EncryptionKey,
             0x05 => Self::ListDevices,
-            _ => return None,
-        })
+            c => Self::Unknown(c),
+        }
+    }
+
+    /// Returns the raw operation code (without the direction bit)
+    pub fn code(self) -> u16 {
+        match self {
+            Self::Unspecified => 0x00,
+            Self::DeviceInfo => 0x02,
+            Self::Import => 0x03,
+            Self::Export => 0x06,
+            Self::UnExport => 0x07,
+            Self::EncryptionKey => 0x04,
+            Self::ListDevices => 0x05,
+            Self::Unknown(c) => c & 0x7FFF,
+        }
     }
 }
 
@@ -129,26 +161,79 @@ pub enum OperationError {
     DirectionMismatch,
     #[error("received PDU with invalid data")]
     InvalidData,
+    #[error("received reply for unknown operation 0x{0:02x}")]
+    UnknownOperation(u16),
+    #[error(
+        "peer requested encryption, which is not supported (usbip traffic is unencrypted, use an SSH or WireGuard tunnel instead)"
+    )]
+    EncryptionUnsupported,
     #[error("some other error ocrrured")]
     Other,
 }
 
-#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE)]
+impl OperationError {
+    /// Returns whether the error means that the peer sent data which could not
+    /// be decoded, as opposed to a status it deliberately replied with
+    pub fn is_decode_error(&self) -> bool {
+        matches!(
+            self,
+            Self::VersionMismatch
+                | Self::DirectionMismatch
+                | Self::InvalidData
+                | Self::UnknownOperation(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
 #[repr(C)]
 pub struct ImportRequest {
     pub bus_id: CharBuf<SYSFS_BUS_ID_SIZE>,
 }
 
-#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE)]
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
 #[repr(C)]
 pub struct ImportReply {
     pub usb_device: RawUsbDeviceInfo,
 }
 
+/// Request body of [`OperationKind::DeviceInfo`] (`struct op_devinfo_request`)
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct DeviceInfoRequest {
+    pub bus_id: CharBuf<SYSFS_BUS_ID_SIZE>,
+}
+
+/// Reply body of [`OperationKind::DeviceInfo`] (`struct op_devinfo_reply`).
+/// Followed by `b_num_interfaces` [`UsbInterfaceInfo`] entries, the same as
+/// each device in a ListDevices reply.
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct DeviceInfoReply {
+    pub usb_device: RawUsbDeviceInfo,
+}
+
+/// Request body of [`OperationKind::Export`] (`struct op_export_request`)
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct ExportRequest {
+    pub usb_device: RawUsbDeviceInfo,
+}
+
+/// Reply body of [`OperationKind::Export`] (`struct op_export_reply`). A
+/// non-zero `return_code` indicates that the server failed to import the
+/// device. `usbip connect` of the reference implementation never reads it, so
+/// peers can't be expected to send it.
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
+#[repr(C)]
+pub struct ExportReply {
+    pub return_code: u32,
+}
+
 pub const SYSFS_PATH_MAX: usize = 256;
 pub const SYSFS_BUS_ID_SIZE: usize = 32;
 
-#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE)]
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
 #[repr(C)]
 pub struct RawUsbDeviceInfo {
     pub path: CharBuf<SYSFS_PATH_MAX>,
@@ -170,17 +255,153 @@ pub struct RawUsbDeviceInfo {
     pub b_num_interfaces: u8,
 }
 
-#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE)]
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
 #[repr(C)]
 pub struct UsbInterfaceInfo {
     pub b_interface_class: u8,
     pub b_interface_sub_class: u8,
     pub b_interface_protocol: u8,
+    #[serde(skip)]
     _padding: u8,
 }
 
-#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE)]
+impl UsbInterfaceInfo {
+    pub fn new(b_interface_class: u8, b_interface_sub_class: u8, b_interface_protocol: u8) -> Self {
+        Self {
+            b_interface_class,
+            b_interface_sub_class,
+            b_interface_protocol,
+            _padding: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PackedSize, EncodeBE, DecodeBE, serde::Serialize, serde::Deserialize)]
 #[repr(C)]
 pub struct ListDevicesReply {
     pub num_devices: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KNOWN: [(u16, OperationKind); 7] = [
+        (0x00, OperationKind::Unspecified),
+        (0x02, OperationKind::DeviceInfo),
+        (0x03, OperationKind::Import),
+        (0x04, OperationKind::EncryptionKey),
+        (0x05, OperationKind::ListDevices),
+        (0x06, OperationKind::Export),
+        (0x07, OperationKind::UnExport),
+    ];
+
+    #[test]
+    fn decodes_known_operations_in_both_directions() {
+        for (code, kind) in KNOWN {
+            assert_eq!(OperationKind::from_code(code), kind);
+            assert_eq!(
+                OperationKind::from_code(Direction::Request as u16 | code),
+                kind
+            );
+            assert_eq!(kind.code(), code);
+        }
+    }
+
+    #[test]
+    fn keeps_the_code_of_unknown_operations() {
+        assert_eq!(OperationKind::from_code(0x01), OperationKind::Unknown(0x01));
+        assert_eq!(
+            OperationKind::from_code(0x8042),
+            OperationKind::Unknown(0x42)
+        );
+        assert_eq!(OperationKind::Unknown(0x42).code(), 0x42);
+    }
+
+    #[test]
+    fn splits_off_the_direction() {
+        assert_eq!(Direction::from_code(0x8003), Direction::Request);
+        assert_eq!(Direction::from_code(0x0003), Direction::Reply);
+    }
+
+    #[test]
+    fn formats_bcd_versions() {
+        assert_eq!(format_version(USBIP_VERSION), "1.1.1");
+        assert_eq!(format_version(0x0100), "1.0.0");
+        assert_eq!(format_version(0x0106), "1.0.6");
+    }
+
+    /// Checks that the value survives a trip through JSON unchanged, as seen by
+    /// the big endian codec, and that replaying the JSON of the decoded bytes
+    /// produces the same bytes again
+    fn assert_round_trips<T>(value: T)
+    where
+        T: EncodeBE + DecodeBE + serde::Serialize + serde::de::DeserializeOwned,
+        [u8; T::PACKED_LEN]:,
+    {
+        let mut encoded = [0; T::PACKED_LEN];
+        value.encode_as_be_bytes(&mut encoded);
+
+        let json = serde_json::to_string(&value).unwrap();
+        let mut replayed = [0; T::PACKED_LEN];
+        serde_json::from_str::<T>(&json)
+            .unwrap()
+            .encode_as_be_bytes(&mut replayed);
+
+        assert_eq!(encoded, replayed, "{json}");
+
+        let decoded = T::decode_from_be_bytes(&encoded);
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+    }
+
+    fn raw_device() -> RawUsbDeviceInfo {
+        (&crate::UsbDeviceInfo {
+            sys_path: "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-2".into(),
+            bus_id: "1-2".into(),
+            bus_num: 1,
+            dev_num: 3,
+            speed: crate::UsbSpeed::High,
+            id_vendor: 0x0781,
+            id_product: 0x5567,
+            bcd_device: 0x0100,
+            b_device_class: 0,
+            b_device_sub_class: 0,
+            b_device_protocol: 0,
+            b_configuration_value: Some(1),
+            b_num_configurations: 1,
+            b_num_interfaces: 1,
+        })
+            .into()
+    }
+
+    #[test]
+    fn operation_headers_round_trip_through_json() {
+        assert_round_trips(OperationHeader {
+            version: USBIP_VERSION,
+            code: Direction::Request as u16 | OperationKind::Import.code(),
+            status: OperationStatus::NoSuchDevice as _,
+        });
+    }
+
+    #[test]
+    fn device_infos_round_trip_through_json() {
+        assert_round_trips(raw_device());
+        assert_round_trips(ImportReply {
+            usb_device: raw_device(),
+        });
+    }
+
+    #[test]
+    fn list_replies_round_trip_through_json() {
+        assert_round_trips(ListDevicesReply { num_devices: 2 });
+        assert_round_trips(UsbInterfaceInfo::new(0x08, 0x06, 0x50));
+    }
+
+    #[test]
+    fn device_infos_serialize_strings_as_text() {
+        let json = serde_json::to_value(raw_device()).unwrap();
+
+        assert_eq!(json["bus_id"], "1-2");
+        assert_eq!(json["id_vendor"], 0x0781);
+    }
+}
@@ -1,24 +1,38 @@
-use std::{ffi::OsStr, io};
+use std::{
+    ffi::OsStr,
+    io, thread,
+    time::{Duration, Instant},
+};
 
-use crate::drivers::{
-    DriverBindingError, DriverUnbindingError, SysfsIoError, bind_usb_driver,
-    host::{MatchListOperation, UsbipHost},
-    unbind_usb_driver,
+use crate::{
+    UsbDeviceInfo,
+    drivers::{
+        DeviceLookupError, DriverBindingError, DriverUnbindingError, SysfsIoError, bind_usb_driver,
+        driver_name,
+        host::{
+            MatchListOperation, USBIP_HOST_DRIVER_NAME, UsbipHost, state::save_original_driver,
+        },
+        open_usb_device, unbind_usb_driver,
+        vhci::is_vhci_attached,
+    },
+    server::{
+        list_local::{self, ListLocalOptions, list_local_exportable_devices_with_options},
+        matcher::DeviceMatcher,
+        unbind::{self, restore_original_driver},
+    },
+    util::{UsbInfoExtractError, extract_usb_info_from_udev_device},
 };
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to create udev context ({0})")]
     CreareUdevContext(io::Error),
-    #[error("USB device not found ({0})")]
-    UdevDeviceNotFound(io::Error),
+    #[error(transparent)]
+    UdevDeviceNotFound(DeviceLookupError),
 
     #[error("Bind loop detected. Device is attached by `vhci_hcd` driver.")]
     AlreadyBoundToVhci,
 
-    // #[error("Could not unbind other driver from device on bus ID `{0}`")]
-    #[error("Failed to query udev attribute `{attribute}` from device with bus ID `{bus_id}`")]
-    FailedToGetUdevDeviceAttribute { bus_id: String, attribute: String },
     /// Hub devices may not be unbound from their drivers and cannot be bound to usbip_host
     #[error("Cannot bind USB hub device on bus ID `{0}`")]
     CannotBindHub(String),
@@ -41,12 +55,126 @@ pub enum Error {
         bus_id: String,
     },
 
+    #[error("Failed to query USB device with bus ID `{bus_id}` ({error})")]
+    UsbInfoExtraction {
+        bus_id: String,
+        error: UsbInfoExtractError,
+    },
+
     #[error("Cannot write to `usbip-host` device to update device ID match list: {0}")]
     UpdatingMatchList(SysfsIoError),
+    #[error("Cannot read the device ID match list of `usbip-host`: {0}")]
+    ReadingMatchList(SysfsIoError),
+    #[error(
+        "Failed to give the device back to its original driver after the bind could not be completed ({0})"
+    )]
+    RevertingBind(unbind::Error),
+
+    #[error(
+        "Timed out waiting for device with bus ID `{bus_id}` to be bound to `usbip-host` (current driver: {})",
+        driver.as_deref().unwrap_or("none")
+    )]
+    BindTimedOut {
+        bus_id: String,
+        driver: Option<String>,
+    },
+}
+
+pub type BindResult = Result<BindOutcome, Error>;
+
+/// Result of a successful [`bind_device`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct BindOutcome {
+    pub bus_id: String,
+    /// The driver which was unbound from the device to make room for
+    /// usbip-host (if any)
+    pub previous_driver: Option<String>,
+}
+
+/// What [`bind_device`] would do to a device, as determined by [`plan_bind`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct BindPlan {
+    pub bus_id: String,
+    pub device_info: UsbDeviceInfo,
+    pub is_hub: bool,
+    /// Whether the device was imported from another host through `vhci_hcd`
+    pub vhci_attached: bool,
+    /// The driver which would be unbound to make room for usbip-host (or
+    /// usbip-host itself if the device is already bound to it)
+    pub current_driver: Option<String>,
+    /// Why the device can't be bound, `None` if binding would go ahead
+    pub blocker: Option<String>,
+}
+
+/// How [`repair`] fixed the state of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairAction {
+    /// The state of the device was consistent, so nothing was changed
+    NothingToRepair,
+    /// The device was in the match list of usbip-host but had no driver (the
+    /// bind was interrupted), so it was bound to usbip-host
+    CompletedBind,
+    /// The device was bound to usbip-host but missing from its match list, so
+    /// it was added to it
+    AddedToMatchList,
+    /// The device was bound to another driver but still in the match list of
+    /// usbip-host (left behind by a failed bind), so it was removed from it
+    RemovedFromMatchList,
+    /// The device was in the match list but had no driver, and binding it to
+    /// usbip-host failed, so it was removed from the match list and given
+    /// back to its original driver
+    RevertedBind,
+}
+
+impl BindPlan {
+    pub fn can_bind(&self) -> bool {
+        self.blocker.is_none()
+    }
+}
+
+/// Performs the same checks as [`bind_device`] without changing anything, so
+/// that callers can inspect what binding the device would do beforehand
+pub fn plan_bind(local_bus_id: &str) -> Result<BindPlan, Error> {
+    let context = udev::Udev::new().map_err(Error::CreareUdevContext)?;
+
+    let usb_device = open_usb_device(&context, local_bus_id).map_err(Error::UdevDeviceNotFound)?;
+
+    let device_info =
+        extract_usb_info_from_udev_device(&usb_device).map_err(|e| Error::UsbInfoExtraction {
+            bus_id: local_bus_id.into(),
+            error: e,
+        })?;
+
+    let vhci_attached = is_vhci_attached(&usb_device);
+    let is_hub = device_info.is_hub();
+    let current_driver = driver_name(&usb_device);
+
+    // the same order of checks as in bind_device, so the first reported error
+    // matches
+    let blocker = if vhci_attached {
+        Some(Error::AlreadyBoundToVhci)
+    } else if is_hub {
+        Some(Error::CannotBindHub(local_bus_id.into()))
+    } else if current_driver.as_deref() == Some(USBIP_HOST_DRIVER_NAME) {
+        Some(Error::AlreadyBoundToUsbipHost(local_bus_id.into()))
+    } else {
+        None
+    };
+
+    Ok(BindPlan {
+        bus_id: local_bus_id.into(),
+        device_info,
+        is_hub,
+        vhci_attached,
+        current_driver,
+        blocker: blocker.map(|e| e.to_string()),
+    })
 }
 
 /// Binds a USB device to the usbip-host driver. If the device is already bound
-/// to another driver it will be unbound before rebinding to usbip-host.
+/// to another driver it will be unbound before rebinding to usbip-host, and the
+/// name of that driver is returned in [`BindOutcome::previous_driver`].
 ///
 /// NOTE: must be a device bus ID (`x-y` or `x-y.z.w`), and NOT an interface bus
 /// ID (`x-y:z.w` or `x-y.z:w.a`)
@@ -54,72 +182,401 @@ pub enum Error {
 /// NOTE: Not all device are allowed to be bound here. Specifically, hub devices
 /// and any devices already attached with vhci_hcd may not be exported using
 /// usbip-host. Leaf devices created by a hub may be exported as normal.
-pub fn bind_device(local_bus_id: &str) -> Result<(), Error> {
+pub fn bind_device(local_bus_id: &str) -> Result<BindOutcome, Error> {
     let context = udev::Udev::new().map_err(Error::CreareUdevContext)?;
 
-    let usb_device = udev::Device::from_subsystem_sysname_with_context(
-        context.clone(),
-        "usb".into(),
-        local_bus_id.into(),
-    )
-    .map_err(Error::UdevDeviceNotFound)?;
+    let usb_device = open_usb_device(&context, local_bus_id).map_err(Error::UdevDeviceNotFound)?;
 
     // Check if this device was attached by the `vhci_hcd` host controller
     // driver. If this is the case, we technically could still bind it and
     // re-export it, but the usbip-host kernel module doesn't allow this.
-    let dev_path = usb_device.devpath().to_str().unwrap();
-    if dev_path.contains("vhci_hcd") {
-        // TODO: there is probably a better way to do this which would reduce
-        // weird driver naming conflicts. We can probably traverse the device
-        // parent hierarchy to look for the hcd driver being vhci_hcd.
-
+    if is_vhci_attached(&usb_device) {
         return Err(Error::AlreadyBoundToVhci);
     }
 
     // Make sure that this device is not a USB hub device. These are special
     // devices which the kernel treats differently and the usbip-host driver
     // does not support them at this time.
-    let b_device_class = usb_device.attribute_value("bDeviceClass").ok_or_else(|| {
-        Error::FailedToGetUdevDeviceAttribute {
+    let device_info =
+        extract_usb_info_from_udev_device(&usb_device).map_err(|e| Error::UsbInfoExtraction {
             bus_id: local_bus_id.into(),
-            attribute: "bDeviceClass".into(),
-        }
-    })?;
-    if b_device_class == OsStr::new("09") {
+            error: e,
+        })?;
+    if device_info.is_hub() {
         return Err(Error::CannotBindHub(local_bus_id.into()));
     }
 
-    // If the device doesn't have a driver bound to it already, we can just
-    // continue forwards with binding to usbip-host
-    if let Some(driver) = usb_device.driver() {
-        // Check that this device is not already bound to the usbip-host driver (we
-        // don't try to rebind in this case).
-        if driver == OsStr::new("usbip-host") {
-            return Err(Error::AlreadyBoundToUsbipHost(local_bus_id.into()));
+    let previous_driver = displace_driver(local_bus_id, driver_name(&usb_device), |driver| {
+        // remember the original driver so unbinding can restore it exactly.
+        // this happens before unbinding it so that a bind which is interrupted
+        // can still be reverted by `repair`. it is best effort since unbinding
+        // can still fall back to asking the kernel to probe the device again.
+        if let Err(e) = save_original_driver(local_bus_id, driver) {
+            tracing::warn!("failed to record original driver of `{local_bus_id}`: {e}");
         }
 
-        unbind_usb_driver(&driver, local_bus_id).map_err(|e| Error::UnbindingDriver {
+        unbind_usb_driver(OsStr::new(driver), local_bus_id).map_err(|e| Error::UnbindingDriver {
             source: e,
-            driver: driver.to_string_lossy().into(),
+            driver: driver.into(),
             bus_id: local_bus_id.into(),
-        })?;
-    }
+        })
+    })?;
 
     UsbipHost::update_bus_id_match_list(local_bus_id, MatchListOperation::Add)
         .map_err(Error::UpdatingMatchList)?;
 
-    if let Err(e) = bind_usb_driver(OsStr::new("usbip-host"), local_bus_id) {
+    if let Err(e) = complete_bind(local_bus_id) {
         // try to remove, but if we encounter an error, there isnt much we can
         // do. if we successfully added the first time then its likely that this
         // will succeed.
         let _ = UsbipHost::update_bus_id_match_list(local_bus_id, MatchListOperation::Remove);
 
-        return Err(Error::BindingDriver {
+        return Err(e);
+    };
+
+    Ok(BindOutcome {
+        bus_id: local_bus_id.into(),
+        previous_driver,
+    })
+}
+
+/// Makes room for usbip-host by releasing the device from the driver currently
+/// bound to it (if any) with `unbind`, returning the name of that driver
+fn displace_driver(
+    local_bus_id: &str,
+    current_driver: Option<String>,
+    unbind: impl FnOnce(&str) -> Result<(), Error>,
+) -> Result<Option<String>, Error> {
+    // If the device doesn't have a driver bound to it already, we can just
+    // continue forwards with binding to usbip-host
+    if let Some(driver) = &current_driver {
+        // Check that this device is not already bound to the usbip-host driver (we
+        // don't try to rebind in this case).
+        if driver == USBIP_HOST_DRIVER_NAME {
+            return Err(Error::AlreadyBoundToUsbipHost(local_bus_id.into()));
+        }
+
+        unbind(driver)?;
+    }
+
+    Ok(current_driver)
+}
+
+/// Detects and fixes the inconsistent states [`bind_device`] and
+/// [`unbind_device`](crate::server::unbind::unbind_device) can leave a device
+/// in when they are interrupted between their steps: a device which is in the
+/// match list of usbip-host but has no driver is bound to usbip-host (or
+/// reverted to its original driver if that fails), a device which is bound to
+/// usbip-host but not in its match list is added to it, and a stale match
+/// list entry of a device which is bound to another driver is removed.
+pub fn repair(local_bus_id: &str) -> Result<RepairAction, Error> {
+    let context = udev::Udev::new().map_err(Error::CreareUdevContext)?;
+
+    let usb_device = open_usb_device(&context, local_bus_id).map_err(Error::UdevDeviceNotFound)?;
+
+    let driver = driver_name(&usb_device);
+    let in_match_list =
+        UsbipHost::is_in_match_list(local_bus_id).map_err(Error::ReadingMatchList)?;
+
+    tracing::debug!(
+        "repairing `{local_bus_id}` (driver = {driver:?}, in_match_list = {in_match_list})"
+    );
+
+    let Some(inconsistency) = find_inconsistency(driver.as_deref(), in_match_list) else {
+        return Ok(RepairAction::NothingToRepair);
+    };
+
+    let action = match inconsistency {
+        // the original driver was already recorded by `bind_device` before it
+        // was unbound, so only the remaining steps are left to do
+        Inconsistency::UnfinishedBind => match complete_bind(local_bus_id) {
+            Ok(()) => RepairAction::CompletedBind,
+            Err(e) => {
+                tracing::warn!("failed to complete the bind of `{local_bus_id}` ({e}), reverting");
+
+                UsbipHost::update_bus_id_match_list(local_bus_id, MatchListOperation::Remove)
+                    .map_err(Error::UpdatingMatchList)?;
+                restore_original_driver(local_bus_id).map_err(Error::RevertingBind)?;
+
+                RepairAction::RevertedBind
+            }
+        },
+        Inconsistency::MissingMatchListEntry => {
+            UsbipHost::update_bus_id_match_list(local_bus_id, MatchListOperation::Add)
+                .map_err(Error::UpdatingMatchList)?;
+
+            RepairAction::AddedToMatchList
+        }
+        Inconsistency::StaleMatchListEntry => {
+            UsbipHost::update_bus_id_match_list(local_bus_id, MatchListOperation::Remove)
+                .map_err(Error::UpdatingMatchList)?;
+
+            RepairAction::RemovedFromMatchList
+        }
+    };
+
+    Ok(action)
+}
+
+/// The inconsistent states [`repair`] knows how to fix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Inconsistency {
+    /// In the match list of usbip-host but without a driver
+    UnfinishedBind,
+    /// Bound to usbip-host but not in its match list
+    MissingMatchListEntry,
+    /// Bound to another driver but still in the match list of usbip-host
+    StaleMatchListEntry,
+}
+
+fn find_inconsistency(driver: Option<&str>, in_match_list: bool) -> Option<Inconsistency> {
+    match (driver, in_match_list) {
+        (None, true) => Some(Inconsistency::UnfinishedBind),
+        (Some(USBIP_HOST_DRIVER_NAME), false) => Some(Inconsistency::MissingMatchListEntry),
+        (Some(driver), true) if driver != USBIP_HOST_DRIVER_NAME => {
+            Some(Inconsistency::StaleMatchListEntry)
+        }
+        _ => None,
+    }
+}
+
+/// The last step of [`bind_device`], once the device is in the match list of
+/// usbip-host and has no driver: binds it to usbip-host. This does not wait for
+/// the bind to take effect, callers which need to sequence anything after it
+/// use [`wait_until_bound`].
+fn complete_bind(local_bus_id: &str) -> Result<(), Error> {
+    bind_usb_driver(OsStr::new(USBIP_HOST_DRIVER_NAME), local_bus_id).map_err(|e| {
+        Error::BindingDriver {
             source: e,
-            driver: "usbip-host".into(),
+            driver: USBIP_HOST_DRIVER_NAME.into(),
             bus_id: local_bus_id.into(),
+        }
+    })
+}
+
+/// Binds all local exportable devices (see
+/// [`list_local_exportable_devices`](crate::server::list_local::list_local_exportable_devices))
+/// to usbip-host, except for those matching any of the `exclude` matchers and
+/// those which are already bound to usbip-host.
+///
+/// Returns the result of binding each device, in the order they were
+/// enumerated. Failing to bind one device does not stop the others from being
+/// bound.
+pub fn bind_all_eligible(
+    exclude: &[DeviceMatcher],
+) -> Result<Vec<(String, BindResult)>, list_local::Error> {
+    let devices = list_local_exportable_devices_with_options(&ListLocalOptions {
+        exclude: exclude.to_vec(),
+        ..Default::default()
+    })?;
+
+    Ok(devices
+        .into_iter()
+        .filter_map(|device| {
+            let bus_id = device.device_info.bus_id.to_string();
+
+            match bind_device(&bus_id) {
+                Err(Error::AlreadyBoundToUsbipHost(_)) => None,
+                result => Some((bus_id, result)),
+            }
+        })
+        .collect())
+}
+
+/// Waits until the device with the given bus ID reports `usbip-host` as its
+/// driver. Binding is asynchronous from the point of view of userspace, so
+/// scripts which bind a device and then immediately export it should wait for
+/// the bind to take effect first.
+pub fn wait_until_bound(local_bus_id: &str, timeout: Duration) -> Result<(), Error> {
+    let context = udev::Udev::new().map_err(Error::CreareUdevContext)?;
+
+    poll_until_bound(local_bus_id, timeout, || {
+        // the device has to be looked up again each time since udev caches
+        // the driver of a device when it is created
+        let usb_device =
+            open_usb_device(&context, local_bus_id).map_err(Error::UdevDeviceNotFound)?;
+
+        Ok(driver_name(&usb_device))
+    })
+}
+
+/// Polls the driver of the device through `current_driver` until it is
+/// `usbip-host` or the timeout expires
+fn poll_until_bound(
+    local_bus_id: &str,
+    timeout: Duration,
+    mut current_driver: impl FnMut() -> Result<Option<String>, Error>,
+) -> Result<(), Error> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    let start = Instant::now();
+
+    loop {
+        let driver = current_driver()?;
+
+        if driver.as_deref() == Some(USBIP_HOST_DRIVER_NAME) {
+            return Ok(());
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(Error::BindTimedOut {
+                bus_id: local_bus_id.into(),
+                driver,
+            });
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_an_unfinished_bind() {
+        assert_eq!(
+            find_inconsistency(None, true),
+            Some(Inconsistency::UnfinishedBind)
+        );
+    }
+
+    #[test]
+    fn finds_a_missing_match_list_entry() {
+        assert_eq!(
+            find_inconsistency(Some(USBIP_HOST_DRIVER_NAME), false),
+            Some(Inconsistency::MissingMatchListEntry)
+        );
+    }
+
+    #[test]
+    fn finds_a_stale_match_list_entry() {
+        assert_eq!(
+            find_inconsistency(Some("usb-storage"), true),
+            Some(Inconsistency::StaleMatchListEntry)
+        );
+    }
+
+    #[test]
+    fn leaves_consistent_states_alone() {
+        assert_eq!(find_inconsistency(None, false), None);
+        assert_eq!(find_inconsistency(Some(USBIP_HOST_DRIVER_NAME), true), None);
+        assert_eq!(find_inconsistency(Some("usb-storage"), false), None);
+    }
+
+    #[test]
+    fn waits_for_the_driver_to_change() {
+        let start = Instant::now();
+        let mut polls = 0;
+
+        // the bind takes effect after a short delay
+        let result = poll_until_bound("1-1", Duration::from_secs(5), || {
+            polls += 1;
+
+            Ok(if start.elapsed() < Duration::from_millis(120) {
+                Some("usb-storage".into())
+            } else {
+                Some(USBIP_HOST_DRIVER_NAME.into())
+            })
         });
-    };
 
-    Ok(())
+        assert!(result.is_ok(), "{result:?}");
+        assert!(polls > 1);
+    }
+
+    #[test]
+    fn times_out_with_the_current_driver() {
+        let result = poll_until_bound("1-1", Duration::from_millis(100), || {
+            Ok(Some("usb-storage".into()))
+        });
+
+        assert!(matches!(
+            result,
+            Err(Error::BindTimedOut { bus_id, driver })
+                if bus_id == "1-1" && driver.as_deref() == Some("usb-storage")
+        ));
+    }
+
+    #[test]
+    fn stops_polling_when_the_device_is_gone() {
+        let mut polls = 0;
+
+        let result = poll_until_bound("1-1", Duration::from_secs(5), || {
+            polls += 1;
+            Err(Error::UdevDeviceNotFound(DeviceLookupError::NotFound(
+                "1-1".into(),
+            )))
+        });
+
+        assert!(matches!(result, Err(Error::UdevDeviceNotFound(_))));
+        assert_eq!(polls, 1);
+    }
+
+    #[test]
+    fn reports_the_displaced_driver() {
+        let mut unbound = None;
+
+        let previous = displace_driver("1-1", Some("usb-storage".into()), |driver| {
+            unbound = Some(driver.to_owned());
+            Ok(())
+        });
+
+        assert_eq!(previous.unwrap().as_deref(), Some("usb-storage"));
+        assert_eq!(unbound.as_deref(), Some("usb-storage"));
+    }
+
+    #[test]
+    fn displaces_nothing_without_a_driver() {
+        let previous = displace_driver("1-1", None, |driver| panic!("unbound {driver}"));
+
+        assert_eq!(previous.unwrap(), None);
+    }
+
+    #[test]
+    fn does_not_displace_usbip_host() {
+        let result = displace_driver("1-1", Some(USBIP_HOST_DRIVER_NAME.into()), |driver| {
+            panic!("unbound {driver}")
+        });
+
+        assert!(matches!(
+            result,
+            Err(Error::AlreadyBoundToUsbipHost(bus_id)) if bus_id == "1-1"
+        ));
+    }
+
+    #[test]
+    fn serializes_a_bind_plan() {
+        let plan = BindPlan {
+            bus_id: "1-1".into(),
+            device_info: UsbDeviceInfo {
+                sys_path: "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-1".into(),
+                bus_id: "1-1".into(),
+                bus_num: 1,
+                dev_num: 2,
+                speed: crate::UsbSpeed::High,
+                id_vendor: 0x0781,
+                id_product: 0x5567,
+                bcd_device: 0x0100,
+                b_device_class: 0,
+                b_device_sub_class: 0,
+                b_device_protocol: 0,
+                b_configuration_value: Some(1),
+                b_num_configurations: 1,
+                b_num_interfaces: 1,
+            },
+            is_hub: false,
+            vhci_attached: false,
+            current_driver: Some("usb-storage".into()),
+            blocker: None,
+        };
+
+        let json = serde_json::to_value(&plan).unwrap();
+
+        assert_eq!(json["bus_id"], "1-1");
+        assert_eq!(json["device_info"]["id_vendor"], 0x0781);
+        assert_eq!(json["is_hub"], false);
+        assert_eq!(json["vhci_attached"], false);
+        assert_eq!(json["current_driver"], "usb-storage");
+        assert!(json["blocker"].is_null());
+    }
 }
@@ -0,0 +1,727 @@
+//! Implements the request handling of the usbip server daemon. Each client
+//! connection carries a single operation, after which the connection is either
+//! closed (ListDevices) or handed off to usbip-host in the kernel (Import).
+//!
+//! In device mode, the gadgets bound to the UDCs of usbip-vudc are exported
+//! instead of the devices bound to usbip-host.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr},
+    os::fd::AsRawFd,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    UsbDeviceInfo,
+    drivers::{
+        InterfaceEnumerationError, driver_name, enumerate_interfaces,
+        host::{USBIP_HOST_DRIVER_NAME, UsbipHost, UsbipHostDeviceStatus},
+        open_usb_device,
+        vudc::{self, Vudc, VudcStatus},
+    },
+    net::UsbIpSocket,
+    proto::{
+        DeviceInfoReply, DeviceInfoRequest, ImportReply, ImportRequest, ListDevicesReply,
+        OperationError, OperationKind, OperationStatus, RawUsbDeviceInfo, UsbInterfaceInfo,
+    },
+    server::policy::ExportPolicy,
+    util::{UsbInfoExtractError, extract_usb_info_from_udev_device},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Network connection failed ({0})")]
+    NetworkIo(io::Error),
+    #[error("usbip network operation failed ({0})")]
+    Operation(#[from] OperationError),
+    #[error("Peer is not speaking the usbip protocol")]
+    NotUsbip,
+    #[error("Received request for unsupported operation {0:?}")]
+    UnsupportedOperation(OperationKind),
+    #[error("Failed to listen on `{addr}` ({error})")]
+    Binding { addr: SocketAddr, error: io::Error },
+
+    #[error("Failed to create udev context ({0})")]
+    CreatingUdevContext(io::Error),
+    #[error("Failed to create udev enumerator ({0})")]
+    CreatingUdevEnumerator(io::Error),
+    #[error("Failed to enumerate USB devices with udev ({0})")]
+    EnumeratingUdevDevices(io::Error),
+    #[error("Failed to read the interfaces of USB device with bus ID `{bus_id}` ({error})")]
+    EnumeratingInterfaces {
+        bus_id: String,
+        error: InterfaceEnumerationError,
+    },
+    #[error(
+        "USB device with bus ID `{bus_id}` reports {expected} interfaces, but {found} were found"
+    )]
+    InterfaceCountMismatch {
+        bus_id: String,
+        expected: u8,
+        found: usize,
+    },
+    #[error("Failed to query USB device with bus ID `{bus_id}` ({error})")]
+    UsbInfoExtraction {
+        bus_id: String,
+        error: UsbInfoExtractError,
+    },
+    #[error("Failed to enumerate virtual UDCs ({0})")]
+    Vudc(#[from] vudc::Error),
+}
+
+/// A device bound to usbip-host (or a gadget bound to a virtual UDC) along with
+/// the interface data sent to clients
+struct ExportedDevice {
+    info: UsbDeviceInfo,
+    interfaces: Vec<UsbInterfaceInfo>,
+}
+
+/// Options controlling how the server handles client connections
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub policy: ExportPolicy,
+    /// Maximum number of connections serviced at the same time, across all
+    /// the addresses passed to [`serve_all`]. Connections accepted beyond this
+    /// limit are closed immediately.
+    pub max_connections: usize,
+    /// How long a client may take to send its request before the connection
+    /// is dropped (`None` waits forever). This keeps clients which connect
+    /// but never send anything from tying up a thread indefinitely.
+    ///
+    /// The timeout covers the whole request, counted from when the connection
+    /// is handled: the read timeout of the socket is shrunk to the time left
+    /// before each part of the request is read.
+    pub request_timeout: Option<Duration>,
+    /// Export the gadgets bound to the virtual UDCs of usbip-vudc instead of
+    /// the devices bound to usbip-host
+    pub device_mode: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            policy: ExportPolicy::default(),
+            max_connections: 64,
+            request_timeout: Some(Duration::from_secs(30)),
+            device_mode: false,
+        }
+    }
+}
+
+/// Decrements the active connection count when the connection thread exits
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Listens for client connections on the given address and services each of
+/// them on its own thread. Only returns if the listening socket can't be
+/// created.
+pub fn serve(addr: SocketAddr, options: ServerOptions) -> Result<(), Error> {
+    serve_all(&[addr], options)
+}
+
+/// Same as [`serve`], but listens on all of the given addresses at once. The
+/// connection limit applies to the connections of all addresses together.
+/// Only returns if one of the listening sockets can't be created.
+pub fn serve_all(addrs: &[SocketAddr], options: ServerOptions) -> Result<(), Error> {
+    let listeners = addrs
+        .iter()
+        .map(|&addr| {
+            let listener =
+                UsbIpSocket::bind(addr).map_err(|error| Error::Binding { addr, error })?;
+
+            tracing::info!("listening on {addr}");
+
+            Ok(listener)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let options = Arc::new(options);
+    let active_connections = Arc::new(AtomicUsize::new(0));
+
+    let threads: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let options = options.clone();
+            let active_connections = active_connections.clone();
+
+            thread::spawn(move || {
+                accept_connections(&listener, &options, &active_connections, || false)
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        if let Err(panic) = thread.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    Ok(())
+}
+
+/// How long to wait before accepting connections again after accepting one
+/// failed
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts connections on the listener, refusing those beyond the connection
+/// limit, until `stop` returns true. `stop` is checked after each connection
+/// (or failure to accept one), and the connection which stopped it is dropped.
+fn accept_connections(
+    listener: &UsbIpSocket,
+    options: &Arc<ServerOptions>,
+    active_connections: &Arc<AtomicUsize>,
+    stop: impl Fn() -> bool,
+) {
+    loop {
+        let accepted = listener.accept();
+
+        if stop() {
+            return;
+        }
+
+        let (mut socket, peer) = match accepted {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!("failed to accept connection: {e}");
+
+                // errors like running out of file descriptors persist until
+                // some connections are closed, so don't spin on them
+                thread::sleep(ACCEPT_ERROR_BACKOFF);
+                continue;
+            }
+        };
+
+        if active_connections.fetch_add(1, Ordering::SeqCst) >= options.max_connections {
+            active_connections.fetch_sub(1, Ordering::SeqCst);
+
+            tracing::warn!(
+                %peer,
+                "connection refused (limit of {} connections reached)",
+                options.max_connections
+            );
+
+            continue;
+        }
+
+        let guard = ConnectionGuard(active_connections.clone());
+        let options = options.clone();
+
+        thread::spawn(move || {
+            let _guard = guard;
+            let _span = tracing::info_span!("connection", %peer).entered();
+
+            tracing::info!("connection accepted");
+
+            match handle_connection(&mut socket, peer, &options) {
+                Ok(_) => tracing::info!("connection finished"),
+                Err(Error::NetworkIo(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    tracing::warn!("client did not send a request in time, dropping connection");
+                }
+                Err(Error::NetworkIo(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    tracing::info!("client disconnected");
+                }
+                Err(e) => tracing::error!("request failed: {e}"),
+            }
+        });
+    }
+}
+
+/// Reads a single request from the client and services it. Requests for
+/// devices denied by the policy are answered as if the device did not exist.
+pub fn handle_connection(
+    socket: &mut UsbIpSocket,
+    peer: SocketAddr,
+    options: &ServerOptions,
+) -> Result<(), Error> {
+    let deadline = options.request_timeout.map(|t| Instant::now() + t);
+
+    limit_read_to(socket, deadline)?;
+    if socket.probe_protocol().map_err(Error::NetworkIo)?.is_none() {
+        return Err(Error::NotUsbip);
+    }
+
+    limit_read_to(socket, deadline)?;
+    let kind = socket.recv_request_header().map_err(Error::NetworkIo)??;
+
+    match kind {
+        OperationKind::ListDevices => {
+            tracing::info!("list requested");
+            handle_list_devices(socket, peer, options)
+        }
+        OperationKind::Import => handle_import(socket, peer, options, deadline),
+        OperationKind::DeviceInfo => handle_device_info(socket, peer, options, deadline),
+        k => {
+            tracing::warn!("received request for unsupported operation {k:?}");
+
+            socket
+                .send_response_header(k, OperationStatus::Failure)
+                .map_err(Error::NetworkIo)?;
+
+            Err(Error::UnsupportedOperation(k))
+        }
+    }
+}
+
+/// Sets the read timeout of the socket to the time left until the request
+/// deadline (if any), failing with [`ErrorKind::TimedOut`] once it has passed
+fn limit_read_to(socket: &UsbIpSocket, deadline: Option<Instant>) -> Result<(), Error> {
+    let Some(deadline) = deadline else {
+        return Ok(());
+    };
+
+    socket
+        .set_read_timeout(Some(time_left(deadline, Instant::now())?))
+        .map_err(Error::NetworkIo)
+}
+
+/// The time left from `now` until the deadline, failing with
+/// [`ErrorKind::TimedOut`] once it has passed
+fn time_left(deadline: Instant, now: Instant) -> Result<Duration, Error> {
+    let remaining = deadline.saturating_duration_since(now);
+
+    if remaining.is_zero() {
+        return Err(Error::NetworkIo(ErrorKind::TimedOut.into()));
+    }
+
+    Ok(remaining)
+}
+
+fn handle_list_devices(
+    socket: &mut UsbIpSocket,
+    peer: SocketAddr,
+    options: &ServerOptions,
+) -> Result<(), Error> {
+    let policy = &options.policy;
+    let op_kind = OperationKind::ListDevices;
+
+    let devices = match list_exported_devices(options.device_mode) {
+        Ok(d) => d,
+        Err(e) => {
+            let _ = socket.send_response_header(op_kind, OperationStatus::Error);
+            return Err(e);
+        }
+    };
+
+    let devices: Vec<_> = devices
+        .into_iter()
+        .filter(|d| policy.is_allowed(&d.info, peer.ip()))
+        .collect();
+
+    tracing::info!("sending {} exported devices", devices.len());
+
+    socket
+        .send_response_header(op_kind, OperationStatus::Ok)
+        .map_err(Error::NetworkIo)?;
+    socket
+        .send_encoded(ListDevicesReply {
+            num_devices: devices.len() as _,
+        })
+        .map_err(Error::NetworkIo)?;
+
+    for device in devices {
+        socket
+            .send_encoded(RawUsbDeviceInfo::from(&device.info))
+            .map_err(Error::NetworkIo)?;
+
+        for iface in device.interfaces {
+            socket.send_encoded(iface).map_err(Error::NetworkIo)?;
+        }
+    }
+
+    // the connection is not handed off to the kernel, so tell the client we're
+    // done rather than leaving it to wait for the socket to be dropped
+    if let Err(e) = socket.shutdown(Shutdown::Both) {
+        tracing::debug!("failed to shut down connection: {e}");
+    }
+
+    Ok(())
+}
+
+fn handle_device_info(
+    socket: &mut UsbIpSocket,
+    peer: SocketAddr,
+    options: &ServerOptions,
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    let policy = &options.policy;
+    let op_kind = OperationKind::DeviceInfo;
+
+    limit_read_to(socket, deadline)?;
+    let request = socket
+        .recv_encoded::<DeviceInfoRequest>()
+        .map_err(Error::NetworkIo)?;
+
+    let device = match request.bus_id.as_c_str().and_then(|b| b.to_str().ok()) {
+        Some(bus_id) => {
+            tracing::info!("device info requested for bus ID `{bus_id}`");
+
+            find_exported_device(bus_id, options.device_mode)?
+                .filter(|d| policy.is_allowed(&d.info, peer.ip()))
+        }
+        None => None,
+    };
+
+    let Some(device) = device else {
+        return socket
+            .send_response_header(op_kind, OperationStatus::NoSuchDevice)
+            .map_err(Error::NetworkIo);
+    };
+
+    socket
+        .send_response_header(op_kind, OperationStatus::Ok)
+        .map_err(Error::NetworkIo)?;
+    socket
+        .send_encoded(DeviceInfoReply {
+            usb_device: RawUsbDeviceInfo::from(&device.info),
+        })
+        .map_err(Error::NetworkIo)?;
+
+    for iface in device.interfaces {
+        socket.send_encoded(iface).map_err(Error::NetworkIo)?;
+    }
+
+    Ok(())
+}
+
+fn handle_import(
+    socket: &mut UsbIpSocket,
+    peer: SocketAddr,
+    options: &ServerOptions,
+    deadline: Option<Instant>,
+) -> Result<(), Error> {
+    let policy = &options.policy;
+    let op_kind = OperationKind::Import;
+
+    limit_read_to(socket, deadline)?;
+    let request = socket
+        .recv_encoded::<ImportRequest>()
+        .map_err(Error::NetworkIo)?;
+
+    let Some(bus_id) = request.bus_id.as_c_str().and_then(|b| b.to_str().ok()) else {
+        tracing::warn!("received import request with invalid bus ID");
+
+        return socket
+            .send_response_header(op_kind, OperationStatus::NoSuchDevice)
+            .map_err(Error::NetworkIo);
+    };
+
+    tracing::info!("import requested for bus ID `{bus_id}`");
+
+    let device = match find_exported_device(bus_id, options.device_mode)? {
+        Some(d) if policy.is_allowed(&d.info, peer.ip()) => d,
+        Some(_) => {
+            tracing::info!("import of `{bus_id}` denied by export policy");
+
+            return socket
+                .send_response_header(op_kind, OperationStatus::NoSuchDevice)
+                .map_err(Error::NetworkIo);
+        }
+        None => {
+            tracing::info!("requested device `{bus_id}` is not exported");
+
+            return socket
+                .send_response_header(op_kind, OperationStatus::NoSuchDevice)
+                .map_err(Error::NetworkIo);
+        }
+    };
+
+    socket
+        .clear_timeouts_for_handoff()
+        .map_err(Error::NetworkIo)?;
+
+    let status = if options.device_mode {
+        export_gadget(socket, bus_id)
+    } else {
+        export_host_device(socket, bus_id)
+    };
+
+    socket
+        .send_response_header(op_kind, status)
+        .map_err(Error::NetworkIo)?;
+
+    if status != OperationStatus::Ok {
+        tracing::info!("import of `{bus_id}` failed with {status:?}");
+        return Ok(());
+    }
+
+    socket
+        .send_encoded(ImportReply {
+            usb_device: RawUsbDeviceInfo::from(&device.info),
+        })
+        .map_err(Error::NetworkIo)?;
+
+    tracing::info!("device `{bus_id}` exported");
+
+    Ok(())
+}
+
+/// Hands the connection off to usbip-host if the device is available
+fn export_host_device(socket: &UsbIpSocket, bus_id: &str) -> OperationStatus {
+    match UsbipHost::device_status(bus_id) {
+        Ok(UsbipHostDeviceStatus::Available) => {
+            match UsbipHost::export_device(bus_id, socket.as_raw_fd()) {
+                Ok(_) => OperationStatus::Ok,
+                Err(e) => {
+                    tracing::error!("failed to export device `{bus_id}`: {e}");
+                    OperationStatus::DeviceError
+                }
+            }
+        }
+        Ok(UsbipHostDeviceStatus::Used) => OperationStatus::DeviceBusy,
+        Ok(UsbipHostDeviceStatus::Error) => OperationStatus::DeviceError,
+        Err(e) => {
+            tracing::error!("failed to read status of device `{bus_id}`: {e}");
+            OperationStatus::DeviceError
+        }
+    }
+}
+
+/// Hands the connection off to usbip-vudc if the UDC is available
+fn export_gadget(socket: &UsbIpSocket, udc: &str) -> OperationStatus {
+    match Vudc::device_status(udc) {
+        Ok(VudcStatus::Available) => match Vudc::export_device(udc, socket.as_raw_fd()) {
+            Ok(_) => OperationStatus::Ok,
+            Err(e) => {
+                tracing::error!("failed to export gadget of `{udc}`: {e}");
+                OperationStatus::DeviceError
+            }
+        },
+        Ok(VudcStatus::Used) => OperationStatus::DeviceBusy,
+        Ok(VudcStatus::Error) => OperationStatus::DeviceError,
+        Err(e) => {
+            tracing::error!("failed to read status of UDC `{udc}`: {e}");
+            OperationStatus::DeviceError
+        }
+    }
+}
+
+/// Lists all the devices which are currently exported
+fn list_exported_devices(device_mode: bool) -> Result<Vec<ExportedDevice>, Error> {
+    if device_mode {
+        return list_exported_gadgets();
+    }
+
+    let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
+
+    let mut enumerator =
+        udev::Enumerator::with_udev(context.clone()).map_err(Error::CreatingUdevEnumerator)?;
+
+    enumerator
+        .match_subsystem("usb")
+        .map_err(Error::CreatingUdevEnumerator)?;
+
+    let mut results = Vec::new();
+
+    for dev in enumerator
+        .scan_devices()
+        .map_err(Error::EnumeratingUdevDevices)?
+    {
+        if driver_name(&dev).as_deref() != Some(USBIP_HOST_DRIVER_NAME) {
+            continue;
+        }
+
+        results.push(read_exported_device(&dev)?);
+    }
+
+    Ok(results)
+}
+
+/// Lists the gadgets bound to the UDCs of usbip-vudc. UDCs without a gadget
+/// have nothing to export and are skipped.
+fn list_exported_gadgets() -> Result<Vec<ExportedDevice>, Error> {
+    let vudc = Vudc::open()?;
+
+    Ok(vudc
+        .udcs()
+        .iter()
+        .filter_map(|udc| udc.usb_device_info())
+        .map(|info| ExportedDevice {
+            info,
+            interfaces: Vec::new(),
+        })
+        .collect())
+}
+
+fn find_exported_device(bus_id: &str, device_mode: bool) -> Result<Option<ExportedDevice>, Error> {
+    if device_mode {
+        return Ok(list_exported_gadgets()?
+            .into_iter()
+            .find(|d| d.info.bus_id == bus_id));
+    }
+
+    let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
+
+    let Ok(dev) = open_usb_device(&context, bus_id) else {
+        return Ok(None);
+    };
+
+    if driver_name(&dev).as_deref() != Some(USBIP_HOST_DRIVER_NAME) {
+        return Ok(None);
+    }
+
+    read_exported_device(&dev).map(Some)
+}
+
+fn read_exported_device(dev: &udev::Device) -> Result<ExportedDevice, Error> {
+    let info = extract_usb_info_from_udev_device(dev).map_err(|e| Error::UsbInfoExtraction {
+        bus_id: dev.sysname().to_string_lossy().into(),
+        error: e,
+    })?;
+
+    let interfaces: Vec<_> = enumerate_interfaces(dev)
+        .map_err(|error| Error::EnumeratingInterfaces {
+            bus_id: info.bus_id.to_string(),
+            error,
+        })?
+        .into_iter()
+        .map(|i| i.info)
+        .collect();
+
+    // the reply announces `bNumInterfaces` interfaces, so sending any other
+    // number would throw the client off
+    if interfaces.len() != info.b_num_interfaces as usize {
+        return Err(Error::InterfaceCountMismatch {
+            bus_id: info.bus_id.to_string(),
+            expected: info.b_num_interfaces,
+            found: interfaces.len(),
+        });
+    }
+
+    Ok(ExportedDevice { info, interfaces })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+
+    #[test]
+    fn request_timeout_covers_the_whole_request() {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // sends the header late, and then never sends the import request
+        let client = thread::spawn(move || {
+            let mut socket = UsbIpSocket::connect(addr).unwrap();
+
+            thread::sleep(Duration::from_secs(1));
+            socket.send_request_header(OperationKind::Import).unwrap();
+            // wait for the server to drop the connection
+            let _ = socket.recv(&mut [0]);
+        });
+
+        let (mut socket, peer) = listener.accept().unwrap();
+        let options = ServerOptions {
+            request_timeout: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let result = handle_connection(&mut socket, peer, &options);
+        drop(socket);
+
+        assert!(
+            matches!(
+                &result,
+                Err(Error::NetworkIo(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+            ),
+            "{result:?}"
+        );
+        // a fresh timeout for the import request would only expire after
+        // about three seconds
+        assert!(start.elapsed() < Duration::from_millis(2600));
+
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn the_time_left_shrinks_towards_the_deadline() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(2);
+
+        assert_eq!(time_left(deadline, start).unwrap(), Duration::from_secs(2));
+        assert_eq!(
+            time_left(deadline, start + Duration::from_millis(1500)).unwrap(),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn no_time_is_left_once_the_deadline_has_passed() {
+        let deadline = Instant::now();
+
+        for now in [deadline, deadline + Duration::from_secs(1)] {
+            let result = time_left(deadline, now);
+
+            assert!(
+                matches!(&result, Err(Error::NetworkIo(e)) if e.kind() == ErrorKind::TimedOut),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_connections_beyond_the_limit() {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = Arc::new(ServerOptions {
+            max_connections: 1,
+            ..Default::default()
+        });
+        let active_connections = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let server = thread::spawn({
+            let stop = stop.clone();
+
+            move || {
+                accept_connections(&listener, &options, &active_connections, || {
+                    stop.load(Ordering::SeqCst)
+                })
+            }
+        });
+
+        // takes up the only connection by never sending a request
+        let mut first = UsbIpSocket::connect(addr).unwrap();
+        first
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        let mut second = UsbIpSocket::connect(addr).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // closed right away instead of waiting for a request
+        assert_eq!(
+            second.recv(&mut [0]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert!(matches!(
+            first.recv(&mut [0]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+        ));
+
+        // wakes up the accept loop so it sees the stop flag
+        stop.store(true, Ordering::SeqCst);
+        UsbIpSocket::connect(addr).unwrap();
+
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,397 @@
+use std::{io, os::fd::AsRawFd};
+
+use crate::{
+    BusIdKey,
+    drivers::{DeviceLookupError, SysfsIoError, host::UsbipHost, open_usb_device},
+    net::UsbIpSocket,
+    proto::{ExportRequest, OperationError, OperationKind, RawUsbDeviceInfo},
+    server::{
+        bind::{self, BindOutcome, bind_device},
+        unbind::{self, unbind_device},
+    },
+    util::{UsbInfoExtractError, extract_usb_info_from_udev_device},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Binding(bind::Error),
+
+    #[error("Failed to create udev context ({0})")]
+    CreatingUdevContext(io::Error),
+    #[error(transparent)]
+    UdevDeviceNotFound(DeviceLookupError),
+    #[error("Failed to query USB device with bus ID `{bus_id}` ({error})")]
+    UsbInfoExtraction {
+        bus_id: String,
+        error: UsbInfoExtractError,
+    },
+
+    #[error("Network connection failed ({0})")]
+    NetworkIo(io::Error),
+    #[error("usbip network operation failed ({0})")]
+    Operation(#[from] OperationError),
+
+    #[error("Failed to hand off the connection to `usbip-host`: {0}")]
+    HandingOffSocket(SysfsIoError),
+}
+
+/// A device which is currently exported to a remote host with
+/// [`export_device`].
+///
+/// When dropped, the device is unbound from usbip-host (which tears down the
+/// connection) and rebound to its original driver. Use [`ExportedDevice::stop`]
+/// to observe any errors that occur while doing so.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ExportedDevice {
+    bus_id: String,
+    /// Whether the device was bound to usbip-host by us (and should be unbound
+    /// again on exit) or was already bound before we started
+    bound_by_us: bool,
+    /// The driver which was displaced by usbip-host when binding
+    previous_driver: Option<String>,
+    stopped: bool,
+    #[derivative(Debug = "ignore")]
+    backend: Box<dyn Backend + Send>,
+}
+
+impl ExportedDevice {
+    pub fn bus_id(&self) -> &str {
+        &self.bus_id
+    }
+
+    /// Key which orders devices by bus ID in natural order (see
+    /// [`compare_bus_ids`](crate::compare_bus_ids))
+    pub fn sort_key(&self) -> BusIdKey<'_> {
+        BusIdKey(&self.bus_id)
+    }
+
+    /// The driver the device was bound to before it was bound to usbip-host
+    /// for this export. `None` if the device had no driver or was already
+    /// bound to usbip-host.
+    ///
+    /// This is only kept for display. Restoring the driver on exit is left to
+    /// [`unbind_device`], which reads it from the state file written when
+    /// binding.
+    pub fn previous_driver(&self) -> Option<&str> {
+        self.previous_driver.as_deref()
+    }
+
+    pub fn stop(mut self) -> Result<(), unbind::Error> {
+        self.cleanup()
+    }
+
+    fn cleanup(&mut self) -> Result<(), unbind::Error> {
+        if std::mem::replace(&mut self.stopped, true) || !self.bound_by_us {
+            return Ok(());
+        }
+
+        tracing::debug!("unbinding exported device `{}`", self.bus_id);
+
+        self.backend.unbind(&self.bus_id)
+    }
+}
+
+impl Drop for ExportedDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.cleanup() {
+            tracing::error!("failed to unbind exported device `{}`: {e}", self.bus_id);
+        }
+    }
+}
+
+/// What exporting does to the system, so that the export sequence can be
+/// tested without a USB device
+trait Backend {
+    fn bind(&mut self, bus_id: &str) -> Result<BindOutcome, bind::Error>;
+    fn query_device(&mut self, bus_id: &str) -> Result<RawUsbDeviceInfo, Error>;
+    /// Hands the connected socket off to usbip-host
+    fn hand_off(&mut self, bus_id: &str, socket: &UsbIpSocket) -> Result<(), SysfsIoError>;
+    fn unbind(&mut self, bus_id: &str) -> Result<(), unbind::Error>;
+}
+
+struct SystemBackend;
+
+impl Backend for SystemBackend {
+    fn bind(&mut self, bus_id: &str) -> Result<BindOutcome, bind::Error> {
+        bind_device(bus_id)
+    }
+
+    fn query_device(&mut self, bus_id: &str) -> Result<RawUsbDeviceInfo, Error> {
+        query_local_device(bus_id)
+    }
+
+    fn hand_off(&mut self, bus_id: &str, socket: &UsbIpSocket) -> Result<(), SysfsIoError> {
+        UsbipHost::export_device(bus_id, socket.as_raw_fd())
+    }
+
+    fn unbind(&mut self, bus_id: &str) -> Result<(), unbind::Error> {
+        unbind_device(bus_id).map(|_| ())
+    }
+}
+
+/// Binds a local USB device to usbip-host and pushes it to a remote host which
+/// is waiting to import devices (the inverse of attaching, also known as
+/// `usbip connect`).
+///
+/// The remote host has to accept [`OperationKind::Export`] requests, which the
+/// daemon of this crate does not. Like `usbip connect` of the reference
+/// implementation, only the status of the reply header is read, since the
+/// reference peers don't send an [`ExportReply`](crate::proto::ExportReply)
+/// body after it.
+///
+/// If the device was already bound to usbip-host, it is left bound when the
+/// returned [`ExportedDevice`] is dropped.
+pub fn export_device(host: &str, port: u16, bus_id: &str) -> Result<ExportedDevice, Error> {
+    export_device_with(host, port, bus_id, Box::new(SystemBackend))
+}
+
+fn export_device_with(
+    host: &str,
+    port: u16,
+    bus_id: &str,
+    mut backend: Box<dyn Backend + Send>,
+) -> Result<ExportedDevice, Error> {
+    let (bound_by_us, previous_driver) = match backend.bind(bus_id) {
+        Ok(outcome) => (true, outcome.previous_driver),
+        Err(bind::Error::AlreadyBoundToUsbipHost(_)) => (false, None),
+        Err(e) => return Err(Error::Binding(e)),
+    };
+
+    tracing::debug!(
+        "device bound to usbip-host (bound_by_us = {bound_by_us}, previous_driver = {previous_driver:?})"
+    );
+
+    let usb_device = backend.query_device(bus_id);
+
+    // from here on, any failures will unbind the device again when the guard
+    // is dropped
+    let mut exported = ExportedDevice {
+        bus_id: bus_id.into(),
+        bound_by_us,
+        previous_driver,
+        stopped: false,
+        backend,
+    };
+
+    let usb_device = usb_device?;
+
+    let mut socket = UsbIpSocket::connect_host_and_port(host, port).map_err(Error::NetworkIo)?;
+
+    let op_kind = OperationKind::Export;
+
+    socket
+        .send_request_header(op_kind)
+        .map_err(Error::NetworkIo)?;
+    socket
+        .send_encoded(ExportRequest { usb_device })
+        .map_err(Error::NetworkIo)?;
+
+    socket
+        .recv_reply_header(op_kind)
+        .map_err(Error::NetworkIo)??;
+
+    // usbip-host takes its own reference to the socket, so it is fine for ours
+    // to be closed once this function returns
+    exported
+        .backend
+        .hand_off(bus_id, &socket)
+        .map_err(Error::HandingOffSocket)?;
+
+    tracing::info!("device `{bus_id}` exported to {host}:{port}");
+
+    Ok(exported)
+}
+
+fn query_local_device(bus_id: &str) -> Result<RawUsbDeviceInfo, Error> {
+    let context = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
+
+    let usb_device = open_usb_device(&context, bus_id).map_err(Error::UdevDeviceNotFound)?;
+
+    let info =
+        extract_usb_info_from_udev_device(&usb_device).map_err(|e| Error::UsbInfoExtraction {
+            bus_id: bus_id.into(),
+            error: e,
+        })?;
+
+    Ok((&info).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::proto::OperationStatus;
+
+    /// Records the steps of the export instead of touching the system
+    struct MockBackend {
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        already_bound: bool,
+    }
+
+    impl Backend for MockBackend {
+        fn bind(&mut self, bus_id: &str) -> Result<BindOutcome, bind::Error> {
+            self.calls.lock().unwrap().push("bind");
+
+            if self.already_bound {
+                return Err(bind::Error::AlreadyBoundToUsbipHost(bus_id.into()));
+            }
+
+            Ok(BindOutcome {
+                bus_id: bus_id.into(),
+                previous_driver: Some("usb-storage".into()),
+            })
+        }
+
+        fn query_device(&mut self, bus_id: &str) -> Result<RawUsbDeviceInfo, Error> {
+            self.calls.lock().unwrap().push("query");
+
+            Ok((&crate::UsbDeviceInfo {
+                bus_id: bus_id.into(),
+                ..Default::default()
+            })
+                .into())
+        }
+
+        fn hand_off(&mut self, _bus_id: &str, _socket: &UsbIpSocket) -> Result<(), SysfsIoError> {
+            self.calls.lock().unwrap().push("hand off");
+            Ok(())
+        }
+
+        fn unbind(&mut self, _bus_id: &str) -> Result<(), unbind::Error> {
+            self.calls.lock().unwrap().push("unbind");
+            Ok(())
+        }
+    }
+
+    /// Accepts a single export request and replies to it with the given status
+    fn mock_server(status: OperationStatus) -> (SocketAddr, JoinHandle<String>) {
+        let listener = UsbIpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+
+            assert_eq!(
+                socket.recv_request_header().unwrap().unwrap(),
+                OperationKind::Export
+            );
+            let request = socket.recv_encoded::<ExportRequest>().unwrap();
+
+            socket
+                .send_response_header(OperationKind::Export, status)
+                .unwrap();
+
+            request
+                .usb_device
+                .bus_id
+                .as_c_str()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        });
+
+        (addr, server)
+    }
+
+    fn export(
+        addr: SocketAddr,
+        already_bound: bool,
+    ) -> (Result<ExportedDevice, Error>, Arc<Mutex<Vec<&'static str>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let backend = MockBackend {
+            calls: calls.clone(),
+            already_bound,
+        };
+
+        let result = export_device_with(
+            &addr.ip().to_string(),
+            addr.port(),
+            "1-2",
+            Box::new(backend),
+        );
+
+        (result, calls)
+    }
+
+    #[test]
+    fn binds_exports_and_unbinds_when_stopped() {
+        let (addr, server) = mock_server(OperationStatus::Ok);
+
+        let (result, calls) = export(addr, false);
+        let exported = result.unwrap();
+
+        assert_eq!(server.join().unwrap(), "1-2");
+        assert_eq!(exported.previous_driver(), Some("usb-storage"));
+        assert_eq!(*calls.lock().unwrap(), ["bind", "query", "hand off"]);
+
+        exported.stop().unwrap();
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["bind", "query", "hand off", "unbind"]
+        );
+    }
+
+    #[test]
+    fn unbinds_when_the_remote_host_refuses() {
+        let (addr, server) = mock_server(OperationStatus::Failure);
+
+        let (result, calls) = export(addr, false);
+        server.join().unwrap();
+
+        assert!(matches!(
+            result,
+            Err(Error::Operation(OperationError::RequestFailed))
+        ));
+        assert_eq!(*calls.lock().unwrap(), ["bind", "query", "unbind"]);
+    }
+
+    #[test]
+    fn unbinds_when_dropped() {
+        let (addr, server) = mock_server(OperationStatus::Ok);
+
+        let (result, calls) = export(addr, false);
+        server.join().unwrap();
+        drop(result.unwrap());
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["bind", "query", "hand off", "unbind"]
+        );
+    }
+
+    #[test]
+    fn leaves_devices_which_were_already_bound() {
+        let (addr, server) = mock_server(OperationStatus::Ok);
+
+        let (result, calls) = export(addr, true);
+        server.join().unwrap();
+        let exported = result.unwrap();
+
+        assert_eq!(exported.previous_driver(), None);
+        exported.stop().unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), ["bind", "query", "hand off"]);
+    }
+
+    #[test]
+    fn leaves_devices_which_were_already_bound_on_errors() {
+        let (addr, server) = mock_server(OperationStatus::NoSuchDevice);
+
+        let (result, calls) = export(addr, true);
+        server.join().unwrap();
+
+        assert!(matches!(
+            result,
+            Err(Error::Operation(OperationError::NoSuchDevice))
+        ));
+        assert_eq!(*calls.lock().unwrap(), ["bind", "query"]);
+    }
+}
@@ -3,8 +3,14 @@ use std::io;
 use serde::Serialize;
 
 use crate::{
-    UsbDeviceInfo,
-    hwdb::{get_class_display_strings, get_device_display_strings},
+    BusIdKey, DeviceInterface, UsbDeviceInfo,
+    drivers::{enumerate_interfaces, vhci::is_vhci_attached},
+    hwdb::{IdSource, IdSourceError, NameResolver},
+    proto::{
+        UsbInterfaceInfo,
+        class::{USB_CLASS_HUB, sysfs_class_value},
+    },
+    server::matcher::{DeviceMatcher, matches_any},
     util::{UsbInfoExtractError, extract_usb_info_from_udev_device},
 };
 
@@ -22,6 +28,9 @@ pub enum Error {
         bus_id: String,
         error: UsbInfoExtractError,
     },
+
+    #[error("Failed to set up USB name lookup ({0})")]
+    NameLookup(#[from] IdSourceError),
 }
 
 #[derive(Debug, Serialize)]
@@ -34,24 +43,57 @@ pub struct LocalExportableDevice {
     pub class: Option<String>,
     pub sub_class: Option<String>,
     pub protocol: Option<String>,
+
+    pub interfaces: Vec<DeviceInterface>,
+}
+
+impl LocalExportableDevice {
+    /// Key which orders devices by bus ID in natural order (see
+    /// [`compare_bus_ids`](crate::compare_bus_ids))
+    pub fn sort_key(&self) -> BusIdKey<'_> {
+        BusIdKey(&self.device_info.bus_id)
+    }
+}
+
+/// Options for [`list_local_exportable_devices_with_options`]
+#[derive(Debug, Clone, Default)]
+pub struct ListLocalOptions {
+    /// Fail the whole listing if any device can't be read, instead of skipping
+    /// it. Devices which are unplugged while the list is being built can't be
+    /// read anymore, so this is off by default.
+    pub strict: bool,
+    /// Devices matching any of these are left out of the list
+    pub exclude: Vec<DeviceMatcher>,
+    /// Where the names of the devices are looked up, instead of the process
+    /// wide source set with [`set_id_source`](crate::hwdb::set_id_source)
+    pub id_source: Option<IdSource>,
 }
 
 /// Lists all local (exportable) devices. This includes all USB devices which
-/// are not hubs and are not virtual (attached by vhci_hcd) devices.
+/// are not hubs and are not virtual (attached by vhci_hcd) devices. Devices
+/// which can't be read (most likely because they were just unplugged) are
+/// skipped.
 pub fn list_local_exportable_devices() -> Result<Vec<LocalExportableDevice>, Error> {
-    #[cfg(feature = "runtime-hwdb")]
-    let hwdb = udev::Hwdb::new()?;
+    list_local_exportable_devices_with_options(&ListLocalOptions::default())
+}
+
+/// Same as [`list_local_exportable_devices`], but allows failing on devices
+/// which can't be read and excluding devices from the list
+pub fn list_local_exportable_devices_with_options(
+    options: &ListLocalOptions,
+) -> Result<Vec<LocalExportableDevice>, Error> {
+    let names = NameResolver::new(options.id_source.as_ref())?;
 
     let udev = udev::Udev::new().map_err(Error::CreatingUdevContext)?;
 
     let mut enumerator =
-        udev::Enumerator::with_udev(udev).map_err(Error::CreatingUdevEnumerator)?;
+        udev::Enumerator::with_udev(udev.clone()).map_err(Error::CreatingUdevEnumerator)?;
 
     enumerator
         .match_subsystem("usb")
         .map_err(Error::CreatingUdevEnumerator)?;
     enumerator
-        .nomatch_attribute("bDeviceClass", "09")
+        .nomatch_attribute("bDeviceClass", sysfs_class_value(USB_CLASS_HUB))
         .map_err(Error::CreatingUdevEnumerator)?;
 
     let mut results = Vec::new();
@@ -67,38 +109,163 @@ pub fn list_local_exportable_devices() -> Result<Vec<LocalExportableDevice>, Err
             continue;
         }
 
-        // TODO: Ignore devices attached to vhci_hcd
+        // Devices imported from another host can't be bound to usbip-host, so
+        // they are not exportable
+        if is_vhci_attached(&dev) {
+            continue;
+        }
 
-        let device_info =
-            extract_usb_info_from_udev_device(&dev).map_err(|e| Error::UsbInfoExtraction {
-                bus_id: dev.sysname().to_string_lossy().into(),
-                error: e,
-            })?;
+        let Some(device_info) = skip_unreadable(
+            &dev.sysname().to_string_lossy(),
+            extract_usb_info_from_udev_device(&dev),
+            options.strict,
+        )?
+        else {
+            continue;
+        };
 
-        let (vendor, product) = get_device_display_strings(
-            #[cfg(feature = "runtime-hwdb")]
-            &hwdb,
-            device_info.id_vendor,
-            device_info.id_product,
-        );
+        let (vendor, product) =
+            names.device_display_strings(device_info.id_vendor, device_info.id_product);
+
+        let interfaces = read_interfaces(&dev);
+
+        let raw_interfaces: Vec<_> = interfaces.iter().map(|(_, iface)| iface.clone()).collect();
 
-        let (class, sub_class, protocol) = get_class_display_strings(
-            #[cfg(feature = "runtime-hwdb")]
-            &hwdb,
+        let (class, sub_class, protocol) = names.device_class_display_strings(
             device_info.b_device_class,
             device_info.b_device_sub_class,
             device_info.b_device_protocol,
+            &raw_interfaces,
         );
 
-        results.push(LocalExportableDevice {
+        let interfaces = interfaces
+            .into_iter()
+            .map(|(bus_id, iface)| DeviceInterface::new(&names, bus_id, &iface))
+            .collect();
+
+        let device = LocalExportableDevice {
             device_info,
             vendor,
             product,
             class,
             sub_class,
             protocol,
-        });
+            interfaces,
+        };
+
+        if matches_any(&options.exclude, &device) {
+            tracing::debug!("excluding device `{}`", device.device_info.bus_id);
+            continue;
+        }
+
+        results.push(device);
     }
 
     Ok(results)
 }
+
+/// Skips a device which could not be read (most likely because it was just
+/// unplugged), unless `strict` is set
+fn skip_unreadable(
+    bus_id: &str,
+    device_info: Result<UsbDeviceInfo, UsbInfoExtractError>,
+    strict: bool,
+) -> Result<Option<UsbDeviceInfo>, Error> {
+    match device_info {
+        Ok(info) => Ok(Some(info)),
+        Err(error) if strict => Err(Error::UsbInfoExtraction {
+            bus_id: bus_id.into(),
+            error,
+        }),
+        Err(error) => {
+            tracing::warn!("skipping device `{bus_id}` which could not be read ({error})");
+            Ok(None)
+        }
+    }
+}
+
+/// Reads the interfaces of the device's active configuration along with their
+/// bus IDs. This is only used for display, so any failures are ignored.
+fn read_interfaces(dev: &udev::Device) -> Vec<(String, UsbInterfaceInfo)> {
+    match enumerate_interfaces(dev) {
+        Ok(interfaces) => interfaces.into_iter().map(|i| (i.bus_id, i.info)).collect(),
+        Err(e) => {
+            tracing::debug!(
+                "failed to read interfaces of `{}` ({e})",
+                dev.sysname().to_string_lossy()
+            );
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UsbSpeed;
+
+    /// The devices `1-1` to `1-3`, of which `1-2` was unplugged before its
+    /// attributes could be read
+    fn devices() -> Vec<(&'static str, Result<UsbDeviceInfo, UsbInfoExtractError>)> {
+        ["1-1", "1-2", "1-3"]
+            .into_iter()
+            .map(|bus_id| {
+                let info = match bus_id {
+                    "1-2" => Err(UsbInfoExtractError::AttributeMissing("idVendor".into())),
+                    _ => Ok(UsbDeviceInfo {
+                        sys_path: format!("/sys/devices/platform/dummy_hcd.0/usb1/{bus_id}"),
+                        bus_id: bus_id.into(),
+                        bus_num: 1,
+                        dev_num: 2,
+                        speed: UsbSpeed::High,
+                        id_vendor: 0x1d6b,
+                        id_product: 0x0104,
+                        bcd_device: 0x0100,
+                        b_device_class: 0,
+                        b_device_sub_class: 0,
+                        b_device_protocol: 0,
+                        b_configuration_value: Some(1),
+                        b_num_configurations: 1,
+                        b_num_interfaces: 1,
+                    }),
+                };
+
+                (bus_id, info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skips_devices_which_can_not_be_read() {
+        let listed: Vec<_> = devices()
+            .into_iter()
+            .map(|(bus_id, info)| skip_unreadable(bus_id, info, false))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .map(|info| info.bus_id)
+            .collect();
+
+        assert_eq!(listed, ["1-1", "1-3"]);
+    }
+
+    #[test]
+    fn fails_on_devices_which_can_not_be_read_when_strict() {
+        let result = devices()
+            .into_iter()
+            .map(|(bus_id, info)| skip_unreadable(bus_id, info, true))
+            .collect::<Result<Vec<_>, _>>();
+
+        assert!(
+            matches!(
+                &result,
+                Err(Error::UsbInfoExtraction {
+                    bus_id,
+                    error: UsbInfoExtractError::AttributeMissing(name),
+                }) if bus_id == "1-2" && name == "idVendor"
+            ),
+            "{result:?}"
+        );
+    }
+}
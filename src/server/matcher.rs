@@ -0,0 +1,225 @@
+//! Matching of local devices by bus ID, vendor/product ID or class, which is
+//! used to exclude devices from bulk operations (like binding all devices)
+
+use std::{fmt, str::FromStr};
+
+use crate::server::list_local::LocalExportableDevice;
+
+/// Selects local devices by one of their properties. Parsed from the following
+/// forms:
+///
+/// - `1-1.2`: the bus ID of the device
+/// - `046d:c52b`: the vendor and product ID of the device (in hex), the product
+///   ID may be `*` to match all devices of the vendor
+/// - `class=03`: the class of the device or any of its interfaces (in hex)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceMatcher {
+    BusId(String),
+    VendorProduct {
+        id_vendor: u16,
+        /// `None` matches any product of the vendor
+        id_product: Option<u16>,
+    },
+    Class(u8),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MatcherParseError {
+    #[error("Empty device matcher")]
+    Empty,
+    #[error("Invalid vendor ID `{0}` (expected 4 hex digits)")]
+    InvalidVendorId(String),
+    #[error("Invalid product ID `{0}` (expected 4 hex digits or `*`)")]
+    InvalidProductId(String),
+    #[error("Invalid class `{0}` (expected 2 hex digits)")]
+    InvalidClass(String),
+}
+
+impl DeviceMatcher {
+    /// Returns whether the device is selected by this matcher. Class matchers
+    /// also match the classes of the device's interfaces, since most devices
+    /// (e.g. keyboards) only declare their class on the interfaces.
+    pub fn matches(&self, device: &LocalExportableDevice) -> bool {
+        let info = &device.device_info;
+
+        match self {
+            Self::BusId(bus_id) => info.bus_id == bus_id.as_str(),
+            Self::VendorProduct {
+                id_vendor,
+                id_product,
+            } => *id_vendor == info.id_vendor && id_product.is_none_or(|p| p == info.id_product),
+            Self::Class(class) => {
+                info.b_device_class == *class
+                    || device
+                        .interfaces
+                        .iter()
+                        .any(|i| i.b_interface_class == *class)
+            }
+        }
+    }
+}
+
+/// Returns whether any of the matchers selects the device
+pub fn matches_any(matchers: &[DeviceMatcher], device: &LocalExportableDevice) -> bool {
+    matchers.iter().any(|m| m.matches(device))
+}
+
+impl FromStr for DeviceMatcher {
+    type Err = MatcherParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if s.is_empty() {
+            return Err(MatcherParseError::Empty);
+        }
+
+        if let Some(class) = s.strip_prefix("class=") {
+            return parse_hex::<u8>(class, 2)
+                .map(Self::Class)
+                .ok_or_else(|| MatcherParseError::InvalidClass(class.into()));
+        }
+
+        // bus IDs never contain a colon (only interface bus IDs do, and those
+        // contain a dot after it)
+        let Some((vendor, product)) = s.split_once(':') else {
+            return Ok(Self::BusId(s.into()));
+        };
+
+        let id_vendor = parse_hex::<u16>(vendor, 4)
+            .ok_or_else(|| MatcherParseError::InvalidVendorId(vendor.into()))?;
+        let id_product = match product {
+            "*" => None,
+            p => Some(
+                parse_hex::<u16>(p, 4)
+                    .ok_or_else(|| MatcherParseError::InvalidProductId(p.into()))?,
+            ),
+        };
+
+        Ok(Self::VendorProduct {
+            id_vendor,
+            id_product,
+        })
+    }
+}
+
+fn parse_hex<T: TryFrom<u32>>(s: &str, max_digits: usize) -> Option<T> {
+    if s.is_empty() || s.len() > max_digits {
+        return None;
+    }
+
+    u32::from_str_radix(s, 16).ok()?.try_into().ok()
+}
+
+impl fmt::Display for DeviceMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BusId(bus_id) => write!(f, "{bus_id}"),
+            Self::VendorProduct {
+                id_vendor,
+                id_product: Some(id_product),
+            } => write!(f, "{id_vendor:04x}:{id_product:04x}"),
+            Self::VendorProduct {
+                id_vendor,
+                id_product: None,
+            } => write!(f, "{id_vendor:04x}:*"),
+            Self::Class(class) => write!(f, "class={class:02x}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceInterface, UsbDeviceInfo};
+
+    fn parse(s: &str) -> Result<DeviceMatcher, MatcherParseError> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_each_form() {
+        assert_eq!(
+            parse("1-1.2").unwrap(),
+            DeviceMatcher::BusId("1-1.2".into())
+        );
+        assert_eq!(
+            parse("046d:c52b").unwrap(),
+            DeviceMatcher::VendorProduct {
+                id_vendor: 0x046d,
+                id_product: Some(0xc52b),
+            }
+        );
+        assert_eq!(
+            parse("046D:*").unwrap(),
+            DeviceMatcher::VendorProduct {
+                id_vendor: 0x046d,
+                id_product: None,
+            }
+        );
+        assert_eq!(parse(" class=03 ").unwrap(), DeviceMatcher::Class(0x03));
+    }
+
+    #[test]
+    fn rejects_invalid_matchers() {
+        assert!(matches!(parse("  "), Err(MatcherParseError::Empty)));
+        assert!(matches!(
+            parse("046dd:c52b"),
+            Err(MatcherParseError::InvalidVendorId(_))
+        ));
+        assert!(matches!(
+            parse("046d:"),
+            Err(MatcherParseError::InvalidProductId(_))
+        ));
+        assert!(matches!(
+            parse("046d:xyz"),
+            Err(MatcherParseError::InvalidProductId(_))
+        ));
+        assert!(matches!(
+            parse("class=103"),
+            Err(MatcherParseError::InvalidClass(_))
+        ));
+    }
+
+    #[test]
+    fn displays_the_parsed_form() {
+        for text in ["1-1.2", "046d:c52b", "046d:*", "class=03"] {
+            assert_eq!(parse(text).unwrap().to_string(), text);
+        }
+    }
+
+    #[test]
+    fn class_matchers_match_interfaces_too() {
+        let device = LocalExportableDevice {
+            device_info: UsbDeviceInfo {
+                bus_id: "1-1".into(),
+                id_vendor: 0x046d,
+                id_product: 0xc52b,
+                ..Default::default()
+            },
+            vendor: None,
+            product: None,
+            class: None,
+            sub_class: None,
+            protocol: None,
+            interfaces: vec![DeviceInterface {
+                bus_id: "1-1:1.0".into(),
+                b_interface_class: 0x03,
+                b_interface_sub_class: 0x01,
+                b_interface_protocol: 0x01,
+                class: None,
+                sub_class: None,
+                protocol: None,
+            }],
+        };
+
+        assert!(parse("class=03").unwrap().matches(&device));
+        assert!(!parse("class=08").unwrap().matches(&device));
+        assert!(parse("046d:*").unwrap().matches(&device));
+        assert!(!parse("046d:c534").unwrap().matches(&device));
+        assert!(matches_any(
+            &[parse("1-2").unwrap(), parse("1-1").unwrap()],
+            &device
+        ));
+    }
+}
@@ -1,3 +1,7 @@
 pub mod bind;
+pub mod daemon;
+pub mod export;
 pub mod list_local;
+pub mod matcher;
+pub mod policy;
 pub mod unbind;
@@ -0,0 +1,135 @@
+//! Export policies used by the server to restrict which of the devices bound to
+//! usbip-host are visible to (and importable by) which clients
+
+use std::net::IpAddr;
+
+use crate::UsbDeviceInfo;
+
+/// A single allow or deny rule. Every criterion which is set must match for the
+/// rule to match, so a rule with no criteria matches everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyRule {
+    /// Bus ID of the exported device on the server
+    pub bus_id: Option<String>,
+    pub id_vendor: Option<u16>,
+    pub id_product: Option<u16>,
+    /// IP address of the connecting client
+    pub client: Option<IpAddr>,
+}
+
+impl PolicyRule {
+    pub fn matches(&self, device: &UsbDeviceInfo, client: IpAddr) -> bool {
+        self.bus_id.as_ref().is_none_or(|b| b == device.bus_id)
+            && self.id_vendor.is_none_or(|v| v == device.id_vendor)
+            && self.id_product.is_none_or(|p| p == device.id_product)
+            && self
+                .client
+                .is_none_or(|c| c.to_canonical() == client.to_canonical())
+    }
+}
+
+/// Decides which exported devices each client is allowed to see and import.
+/// The default policy allows everything.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportPolicy {
+    /// If not empty, only devices matching at least one of these rules are
+    /// exported
+    pub allow: Vec<PolicyRule>,
+    /// Devices matching any of these rules are never exported (takes
+    /// precedence over `allow`)
+    pub deny: Vec<PolicyRule>,
+}
+
+impl ExportPolicy {
+    /// Returns whether the given client may list and import the device
+    pub fn is_allowed(&self, device: &UsbDeviceInfo, client: IpAddr) -> bool {
+        if self.deny.iter().any(|r| r.matches(device, client)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|r| r.matches(device, client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 21));
+
+    fn device(bus_id: &str, id_vendor: u16, id_product: u16) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            bus_id: bus_id.into(),
+            id_vendor,
+            id_product,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_policy_allows_everything() {
+        let policy = ExportPolicy::default();
+
+        assert!(policy.is_allowed(&device("1-1", 0x046d, 0xc52b), CLIENT));
+    }
+
+    #[test]
+    fn allow_rules_restrict_the_exported_devices() {
+        let policy = ExportPolicy {
+            allow: vec![PolicyRule {
+                id_vendor: Some(0x046d),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        assert!(policy.is_allowed(&device("1-1", 0x046d, 0xc52b), CLIENT));
+        assert!(!policy.is_allowed(&device("1-2", 0x0781, 0x5581), CLIENT));
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() {
+        let policy = ExportPolicy {
+            allow: vec![PolicyRule {
+                id_vendor: Some(0x046d),
+                ..Default::default()
+            }],
+            deny: vec![PolicyRule {
+                bus_id: Some("1-1".into()),
+                client: Some(CLIENT),
+                ..Default::default()
+            }],
+        };
+
+        assert!(!policy.is_allowed(&device("1-1", 0x046d, 0xc52b), CLIENT));
+        assert!(policy.is_allowed(&device("1-1", 0x046d, 0xc52b), OTHER_CLIENT));
+        assert!(policy.is_allowed(&device("1-2", 0x046d, 0xc52b), CLIENT));
+    }
+
+    #[test]
+    fn rules_match_only_if_every_criterion_matches() {
+        let rule = PolicyRule {
+            id_vendor: Some(0x046d),
+            id_product: Some(0xc52b),
+            ..Default::default()
+        };
+
+        assert!(rule.matches(&device("1-1", 0x046d, 0xc52b), CLIENT));
+        assert!(!rule.matches(&device("1-1", 0x046d, 0xc534), CLIENT));
+        assert!(PolicyRule::default().matches(&device("1-1", 0x0781, 0x5581), CLIENT));
+    }
+
+    #[test]
+    fn clients_match_ipv4_mapped_addresses() {
+        let rule = PolicyRule {
+            client: Some(CLIENT),
+            ..Default::default()
+        };
+        let mapped = IpAddr::V6("::ffff:192.168.1.20".parse().unwrap());
+
+        assert!(rule.matches(&device("1-1", 0x046d, 0xc52b), mapped));
+    }
+}
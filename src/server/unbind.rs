@@ -1,17 +1,20 @@
 use std::{ffi::OsStr, io};
 
 use crate::drivers::{
-    DriverUnbindingError, SysfsIoError,
-    host::{MatchListOperation, UsbipHost},
-    unbind_usb_driver,
+    DeviceLookupError, DriverUnbindingError, SysfsIoError, bind_usb_driver, driver_name,
+    host::{
+        MatchListOperation, USBIP_HOST_DRIVER_NAME, UsbipHost,
+        state::{delete_original_driver, read_original_driver},
+    },
+    open_usb_device, unbind_usb_driver,
 };
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to create udev context ({0})")]
     CreareUdevContext(io::Error),
-    #[error("USB device not found ({0})")]
-    UdevDeviceNotFound(io::Error),
+    #[error(transparent)]
+    UdevDeviceNotFound(DeviceLookupError),
 
     #[error("USB device was not already bound to `usbip-host` driver")]
     NotAlreadyBound,
@@ -30,27 +33,29 @@ pub enum Error {
     RebindingDevice(SysfsIoError),
 }
 
-pub fn unbind_device(local_bus_id: &str) -> Result<(), Error> {
+/// Result of a successful [`unbind_device`]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct UnbindOutcome {
+    pub bus_id: String,
+    /// The driver the device was bound to before it was bound to usbip-host,
+    /// as recorded by [`bind_device`](crate::server::bind::bind_device), or
+    /// `None` if no driver was recorded and the kernel was asked to find one
+    pub original_driver: Option<String>,
+}
+
+pub fn unbind_device(local_bus_id: &str) -> Result<UnbindOutcome, Error> {
     let context = udev::Udev::new().map_err(Error::CreareUdevContext)?;
 
-    let usb_device = udev::Device::from_subsystem_sysname_with_context(
-        context.clone(),
-        "usb".into(),
-        local_bus_id.into(),
-    )
-    .map_err(Error::UdevDeviceNotFound)?;
-
-    if usb_device
-        .driver()
-        .is_none_or(|d| d != OsStr::new("usbip-host"))
-    {
+    let usb_device = open_usb_device(&context, local_bus_id).map_err(Error::UdevDeviceNotFound)?;
+
+    if driver_name(&usb_device).as_deref() != Some(USBIP_HOST_DRIVER_NAME) {
         return Err(Error::NotAlreadyBound);
     }
 
-    unbind_usb_driver(OsStr::new("usbip-host"), local_bus_id).map_err(|e| {
+    unbind_usb_driver(OsStr::new(USBIP_HOST_DRIVER_NAME), local_bus_id).map_err(|e| {
         Error::UnbindingDriver {
             source: e,
-            driver: "usbip-host".into(),
+            driver: USBIP_HOST_DRIVER_NAME.into(),
             bus_id: local_bus_id.into(),
         }
     })?;
@@ -58,7 +63,46 @@ pub fn unbind_device(local_bus_id: &str) -> Result<(), Error> {
     UsbipHost::update_bus_id_match_list(local_bus_id, MatchListOperation::Remove)
         .map_err(Error::UpdatingMatchList)?;
 
-    UsbipHost::trigger_device_rebind(local_bus_id).map_err(Error::RebindingDevice)?;
+    let original_driver = restore_original_driver(local_bus_id)?;
+
+    Ok(UnbindOutcome {
+        bus_id: local_bus_id.into(),
+        original_driver,
+    })
+}
+
+/// Binds the device back to the driver recorded when it was bound to
+/// usbip-host. If no driver was recorded (e.g. the device was bound by another
+/// tool) or binding it fails, the kernel is asked to find a driver instead.
+///
+/// Returns the recorded driver (if any).
+pub(crate) fn restore_original_driver(local_bus_id: &str) -> Result<Option<String>, Error> {
+    let original_driver = read_original_driver(local_bus_id).unwrap_or_else(|e| {
+        tracing::warn!("{e}");
+        None
+    });
+
+    let restored = match &original_driver {
+        Some(driver) => match bind_usb_driver(OsStr::new(driver), local_bus_id) {
+            Ok(()) => {
+                tracing::debug!("restored original driver `{driver}` of `{local_bus_id}`");
+                true
+            }
+            Err(e) => {
+                tracing::warn!("failed to restore original driver `{driver}`: {e}");
+                false
+            }
+        },
+        None => false,
+    };
+
+    if !restored {
+        UsbipHost::trigger_device_rebind(local_bus_id).map_err(Error::RebindingDevice)?;
+    }
+
+    if let Err(e) = delete_original_driver(local_bus_id) {
+        tracing::warn!("{e}");
+    }
 
-    Ok(())
+    Ok(original_driver)
 }
@@ -0,0 +1,171 @@
+//! Formatting and parsing of `usbip://host:port/bus_id` URLs, which are used
+//! to refer to a device exported by a specific server
+
+use crate::net::UsbIpSocket;
+
+const SCHEME: &str = "usbip://";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbipUrl {
+    pub host: String,
+    pub port: u16,
+    pub bus_id: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum UrlParseError {
+    #[error("URL does not start with `{SCHEME}`")]
+    InvalidScheme,
+    #[error("URL is missing the host")]
+    MissingHost,
+    #[error("URL is missing the bus ID")]
+    MissingBusId,
+    #[error("Invalid port `{0}` in URL")]
+    InvalidPort(String),
+    #[error("Unterminated IPv6 address in URL")]
+    UnterminatedIpv6,
+}
+
+/// Formats the URL of a device exported by the given server. IPv6 addresses
+/// are enclosed in brackets so the port stays unambiguous.
+pub fn format_usbip_url(host: &str, port: u16, bus_id: &str) -> String {
+    if host.contains(':') {
+        format!("{SCHEME}[{host}]:{port}/{bus_id}")
+    } else {
+        format!("{SCHEME}{host}:{port}/{bus_id}")
+    }
+}
+
+/// Parses a URL created by [`format_usbip_url`]. The port may be omitted, in
+/// which case the default usbip port is used.
+pub fn parse_usbip_url(url: &str) -> Result<UsbipUrl, UrlParseError> {
+    let rest = url
+        .strip_prefix(SCHEME)
+        .ok_or(UrlParseError::InvalidScheme)?;
+
+    let (authority, bus_id) = rest.split_once('/').ok_or(UrlParseError::MissingBusId)?;
+
+    if bus_id.is_empty() {
+        return Err(UrlParseError::MissingBusId);
+    }
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, rest) = bracketed
+            .split_once(']')
+            .ok_or(UrlParseError::UnterminatedIpv6)?;
+
+        match rest.strip_prefix(':') {
+            Some(port) => (host, Some(port)),
+            None if rest.is_empty() => (host, None),
+            None => return Err(UrlParseError::InvalidPort(rest.into())),
+        }
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    if host.is_empty() {
+        return Err(UrlParseError::MissingHost);
+    }
+
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| UrlParseError::InvalidPort(port.into()))?,
+        None => UsbIpSocket::DEFAULT_PORT,
+    };
+
+    Ok(UsbipUrl {
+        host: host.into(),
+        port,
+        bus_id: bus_id.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(host: &str, port: u16, bus_id: &str) -> UsbipUrl {
+        UsbipUrl {
+            host: host.into(),
+            port,
+            bus_id: bus_id.into(),
+        }
+    }
+
+    #[test]
+    fn formats_urls() {
+        assert_eq!(
+            format_usbip_url("192.168.1.20", 3240, "1-1.2"),
+            "usbip://192.168.1.20:3240/1-1.2"
+        );
+        assert_eq!(
+            format_usbip_url("fe80::1", 3240, "1-1"),
+            "usbip://[fe80::1]:3240/1-1"
+        );
+    }
+
+    #[test]
+    fn round_trips_formatted_urls() {
+        for (host, port, bus_id) in [
+            ("192.168.1.20", 3240, "1-1.2"),
+            ("server.local", 4000, "2-3"),
+            ("fe80::1", 3240, "1-1"),
+        ] {
+            let formatted = format_usbip_url(host, port, bus_id);
+
+            assert_eq!(
+                parse_usbip_url(&formatted).unwrap(),
+                url(host, port, bus_id),
+                "{formatted}"
+            );
+        }
+    }
+
+    #[test]
+    fn defaults_the_port() {
+        assert_eq!(
+            parse_usbip_url("usbip://server/1-1").unwrap(),
+            url("server", UsbIpSocket::DEFAULT_PORT, "1-1")
+        );
+        assert_eq!(
+            parse_usbip_url("usbip://[::1]/1-1").unwrap(),
+            url("::1", UsbIpSocket::DEFAULT_PORT, "1-1")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        assert!(matches!(
+            parse_usbip_url("http://server/1-1"),
+            Err(UrlParseError::InvalidScheme)
+        ));
+        assert!(matches!(
+            parse_usbip_url("usbip://:3240/1-1"),
+            Err(UrlParseError::MissingHost)
+        ));
+        assert!(matches!(
+            parse_usbip_url("usbip://server"),
+            Err(UrlParseError::MissingBusId)
+        ));
+        assert!(matches!(
+            parse_usbip_url("usbip://server/"),
+            Err(UrlParseError::MissingBusId)
+        ));
+        assert!(matches!(
+            parse_usbip_url("usbip://server:port/1-1"),
+            Err(UrlParseError::InvalidPort(p)) if p == "port"
+        ));
+        assert!(matches!(
+            parse_usbip_url("usbip://[::1]3240/1-1"),
+            Err(UrlParseError::InvalidPort(_))
+        ));
+        assert!(matches!(
+            parse_usbip_url("usbip://[::1/1-1"),
+            Err(UrlParseError::UnterminatedIpv6)
+        ));
+    }
+}
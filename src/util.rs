@@ -1,7 +1,45 @@
+//! Helpers shared by the rest of the crate, mostly for reading USB device
+//! information from udev (which are only available on Linux).
+//!
+//! Attributes are decoded with one of two policies depending on what they are
+//! used for:
+//!
+//! - Structured fields (IDs, classes, speeds, bus IDs and paths) must be valid
+//!   UTF-8 and fail with [`UsbInfoExtractError::AttributeNotUtf8`] otherwise,
+//!   since a replacement character would change their meaning.
+//! - Human-readable strings (like the manufacturer and product string
+//!   descriptors) are decoded lossily with [`read_display_attribute`], since
+//!   devices with odd string descriptors should still be listed.
+
+#[cfg(target_os = "linux")]
 use core::str::FromStr;
+use std::io::{self, ErrorKind};
+#[cfg(target_os = "linux")]
+use std::{ffi::OsStr, path::PathBuf};
+
+#[cfg(target_os = "linux")]
+use crate::{UsbDeviceInfo, UsbSpeed, proto::UsbInterfaceInfo};
 
-use crate::{UsbDeviceInfo, UsbSpeed};
+/// Runs a blocking I/O operation again for as long as it is interrupted by a
+/// signal. The `read_exact`/`write_all` helpers of std already do this, but
+/// single system calls (opening files, accepting, peeking) surface `EINTR` to
+/// the caller, which would turn e.g. a `SIGCHLD` in the daemon into a spurious
+/// failure.
+///
+/// Connecting must not be retried this way, since an interrupted connect keeps
+/// going in the background (and fails with `EALREADY` when retried).
+pub(crate) fn retry_interrupted<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match op() {
+            Err(e) if e.kind() == ErrorKind::Interrupted => {
+                tracing::trace!("I/O operation interrupted, retrying");
+            }
+            result => return result,
+        }
+    }
+}
 
+#[cfg(target_os = "linux")]
 #[derive(Debug, thiserror::Error)]
 pub enum UsbInfoExtractError {
     #[error("Failed to get value for udev attribute `{0}`")]
@@ -12,12 +50,33 @@ pub enum UsbInfoExtractError {
     AttributeParsingFailed(String),
 }
 
+#[cfg(target_os = "linux")]
 pub fn extract_usb_info_from_udev_device(
     udev: &udev::Device,
+) -> Result<UsbDeviceInfo, UsbInfoExtractError> {
+    let sys_path = udev
+        .syspath()
+        .to_str()
+        .ok_or_else(|| UsbInfoExtractError::AttributeNotUtf8("syspath".into()))?;
+    let bus_id = udev
+        .sysname()
+        .to_str()
+        .ok_or_else(|| UsbInfoExtractError::AttributeNotUtf8("sysname".into()))?;
+
+    extract_usb_info(sys_path, bus_id, |name| udev.attribute_value(name))
+}
+
+/// Builds the device info from the sysfs attributes returned by `attribute`,
+/// which is kept apart from udev so the parsing can be tested on its own
+#[cfg(target_os = "linux")]
+fn extract_usb_info<'a>(
+    sys_path: &str,
+    bus_id: &str,
+    attribute: impl Fn(&str) -> Option<&'a OsStr>,
 ) -> Result<UsbDeviceInfo, UsbInfoExtractError> {
     macro_rules! extract_attr {
         ($name:ident) => {
-            udev.attribute_value(stringify!($name))
+            attribute(stringify!($name))
                 .ok_or_else(|| UsbInfoExtractError::AttributeMissing(stringify!($name).into()))?
                 .to_str()
                 .ok_or_else(|| UsbInfoExtractError::AttributeNotUtf8(stringify!($name).into()))?
@@ -41,23 +100,25 @@ pub fn extract_usb_info_from_udev_device(
         };
     }
 
-    // Some values need special handling since they might not be set in all
-    // cases and so parsing them may fail
+    // `bConfigurationValue` and `bNumInterfaces` are only meaningful when the
+    // device has an active configuration. For unconfigured devices the kernel
+    // leaves them empty (or they may not be readable at all), so they are
+    // treated as optional instead of failing the whole device query.
     macro_rules! try_parse_attr_hex {
         ($ty:ty, $name:ident) => {
-            <$ty>::from_str_radix(extract_attr!($name), 16).unwrap_or_default()
+            attribute(stringify!($name))
+                .and_then(|v| v.to_str())
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    <$ty>::from_str_radix(v, 16).map_err(|_| {
+                        UsbInfoExtractError::AttributeParsingFailed(stringify!($name).into())
+                    })
+                })
+                .transpose()?
         };
     }
 
-    let sys_path = udev
-        .syspath()
-        .to_str()
-        .ok_or_else(|| UsbInfoExtractError::AttributeNotUtf8("syspath".into()))?;
-    let bus_id = udev
-        .sysname()
-        .to_str()
-        .ok_or_else(|| UsbInfoExtractError::AttributeNotUtf8("sysname".into()))?;
-
     Ok(UsbDeviceInfo {
         sys_path: sys_path.into(),
         bus_id: bus_id.into(),
@@ -72,6 +133,332 @@ pub fn extract_usb_info_from_udev_device(
         b_device_protocol: parse_attr_hex!(u8, bDeviceProtocol),
         b_configuration_value: try_parse_attr_hex!(u8, bConfigurationValue),
         b_num_configurations: parse_attr_hex!(u8, bNumConfigurations),
-        b_num_interfaces: try_parse_attr_hex!(u8, bNumInterfaces),
+        b_num_interfaces: try_parse_attr_hex!(u8, bNumInterfaces).unwrap_or_default(),
     })
 }
+
+/// Reads an attribute which is only used for display, replacing any invalid
+/// UTF-8 with replacement characters (see the module documentation)
+#[cfg(target_os = "linux")]
+pub fn read_display_attribute(udev: &udev::Device, name: &str) -> Option<String> {
+    udev.attribute_value(name).map(display_string)
+}
+
+#[cfg(target_os = "linux")]
+fn display_string(value: &OsStr) -> String {
+    value.to_string_lossy().trim().to_owned()
+}
+
+/// Parses the interface info from the attributes returned by `attribute`
+/// (e.g. [`udev::Device::attribute_value`])
+#[cfg(target_os = "linux")]
+pub fn extract_usb_interface_info<'a>(
+    attribute: impl Fn(&str) -> Option<&'a OsStr>,
+) -> Result<UsbInterfaceInfo, UsbInfoExtractError> {
+    let parse_attr_hex = |name: &str| {
+        let value = attribute(name)
+            .ok_or_else(|| UsbInfoExtractError::AttributeMissing(name.into()))?
+            .to_str()
+            .ok_or_else(|| UsbInfoExtractError::AttributeNotUtf8(name.into()))?
+            .trim();
+
+        u8::from_str_radix(value, 16)
+            .map_err(|_| UsbInfoExtractError::AttributeParsingFailed(name.into()))
+    };
+
+    Ok(UsbInterfaceInfo::new(
+        parse_attr_hex("bInterfaceClass")?,
+        parse_attr_hex("bInterfaceSubClass")?,
+        parse_attr_hex("bInterfaceProtocol")?,
+    ))
+}
+
+/// Collects the `/dev` nodes of the device and all of its descendants (e.g. the
+/// `/dev/sdX` disks of a mass storage device or the `/dev/ttyACMx` of a modem),
+/// starting with the device's own node if it has one. Devices without a node of
+/// their own (like composite parents) are still covered by their descendants.
+#[cfg(target_os = "linux")]
+pub fn find_dev_nodes(device: &udev::Device) -> io::Result<Vec<PathBuf>> {
+    let mut enumerator = udev::Enumerator::new()?;
+
+    // this also matches the parent itself
+    enumerator.match_parent(device)?;
+
+    Ok(collect_dev_nodes(
+        enumerator
+            .scan_devices()?
+            .map(|d| (d.syspath().to_path_buf(), d.devnode().map(PathBuf::from)))
+            .collect(),
+    ))
+}
+
+/// Takes the sysfs paths and (optional) `/dev` nodes of a device and its
+/// descendants
+#[cfg(target_os = "linux")]
+fn collect_dev_nodes(mut devices: Vec<(PathBuf, Option<PathBuf>)>) -> Vec<PathBuf> {
+    // parents sort before their descendants, so the device's own node comes
+    // first
+    devices.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    devices.into_iter().filter_map(|(_, node)| node).collect()
+}
+
+/// A directory below the temp directory which is removed again when dropped,
+/// for tests which need files on disk (like a mock sysfs tree)
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub(crate) fn new() -> Self {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "usbip-rs-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `f` with a subscriber which collects everything logged on the current
+/// thread (down to the debug level), for tests which check what gets logged
+#[cfg(test)]
+pub(crate) fn capture_logs(f: impl FnOnce()) -> String {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct Writer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Writer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let writer = Writer(Default::default());
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer({
+            let writer = writer.clone();
+            move || writer.clone()
+        })
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, f);
+
+    String::from_utf8(writer.0.lock().unwrap().clone()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_interrupted_operations() {
+        let mut calls = 0;
+
+        let result = retry_interrupted(|| {
+            calls += 1;
+
+            match calls {
+                1 => Err(ErrorKind::Interrupted.into()),
+                _ => Ok(42),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let mut calls = 0;
+
+        let result = retry_interrupted::<()>(|| {
+            calls += 1;
+            Err(ErrorKind::WouldBlock.into())
+        });
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(calls, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    const CONFIGURED_ATTRIBUTES: &[(&str, &str)] = &[
+        ("busnum", "3"),
+        ("devnum", "a"),
+        ("speed", "480"),
+        ("idVendor", "1d6b"),
+        ("idProduct", "0104"),
+        ("bcdDevice", "0100"),
+        ("bDeviceClass", "00"),
+        ("bDeviceSubClass", "00"),
+        ("bDeviceProtocol", "00"),
+        ("bConfigurationValue", "1"),
+        ("bNumConfigurations", "1"),
+        ("bNumInterfaces", " 2"),
+    ];
+
+    /// Extracts the device info from the given attributes, leaving out those
+    /// named in `missing`
+    #[cfg(target_os = "linux")]
+    fn extract(
+        attributes: &[(&str, &'static str)],
+        missing: &[&str],
+    ) -> Result<UsbDeviceInfo, UsbInfoExtractError> {
+        extract_usb_info("/sys/devices/usb3/3-1", "3-1", |name| {
+            attributes
+                .iter()
+                .find(|(n, _)| *n == name && !missing.contains(n))
+                .map(|(_, value)| OsStr::new(*value))
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn extracts_a_configured_device() {
+        let info = extract(CONFIGURED_ATTRIBUTES, &[]).unwrap();
+
+        assert_eq!(info.bus_num, 3);
+        assert_eq!(info.dev_num, 10);
+        assert_eq!(info.speed, UsbSpeed::High);
+        assert_eq!(info.b_configuration_value, Some(1));
+        assert_eq!(info.b_num_interfaces, 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn extracts_an_unconfigured_device() {
+        let info = extract(
+            CONFIGURED_ATTRIBUTES,
+            &["bConfigurationValue", "bNumInterfaces"],
+        )
+        .unwrap();
+
+        assert_eq!(info.b_configuration_value, None);
+        assert_eq!(info.b_num_interfaces, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn treats_empty_configuration_attributes_as_unconfigured() {
+        let mut attributes = CONFIGURED_ATTRIBUTES.to_vec();
+        for (name, value) in &mut attributes {
+            if matches!(*name, "bConfigurationValue" | "bNumInterfaces") {
+                *value = "\n";
+            }
+        }
+
+        let info = extract(&attributes, &[]).unwrap();
+
+        assert_eq!(info.b_configuration_value, None);
+        assert_eq!(info.b_num_interfaces, 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fails_on_missing_required_attributes() {
+        assert!(matches!(
+            extract(CONFIGURED_ATTRIBUTES, &["idVendor"]),
+            Err(UsbInfoExtractError::AttributeMissing(name)) if name == "idVendor"
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn collects_the_dev_nodes_of_the_device_and_its_descendants() {
+        let device = |sys_path: &str, node: Option<&str>| {
+            (
+                PathBuf::from(format!("/sys/devices/platform/vhci_hcd.0/usb3/{sys_path}")),
+                node.map(PathBuf::from),
+            )
+        };
+
+        let nodes = collect_dev_nodes(vec![
+            device(
+                "3-1/3-1:1.0/host0/target0:0:0/0:0:0:0/block/sda",
+                Some("/dev/sda"),
+            ),
+            // a composite parent without a node of its own
+            device("3-1/3-1:1.1", None),
+            device("3-1", Some("/dev/bus/usb/003/002")),
+            device("3-1/3-1:1.1/tty/ttyACM0", Some("/dev/ttyACM0")),
+        ]);
+
+        assert_eq!(
+            nodes,
+            [
+                PathBuf::from("/dev/bus/usb/003/002"),
+                PathBuf::from("/dev/sda"),
+                PathBuf::from("/dev/ttyACM0"),
+            ]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn devices_without_dev_nodes_have_none() {
+        let nodes = collect_dev_nodes(vec![(
+            PathBuf::from("/sys/devices/platform/vhci_hcd.0/usb3/3-1/3-1:1.0"),
+            None,
+        )]);
+
+        assert!(nodes.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn decodes_display_strings_lossily() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let manufacturer = OsStr::from_bytes(b"ACME\xff Corp\n");
+
+        assert_eq!(display_string(manufacturer), "ACME\u{fffd} Corp");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rejects_structured_attributes_which_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let result = extract_usb_info("/sys/devices/usb3/3-1", "3-1", |name| {
+            if name == "idVendor" {
+                return Some(OsStr::from_bytes(b"1d\xff6b"));
+            }
+
+            CONFIGURED_ATTRIBUTES
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, value)| OsStr::new(*value))
+        });
+
+        assert!(
+            matches!(&result, Err(UsbInfoExtractError::AttributeNotUtf8(name)) if name == "idVendor"),
+            "{result:?}"
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Exit codes of the CLI, which let scripts tell common failures apart without
+//! parsing the error message. The codes are part of the CLI's interface and
+//! will not change:
+//!
+//! | Code | Meaning                                                          |
+//! |------|------------------------------------------------------------------|
+//! | 0    | Success                                                          |
+//! | 1    | Any error not covered below                                      |
+//! | 2    | Invalid command line arguments (reported by clap)                |
+//! | 3    | The device (or port) does not exist                              |
+//! | 4    | Insufficient permissions (e.g. not running as root)              |
+//! | 5    | Network failure (connecting, timeouts, connection dropped)       |
+//! | 6    | The device is busy (e.g. exported to another client, no free ports) |
+
+use std::any::Any;
+
+use usbip::{
+    client::{attach, detach, list, port, read_only},
+    drivers::{
+        DeviceLookupError, DriverBindingError, DriverUnbindingError, RootRequiredError,
+        SysfsIoError,
+        vhci::{Error as VhciHcdError, state::FsStateError},
+    },
+    proto::OperationError,
+    server::{bind, export, unbind},
+};
+
+pub const OTHER: i32 = 1;
+pub const NOT_FOUND: i32 = 3;
+pub const PERMISSION_DENIED: i32 = 4;
+pub const NETWORK: i32 = 5;
+pub const BUSY: i32 = 6;
+
+/// Maps an error reported by one of the subcommands to its exit code. Errors
+/// of types which aren't known here (like plain messages) exit with
+/// [`OTHER`].
+pub fn exit_code_for(error: &dyn Any) -> i32 {
+    if let Some(e) = error.downcast_ref::<attach::Error>() {
+        attach_error(e)
+    } else if let Some(e) = error.downcast_ref::<detach::Error>() {
+        detach_error(e)
+    } else if let Some(e) = error.downcast_ref::<list::Error>() {
+        list_error(e)
+    } else if let Some(e) = error.downcast_ref::<port::Error>() {
+        port_error(e)
+    } else if let Some(e) = error.downcast_ref::<VhciHcdError>() {
+        vhci_error(e)
+    } else if let Some(e) = error.downcast_ref::<bind::Error>() {
+        bind_error(e)
+    } else if let Some(e) = error.downcast_ref::<unbind::Error>() {
+        unbind_error(e)
+    } else if let Some(e) = error.downcast_ref::<export::Error>() {
+        export_error(e)
+    } else if error.is::<RootRequiredError>() {
+        PERMISSION_DENIED
+    } else {
+        OTHER
+    }
+}
+
+// the matches below list every variant, so that new variants have to be
+// sorted into one of the codes instead of silently exiting with `OTHER`
+
+fn attach_error(error: &attach::Error) -> i32 {
+    use attach::Error;
+
+    match error {
+        Error::NetworkIo(_) | Error::Timeout => NETWORK,
+        Error::Listing(e) => list_error(e),
+        Error::DeviceNumberNotFound(_) | Error::NoSuchDeviceOnServer(_) => NOT_FOUND,
+        Error::DeviceBusy(_) | Error::MaxAttemptsExceeded | Error::AliasInUse(..) => BUSY,
+        Error::Operation(e) => operation_error(e),
+        Error::VhciHcdDriver(e) => vhci_error(e),
+        Error::ReadOnly(e) => read_only_error(e),
+        Error::CheckingAlias(e) => detach_error(e),
+        Error::RecordNotSaved { error, .. } => fs_state_error(error),
+        Error::BusIdTooLong
+        | Error::BusIdMismatch
+        | Error::AttachVerificationFailed { .. }
+        | Error::PortOutOfRange(_)
+        | Error::DeviceInErrorState(_)
+        | Error::Protocol(_)
+        | Error::InvalidAlias(_)
+        | Error::AliasNotRecorded => OTHER,
+    }
+}
+
+fn detach_error(error: &detach::Error) -> i32 {
+    use detach::Error;
+
+    match error {
+        Error::VhciHcd(e) => vhci_error(e),
+        Error::InvalidPortNumber
+        | Error::InvalidControllerIndex { .. }
+        | Error::NoPortForUrl(_)
+        | Error::NoPortForAlias(_) => NOT_FOUND,
+        Error::FsState(e) => fs_state_error(e),
+        Error::InvalidUrl(_) => OTHER,
+    }
+}
+
+fn list_error(error: &list::Error) -> i32 {
+    use list::Error;
+
+    match error {
+        Error::NetworkIo(_) | Error::TruncatedInterfaceList { .. } => NETWORK,
+        Error::Operation(e) => operation_error(e),
+        Error::ProtocolUsbDevice(_)
+        | Error::Utf8(_)
+        | Error::TooManyDevices { .. }
+        | Error::MalformedDeviceRecord { .. }
+        | Error::NameLookup(_) => OTHER,
+    }
+}
+
+fn port_error(error: &port::Error) -> i32 {
+    use port::Error;
+
+    match error {
+        Error::VhciHcdDriver(e) => vhci_error(e),
+        Error::QueryingLocalUsbDevice(e) => device_lookup_error(e),
+        Error::ReadingRecords(e) => fs_state_error(e),
+        Error::CreatingUdevContext(_)
+        | Error::MissingUdevAttribute { .. }
+        | Error::EnumeratingDevNodes(..)
+        | Error::NameLookup(_) => OTHER,
+    }
+}
+
+fn read_only_error(error: &read_only::Error) -> i32 {
+    use read_only::Error;
+
+    match error {
+        Error::VhciHcd(e) => vhci_error(e),
+        Error::DeviceNotEnumerated(_)
+        | Error::NotMassStorage(_)
+        | Error::NoDisks(_)
+        | Error::CreatingUdevContext(_)
+        | Error::OpeningUsbDevice { .. }
+        | Error::EnumeratingUdevDevices(_)
+        | Error::RunningBlockdev(_)
+        | Error::SettingReadOnly { .. } => OTHER,
+    }
+}
+
+fn vhci_error(error: &VhciHcdError) -> i32 {
+    match error {
+        VhciHcdError::SysfsPermissionDenied => PERMISSION_DENIED,
+        VhciHcdError::NoFreePorts(_) => BUSY,
+        VhciHcdError::PortOutOfRange { .. } => NOT_FOUND,
+        VhciHcdError::QueryingLocalUsbDevice(e) => device_lookup_error(e),
+        VhciHcdError::ReloadedWhileRunning(e) => vhci_error(e),
+        VhciHcdError::CreatingUdevContext(_)
+        | VhciHcdError::VhciDeviceNotFound { .. }
+        | VhciHcdError::OpeningVhciDevice(..)
+        | VhciHcdError::VhciDeviceMissingSysfsAttribute(_)
+        | VhciHcdError::VhciDeviceUtf8SysfsAttribute(_)
+        | VhciHcdError::VhciDeviceParsingSysfsAttribute(_)
+        | VhciHcdError::VhciDeviceParsingStatusLine { .. }
+        | VhciHcdError::SysfsIo(_)
+        | VhciHcdError::VhciNoAvailablePorts
+        | VhciHcdError::EnumeratingControllers(_)
+        | VhciHcdError::NoControllers { .. }
+        | VhciHcdError::ConflictingStatusData(_)
+        | VhciHcdError::StatusPortCountMismatch { .. }
+        | VhciHcdError::InvalidSocketFd(_)
+        | VhciHcdError::NotASocket(_)
+        | VhciHcdError::UsbInfoExtraction { .. } => OTHER,
+    }
+}
+
+fn bind_error(error: &bind::Error) -> i32 {
+    use bind::Error;
+
+    match error {
+        Error::UdevDeviceNotFound(e) => device_lookup_error(e),
+        Error::AlreadyBoundToUsbipHost(_) => BUSY,
+        Error::BindingDriver { source, .. } => driver_binding_error(source),
+        Error::UnbindingDriver { source, .. } => driver_unbinding_error(source),
+        Error::UpdatingMatchList(e) | Error::ReadingMatchList(e) => sysfs_error(e),
+        Error::RevertingBind(e) => unbind_error(e),
+        Error::CreareUdevContext(_)
+        | Error::AlreadyBoundToVhci
+        | Error::CannotBindHub(_)
+        | Error::UsbInfoExtraction { .. }
+        | Error::BindTimedOut { .. } => OTHER,
+    }
+}
+
+fn unbind_error(error: &unbind::Error) -> i32 {
+    use unbind::Error;
+
+    match error {
+        Error::UdevDeviceNotFound(e) => device_lookup_error(e),
+        Error::UnbindingDriver { source, .. } => driver_unbinding_error(source),
+        Error::UpdatingMatchList(e) | Error::RebindingDevice(e) => sysfs_error(e),
+        Error::CreareUdevContext(_) | Error::NotAlreadyBound => OTHER,
+    }
+}
+
+fn export_error(error: &export::Error) -> i32 {
+    use export::Error;
+
+    match error {
+        Error::Binding(e) => bind_error(e),
+        Error::UdevDeviceNotFound(e) => device_lookup_error(e),
+        Error::NetworkIo(_) => NETWORK,
+        Error::Operation(e) => operation_error(e),
+        Error::HandingOffSocket(e) => sysfs_error(e),
+        Error::CreatingUdevContext(_) | Error::UsbInfoExtraction { .. } => OTHER,
+    }
+}
+
+fn operation_error(error: &OperationError) -> i32 {
+    match error {
+        OperationError::NoSuchDevice => NOT_FOUND,
+        OperationError::DeviceBusy => BUSY,
+        OperationError::RequestFailed
+        | OperationError::DeviceError
+        | OperationError::VersionMismatch
+        | OperationError::DirectionMismatch
+        | OperationError::InvalidData
+        | OperationError::UnknownOperation(_)
+        | OperationError::EncryptionUnsupported
+        | OperationError::Other => OTHER,
+    }
+}
+
+fn device_lookup_error(error: &DeviceLookupError) -> i32 {
+    match error {
+        DeviceLookupError::NotFound(_) => NOT_FOUND,
+        DeviceLookupError::CreatingUdevContext(_) | DeviceLookupError::Udev { .. } => OTHER,
+    }
+}
+
+fn fs_state_error(error: &FsStateError) -> i32 {
+    match error {
+        FsStateError::StateDirPermissions { .. } => PERMISSION_DENIED,
+        FsStateError::IoWrite(_)
+        | FsStateError::NotADirectory
+        | FsStateError::IoRead(..)
+        | FsStateError::Parsing(_)
+        | FsStateError::IoReadDir(_)
+        | FsStateError::IoRemove(_) => OTHER,
+    }
+}
+
+fn driver_binding_error(error: &DriverBindingError) -> i32 {
+    match error {
+        DriverBindingError::Sysfs(e) => sysfs_error(e),
+        DriverBindingError::NoDevice => NOT_FOUND,
+        DriverBindingError::AlreadyBoundOther | DriverBindingError::AlreadyBound => OTHER,
+    }
+}
+
+fn driver_unbinding_error(error: &DriverUnbindingError) -> i32 {
+    match error {
+        DriverUnbindingError::Sysfs(e) => sysfs_error(e),
+        DriverUnbindingError::NoDevice => NOT_FOUND,
+        DriverUnbindingError::NotBound => OTHER,
+    }
+}
+
+fn sysfs_error(error: &SysfsIoError) -> i32 {
+    match error {
+        SysfsIoError::PermissionDenied { .. } => PERMISSION_DENIED,
+        SysfsIoError::DoesNotExist { .. } => NOT_FOUND,
+        SysfsIoError::Other { .. } => OTHER,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::PathBuf};
+
+    use usbip::{drivers::vhci::HubSpeed, net::NetworkError};
+
+    use super::*;
+
+    #[test]
+    fn maps_network_errors() {
+        let error = attach::Error::NetworkIo(NetworkError::from(io::Error::from(
+            io::ErrorKind::ConnectionRefused,
+        )));
+
+        assert_eq!(exit_code_for(&error), NETWORK);
+        assert_eq!(exit_code_for(&attach::Error::Timeout), NETWORK);
+    }
+
+    #[test]
+    fn maps_missing_devices() {
+        assert_eq!(
+            exit_code_for(&attach::Error::NoSuchDeviceOnServer("1-1".into())),
+            NOT_FOUND
+        );
+        assert_eq!(
+            exit_code_for(&bind::Error::UdevDeviceNotFound(
+                DeviceLookupError::NotFound("1-1".into())
+            )),
+            NOT_FOUND
+        );
+        assert_eq!(exit_code_for(&detach::Error::InvalidPortNumber), NOT_FOUND);
+    }
+
+    #[test]
+    fn maps_busy_devices() {
+        assert_eq!(
+            exit_code_for(&attach::Error::Operation(OperationError::DeviceBusy)),
+            BUSY
+        );
+        assert_eq!(
+            exit_code_for(&VhciHcdError::NoFreePorts(HubSpeed::High)),
+            BUSY
+        );
+    }
+
+    #[test]
+    fn maps_permission_errors() {
+        let sysfs = SysfsIoError::PermissionDenied {
+            path: PathBuf::from("/sys/bus/usb/drivers/usbip-host/match_busid"),
+        };
+
+        assert_eq!(
+            exit_code_for(&bind::Error::UpdatingMatchList(sysfs)),
+            PERMISSION_DENIED
+        );
+        assert_eq!(
+            exit_code_for(&attach::Error::RecordNotSaved {
+                port: 3,
+                error: FsStateError::StateDirPermissions {
+                    owner_uid: 0,
+                    euid: 1000,
+                },
+            }),
+            PERMISSION_DENIED
+        );
+    }
+
+    #[test]
+    fn follows_nested_errors() {
+        let error = port::Error::VhciHcdDriver(VhciHcdError::ReloadedWhileRunning(Box::new(
+            VhciHcdError::SysfsPermissionDenied,
+        )));
+
+        assert_eq!(exit_code_for(&error), PERMISSION_DENIED);
+        assert_eq!(
+            exit_code_for(&attach::Error::CheckingAlias(
+                detach::Error::NoPortForAlias("camera".into())
+            )),
+            NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn maps_everything_else_to_other() {
+        assert_eq!(
+            exit_code_for(&VhciHcdError::NoControllers {
+                device: "vhci_hcd.0".into(),
+                driver: "vhci_hcd".into(),
+            }),
+            OTHER
+        );
+        assert_eq!(exit_code_for(&unbind::Error::NotAlreadyBound), OTHER);
+        assert_eq!(exit_code_for(&"plain message".to_string()), OTHER);
+    }
+}
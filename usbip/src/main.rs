@@ -1,3 +1,9 @@
+use std::{
+    fmt::Display,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
 use clap::{
     CommandFactory, Parser,
     error::{ContextKind, ContextValue, ErrorKind},
@@ -5,21 +11,47 @@ use clap::{
 use colored::Colorize;
 use tracing_subscriber::filter::LevelFilter;
 use usbip::{
-    UsbSpeed,
     client::{
-        attach::attach_device,
-        detach::detach_device,
-        list::{RemoteExportedDevice, list_remote_exported_devices},
-        port::{ImportedDevice, list_imported_devices},
+        attach::{
+            AttachOptions, BatchProgress, DeviceNumber, attach_batch_with_progress,
+            attach_device_by_number_with_options, attach_device_with_options, parse_attach_specs,
+        },
+        detach::{detach_controller, detach_device, detach_device_by_alias, detach_device_by_url},
+        list::{ListOptions, list_remote_exported_devices, list_remote_exported_devices_streaming},
+        port::{self, list_imported_devices},
+        watchdog::{ImportWatchdog, WatchdogParams},
     },
-    drivers::vhci::VhciDeviceStatus,
+    drivers::{
+        require_root_for,
+        vhci::{VhciHcd, read_raw_status},
+        vudc::Vudc,
+    },
+    hwdb::{IdSource, set_id_source},
+    net::{ConnectOptions, UsbIpSocket},
     server::{
-        bind::bind_device,
-        list_local::{LocalExportableDevice, list_local_exportable_devices},
+        bind::{self, bind_all_eligible, bind_device, plan_bind},
+        export::export_device,
+        list_local::{ListLocalOptions, list_local_exportable_devices_with_options},
+        matcher::DeviceMatcher,
         unbind::unbind_device,
     },
 };
 
+use crate::{
+    exit_code::exit_code_for,
+    output::{
+        BatchAttachResult, BatchBindResult, CapacityReport, DebugDump, OutputFormat, Report,
+        VersionReport, print_ndjson_line, print_remote_devices_header,
+        print_remote_exported_device, render,
+    },
+};
+
+mod exit_code;
+mod output;
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("the usbip CLI only supports Linux, since it relies on the usbip kernel modules");
+
 #[derive(clap::Parser)]
 #[clap(name = "usbip")]
 struct Args {
@@ -28,19 +60,30 @@ struct Args {
     /// Enables extra debug printing to STDERR
     #[arg(short = 'd', long)]
     debug: bool,
+    /// Format used to print the result to STDOUT in all success cases
+    #[arg(short = 'f', long, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
     /// Outputs the result to STDOUT in JSON format with a `\n` terminator in
-    /// all success cases
-    #[arg(short = 'j', long)]
+    /// all success cases (deprecated alias for `--format json`)
+    #[arg(short = 'j', long, conflicts_with = "format")]
     json_output: bool,
+    /// Suppresses the human readable success output (attach only prints the
+    /// port number). Has no effect on machine readable formats.
+    #[arg(short = 'q', long)]
+    quiet: bool,
+    /// Where USB vendor, product and class names are looked up: `runtime`
+    /// (the udev hwdb), `baked` (the database built into this binary),
+    /// `file:PATH` (a `usb.ids` file) or `none` (don't look up any names).
+    /// Defaults to the udev hwdb if it was compiled in, the baked database
+    /// otherwise.
+    #[arg(long, value_name = "SOURCE")]
+    id_source: Option<IdSource>,
     // TODO: add a flag to switch between the old legacy interface (for existing
     // parsers) that exists for backwards compatibility and a new shiny one with
     // colors :). legacy mode will only output the same exact output in the
     // success case. errors should still be formatted in the old way.
     //
     // TODO: env variable to use legacy mode by default (use a cli flag to enable it normally)
-    //
-    // TODO: use baked usb ids database
-    // TODO: use custom usb ids database path
 }
 
 #[derive(clap::Subcommand)]
@@ -49,21 +92,87 @@ enum Command {
     Attach {
         // TODO: TCP port
         /// The machine with exported USB devices
-        #[arg(short = 'r', long = "remote", name = "HOST")]
-        remote_host: String,
+        #[arg(
+            short = 'r',
+            long = "remote",
+            name = "HOST",
+            required_unless_present = "from"
+        )]
+        remote_host: Option<String>,
         /// Bus ID of the device on the remote host
-        #[arg(short = 'b', long, conflicts_with = "device")]
+        #[arg(
+            short = 'b',
+            long,
+            conflicts_with_all = ["device", "dev"],
+            required_unless_present_any = ["device", "dev", "from"]
+        )]
         bus_id: Option<String>,
         /// ID of the virtual UDC on the remote host
-        #[arg(short = 'd', long, conflicts_with = "bus_id")]
+        #[arg(short = 'd', long, conflicts_with_all = ["bus_id", "dev"])]
         device: Option<String>,
+        /// Bus and device number of the device on the remote host (e.g. `3/5`).
+        /// These change whenever the device is replugged.
+        #[arg(long, value_name = "BUS_NUM/DEV_NUM", conflicts_with_all = ["bus_id", "device", "keep_alive"])]
+        dev: Option<DeviceNumber>,
+        /// Attach all devices listed in a file (one `HOST BUS_ID` pair or
+        /// `usbip://` URL per line) instead of a single device
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = ["HOST", "bus_id", "device", "dev", "keep_alive"]
+        )]
+        from: Option<PathBuf>,
+        /// Stay in the foreground and re-attach the device whenever the
+        /// connection to the server is lost
+        #[arg(long)]
+        keep_alive: bool,
+        /// After attaching, check that the device came up on the local port at
+        /// the expected speed
+        #[arg(long)]
+        verify: bool,
+        /// Block writes to the device by making its disks read-only after
+        /// attaching (mass storage devices only)
+        #[arg(long)]
+        read_only: bool,
+        /// Don't save the remote host of the device in the state directory
+        /// (`port` will not be able to show it)
+        #[arg(long)]
+        no_record: bool,
+        /// Give up if the device is not attached within this many seconds
+        #[arg(long, value_name = "SECS", conflicts_with_all = ["from", "keep_alive"])]
+        timeout: Option<u64>,
+        /// Name to remember the device by, which is shown by `port` and can be
+        /// passed to `detach --alias`
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["from", "keep_alive", "no_record"])]
+        alias: Option<String>,
+        /// Size of the send and receive buffers (in bytes) of the connection
+        /// handed to vhci_hcd. Larger buffers can improve the throughput of
+        /// e.g. mass storage devices over high-latency links.
+        #[arg(long, value_name = "SIZE")]
+        sockbuf: Option<usize>,
     },
     /// Detach a remote USB device
     Detach {
         // TODO: TCP port?
         /// Local vhci_hcd port the device is bound to
-        #[arg(short = 'p', long)]
-        port: u16,
+        #[arg(
+            short = 'p',
+            long,
+            required_unless_present_any = ["url", "alias", "controller"],
+            conflicts_with_all = ["url", "alias", "controller"]
+        )]
+        port: Option<u16>,
+        /// Detach the device attached from this `usbip://host:port/bus_id` URL
+        /// (as shown by `usbip port`)
+        #[arg(long, conflicts_with_all = ["alias", "controller"])]
+        url: Option<String>,
+        /// Detach the device attached with this alias (`attach --alias`)
+        #[arg(long, value_name = "NAME", conflicts_with = "controller")]
+        alias: Option<String>,
+        /// Detach all devices attached to the ports of this vhci_hcd
+        /// controller (counting from 0)
+        #[arg(long, value_name = "N")]
+        controller: Option<u16>,
     },
     /// List exportable or local USB devices
     List {
@@ -85,15 +194,41 @@ enum Command {
         #[arg(short = 'd', long, conflicts_with = "local", conflicts_with = "HOST")]
         device: bool,
 
-        /// Prints the output in a parsable format (use --json-output instead for better results)
+        /// Prints the output in a parsable format (alias for `--format parsable`)
         #[arg(short = 'p', long)]
         parsable: bool,
+        /// Sort the devices by bus ID (otherwise they are listed in the order
+        /// they were enumerated in, which may change between runs)
+        #[arg(long)]
+        sort: bool,
+        /// Leave out local devices matching a bus ID (`1-1`), vendor and
+        /// product ID (`046d:c52b` or `046d:*`) or class (`class=03`). May be
+        /// given multiple times.
+        #[arg(long, value_name = "MATCHER", requires = "local")]
+        exclude: Vec<DeviceMatcher>,
     },
     /// Bind device to usbip_host.ko
     Bind {
         /// Local bus ID of the USB device
-        #[arg(short = 'b', long)]
-        bus_id: String,
+        #[arg(short = 'b', long, required_unless_present = "all")]
+        bus_id: Option<String>,
+        /// Bind all local devices which are eligible (see `list --local`) and
+        /// not yet bound to usbip_host.ko
+        #[arg(long, conflicts_with_all = ["bus_id", "dry_run", "repair"])]
+        all: bool,
+        /// Skip devices matching a bus ID (`1-1`), vendor and product ID
+        /// (`046d:c52b` or `046d:*`) or class (`class=03`) when binding all
+        /// devices. May be given multiple times.
+        #[arg(long, value_name = "MATCHER", requires = "all")]
+        exclude: Vec<DeviceMatcher>,
+        /// Only check whether the device can be bound and which driver would
+        /// be displaced, without binding it
+        #[arg(long)]
+        dry_run: bool,
+        /// Fix the state of a device left behind by an interrupted bind or
+        /// unbind (e.g. in the match list of usbip_host.ko without a driver)
+        #[arg(long, conflicts_with = "dry_run")]
+        repair: bool,
     },
     /// Unbind device from usbip_host.ko
     Unbind {
@@ -101,8 +236,60 @@ enum Command {
         #[arg(short = 'b', long)]
         bus_id: String,
     },
+    /// Export a local USB device to a remote host until interrupted
+    ///
+    /// The device is bound to usbip_host.ko if necessary and unbound again
+    /// (restoring its original driver) on exit.
+    Export {
+        /// The machine which will import the USB device
+        #[arg(short = 'r', long = "remote", name = "HOST")]
+        remote_host: String,
+        /// Local bus ID of the USB device
+        #[arg(short = 'b', long)]
+        bus_id: String,
+    },
     /// Show all imported USB devices
-    Port,
+    Port {
+        /// Group the devices by the remote host they were attached from
+        #[arg(long)]
+        group_by_host: bool,
+    },
+    /// Show the number of local vhci_hcd controllers and (free) ports
+    Capacity,
+    /// Dump the raw vhci_hcd port status reported by the kernel alongside its
+    /// interpretation (for troubleshooting)
+    DebugDump,
+    /// Show the version of the CLI and the usbip protocol it speaks
+    Version,
+}
+
+impl Command {
+    /// Describes the operation of the command if it requires root privileges
+    fn privileged_operation(&self) -> Option<&'static str> {
+        match self {
+            Command::Attach { .. } => Some("attach devices"),
+            Command::Detach { .. } => Some("detach devices"),
+            Command::Bind { dry_run: false, .. } => Some("bind devices"),
+            Command::Unbind { .. } => Some("unbind devices"),
+            Command::Export { .. } => Some("export devices"),
+            Command::Bind { dry_run: true, .. }
+            | Command::List { .. }
+            | Command::Port { .. }
+            | Command::Capacity
+            | Command::DebugDump
+            | Command::Version => None,
+        }
+    }
+}
+
+impl Args {
+    fn output_format(&self) -> OutputFormat {
+        match (self.json_output, self.format) {
+            (true, _) => OutputFormat::Json,
+            (false, OutputFormat::Human) if self.quiet => OutputFormat::Quiet,
+            (false, format) => format,
+        }
+    }
 }
 
 fn main() {
@@ -117,96 +304,236 @@ fn main() {
         .with_writer(std::io::stderr)
         .init();
 
-    match args.command {
+    if let Some(source) = &args.id_source {
+        set_id_source(source).unwrap_or_else(|e| fail(e));
+    }
+
+    let mut format = args.output_format();
+
+    if let Some(operation) = args.command.privileged_operation() {
+        require_root_for(operation).unwrap_or_else(|e| fail(e));
+    }
+
+    let report = match args.command {
         Command::Attach {
             remote_host,
             bus_id,
             device,
+            dev,
+            from,
+            keep_alive,
+            verify,
+            read_only,
+            no_record,
+            timeout,
+            alias,
+            sockbuf,
         } => {
+            let options = AttachOptions {
+                connect: ConnectOptions {
+                    buffer_size: sockbuf,
+                    ..Default::default()
+                },
+                verify,
+                read_only,
+                record_state: !no_record,
+                deadline: timeout.map(|secs| Instant::now() + Duration::from_secs(secs)),
+                alias,
+                ..Default::default()
+            };
+
+            if let Some(path) = from {
+                let text = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|e| fail(format!("Failed to read `{}` ({e})", path.display())));
+                let specs = parse_attach_specs(&text).unwrap_or_else(|e| fail(e));
+
+                let show_progress = format == OutputFormat::Human;
+
+                // the results are only rendered once the whole batch is done,
+                // so show which device is being attached in the meantime
+                let progress = |p: BatchProgress<'_>| {
+                    if let BatchProgress::Starting { index, total, spec } = p
+                        && show_progress
+                    {
+                        eprintln!(
+                            "attaching {}/{total}: {} from {}:{}...",
+                            index + 1,
+                            spec.bus_id,
+                            spec.host,
+                            spec.port
+                        );
+                    }
+                };
+
+                let results: Vec<_> = attach_batch_with_progress(&specs, &options, progress)
+                    .into_iter()
+                    .map(|(spec, result)| BatchAttachResult::new(spec, result))
+                    .collect();
+
+                let failed = results.iter().any(|r| r.error.is_some());
+
+                render(&Report::BatchAttached(results), format);
+
+                if failed {
+                    std::process::exit(exit_code::OTHER);
+                }
+
+                return;
+            }
+
+            let remote_host = remote_host.unwrap();
+
+            if let Some(number) = dev {
+                let port = attach_device_by_number_with_options(
+                    &remote_host,
+                    UsbIpSocket::DEFAULT_PORT,
+                    number,
+                    &options,
+                )
+                .unwrap_or_else(|e| fail(e));
+
+                render(&Report::Attached { port }, format);
+
+                return;
+            }
+
             // These are 2 different CLI arguments but the server actually
             // treats them the same so we dont make any disctinction here
             assert_ne!(bus_id.is_some(), device.is_some());
             let bus_id = bus_id.or(device).unwrap();
 
-            match attach_device(&remote_host, &bus_id) {
-                Ok(port) => {
-                    if args.json_output {
-                        let v = serde_json::json!({
-                            "port": port
-                        });
+            if keep_alive {
+                let params = WatchdogParams {
+                    attach_options: options,
+                    ..WatchdogParams::new(&remote_host, UsbIpSocket::DEFAULT_PORT, &bus_id)
+                };
 
-                        println!("{}", serde_json::to_string(&v).unwrap())
-                    } else {
-                        println!("Device attached successfuly to port {port}")
-                    }
-                }
-                Err(e) => {
-                    eprintln!("{} {e}", "Error:".red());
-                    std::process::exit(1);
+                let watchdog = ImportWatchdog::start(params).unwrap_or_else(|e| fail(e));
+
+                render(
+                    &Report::Attached {
+                        port: watchdog.port(),
+                    },
+                    format,
+                );
+
+                if format == OutputFormat::Human {
+                    println!("Keeping device alive, press Ctrl-C to exit");
                 }
+
+                watchdog.wait();
+
+                return;
             }
+
+            let port = attach_device_with_options(
+                &remote_host,
+                UsbIpSocket::DEFAULT_PORT,
+                &bus_id,
+                &options,
+            )
+            .unwrap_or_else(|e| fail(e));
+
+            Report::Attached { port }
         }
-        Command::Detach { port } => match detach_device(port, true) {
-            Ok(_) => {
-                if args.json_output {
-                    let v = serde_json::json!({
-                        "port": port
-                    });
-
-                    println!("{}", serde_json::to_string(&v).unwrap())
-                } else {
-                    println!("Device detached successfully from port {port}")
+        Command::Detach {
+            controller: Some(controller),
+            ..
+        } => {
+            let ports = detach_controller(controller, true).unwrap_or_else(|e| fail(e));
+
+            Report::DetachedController { controller, ports }
+        }
+        Command::Detach {
+            port, url, alias, ..
+        } => {
+            let port = match (port, url, alias) {
+                (Some(port), _, _) => {
+                    detach_device(port, true).unwrap_or_else(|e| fail(e));
+                    port
                 }
-            }
-            Err(e) => {
-                eprintln!("{} {e}", "Error:".red());
-                std::process::exit(1);
-            }
-        },
+                (None, Some(url), _) => {
+                    detach_device_by_url(&url, true).unwrap_or_else(|e| fail(e))
+                }
+                (None, None, Some(alias)) => {
+                    detach_device_by_alias(&alias, true).unwrap_or_else(|e| fail(e))
+                }
+                (None, None, None) => {
+                    unreachable!("clap requires --port, --url, --alias or --controller")
+                }
+            };
+
+            Report::Detached { port }
+        }
         Command::List {
             remote_host,
             local,
             device,
             parsable,
+            sort,
+            exclude,
         } => {
             assert!(!(remote_host.is_some() && local));
             assert!(!(remote_host.is_some() && device));
             assert!(!(local && device));
 
+            if parsable && matches!(format, OutputFormat::Human | OutputFormat::Quiet) {
+                format = OutputFormat::Parsable;
+            }
+
             if let Some(host) = remote_host {
-                match list_remote_exported_devices(&host) {
-                    Ok(devices) => {
-                        if args.json_output {
-                            println!("{}", serde_json::to_string(&devices).unwrap())
-                        } else {
-                            if devices.is_empty() {
-                                return;
-                            }
-
-                            print_remote_exported_devices(&host, &devices);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{} {e}", "Error:".red());
-                        std::process::exit(1);
-                    }
+                // the legacy format can show each device as soon as it has
+                // been received, unless they have to be sorted first
+                if format == OutputFormat::Human && !sort {
+                    print_remote_devices_header(&host);
+
+                    list_remote_exported_devices_streaming(
+                        &host,
+                        UsbIpSocket::DEFAULT_PORT,
+                        &ListOptions::default(),
+                        |device| print_remote_exported_device(&device),
+                    )
+                    .unwrap_or_else(|e| fail(e));
+
+                    return;
+                }
+
+                // same for NDJSON, which is meant to be processed incrementally
+                if format == OutputFormat::Ndjson && !sort {
+                    list_remote_exported_devices_streaming(
+                        &host,
+                        UsbIpSocket::DEFAULT_PORT,
+                        &ListOptions::default(),
+                        |device| print_ndjson_line(&device),
+                    )
+                    .unwrap_or_else(|e| fail(e));
+
+                    return;
                 }
+
+                let mut devices = list_remote_exported_devices(&host).unwrap_or_else(|e| fail(e));
+
+                if sort {
+                    devices.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+                }
+
+                Report::RemoteDevices { host, devices }
             } else if device {
-                todo!("list vudc gadget devices")
+                let vudc = Vudc::open().unwrap_or_else(|e| fail(e));
+
+                Report::VirtualUdcs(vudc.udcs().to_vec())
             } else if local {
-                match list_local_exportable_devices() {
-                    Ok(devices) => {
-                        if args.json_output {
-                            println!("{}", serde_json::to_string(&devices).unwrap())
-                        } else {
-                            print_local_exportable_devices(&devices, parsable);
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{} {e}", "Error:".red());
-                        std::process::exit(1);
-                    }
+                let mut devices = list_local_exportable_devices_with_options(&ListLocalOptions {
+                    exclude,
+                    ..Default::default()
+                })
+                .unwrap_or_else(|e| fail(e));
+
+                if sort {
+                    devices.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
                 }
+
+                Report::LocalDevices(devices)
             } else {
                 let mut error =
                     clap::Error::new(ErrorKind::MissingRequiredArgument).with_cmd(&Args::command());
@@ -223,257 +550,132 @@ fn main() {
                 error.exit();
             }
         }
-        Command::Bind { bus_id } => match bind_device(&bus_id) {
-            Ok(_) => {
-                if args.json_output {
-                    let v = serde_json::json!({});
-
-                    println!("{}", serde_json::to_string(&v).unwrap())
-                } else {
-                    // TODO: what should this output be?
-                    println!("Device with bus id {bus_id} bound successfully")
-                }
-            }
-            Err(e) => {
-                eprintln!("{} {e}", "Error:".red());
-                std::process::exit(1);
-            }
-        },
-        Command::Unbind { bus_id } => match unbind_device(&bus_id) {
-            Ok(_) => {
-                if args.json_output {
-                    let v = serde_json::json!({});
-
-                    println!("{}", serde_json::to_string(&v).unwrap())
-                } else {
-                    // TODO: what should this output be?
-                    println!("Device with bus id {bus_id} unbound successfully")
-                }
-            }
-            Err(e) => {
-                eprintln!("{} {e}", "Error:".red());
-                std::process::exit(1);
-            }
-        },
-        Command::Port => match list_imported_devices() {
-            Ok(devices) => {
-                if args.json_output {
-                    println!("{}", serde_json::to_string(&devices).unwrap())
-                } else {
-                    print_imported_devices(&devices);
-                }
-            }
-            Err(e) => {
-                eprintln!("{} {e}", "Error:".red());
-                std::process::exit(1);
-            }
-        },
-    }
-}
-
-fn print_imported_devices(devices: &[ImportedDevice]) {
-    println!("Imported USB devices");
-    println!("====================");
-
-    for device in devices {
-        let info = &device.local_device_info;
-
-        print!("Port {:02}: <", device.port);
-
-        match device.status {
-            // TODO: impl printing for unused and initializing ports if we allow outputting those
-            VhciDeviceStatus::NotConnected | VhciDeviceStatus::NotAssigned => unreachable!(),
-            VhciDeviceStatus::Used => print!("Port in Use"),
-            VhciDeviceStatus::Error => print!("Port Error"),
-        }
+        Command::Bind {
+            all: true, exclude, ..
+        } => {
+            let results: Vec<_> = bind_all_eligible(&exclude)
+                .unwrap_or_else(|e| fail(e))
+                .into_iter()
+                .map(|(bus_id, result)| BatchBindResult::new(bus_id, result))
+                .collect();
 
-        print!("> at ");
-
-        match info.speed {
-            UsbSpeed::Unknown => print!("Unknown Speed"),
-            UsbSpeed::Low => print!("Low Speed(1.5Mbps)"),
-            UsbSpeed::Full => print!("Full Speed(12Mbps)"),
-            UsbSpeed::High => print!("High Speed(480Mbps)"),
-            UsbSpeed::Wireless => print!("Wireless"),
-            UsbSpeed::Super => print!("Super Speed(5000Mbps)"),
-            // not in the original impl since it was stanrdized after that code
-            // was written, but probably good to have
-            UsbSpeed::SuperPlus => print!("Super Speed Plus(10000Mbps)"),
-        }
+            let failed = results.iter().any(|r| r.error.is_some());
 
-        println!();
+            render(&Report::BatchBound(results), format);
 
-        print!("       ");
+            if failed {
+                std::process::exit(exit_code::OTHER);
+            }
 
-        if let Some(vendor) = &device.vendor {
-            print!("{vendor}");
-        } else {
-            print!("unknown vendor");
+            return;
         }
+        Command::Bind {
+            bus_id,
+            dry_run,
+            repair,
+            ..
+        } => {
+            let bus_id = bus_id.unwrap();
 
-        print!(" : ");
+            if repair {
+                let action = bind::repair(&bus_id).unwrap_or_else(|e| fail(e));
 
-        if let Some(product) = &device.product {
-            print!("{product}");
-        } else {
-            print!("unknown product");
+                Report::Repaired { bus_id, action }
+            } else if dry_run {
+                Report::BindPlanned(plan_bind(&bus_id).unwrap_or_else(|e| fail(e)))
+            } else {
+                Report::Bound(bind_device(&bus_id).unwrap_or_else(|e| fail(e)))
+            }
         }
-
-        println!(" ({:04x}:{:04x})", info.id_vendor, info.id_product);
-
-        print!("{:>10} -> ", info.bus_id);
-
-        if let Some(url) = &device.url {
-            print!("{}", url);
-        } else {
-            print!("unknown host, remote port and remote busid");
+        Command::Unbind { bus_id } => {
+            Report::Unbound(unbind_device(&bus_id).unwrap_or_else(|e| fail(e)))
         }
+        Command::Export {
+            remote_host,
+            bus_id,
+        } => {
+            let (tx, rx) = std::sync::mpsc::channel();
+            ctrlc::set_handler(move || {
+                let _ = tx.send(());
+            })
+            .unwrap_or_else(|e| fail(e));
+
+            let exported = export_device(&remote_host, UsbIpSocket::DEFAULT_PORT, &bus_id)
+                .unwrap_or_else(|e| fail(e));
+
+            render(
+                &Report::Exported {
+                    host: remote_host,
+                    bus_id,
+                },
+                format,
+            );
 
-        println!();
-
-        println!(
-            "{:>10} -> remote bus/dev {:03}/{:03}",
-            "", device.remote_bus_num, device.remote_dev_num
-        );
-    }
-}
-
-fn print_remote_exported_devices(host: &str, devices: &[RemoteExportedDevice]) {
-    println!("Exportable USB devices");
-    println!("======================");
-
-    println!(" - {}", host);
-
-    for device in devices {
-        let info = &device.remote_device_info;
-
-        print!("{:>11}: ", info.bus_id,);
-
-        if let Some(vendor) = &device.vendor {
-            print!("{vendor}");
-        } else {
-            print!("unknown vendor");
-        }
+            let _ = rx.recv();
 
-        print!(" : ");
+            exported.stop().unwrap_or_else(|e| fail(e));
 
-        if let Some(product) = &device.product {
-            print!("{product}");
-        } else {
-            print!("unknown product");
+            return;
         }
+        Command::Port { group_by_host } => {
+            let devices = list_imported_devices().unwrap_or_else(|e| fail(e));
 
-        println!(" ({:04x}:{:04x})", info.id_vendor, info.id_product);
-
-        println!("{:>11}: {}", "", info.sys_path);
-
-        print!("{:>11}: ", "");
-
-        if info.b_device_class == 0 && info.b_device_sub_class == 0 && info.b_device_protocol == 0 {
-            print!("(Defined at Interface level)");
-        } else {
-            if let Some(class) = &device.class {
-                print!("{class}");
-            } else {
-                print!("unknown class");
-            }
-
-            print!(" / ");
-
-            if let Some(sub_class) = &device.sub_class {
-                print!("{sub_class}");
+            if group_by_host {
+                Report::ImportedDevicesByHost(port::group_by_host(devices))
             } else {
-                print!("unknown subclass");
-            }
-
-            print!(" / ");
-
-            if let Some(protocol) = &device.protocol {
-                print!("{protocol}");
-            } else {
-                print!("unknown protocol");
+                Report::ImportedDevices(devices)
             }
         }
+        Command::Capacity => {
+            let vhci_hcd = VhciHcd::open().unwrap_or_else(|e| fail(e));
 
-        println!(
-            " ({:02x}/{:02x}/{:02x})",
-            info.b_device_class, info.b_device_sub_class, info.b_device_protocol
-        );
-
-        for (i, iface) in device.interfaces.iter().enumerate() {
-            print!("{:>11}: {:>2} - ", "", i);
-
-            if let Some(class) = &iface.class {
-                print!("{class}");
-            } else {
-                print!("unknown class");
-            }
-
-            print!(" / ");
-
-            if let Some(sub_class) = &iface.sub_class {
-                print!("{sub_class}");
-            } else {
-                print!("unknown subclass");
-            }
-
-            print!(" / ");
-
-            if let Some(protocol) = &iface.protocol {
-                print!("{protocol}");
-            } else {
-                print!("unknown protocol");
-            }
+            Report::Capacity(CapacityReport::new(&vhci_hcd))
+        }
+        Command::DebugDump => {
+            // the dump is most useful when the status can't be parsed, so it
+            // must not go through `VhciHcd::open`
+            let raw_status = read_raw_status().unwrap_or_else(|e| fail(e));
 
-            println!(
-                " ({:02x}/{:02x}/{:02x})",
-                iface.b_interface_class, iface.b_interface_sub_class, iface.b_interface_protocol
-            );
+            Report::DebugDump(DebugDump::new(raw_status))
         }
+        Command::Version => Report::Version(VersionReport::new()),
+    };
 
-        println!();
-    }
+    render(&report, format);
 }
 
-fn print_local_exportable_devices(devices: &[LocalExportableDevice], parsable: bool) {
-    for device in devices {
-        if parsable {
-            print!(
-                "busid={}#usbid={:04x}:{:04x}#",
-                device.device_info.bus_id,
-                device.device_info.id_vendor,
-                device.device_info.id_product
-            );
-        } else {
-            println!(
-                " - busid {} ({:04x}:{:04x})",
-                device.device_info.bus_id,
-                device.device_info.id_vendor,
-                device.device_info.id_product
-            );
-
-            print!("   ");
+/// Prints the error and exits with the code for its kind of failure (see
+/// [`exit_code`])
+fn fail<E: Display + 'static>(e: E) -> ! {
+    eprintln!("{} {e}", "Error:".red());
+    std::process::exit(exit_code_for(&e));
+}
 
-            if let Some(vendor) = &device.vendor {
-                print!("{vendor}");
-            } else {
-                print!("unknown vendor");
-            }
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
 
-            print!(" : ");
+    use super::*;
 
-            if let Some(product) = &device.product {
-                print!("{product}");
-            } else {
-                print!("unknown product");
-            }
+    fn output_format(flags: &[&str]) -> OutputFormat {
+        let args = ["usbip"].iter().chain(flags).chain(&["port"]);
 
-            println!(
-                " ({:04x}:{:04x})",
-                device.device_info.id_vendor, device.device_info.id_product
-            );
-        }
+        Args::try_parse_from(args).unwrap().output_format()
+    }
 
-        println!();
+    #[test]
+    fn quiet_only_affects_human_output() {
+        assert_eq!(output_format(&[]), OutputFormat::Human);
+        assert_eq!(output_format(&["-q"]), OutputFormat::Quiet);
+        assert_eq!(output_format(&["--quiet", "-j"]), OutputFormat::Json);
+        assert_eq!(
+            output_format(&["-q", "--format", "json"]),
+            OutputFormat::Json
+        );
+        assert_eq!(
+            output_format(&["-q", "--format", "table"]),
+            OutputFormat::Table
+        );
+        assert_eq!(output_format(&["--json-output"]), OutputFormat::Json);
     }
 }
@@ -0,0 +1,1152 @@
+//! Output layer shared by all subcommands. Each subcommand produces a
+//! [`Report`] which is then rendered in the format selected on the command line.
+
+use std::collections::HashMap;
+
+use colored::Colorize;
+use serde_json::Value;
+use usbip::{
+    UsbSpeed,
+    client::{
+        attach::{AttachSpec, Error as AttachError},
+        list::{DeviceInterface, RemoteExportedDevice},
+        port::{ImportedDevice, UNKNOWN_HOST},
+    },
+    drivers::{
+        vhci::{
+            HubSpeed, KernelCompat, RawStatus, StatusLine, VhciDeviceStatus, VhciHcd,
+            kernel_compat, parse_status, status_attr_name,
+        },
+        vudc::{VirtualUdc, VudcStatus},
+    },
+    hwdb::id_source,
+    proto::{USBIP_VERSION, format_version},
+    server::{
+        bind::{BindOutcome, BindPlan, BindResult, RepairAction},
+        list_local::LocalExportableDevice,
+        unbind::UnbindOutcome,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable output
+    Human,
+    /// A single JSON value terminated by a `\n`
+    Json,
+    /// Newline-delimited JSON, with one line per device (or other entry) of
+    /// the report
+    Ndjson,
+    /// YAML document
+    Yaml,
+    /// Columns of the flattened report fields
+    Table,
+    /// `key=value#` pairs with one entry per line
+    Parsable,
+    /// Only the bare minimum (e.g. attached ports), selected with `--quiet`
+    #[value(skip)]
+    Quiet,
+}
+
+/// The result of a successful subcommand
+pub enum Report {
+    Attached {
+        port: u32,
+    },
+    BatchAttached(Vec<BatchAttachResult>),
+    Detached {
+        port: u16,
+    },
+    DetachedController {
+        controller: u16,
+        ports: Vec<u16>,
+    },
+    Bound(BindOutcome),
+    BatchBound(Vec<BatchBindResult>),
+    BindPlanned(BindPlan),
+    Repaired {
+        bus_id: String,
+        action: RepairAction,
+    },
+    Unbound(UnbindOutcome),
+    Exported {
+        host: String,
+        bus_id: String,
+    },
+    RemoteDevices {
+        host: String,
+        devices: Vec<RemoteExportedDevice>,
+    },
+    LocalDevices(Vec<LocalExportableDevice>),
+    ImportedDevices(Vec<ImportedDevice>),
+    ImportedDevicesByHost(HashMap<String, Vec<ImportedDevice>>),
+    Capacity(CapacityReport),
+    DebugDump(DebugDump),
+    VirtualUdcs(Vec<VirtualUdc>),
+    Version(VersionReport),
+}
+
+/// Outcome of attaching a single device of a batch
+#[derive(Debug, serde::Serialize)]
+pub struct BatchAttachResult {
+    #[serde(flatten)]
+    pub spec: AttachSpec,
+    /// Local vhci_hcd port the device was attached to
+    pub vhci_port: Option<u32>,
+    pub error: Option<String>,
+}
+
+impl BatchAttachResult {
+    pub fn new(spec: AttachSpec, result: Result<u32, AttachError>) -> Self {
+        let (vhci_port, error) = match result {
+            Ok(port) => (Some(port), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        Self {
+            spec,
+            vhci_port,
+            error,
+        }
+    }
+}
+
+/// Outcome of binding a single device with `bind --all`
+#[derive(Debug, serde::Serialize)]
+pub struct BatchBindResult {
+    pub bus_id: String,
+    pub previous_driver: Option<String>,
+    pub error: Option<String>,
+}
+
+impl BatchBindResult {
+    pub fn new(bus_id: String, result: BindResult) -> Self {
+        let (previous_driver, error) = match result {
+            Ok(outcome) => (outcome.previous_driver, None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        Self {
+            bus_id,
+            previous_driver,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CapacityReport {
+    pub controllers: u16,
+    pub total_ports: u16,
+    pub ports: PortsBySpeed,
+    pub free_ports: PortsBySpeed,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PortsBySpeed {
+    pub high: u16,
+    #[serde(rename = "super")]
+    pub super_: u16,
+}
+
+impl CapacityReport {
+    pub fn new(vhci_hcd: &VhciHcd) -> Self {
+        Self {
+            controllers: vhci_hcd.controller_count(),
+            total_ports: vhci_hcd.total_port_count(),
+            ports: PortsBySpeed {
+                high: vhci_hcd.port_count(HubSpeed::High),
+                super_: vhci_hcd.port_count(HubSpeed::Super),
+            },
+            free_ports: PortsBySpeed {
+                high: vhci_hcd.free_port_count(HubSpeed::High),
+                super_: vhci_hcd.free_port_count(HubSpeed::Super),
+            },
+        }
+    }
+}
+
+/// Raw and parsed vhci_hcd state, used to troubleshoot wrong `port` output
+#[derive(Debug, serde::Serialize)]
+pub struct DebugDump {
+    pub kernel: KernelCompat,
+    pub nports: u16,
+    pub controllers: u16,
+    pub status: Vec<ControllerStatus>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ControllerStatus {
+    pub attribute: String,
+    /// The attribute contents exactly as reported by the kernel
+    pub raw: String,
+    pub parsed: Option<Vec<StatusLine>>,
+    pub parse_error: Option<String>,
+}
+
+impl DebugDump {
+    pub fn new(raw_status: RawStatus) -> Self {
+        let status = raw_status
+            .status
+            .into_iter()
+            .enumerate()
+            .map(|(i, raw)| {
+                let (parsed, parse_error) = match parse_status(&raw) {
+                    Ok(lines) => (Some(lines), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+
+                ControllerStatus {
+                    attribute: status_attr_name(i as _),
+                    raw,
+                    parsed,
+                    parse_error,
+                }
+            })
+            .collect();
+
+        Self {
+            kernel: kernel_compat(),
+            nports: raw_status.num_ports as _,
+            controllers: raw_status.num_controllers as _,
+            status,
+        }
+    }
+}
+
+/// Versions to include in bug reports
+#[derive(Debug, serde::Serialize)]
+pub struct VersionReport {
+    pub version: &'static str,
+    /// The usbip protocol version sent in every request (e.g. `1.1.1`)
+    pub protocol_version: String,
+    /// Where USB vendor, product and class names are looked up
+    pub hwdb: String,
+}
+
+impl VersionReport {
+    pub fn new() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            protocol_version: format_version(USBIP_VERSION),
+            hwdb: id_source().to_string(),
+        }
+    }
+}
+
+impl Report {
+    /// The machine readable form of the report shared by the JSON, YAML,
+    /// table and parsable formats
+    fn to_value(&self) -> Value {
+        match self {
+            Report::Attached { port } => serde_json::json!({ "port": port }),
+            Report::BatchAttached(results) => serde_json::to_value(results).unwrap(),
+            Report::Detached { port } => serde_json::json!({ "port": port }),
+            Report::DetachedController { controller, ports } => serde_json::json!({
+                "controller": controller,
+                "ports": ports,
+            }),
+            Report::Bound(outcome) => serde_json::json!({
+                "bus_id": outcome.bus_id,
+                "action": "bind",
+                "previous_driver": outcome.previous_driver,
+            }),
+            Report::BatchBound(results) => serde_json::to_value(results).unwrap(),
+            Report::BindPlanned(plan) => serde_json::to_value(plan).unwrap(),
+            Report::Repaired { bus_id, action } => serde_json::json!({
+                "bus_id": bus_id,
+                "action": action,
+            }),
+            Report::Unbound(outcome) => serde_json::json!({
+                "bus_id": outcome.bus_id,
+                "action": "unbind",
+                "previous_driver": outcome.original_driver,
+            }),
+            Report::Exported { host, bus_id } => {
+                serde_json::json!({ "host": host, "bus_id": bus_id })
+            }
+            Report::RemoteDevices { devices, .. } => serde_json::to_value(devices).unwrap(),
+            Report::LocalDevices(devices) => serde_json::to_value(devices).unwrap(),
+            Report::VirtualUdcs(udcs) => serde_json::to_value(udcs).unwrap(),
+            Report::ImportedDevices(devices) => serde_json::to_value(devices).unwrap(),
+            Report::ImportedDevicesByHost(groups) => Value::Object(
+                sorted_hosts(groups)
+                    .into_iter()
+                    .map(|host| (host.clone(), serde_json::to_value(&groups[host]).unwrap()))
+                    .collect(),
+            ),
+            Report::Capacity(capacity) => serde_json::to_value(capacity).unwrap(),
+            Report::DebugDump(dump) => serde_json::to_value(dump).unwrap(),
+            Report::Version(version) => serde_json::to_value(version).unwrap(),
+        }
+    }
+}
+
+pub fn render(report: &Report, format: OutputFormat) {
+    match format {
+        OutputFormat::Human => print_human(report),
+        OutputFormat::Quiet => print_quiet(report),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&report.to_value()).unwrap()),
+        OutputFormat::Ndjson => print!("{}", format_ndjson(&report.to_value())),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&report.to_value()).unwrap()),
+        OutputFormat::Table => print!("{}", format_table(&report.to_value())),
+        OutputFormat::Parsable => match report {
+            // keep the exact format of the original CLI
+            Report::LocalDevices(devices) => print_local_exportable_devices(devices, true),
+            r => print!("{}", format_parsable(&r.to_value())),
+        },
+    }
+}
+
+/// Prints a single line of `--format ndjson` output
+pub fn print_ndjson_line(value: &impl serde::Serialize) {
+    println!("{}", serde_json::to_string(value).unwrap());
+}
+
+fn print_human(report: &Report) {
+    match report {
+        Report::Attached { port } => println!("Device attached successfuly to port {port}"),
+        Report::BatchAttached(results) => {
+            for r in results {
+                let AttachSpec { host, port, bus_id } = &r.spec;
+
+                match (&r.vhci_port, &r.error) {
+                    (Some(vhci_port), _) => println!(
+                        "Device {bus_id} from {host}:{port} attached successfuly to port {vhci_port}"
+                    ),
+                    (None, _) => print_batch_attach_error(r),
+                }
+            }
+        }
+        Report::Detached { port } => println!("Device detached successfully from port {port}"),
+        Report::DetachedController { controller, ports } => {
+            if ports.is_empty() {
+                println!("No devices attached to controller {controller}");
+            }
+
+            for port in ports {
+                println!("Device detached successfully from port {port}");
+            }
+        }
+        Report::Bound(BindOutcome {
+            bus_id,
+            previous_driver,
+        }) => match previous_driver {
+            Some(driver) => println!(
+                "Device with bus id {bus_id} bound successfully (previously bound to {driver})"
+            ),
+            None => println!("Device with bus id {bus_id} bound successfully"),
+        },
+        Report::BatchBound(results) => {
+            if results.is_empty() {
+                println!("No devices to bind");
+            }
+
+            for r in results {
+                match (&r.error, &r.previous_driver) {
+                    (Some(_), _) => print_batch_bind_error(r),
+                    (None, Some(driver)) => println!(
+                        "Device with bus id {} bound successfully (previously bound to {driver})",
+                        r.bus_id
+                    ),
+                    (None, None) => println!("Device with bus id {} bound successfully", r.bus_id),
+                }
+            }
+        }
+        Report::Repaired { bus_id, action } => match action {
+            RepairAction::NothingToRepair => {
+                println!("Device with bus id {bus_id} is in a consistent state, nothing to repair")
+            }
+            RepairAction::CompletedBind => {
+                println!("Device with bus id {bus_id} was not bound yet, bound it to usbip-host")
+            }
+            RepairAction::AddedToMatchList => println!(
+                "Device with bus id {bus_id} was missing from the match list of usbip-host, added it"
+            ),
+            RepairAction::RemovedFromMatchList => println!(
+                "Device with bus id {bus_id} had a stale entry in the match list of usbip-host, removed it"
+            ),
+            RepairAction::RevertedBind => println!(
+                "Device with bus id {bus_id} could not be bound to usbip-host, gave it back to its original driver"
+            ),
+        },
+        Report::BindPlanned(plan) => match (&plan.blocker, &plan.current_driver) {
+            (Some(blocker), _) => println!(
+                "Device with bus id {} would not be bound: {blocker}",
+                plan.bus_id
+            ),
+            (None, Some(driver)) => println!(
+                "Device with bus id {} would be bound (displacing {driver})",
+                plan.bus_id
+            ),
+            (None, None) => println!("Device with bus id {} would be bound", plan.bus_id),
+        },
+        Report::Unbound(UnbindOutcome {
+            bus_id,
+            original_driver,
+        }) => match original_driver {
+            Some(driver) => println!(
+                "Device with bus id {bus_id} unbound successfully (given back to {driver})"
+            ),
+            None => println!("Device with bus id {bus_id} unbound successfully"),
+        },
+        Report::Exported { host, bus_id } => {
+            println!("Device with bus id {bus_id} exported to {host}, press Ctrl-C to stop")
+        }
+        Report::RemoteDevices { host, devices } => print_remote_exported_devices(host, devices),
+        Report::LocalDevices(devices) => print_local_exportable_devices(devices, false),
+        Report::ImportedDevices(devices) => print_imported_devices(devices),
+        Report::ImportedDevicesByHost(groups) => print_imported_devices_by_host(groups),
+        Report::Capacity(capacity) => print_capacity(capacity),
+        Report::DebugDump(dump) => print_debug_dump(dump),
+        Report::VirtualUdcs(udcs) => print_virtual_udcs(udcs),
+        Report::Version(version) => {
+            println!("usbip {}", version.version);
+            println!(
+                "protocol version {} (0x{USBIP_VERSION:04x})",
+                version.protocol_version
+            );
+            println!("hwdb: {}", version.hwdb);
+        }
+    }
+}
+
+fn print_batch_attach_error(result: &BatchAttachResult) {
+    let AttachSpec { host, port, bus_id } = &result.spec;
+
+    eprintln!(
+        "{} Device {bus_id} from {host}:{port} could not be attached: {}",
+        "Error:".red(),
+        result.error.as_deref().unwrap_or_default()
+    );
+}
+
+fn print_batch_bind_error(result: &BatchBindResult) {
+    eprintln!(
+        "{} Device with bus id {} could not be bound: {}",
+        "Error:".red(),
+        result.bus_id,
+        result.error.as_deref().unwrap_or_default()
+    );
+}
+
+fn print_quiet(report: &Report) {
+    print!("{}", format_quiet(report));
+
+    // errors are always printed
+    match report {
+        Report::BatchAttached(results) => {
+            for r in results.iter().filter(|r| r.vhci_port.is_none()) {
+                print_batch_attach_error(r);
+            }
+        }
+        Report::BatchBound(results) => {
+            for r in results.iter().filter(|r| r.error.is_some()) {
+                print_batch_bind_error(r);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The success output of `--quiet`, which is only the bare ports of attached
+/// devices
+fn format_quiet(report: &Report) -> String {
+    match report {
+        Report::Attached { port } => format!("{port}\n"),
+        Report::BatchAttached(results) => results
+            .iter()
+            .filter_map(|r| r.vhci_port)
+            .map(|port| format!("{port}\n"))
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+/// Flattens nested objects into `parent.child` keys. Arrays are summarized by
+/// their length since they can't be represented in a single cell.
+fn flatten(value: &Value, prefix: &str, out: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map {
+                let key = if prefix.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{prefix}.{k}")
+                };
+
+                flatten(v, &key, out);
+            }
+        }
+        Value::Array(items) => out.push((prefix.into(), items.len().to_string())),
+        Value::Null => out.push((prefix.into(), String::new())),
+        Value::String(s) => out.push((prefix.into(), s.clone())),
+        v => out.push((prefix.into(), v.to_string())),
+    }
+}
+
+fn flatten_rows(value: &Value) -> Vec<Vec<(String, String)>> {
+    let items = match value {
+        Value::Array(items) => items.iter().collect(),
+        v => vec![v],
+    };
+
+    items
+        .into_iter()
+        .map(|item| {
+            let mut row = Vec::new();
+            flatten(item, "", &mut row);
+            row
+        })
+        .filter(|row| !row.is_empty())
+        .collect()
+}
+
+fn format_table(value: &Value) -> String {
+    let rows = flatten_rows(value);
+
+    let mut columns: Vec<String> = Vec::new();
+    for (key, _) in rows.iter().flatten() {
+        if !columns.contains(key) {
+            columns.push(key.clone());
+        }
+    }
+
+    if columns.is_empty() {
+        return String::new();
+    }
+
+    let cell = |row: &[(String, String)], column: &str| {
+        row.iter()
+            .find(|(k, _)| k == column)
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default()
+    };
+
+    let widths: Vec<usize> = columns
+        .iter()
+        .map(|c| {
+            rows.iter()
+                .map(|r| cell(r, c).len())
+                .chain([c.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let mut table = String::new();
+
+    let mut push_row = |cells: Vec<String>| {
+        let line: Vec<_> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{c:<w$}"))
+            .collect();
+
+        table += line.join("  ").trim_end();
+        table.push('\n');
+    };
+
+    push_row(columns.clone());
+    push_row(widths.iter().map(|w| "-".repeat(*w)).collect());
+
+    for row in &rows {
+        push_row(columns.iter().map(|c| cell(row, c)).collect());
+    }
+
+    table
+}
+
+/// One line per item if the report is a list, otherwise a single line
+fn format_ndjson(value: &Value) -> String {
+    let items = match value {
+        Value::Array(items) => items.as_slice(),
+        value => core::slice::from_ref(value),
+    };
+
+    items
+        .iter()
+        .map(|item| serde_json::to_string(item).unwrap() + "\n")
+        .collect()
+}
+
+fn format_parsable(value: &Value) -> String {
+    let mut parsable = String::new();
+
+    for row in flatten_rows(value) {
+        for (k, v) in row {
+            parsable += &format!("{k}={v}#");
+        }
+
+        parsable.push('\n');
+    }
+
+    parsable
+}
+
+fn print_capacity(capacity: &CapacityReport) {
+    println!("vhci_hcd capacity");
+    println!("=================");
+
+    println!("Controllers: {}", capacity.controllers);
+    println!("Total ports: {}", capacity.total_ports);
+
+    println!(
+        "High Speed ports: {} ({} free)",
+        capacity.ports.high, capacity.free_ports.high
+    );
+    println!(
+        "Super Speed ports: {} ({} free)",
+        capacity.ports.super_, capacity.free_ports.super_
+    );
+}
+
+fn print_virtual_udcs(udcs: &[VirtualUdc]) {
+    println!("List of virtual UDCs");
+    println!("====================");
+
+    for udc in udcs {
+        let status = match udc.status {
+            VudcStatus::Available => "available",
+            VudcStatus::Used => "used",
+            VudcStatus::Error => "error",
+        };
+
+        match &udc.device_descriptor {
+            Some(desc) => println!(
+                " - {} ({status}): gadget {:04x}:{:04x} at {:?} speed",
+                udc.name, desc.id_vendor, desc.id_product, udc.current_speed
+            ),
+            None => println!(" - {} ({status}): no gadget bound", udc.name),
+        }
+    }
+}
+
+fn print_debug_dump(dump: &DebugDump) {
+    println!("vhci_hcd debug dump");
+    println!("===================");
+
+    match &dump.kernel {
+        KernelCompat::Supported { version } => println!("kernel: {version} (supported)"),
+        KernelCompat::Unsupported { version, reason } => {
+            println!("kernel: {version} ({}: {reason})", "unsupported".red())
+        }
+        KernelCompat::Unknown { release } => println!("kernel: {release:?} (unknown version)"),
+    }
+
+    println!("nports: {}", dump.nports);
+    println!("controllers: {}", dump.controllers);
+
+    for controller in &dump.status {
+        println!();
+        println!("{}:", controller.attribute.bold());
+        print!("{}", controller.raw);
+
+        if !controller.raw.ends_with('\n') {
+            println!();
+        }
+
+        println!();
+        println!("parsed:");
+
+        match (&controller.parsed, &controller.parse_error) {
+            (Some(lines), _) => {
+                for line in lines {
+                    println!(
+                        "  {} port {}: status {}, speed {}, device {:08x}, fd {}, bus id {}",
+                        line.hub,
+                        line.port,
+                        line.status,
+                        line.speed,
+                        line.device_id,
+                        line.socket_fd,
+                        line.bus_id
+                    );
+                }
+            }
+            (None, error) => println!("  {}", error.as_deref().unwrap_or_default().red()),
+        }
+    }
+}
+
+/// The hosts of the groups in display order, with the group of devices whose
+/// host is unknown last
+fn sorted_hosts(groups: &HashMap<String, Vec<ImportedDevice>>) -> Vec<&String> {
+    let mut hosts: Vec<_> = groups.keys().collect();
+
+    hosts.sort_by_key(|host| (host.as_str() == UNKNOWN_HOST, host.as_str()));
+
+    hosts
+}
+
+fn print_imported_devices(devices: &[ImportedDevice]) {
+    println!("Imported USB devices");
+    println!("====================");
+
+    for device in devices {
+        print_imported_device(device);
+    }
+}
+
+fn print_imported_devices_by_host(groups: &HashMap<String, Vec<ImportedDevice>>) {
+    println!("Imported USB devices");
+    println!("====================");
+
+    for host in sorted_hosts(groups) {
+        if host == UNKNOWN_HOST {
+            println!("Unknown host:");
+        } else {
+            println!("{host}:");
+        }
+
+        for device in &groups[host] {
+            print_imported_device(device);
+        }
+    }
+}
+
+fn print_imported_device(device: &ImportedDevice) {
+    let info = &device.local_device_info;
+
+    print!("Port {:02}: ", device.port);
+
+    if let Some(alias) = &device.alias {
+        print!("[{alias}] ");
+    }
+
+    print!("<");
+
+    match device.status {
+        // TODO: impl printing for unused and initializing ports if we allow outputting those
+        VhciDeviceStatus::NotConnected | VhciDeviceStatus::NotAssigned => unreachable!(),
+        VhciDeviceStatus::Used => print!("Port in Use"),
+        VhciDeviceStatus::Error => print!("Port Error"),
+    }
+
+    print!("> at ");
+
+    match info.speed {
+        UsbSpeed::Unknown => print!("Unknown Speed"),
+        UsbSpeed::Low => print!("Low Speed(1.5Mbps)"),
+        UsbSpeed::Full => print!("Full Speed(12Mbps)"),
+        UsbSpeed::High => print!("High Speed(480Mbps)"),
+        UsbSpeed::Wireless => print!("Wireless"),
+        UsbSpeed::Super => print!("Super Speed(5000Mbps)"),
+        // not in the original impl since it was stanrdized after that code
+        // was written, but probably good to have
+        UsbSpeed::SuperPlus => print!("Super Speed Plus(10000Mbps)"),
+    }
+
+    println!();
+
+    print!("       ");
+
+    if let Some(vendor) = &device.vendor {
+        print!("{vendor}");
+    } else {
+        print!("unknown vendor");
+    }
+
+    print!(" : ");
+
+    if let Some(product) = &device.product {
+        print!("{product}");
+    } else {
+        print!("unknown product");
+    }
+
+    println!(" ({:04x}:{:04x})", info.id_vendor, info.id_product);
+
+    print!("{:>10} -> ", info.bus_id);
+
+    if let Some(url) = &device.url {
+        print!("{}", url);
+    } else {
+        print!("unknown host, remote port and remote busid");
+    }
+
+    println!();
+
+    println!(
+        "{:>10} -> remote bus/dev {:03}/{:03}",
+        "", device.remote_bus_num, device.remote_dev_num
+    );
+}
+
+fn print_remote_exported_devices(host: &str, devices: &[RemoteExportedDevice]) {
+    print_remote_devices_header(host);
+
+    for device in devices {
+        print_remote_exported_device(device);
+    }
+}
+
+/// Prints the header of the human readable remote device list, which comes
+/// before the devices printed by [`print_remote_exported_device`] (and is
+/// printed even if the server has no devices)
+pub fn print_remote_devices_header(host: &str) {
+    println!("Exportable USB devices");
+    println!("======================");
+
+    println!(" - {}", host);
+}
+
+/// Prints a single device of the human readable remote device list (used to
+/// print devices as they are received)
+pub fn print_remote_exported_device(device: &RemoteExportedDevice) {
+    let info = &device.remote_device_info;
+
+    print!("{:>11}: ", info.bus_id,);
+
+    if let Some(vendor) = &device.vendor {
+        print!("{vendor}");
+    } else {
+        print!("unknown vendor");
+    }
+
+    print!(" : ");
+
+    if let Some(product) = &device.product {
+        print!("{product}");
+    } else {
+        print!("unknown product");
+    }
+
+    println!(" ({:04x}:{:04x})", info.id_vendor, info.id_product);
+
+    println!("{:>11}: {}", "", info.sys_path);
+
+    if info.class_defined_at_interface_level() {
+        println!(
+            "{:>11}: (Defined at Interface level) ({:02x}/{:02x}/{:02x})",
+            "", info.b_device_class, info.b_device_sub_class, info.b_device_protocol
+        );
+    } else {
+        println!(
+            "{:>11}: {}",
+            "",
+            format_class_display(
+                info.b_device_class,
+                info.b_device_sub_class,
+                info.b_device_protocol,
+                [
+                    device.class.as_deref(),
+                    device.sub_class.as_deref(),
+                    device.protocol.as_deref(),
+                ],
+            )
+        );
+    }
+
+    print_device_interfaces(&device.interfaces);
+
+    println!();
+}
+
+/// Prints the interfaces of a device below it, indented to line up with the
+/// legacy `usbip list` output
+fn print_device_interfaces(interfaces: &[DeviceInterface]) {
+    for (i, iface) in interfaces.iter().enumerate() {
+        println!(
+            "{:>11}: {:>2} - {} [{}]",
+            "",
+            i,
+            format_class_display(
+                iface.b_interface_class,
+                iface.b_interface_sub_class,
+                iface.b_interface_protocol,
+                [
+                    iface.class.as_deref(),
+                    iface.sub_class.as_deref(),
+                    iface.protocol.as_deref(),
+                ],
+            ),
+            iface.bus_id
+        );
+    }
+}
+
+/// Formats a class triple like lsusb does (`Name / Name / Name (xx/xx/xx)`),
+/// where `names` are the display names of the class, subclass and protocol
+fn format_class_display(
+    class: u8,
+    sub_class: u8,
+    protocol: u8,
+    names: [Option<&str>; 3],
+) -> String {
+    let [class_name, sub_class_name, protocol_name] = names;
+
+    format!(
+        "{} / {} / {} ({class:02x}/{sub_class:02x}/{protocol:02x})",
+        class_name.unwrap_or("unknown class"),
+        sub_class_name.unwrap_or("unknown subclass"),
+        protocol_name.unwrap_or("unknown protocol"),
+    )
+}
+
+fn print_local_exportable_devices(devices: &[LocalExportableDevice], parsable: bool) {
+    for device in devices {
+        if parsable {
+            print!(
+                "busid={}#usbid={:04x}:{:04x}#",
+                device.device_info.bus_id,
+                device.device_info.id_vendor,
+                device.device_info.id_product
+            );
+        } else {
+            println!(
+                " - busid {} ({:04x}:{:04x})",
+                device.device_info.bus_id,
+                device.device_info.id_vendor,
+                device.device_info.id_product
+            );
+
+            print!("   ");
+
+            if let Some(vendor) = &device.vendor {
+                print!("{vendor}");
+            } else {
+                print!("unknown vendor");
+            }
+
+            print!(" : ");
+
+            if let Some(product) = &device.product {
+                print!("{product}");
+            } else {
+                print!("unknown product");
+            }
+
+            println!(
+                " ({:04x}:{:04x})",
+                device.device_info.id_vendor, device.device_info.id_product
+            );
+
+            print_device_interfaces(&device.interfaces);
+        }
+
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn imported_device(port: u16, bus_id: &str) -> ImportedDevice {
+        ImportedDevice {
+            port,
+            hub_speed: HubSpeed::High,
+            status: VhciDeviceStatus::Used,
+            remote_host: Some("192.168.1.20".into()),
+            remote_port: Some(3240),
+            remote_bus_id: Some(bus_id.into()),
+            url: Some(format!("usbip://192.168.1.20:3240/{bus_id}")),
+            alias: None,
+            remote_bus_num: 1,
+            remote_dev_num: 2,
+            vendor: Some("Linux Foundation".into()),
+            product: None,
+            manufacturer_string: "Linux 6.1 dummy_hcd".into(),
+            product_string: "Gadget Zero".into(),
+            local_device_info: usbip::UsbDeviceInfo {
+                sys_path: format!("/sys/devices/platform/vhci_hcd.0/usb3/3-{port}"),
+                bus_id: format!("3-{port}").into(),
+                bus_num: 3,
+                dev_num: 2,
+                speed: UsbSpeed::High,
+                id_vendor: 0x1d6b,
+                id_product: 0x0104,
+                bcd_device: 0x0100,
+                b_device_class: 0,
+                b_device_sub_class: 0,
+                b_device_protocol: 0,
+                b_configuration_value: Some(1),
+                b_num_configurations: 1,
+                b_num_interfaces: 1,
+            },
+            dev_nodes: vec!["/dev/bus/usb/003/002".into()],
+        }
+    }
+
+    fn port_report() -> Report {
+        Report::ImportedDevices(vec![imported_device(1, "1-1"), imported_device(2, "1-2")])
+    }
+
+    #[test]
+    fn port_report_round_trips_through_json_and_yaml() {
+        let value = port_report().to_value();
+
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+
+        let yaml = serde_yaml::to_string(&value).unwrap();
+        assert_eq!(serde_yaml::from_str::<Value>(&yaml).unwrap(), value);
+    }
+
+    #[test]
+    fn port_report_renders_one_table_row_per_device() {
+        let table = format_table(&port_report().to_value());
+        let lines: Vec<_> = table.lines().collect();
+
+        // header, separator and one row per device
+        assert_eq!(lines.len(), 4, "{table}");
+
+        let columns: Vec<_> = lines[0].split_whitespace().collect();
+        assert_eq!(columns[0], "port");
+        assert!(columns.contains(&"local_device_info.bus_id"), "{table}");
+        assert!(columns.contains(&"dev_nodes"), "{table}");
+
+        assert!(lines[1].chars().all(|c| c == '-' || c == ' '));
+        assert!(lines[2].starts_with("1 "), "{table}");
+        assert!(lines[3].starts_with("2 "), "{table}");
+    }
+
+    #[test]
+    fn port_report_renders_one_parsable_line_per_device() {
+        let parsable = format_parsable(&port_report().to_value());
+        let lines: Vec<_> = parsable.lines().collect();
+
+        assert_eq!(lines.len(), 2, "{parsable}");
+
+        for (line, port) in lines.iter().zip(1..) {
+            let fields: HashMap<_, _> = line
+                .strip_suffix('#')
+                .unwrap()
+                .split('#')
+                .map(|field| field.split_once('=').unwrap())
+                .collect();
+
+            assert_eq!(fields["port"], port.to_string());
+            assert_eq!(fields["remote_bus_id"], format!("1-{port}"));
+            assert_eq!(fields["local_device_info.bus_id"], format!("3-{port}"));
+            assert_eq!(fields["dev_nodes"], "1");
+        }
+    }
+
+    #[test]
+    fn port_report_renders_one_json_document_per_line() {
+        let ndjson = format_ndjson(&port_report().to_value());
+        let lines: Vec<_> = ndjson.lines().collect();
+
+        assert_eq!(lines.len(), 2, "{ndjson}");
+
+        for (line, port) in lines.iter().zip(1..) {
+            let device: Value = serde_json::from_str(line).unwrap();
+
+            assert_eq!(device["port"], port);
+            assert_eq!(device["remote_bus_id"], format!("1-{port}"));
+        }
+    }
+
+    #[test]
+    fn single_reports_render_as_one_ndjson_line() {
+        let report = Report::Bound(BindOutcome {
+            bus_id: "1-1".into(),
+            previous_driver: None,
+        });
+
+        let ndjson = format_ndjson(&report.to_value());
+
+        assert_eq!(ndjson.lines().count(), 1, "{ndjson}");
+        assert_eq!(
+            serde_json::from_str::<Value>(ndjson.trim_end()).unwrap(),
+            report.to_value()
+        );
+    }
+
+    #[test]
+    fn bind_reports_the_displaced_driver() {
+        let report = Report::Bound(BindOutcome {
+            bus_id: "1-1".into(),
+            previous_driver: Some("usb-storage".into()),
+        });
+
+        assert_eq!(
+            report.to_value(),
+            serde_json::json!({
+                "bus_id": "1-1",
+                "action": "bind",
+                "previous_driver": "usb-storage",
+            })
+        );
+    }
+
+    #[test]
+    fn unbind_reports_the_original_driver() {
+        let report = Report::Unbound(UnbindOutcome {
+            bus_id: "1-1".into(),
+            original_driver: Some("usb-storage".into()),
+        });
+
+        assert_eq!(
+            report.to_value(),
+            serde_json::json!({
+                "bus_id": "1-1",
+                "action": "unbind",
+                "previous_driver": "usb-storage",
+            })
+        );
+
+        let report = Report::Unbound(UnbindOutcome {
+            bus_id: "1-1".into(),
+            original_driver: None,
+        });
+
+        assert_eq!(report.to_value()["previous_driver"], Value::Null);
+    }
+
+    #[test]
+    fn quiet_output_is_only_the_attached_ports() {
+        assert_eq!(format_quiet(&Report::Attached { port: 8 }), "8\n");
+
+        let spec = |bus_id: &str| AttachSpec {
+            host: "server.local".into(),
+            port: 3240,
+            bus_id: bus_id.into(),
+        };
+        let report = Report::BatchAttached(vec![
+            BatchAttachResult::new(spec("1-1"), Ok(0)),
+            BatchAttachResult::new(spec("1-2"), Err(AttachError::MaxAttemptsExceeded)),
+            BatchAttachResult::new(spec("1-3"), Ok(9)),
+        ]);
+        assert_eq!(format_quiet(&report), "0\n9\n");
+
+        assert_eq!(format_quiet(&Report::Detached { port: 8 }), "");
+        assert_eq!(format_quiet(&port_report()), "");
+    }
+
+    #[test]
+    fn formats_class_names_with_their_codes() {
+        assert_eq!(
+            format_class_display(
+                0x03,
+                0x01,
+                0x02,
+                [
+                    Some("Human Interface Device"),
+                    Some("Boot Interface Subclass"),
+                    Some("Mouse")
+                ]
+            ),
+            "Human Interface Device / Boot Interface Subclass / Mouse (03/01/02)"
+        );
+    }
+
+    #[test]
+    fn formats_unknown_class_names() {
+        assert_eq!(
+            format_class_display(
+                0xff,
+                0x0a,
+                0x00,
+                [Some("Vendor Specific Class"), None, None]
+            ),
+            "Vendor Specific Class / unknown subclass / unknown protocol (ff/0a/00)"
+        );
+        assert_eq!(
+            format_class_display(0x00, 0x00, 0x00, [None, None, None]),
+            "unknown class / unknown subclass / unknown protocol (00/00/00)"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! The daemon's configuration file. Every setting is optional, so an empty
+//! file results in the same configuration as passing no flags at all.
+//!
+//! ```toml
+//! addresses = ["0.0.0.0", "::"]
+//! port = 3240
+//! max_connections = 16
+//! request_timeout_secs = 10
+//! device_mode = false
+//!
+//! [[policy.allow]]
+//! id_vendor = 0x046d
+//!
+//! [[policy.deny]]
+//! client = "192.168.1.20"
+//! ```
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+
+use usbip::{
+    net::UsbIpSocket,
+    server::{daemon::ServerOptions, policy::ExportPolicy},
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on for client connections
+    pub addresses: Vec<IpAddr>,
+    /// TCP port to listen on (shared by all addresses)
+    pub port: u16,
+    /// Maximum number of client connections serviced at the same time, across
+    /// all addresses
+    pub max_connections: usize,
+    /// Seconds a client may take to send its request (0 disables the timeout)
+    pub request_timeout_secs: u64,
+    /// Export the gadgets bound to usbip-vudc instead of the devices bound to
+    /// usbip-host
+    pub device_mode: bool,
+    pub policy: ExportPolicy,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addresses: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port: UsbIpSocket::DEFAULT_PORT,
+            max_connections: ServerOptions::default().max_connections,
+            request_timeout_secs: ServerOptions::default()
+                .request_timeout
+                .map_or(0, |t| t.as_secs()),
+            device_mode: false,
+            policy: ExportPolicy::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    pub fn server_options(&self) -> ServerOptions {
+        ServerOptions {
+            policy: self.policy.clone(),
+            max_connections: self.max_connections,
+            request_timeout: (self.request_timeout_secs != 0)
+                .then(|| Duration::from_secs(self.request_timeout_secs)),
+            device_mode: self.device_mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use usbip::server::policy::PolicyRule;
+
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_the_defaults() {
+        let config = ServerConfig::from_toml("").unwrap();
+        let default = ServerConfig::default();
+
+        assert_eq!(config.addresses, default.addresses);
+        assert_eq!(config.port, default.port);
+        assert_eq!(config.max_connections, default.max_connections);
+        assert_eq!(config.request_timeout_secs, default.request_timeout_secs);
+        assert!(!config.device_mode);
+        assert!(config.policy.allow.is_empty() && config.policy.deny.is_empty());
+    }
+
+    #[test]
+    fn parses_a_sample_config() {
+        let config = ServerConfig::from_toml(
+            r#"
+            addresses = ["0.0.0.0", "::"]
+            port = 3241
+            max_connections = 16
+            request_timeout_secs = 0
+            device_mode = true
+
+            [[policy.allow]]
+            id_vendor = 0x046d
+
+            [[policy.deny]]
+            client = "192.168.1.20"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.addresses,
+            [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            ]
+        );
+        assert_eq!(config.port, 3241);
+        assert!(config.device_mode);
+        assert_eq!(
+            config.policy.allow,
+            [PolicyRule {
+                id_vendor: Some(0x046d),
+                ..Default::default()
+            }]
+        );
+        assert_eq!(
+            config.policy.deny,
+            [PolicyRule {
+                client: Some("192.168.1.20".parse().unwrap()),
+                ..Default::default()
+            }]
+        );
+
+        let options = config.server_options();
+        assert_eq!(options.max_connections, 16);
+        assert_eq!(options.request_timeout, None);
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(ServerConfig::from_toml("prot = 3240").is_err());
+    }
+}
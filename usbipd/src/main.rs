@@ -1,3 +1,173 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use colored::Colorize;
+use tracing_subscriber::filter::LevelFilter;
+use usbip::{drivers::vudc::Vudc, server::daemon::serve_all};
+
+use crate::config::ServerConfig;
+
+mod config;
+
+#[cfg(not(target_os = "linux"))]
+compile_error!("usbipd only supports Linux, since it relies on the usbip kernel modules");
+
+#[derive(clap::Parser)]
+#[clap(name = "usbipd")]
+struct Args {
+    /// Enables extra debug printing to STDERR
+    #[arg(short = 'd', long)]
+    debug: bool,
+    /// TOML file to read the configuration from. Flags passed on the command
+    /// line take precedence over the values in the file.
+    #[arg(short = 'c', long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Address to listen on for client connections [default: 0.0.0.0]
+    #[arg(short = 'a', long)]
+    address: Option<IpAddr>,
+    /// TCP port to listen on for client connections [default: 3240]
+    #[arg(short = 't', long)]
+    tcp_port: Option<u16>,
+    /// Maximum number of client connections serviced at the same time, across
+    /// all addresses [default: 64]
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// Seconds a client may take to send its request before the connection is
+    /// dropped, 0 to wait forever [default: 30]
+    #[arg(long, value_name = "SECS")]
+    request_timeout: Option<u64>,
+    /// Run in device mode, exporting the gadgets bound to the virtual UDCs of
+    /// `usbip-vudc` instead of the devices bound to `usbip-host`
+    #[arg(short = 'e', long)]
+    device: bool,
+}
+
+impl Args {
+    /// Overrides the values of the config with those passed on the command line
+    fn apply_to(&self, config: &mut ServerConfig) {
+        if let Some(address) = self.address {
+            config.addresses = vec![address];
+        }
+
+        if let Some(port) = self.tcp_port {
+            config.port = port;
+        }
+
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = max_connections;
+        }
+
+        if let Some(request_timeout) = self.request_timeout {
+            config.request_timeout_secs = request_timeout;
+        }
+
+        if self.device {
+            config.device_mode = true;
+        }
+    }
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_max_level(if args.debug {
+            LevelFilter::TRACE
+        } else {
+            LevelFilter::INFO
+        })
+        .with_writer(std::io::stderr)
+        .init();
+
+    let mut config = match &args.config {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .unwrap_or_else(|e| fail(format!("Failed to read `{}` ({e})", path.display())));
+
+            ServerConfig::from_toml(&text)
+                .unwrap_or_else(|e| fail(format!("Invalid config `{}`: {e}", path.display())))
+        }
+        None => ServerConfig::default(),
+    };
+
+    args.apply_to(&mut config);
+
+    if config.addresses.is_empty() {
+        fail("No addresses to listen on");
+    }
+
+    // fail early instead of on every request if usbip-vudc isn't loaded
+    if config.device_mode
+        && let Err(e) = Vudc::open()
+    {
+        fail(e);
+    }
+
+    let addrs: Vec<_> = config
+        .addresses
+        .iter()
+        .map(|&address| SocketAddr::new(address, config.port))
+        .collect();
+
+    if let Err(e) = serve_all(&addrs, config.server_options()) {
+        fail(e);
+    }
+}
+
+fn fail(e: impl Display) -> ! {
+    eprintln!("{} {e}", "Error:".red());
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        addresses = ["::1"]
+        port = 3241
+        max_connections = 16
+        request_timeout_secs = 5
+    "#;
+
+    #[test]
+    fn flags_take_precedence_over_the_config_file() {
+        let mut config = ServerConfig::from_toml(CONFIG).unwrap();
+
+        Args::parse_from([
+            "usbipd",
+            "--address",
+            "127.0.0.1",
+            "--tcp-port",
+            "3240",
+            "--request-timeout",
+            "0",
+            "--device",
+        ])
+        .apply_to(&mut config);
+
+        assert_eq!(config.addresses, ["127.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(config.port, 3240);
+        assert_eq!(config.request_timeout_secs, 0);
+        assert!(config.device_mode);
+        // not passed on the command line, so the file's value is kept
+        assert_eq!(config.max_connections, 16);
+    }
+
+    #[test]
+    fn no_flags_keep_the_config_file() {
+        let mut config = ServerConfig::from_toml(CONFIG).unwrap();
+
+        Args::parse_from(["usbipd"]).apply_to(&mut config);
+
+        assert_eq!(config.addresses, ["::1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(config.port, 3241);
+        assert_eq!(config.max_connections, 16);
+        assert_eq!(config.request_timeout_secs, 5);
+        assert!(!config.device_mode);
+    }
 }